@@ -0,0 +1,88 @@
+//! Benchmarks for the hot paths of autocomplete and fulltext search.
+//! exec_autocomp and exec_fulltext run against a testing::MockClient, so only pachydurable's own work is measured:
+//! cargo bench --features testing
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::runtime::Runtime;
+use tokio_postgres::types::Type;
+use pachydurable::autocomplete::{AutoComp, WhoWhatWhere};
+use pachydurable::client::RowLike;
+use pachydurable::fulltext::{exec_fulltext, sanitize_tsquery, ts_expression, FullText};
+use pachydurable::testing::{MockClient, MockRow};
+
+struct Animal {
+    _id: i32,
+    _name: String,
+}
+
+impl AutoComp<i32> for Animal {
+    fn query_autocomp() -> &'static str {
+        "SELECT id, name FROM animals WHERE autocomp_tsv @@ to_tsquery('simple', $1) ORDER BY LENGTH(name) ASC LIMIT 5;"
+    }
+    fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+        WhoWhatWhere::new("animal", row.get(0), row.get(1))
+    }
+}
+
+impl FullText for Animal {
+    fn query_fulltext() -> &'static str {
+        "SELECT id, name FROM animals WHERE fulltext_tsv @@ to_tsquery('english', $1) LIMIT 10;"
+    }
+    fn rowfunc_fulltext<R: RowLike>(row: &R) -> Self {
+        Animal{_id: row.get(0), _name: row.get(1)}
+    }
+}
+
+const WORDS: &[&str] = &["The", "quick", "brown", "fox", "&", "jumps", "over!", "the", "lazy", "dog's", "(tail)", "|", "again"];
+
+// a phrase of roughly len characters, with stopwords and tsquery operators mixed in
+fn phrase_of_len(len: usize) -> String {
+    let mut phrase = String::new();
+    let mut i = 0;
+    while phrase.len() < len {
+        phrase.push_str(WORDS[i % WORDS.len()]);
+        phrase.push(' ');
+        i += 1;
+    }
+    phrase.truncate(len);
+    phrase
+}
+
+fn animal_rows(n: usize) -> Vec<MockRow> {
+    (0..n).map(|i| MockRow::new().with("id", Type::INT4, &(i as i32)).with("name", Type::TEXT, &format!("animal {}", i))).collect()
+}
+
+fn bench_sanitize_tsquery(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sanitize_tsquery");
+    for len in [1, 10, 25, 50, 100] {
+        let phrase = phrase_of_len(len);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &phrase, |b, phrase| b.iter(|| sanitize_tsquery(phrase)));
+    }
+    group.finish();
+}
+
+fn bench_ts_expression(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ts_expression");
+    for words in [1, 3, 8] {
+        let phrase = WORDS[..words].join(" ");
+        group.bench_with_input(BenchmarkId::from_parameter(words), &phrase, |b, phrase| b.iter(|| ts_expression(phrase)));
+    }
+    group.finish();
+}
+
+fn bench_exec(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    // a MockClient replays each reply once, so each iteration gets a fresh one
+    c.bench_function("exec_autocomp_mock_5_rows", |b| b.to_async(&rt).iter(|| async {
+        let client = MockClient::new().with_rows(animal_rows(5));
+        Animal::exec_autocomp(&client, "fi").await.unwrap()
+    }));
+    c.bench_function("exec_fulltext_mock_10_rows", |b| b.to_async(&rt).iter(|| async {
+        let client = MockClient::new().with_rows(animal_rows(10));
+        let hits: Vec<Animal> = exec_fulltext(&client, "swims in the sea").await.unwrap();
+        hits
+    }));
+}
+
+criterion_group!(benches, bench_sanitize_tsquery, bench_ts_expression, bench_exec);
+criterion_main!(benches);
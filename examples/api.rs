@@ -1,14 +1,17 @@
-use std::{sync::Arc, fmt, error::Error};
+use std::{sync::Arc, fmt, error::Error, time::Duration};
 use serde::Serialize;
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
-use hyperactive::server::{self, build_response_json, get_query_param, ServerError};
-use tokio_postgres::row::Row;
-use pachydurable::autocomplete::{WhoWhatWhere, AutoComp}; // bring the trait into scope
-use pachydurable::fulltext::FullText; // bring the trait into scope
+use hyper::{Body, Method, Request, Response, Server, StatusCode, header};
+use hyperactive::server::{self, ServerError};
+use pachydurable::client::RowLike;
+use pachydurable::autocomplete::builder::{AutocompSpec, OrderBy};
+use pachydurable::impl_autocomp;
+use pachydurable::fulltext::{FullText, exec_fulltext}; // bring the trait into scope
 use pachydurable::connect::{ConnPoolNoTLS, ClientNoTLS};
 use pachydurable::err::PachyDarn;
+use pachydurable::redis::{CachedAutoComp, PreWarmDepth, RedisPool};
+use pachydurable::http_server::{switch_psql_handler, cached_autocomp_handler, build_response_json, unknown_data_type, AutocompRegistry, CorsPolicy, SwitchFuture};
 
 static INDEX: &[u8] = b"Hello from Rust -> Tokio -> Hyper -> Pachydurable !";
 static NOTFOUND: &[u8] = b"Not Found";
@@ -22,19 +25,17 @@ struct Animal {
     description: Option<String>,
 }
 
-impl AutoComp<i32> for Animal {
-    fn query_autocomp() ->  & 'static str {
-        "SELECT id, name 
-        FROM animals
-        WHERE autocomp_tsv @@ to_tsquery('simple', $1)
-        ORDER BY LENGTH(name) ASC 
-        LIMIT 5;"
+impl_autocomp!(Animal, i32, AutocompSpec::new("animals").data_type("animal").order(OrderBy::NameLength).limit(5));
+
+impl CachedAutoComp<i32> for Animal {
+    fn dtype() -> &'static str {
+        "animal"
     }
-    fn rowfunc_autocomp(row: &tokio_postgres::Row) -> WhoWhatWhere<i32> {
-        let data_type = "animal".to_string();
-        let pk: i32 = row.get(0);
-        let name: String = row.get(1);
-        WhoWhatWhere{data_type, pk, name}
+    fn seconds_expiry() -> usize {
+        60*10
+    }
+    fn prewarm_depth() -> PreWarmDepth {
+        PreWarmDepth::Char1
     }
 }
 
@@ -45,7 +46,7 @@ impl FullText for Animal {
         WHERE fulltext_tsv @@ to_tsquery('english', $1)
         LIMIT 10;"
     }
-    fn rowfunc_fulltext(row: &Row) -> Self {
+    fn rowfunc_fulltext<R: RowLike>(row: &R) -> Self {
         let id: i32 = row.get(0);
         let name: String = row.get(1);
         let description: Option<String> = row.get(2);
@@ -61,18 +62,17 @@ struct Food {
     color: Option<String>
 }
 
-impl AutoComp<String> for Food {
-    fn query_autocomp() ->  &'static str {
-        "SELECT name
-        FROM foods 
-        WHERE autocomp_tsv @@ to_tsquery('simple', $1)
-        LIMIT 10;"
+impl_autocomp!(Food, String, AutocompSpec::new("foods").data_type("food").pk_columns(&["name"]).limit(10));
+
+impl CachedAutoComp<String> for Food {
+    fn dtype() -> &'static str {
+        "food"
     }
-    fn rowfunc_autocomp(row: &tokio_postgres::Row) -> WhoWhatWhere<String> {
-        let data_type = "food".to_string();
-        let pk: String = row.get(0);
-        let name: String = row.get(0);
-        WhoWhatWhere{data_type, pk, name}
+    fn seconds_expiry() -> usize {
+        60*60
+    }
+    fn prewarm_depth() -> PreWarmDepth {
+        PreWarmDepth::Char2
     }
 }
 
@@ -83,7 +83,7 @@ impl FullText for Food {
         WHERE fulltext_tsv @@ to_tsquery('english', $1)
         LIMIT 10;"
     }
-    fn rowfunc_fulltext(row: &Row) -> Self {
+    fn rowfunc_fulltext<R: RowLike>(row: &R) -> Self {
         let name: String = row.get(0);
         let color: Option<String> = row.get(1);
         Food{name, color}
@@ -92,11 +92,11 @@ impl FullText for Food {
 
 
 
+// PachyDarn covers everything pachydurable can throw; hyper errors are boxed up
 #[derive(Debug)]
 enum MyCustomError {
     Pachy(PachyDarn),
-    Hyper(hyper::Error),
-    HyperHTTP(hyper::http::Error),
+    Http(Box<dyn Error + Send + Sync>),
     Hyperactive(ServerError),
 }
 
@@ -116,13 +116,13 @@ impl From<PachyDarn> for MyCustomError {
 
 impl From<hyper::Error> for MyCustomError {
     fn from(err: hyper::Error) -> Self {
-        MyCustomError::Hyper(err)
+        MyCustomError::Http(Box::new(err))
     }
 }
 
 impl From<hyper::http::Error> for MyCustomError {
     fn from(err: hyper::http::Error) -> Self  {
-        MyCustomError::HyperHTTP(err)
+        MyCustomError::Http(Box::new(err))
     }
 }
 
@@ -132,75 +132,43 @@ impl From<ServerError> for MyCustomError {
     }
 }
 
-impl From<server::ArgError> for MyCustomError {
-    fn from(err: server::ArgError) -> Self {
-        let srverr = server::ServerError::from(err);
-        MyCustomError::from(srverr)
-    }
-}
 
 
-// this function matches the data_type=, q= params from a request to return a vector of WhoWhatWhere<PK> structs
-async fn autocomp_switcher(req: &Request<Body>, client: &ClientNoTLS) -> Result<Response<Body>, MyCustomError> {
-    let data_type: String = get_query_param(&req, "data_type")?;
-    let phrase: String = get_query_param(&req, "q")?;
-    match data_type.as_ref() {
-        "animal"  => {
-            let hits = Animal::exec_autocomp(client, &phrase).await?;
-			Ok(build_response_json(&hits)?)
-        },
-        "food"  => {
-            let hits = Food::exec_autocomp(client, &phrase).await?;
-			Ok(build_response_json(&hits)?)
-        },
-        _ => {
-            Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(format!("Unknown data type {}", &data_type).into())
-                ?)
-        },
-    }
+// this function matches the data_type to return a vector of <T> fulltext hits for the phrase
+fn fulltext_switcher<'a>(data_type: &'a str, phrase: &'a String, client: &'a ClientNoTLS) -> SwitchFuture<'a> {
+    Box::pin(async move {
+        match data_type {
+            "animal" => build_response_json(&exec_fulltext::<Animal>(client, phrase).await?),
+            "food" => build_response_json(&exec_fulltext::<Food>(client, phrase).await?),
+            _ => Err(unknown_data_type(data_type)),
+        }
+    })
 }
 
 
-// this function matches the data_type=, q= params from a request to return a vector of <T> fulltext hits
-async fn fulltext_switcher(req: &Request<Body>, client: &ClientNoTLS) -> Result<Response<Body>, MyCustomError> {
-    let data_type: String = get_query_param(&req, "data_type")?;
-    let phrase: String = get_query_param(&req, "q")?;
-    match data_type.as_ref() {
-        "animal"  => {
-            let hits: Vec<Animal> = pachydurable::fulltext::exec_fulltext(client, &phrase).await?;
-			Ok(build_response_json(&hits)?)
-        },
-        "food"  => {
-            let hits: Vec<Food> = pachydurable::fulltext::exec_fulltext(client, &phrase).await?;
-            Ok(build_response_json(&hits)?)
-        },
-        _ => {
-            Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(format!("Unknown data type {}", &data_type).into())
-                ?)
-        },
-    }
-}
-async fn request_router(req: Request<Body>, arc_pool: Arc<ConnPoolNoTLS>, _ip_address: String) -> Result<Response<Body>, MyCustomError> {
+async fn request_router(req: Request<Body>, arc_pool: Arc<ConnPoolNoTLS>, arc_rpool: Arc<RedisPool>, arc_registry: Arc<AutocompRegistry>, arc_cors: Arc<CorsPolicy>, _ip_address: String) -> Result<Response<Body>, MyCustomError> {
     /* Notice a pattern in the signature for this function:
     All the arguments consume them, but then the routing consumes a reference to the consumed arguments */
     let _hdrs = server::get_common_headers(&req);
-    let client = arc_pool.get().await.unwrap();
-    match (req.method(), req.uri().path()) {
-        (&Method::OPTIONS, _) => Ok(server::preflight_cors(req).await?),
-        (&Method::GET,  "/") => Ok(Response::new(INDEX.into())),
-        (&Method::GET, "/autocomp") => Ok(autocomp_switcher(&req, &client).await?),
-        (&Method::GET, "/fulltext") => Ok(fulltext_switcher(&req, &client).await?),
+    let mut resp = match (req.method(), req.uri().path()) {
+        (&Method::OPTIONS, _) => return Ok(arc_cors.preflight(&req)),
+        (&Method::GET,  "/") => Response::new(INDEX.into()),
+        // the handler only checks a Postgres client out of the pool on a cache miss
+        (&Method::GET, "/autocomp") => cached_autocomp_handler(&req, &arc_registry, &arc_rpool, &arc_pool).await,
+        (&Method::GET, "/fulltext") => {
+            let client = arc_pool.get().await.unwrap();
+            switch_psql_handler(&req, &client, fulltext_switcher).await
+        },
         _ => { // Return 404 not found response.
-            Ok(Response::builder()
+            Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(NOTFOUND.into())
-                ?)
+                ?
         }
-    }
+    };
+    // add the CORS headers the policy allows for the request's Origin 
+    arc_cors.apply(req.headers().get(header::ORIGIN), &mut resp);
+    Ok(resp)
 }
 
 
@@ -210,17 +178,33 @@ async fn main() -> Result<(), MyCustomError> {
 
     // Initialize stuff that needs unwrapped. If you're gonna fail, fail early
     let arc_pool = Arc::new(pachydurable::connect::pool_no_tls_from_env().await?);
+    let arc_rpool = Arc::new(pachydurable::redis::new_pool_from_env().await?);
+    // register each type that implements CachedAutoComp with its data_type
+    let arc_registry = Arc::new(AutocompRegistry::new()
+        .register::<i32, Animal>("animal")
+        .register::<String, Food>("food"));
+    // any origin may call this demo API. List your own origins (i.e. "https://*.example.com") in production
+    let arc_cors = Arc::new(CorsPolicy::new(
+        vec!["*".to_string()],
+        vec![Method::GET, Method::OPTIONS],
+        vec!["content-type".to_string()],
+        false,
+        Some(Duration::from_secs(3600)),
+    )?);
     
     let new_service = make_service_fn(move |conn: &AddrStream| {
         // the request_router consumes all its arguments so it can live as long as needed
         // clone whatever you need for it here 
         let arc_pool = arc_pool.clone();
+        let arc_rpool = arc_rpool.clone();
+        let arc_registry = arc_registry.clone();
+        let arc_cors = arc_cors.clone();
         let remote_addr = conn.remote_addr();
         let ip_address = remote_addr.ip().to_string();
         async {
             Ok::<_, MyCustomError>(service_fn(move |req| {
                 // Clone again to ensure everything you need outlives this closure.
-                request_router(req, arc_pool.to_owned(), ip_address.to_owned())
+                request_router(req, arc_pool.to_owned(), arc_rpool.to_owned(), arc_registry.to_owned(), arc_cors.to_owned(), ip_address.to_owned())
             }))
         }
     });
@@ -19,3 +19,11 @@ sudo docker run -d --rm  \
   --env=POSTGRES_DB=${PSQL_DB} \
   --name=pachydurable-demo \
   postgres:15.1 
+
+# The /autocomp endpoint caches results in Redis
+echo ""
+echo "spinning up the 'pachydurable-demo-redis' redis container with REDIS_PORT=${REDIS_PORT:=6379}"
+sudo docker run -d --rm  \
+  -p "127.0.0.1:${REDIS_PORT}:6379" \
+  --name=pachydurable-demo-redis \
+  redis:7.0
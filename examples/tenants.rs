@@ -0,0 +1,91 @@
+// A multi-tenant API where Postgres row-level security keeps each tenant's notes apart.
+// The tenant comes from the X-Tenant-Id request header, and connect::with_tenant sets it as app.tenant_id
+// for the request's transaction, which the policy on the notes table reads with current_setting.
+//
+// Superusers bypass row-level security, so connect as a regular role, i.e.
+// CREATE ROLE notes_app LOGIN PASSWORD 'abc123'; GRANT SELECT, INSERT ON notes TO notes_app;
+// export PSQL_USER=notes_app
+// cargo run --example tenants --features hyper
+// curl -H "X-Tenant-Id: 1" "http://127.0.0.1:8080/notes?q=invoice"
+use std::{convert::Infallible, sync::Arc};
+use serde::Serialize;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use pachydurable::client::RowLike;
+use pachydurable::connect::{with_tenant, ConnPoolNoTLS};
+use pachydurable::err::PachyDarn;
+use pachydurable::fulltext::{FullText, exec_fulltext};
+use pachydurable::http_server::{build_response_json, error_response, get_query_param};
+
+// run once by a superuser or the table owner
+#[allow(dead_code)]
+const SCHEMA_SQL: &str = "CREATE TABLE IF NOT EXISTS notes (
+    id SERIAL PRIMARY KEY,
+    tenant_id INT NOT NULL,
+    body VARCHAR NOT NULL,
+    fulltext_tsv tsvector GENERATED ALWAYS AS (to_tsvector('english', body)) STORED
+);
+ALTER TABLE notes ENABLE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_notes ON notes;
+CREATE POLICY tenant_notes ON notes USING (tenant_id = current_setting('app.tenant_id')::int);";
+
+
+#[derive(Serialize)]
+struct Note {
+    id: i32,
+    body: String,
+}
+
+// there's no tenant filter here- the policy adds it
+impl FullText for Note {
+    fn query_fulltext() -> &'static str {
+        "SELECT id, body FROM notes WHERE fulltext_tsv @@ to_tsquery('english', $1) LIMIT 20"
+    }
+    fn rowfunc_fulltext<R: RowLike>(row: &R) -> Self {
+        Note{id: row.get(0), body: row.get(1)}
+    }
+}
+
+
+// the tenant ID must be a number, so a bad header is a 400 rather than reaching Postgres
+fn tenant_id(req: &Request<Body>) -> Result<i32, PachyDarn> {
+    req.headers().get("x-tenant-id")
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.parse::<i32>().ok())
+        .ok_or_else(|| PachyDarn::custom_with_status("missing_tenant", "the X-Tenant-Id header must be a tenant ID", 400))
+}
+
+async fn search_notes(req: &Request<Body>, pool: &ConnPoolNoTLS) -> Result<Response<Body>, PachyDarn> {
+    let tenant = tenant_id(req)?.to_string();
+    let phrase: String = get_query_param(req, "q")?;
+    let mut client = pool.get().await?;
+    // app.tenant_id only lasts as long as the guard, so the pooled connection can't carry it into another request
+    let guard = with_tenant(&mut client, &[("app.tenant_id", &tenant)]).await?;
+    let notes: Vec<Note> = exec_fulltext(&guard, &phrase).await?;
+    build_response_json(&notes)
+}
+
+async fn request_router(req: Request<Body>, pool: Arc<ConnPoolNoTLS>) -> Result<Response<Body>, Infallible> {
+    let resp = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/notes") => search_notes(&req, &pool).await,
+        _ => Err(PachyDarn::custom_with_status("not_found", "Not Found", StatusCode::NOT_FOUND.as_u16())),
+    };
+    Ok(resp.unwrap_or_else(|err| error_response(&err)))
+}
+
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let pool = Arc::new(pachydurable::connect::pool_no_tls_from_env().await?);
+    let new_service = make_service_fn(move |_conn| {
+        let pool = pool.clone();
+        async {
+            Ok::<_, Infallible>(service_fn(move |req| request_router(req, pool.to_owned())))
+        }
+    });
+    let bind_to = "0.0.0.0:8080".parse().unwrap();
+    let server = Server::bind(&bind_to).serve(new_service);
+    println!("Listening on http://{}", &bind_to);
+    server.await?;
+    Ok(())
+}
@@ -0,0 +1,378 @@
+//! The analytics module records what people search for, i.e. to find phrases that return no results.
+//! A SearchAnalytics sink is handed to redis::cached_autocomp_with_analytics (or to record_search, after
+//! calling exec_autocomp etc. directly), which records the phrase, the number of hits, and whether they came from the cache:
+//! ```ignore
+//! let analytics = PgSearchLog::spawn(pool.clone(), SearchLogConfig::default());
+//! let hits = cached_autocomp_with_analytics::<i32, Animal>(&rpool, &client, &phrase, Some(&analytics)).await?;
+//! ```
+//! Two sinks are included: RedisSearchCounts counts searches per phrase and day in Redis hashes,
+//! and PgSearchLog writes each search to a search_log table (see SEARCH_LOG_SQL) in batches on a background task.
+//! Recording is best effort: a sink that fails is logged, and the search is returned regardless
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+use crate::{client::{PachyClient, PgPoolLike}, err::PachyDarn, utils::pachy_log};
+#[cfg(feature = "redis")]
+use std::collections::HashMap;
+#[cfg(feature = "redis")]
+use crate::redis::{rediserde, RedisPool};
+
+
+/// Somewhere to record searches. See the module documentation
+#[async_trait]
+pub trait SearchAnalytics: Send + Sync {
+    /// Record one search for the dtype (i.e. CachedAutoComp::dtype()) and phrase
+    async fn record(&self, dtype: &str, phrase: &str, hit_count: usize, from_cache: bool) -> Result<(), PachyDarn>;
+}
+
+/// Record a search with the sink, if there is one. An error is logged rather than returned, so analytics can't fail a search
+pub async fn record_search(analytics: Option<&dyn SearchAnalytics>, dtype: &str, phrase: &str, hit_count: usize, from_cache: bool) {
+    if let Some(analytics) = analytics {
+        if let Err(e) = analytics.record(dtype, phrase, hit_count, from_cache).await {
+            pachy_log!(warn, "pachydurable::analytics", "failed to record the {} search \"{}\": {}", dtype, phrase, e);
+        }
+    }
+}
+
+
+// the UTC date of a time as YYYY-MM-DD, without chrono (see http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+#[cfg_attr(not(feature = "redis"), allow(dead_code))]
+fn utc_date(time: SystemTime) -> String {
+    let days = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs() / 86400).unwrap_or(0) as i64;
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+
+/// Counts searches in a Redis hash per dtype and (UTC) day, keyed by the phrase after fulltext::normalize_phrase, i.e.
+/// search_counts_animal_2023-04-05 = {"fi": 12, "fis": 3}. Searches with no hits are also counted in
+/// search_counts_zero_hits_animal_2023-04-05. Each day's hashes expire retention_days after they were last written
+#[cfg(feature = "redis")]
+pub struct RedisSearchCounts {
+    pool: RedisPool,
+    key_prefix: String,
+    retention_days: usize,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSearchCounts {
+    /// Counts kept for 30 days, under keys starting with search_counts
+    pub fn new(pool: RedisPool) -> Self {
+        RedisSearchCounts{pool, key_prefix: "search_counts".to_string(), retention_days: 30}
+    }
+
+    pub fn key_prefix(mut self, key_prefix: &str) -> Self {
+        self.key_prefix = key_prefix.to_string();
+        self
+    }
+
+    pub fn retention_days(mut self, retention_days: usize) -> Self {
+        self.retention_days = retention_days;
+        self
+    }
+
+    /// The key of the hash counting searches for the dtype on the date (YYYY-MM-DD)
+    pub fn counts_key(&self, dtype: &str, date: &str) -> String {
+        format!("{}_{}_{}", self.key_prefix, dtype, date)
+    }
+
+    /// The key of the hash counting searches with no hits for the dtype on the date (YYYY-MM-DD)
+    pub fn zero_hits_key(&self, dtype: &str, date: &str) -> String {
+        format!("{}_zero_hits_{}_{}", self.key_prefix, dtype, date)
+    }
+
+    /// The searches for the dtype on the date (YYYY-MM-DD) by phrase
+    pub async fn counts(&self, dtype: &str, date: &str) -> Result<HashMap<String, u64>, PachyDarn> {
+        rediserde::hgetall_u64(&self.pool, &self.counts_key(dtype, date)).await
+    }
+
+    /// The searches with no hits for the dtype on the date (YYYY-MM-DD) by phrase
+    pub async fn zero_hits(&self, dtype: &str, date: &str) -> Result<HashMap<String, u64>, PachyDarn> {
+        rediserde::hgetall_u64(&self.pool, &self.zero_hits_key(dtype, date)).await
+    }
+
+    /// The (at most) limit most searched phrases for the dtype over the last days UTC days (including today), most searched first.
+    /// i.e. for redis::PhraseSource::TopSearched
+    pub async fn top_phrases(&self, dtype: &str, days: usize, limit: usize) -> Result<Vec<String>, PachyDarn> {
+        let now = SystemTime::now();
+        let mut totals: HashMap<String, u64> = HashMap::new();
+        for day in 0..days as u64 {
+            let date = utc_date(now - Duration::from_secs(day * 86400));
+            for (phrase, count) in self.counts(dtype, &date).await? {
+                *totals.entry(phrase).or_insert(0) += count;
+            }
+        }
+        let mut ranked: Vec<(String, u64)> = totals.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Ok(ranked.into_iter().take(limit).map(|(phrase, _)| phrase).collect())
+    }
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl SearchAnalytics for RedisSearchCounts {
+    async fn record(&self, dtype: &str, phrase: &str, hit_count: usize, _from_cache: bool) -> Result<(), PachyDarn> {
+        let date = utc_date(SystemTime::now());
+        let phrase = crate::fulltext::normalize_phrase(phrase);
+        let seconds_expiry = self.retention_days * 86400;
+        rediserde::hincr_ex(&self.pool, &self.counts_key(dtype, &date), &phrase, seconds_expiry).await?;
+        if hit_count == 0 {
+            rediserde::hincr_ex(&self.pool, &self.zero_hits_key(dtype, &date), &phrase, seconds_expiry).await?;
+        }
+        Ok(())
+    }
+}
+
+
+/// The table PgSearchLog writes to
+pub const SEARCH_LOG_SQL: &str = "CREATE TABLE IF NOT EXISTS search_log (
+    dtype VARCHAR NOT NULL,
+    phrase VARCHAR NOT NULL,
+    hit_count INT NOT NULL,
+    from_cache BOOLEAN NOT NULL,
+    searched_at TIMESTAMPTZ NOT NULL
+);";
+
+/// How PgSearchLog batches its writes
+#[derive(Debug, Clone)]
+pub struct SearchLogConfig {
+    /// a batch is written once it has this many searches
+    pub batch_size: usize,
+    /// and a partial batch is written this often
+    pub flush_interval: Duration,
+    /// searches waiting to be batched, beyond which record returns an error of kind search_log_full
+    pub capacity: usize,
+}
+
+impl Default for SearchLogConfig {
+    fn default() -> Self {
+        SearchLogConfig{batch_size: 100, flush_interval: Duration::from_secs(5), capacity: 10_000}
+    }
+}
+
+// one search waiting to be written
+struct SearchLogEntry {
+    dtype: String,
+    phrase: String,
+    hit_count: i32,
+    from_cache: bool,
+    searched_at: SystemTime,
+}
+
+/// Writes searches to the search_log table (see SEARCH_LOG_SQL) in batches. record only queues the search on a bounded
+/// channel, so it never waits for Postgres: a background task writes a batch when it is full or the flush_interval passes.
+/// A batch that fails to write is logged and dropped
+pub struct PgSearchLog {
+    tx: mpsc::Sender<SearchLogEntry>,
+}
+
+impl PgSearchLog {
+    /// Spawn the task writing batches with clients from the pool. It has to be called within a tokio runtime.
+    /// Once the PgSearchLog is dropped, the task writes what is left and ends
+    pub fn spawn<P: PgPoolLike + Send + 'static>(pool: P, config: SearchLogConfig) -> Self {
+        let (tx, rx) = mpsc::channel(config.capacity.max(1));
+        tokio::spawn(write_batches(pool, rx, config));
+        PgSearchLog{tx}
+    }
+}
+
+#[async_trait]
+impl SearchAnalytics for PgSearchLog {
+    async fn record(&self, dtype: &str, phrase: &str, hit_count: usize, from_cache: bool) -> Result<(), PachyDarn> {
+        let entry = SearchLogEntry{dtype: dtype.to_string(), phrase: phrase.to_string(), hit_count: hit_count.min(i32::MAX as usize) as i32,
+            from_cache, searched_at: SystemTime::now()};
+        self.tx.try_send(entry).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => PachyDarn::custom("search_log_full", "the search_log queue is full, so the search was dropped"),
+            mpsc::error::TrySendError::Closed(_) => PachyDarn::custom("search_log_closed", "the task writing to search_log has ended"),
+        })
+    }
+}
+
+// the PgSearchLog background task
+async fn write_batches<P: PgPoolLike>(pool: P, mut rx: mpsc::Receiver<SearchLogEntry>, config: SearchLogConfig) {
+    let mut batch = Vec::with_capacity(config.batch_size);
+    // interval's first tick is immediate, so start a flush_interval from now rather than flushing the first entry alone
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + config.flush_interval, config.flush_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        let flush = tokio::select! {
+            entry = rx.recv() => match entry {
+                Some(entry) => {
+                    batch.push(entry);
+                    batch.len() >= config.batch_size
+                },
+                None => break,
+            },
+            _ = interval.tick() => !batch.is_empty(),
+        };
+        if flush {
+            write_batch(&pool, std::mem::take(&mut batch)).await;
+        }
+    }
+    if !batch.is_empty() {
+        write_batch(&pool, batch).await;
+    }
+}
+
+// insert the batch with one UNNEST, like connect::execute_unnest. The columns are bound as typed arrays rather than
+// execute_unnest's boxed values, since those aren't Send and the write happens on a spawned task
+async fn write_batch<P: PgPoolLike>(pool: &P, batch: Vec<SearchLogEntry>) {
+    let n = batch.len();
+    let mut dtypes = Vec::with_capacity(n);
+    let mut phrases = Vec::with_capacity(n);
+    let mut hit_counts = Vec::with_capacity(n);
+    let mut from_caches = Vec::with_capacity(n);
+    let mut searched_ats = Vec::with_capacity(n);
+    for entry in batch {
+        dtypes.push(entry.dtype);
+        phrases.push(entry.phrase);
+        hit_counts.push(entry.hit_count);
+        from_caches.push(entry.from_cache);
+        searched_ats.push(entry.searched_at);
+    }
+    let query = "INSERT INTO search_log (dtype, phrase, hit_count, from_cache, searched_at)
+        SELECT * FROM UNNEST($1::varchar[], $2::varchar[], $3::int[], $4::boolean[], $5::timestamptz[])";
+    let written = match pool.client().await {
+        Ok(client) => client.execute(query, &[&dtypes, &phrases, &hit_counts, &from_caches, &searched_ats]).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        pachy_log!(warn, "pachydurable::analytics", "failed to write {} searches to search_log: {}", n, e);
+    }
+}
+
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tokio::runtime::Runtime;
+    use crate::testing::TestDb;
+    use super::*;
+
+    #[test]
+    fn utc_dates() {
+        let day = |days: u64| utc_date(UNIX_EPOCH + Duration::from_secs(days * 86400 + 3600));
+        assert_eq!(day(0), "1970-01-01");
+        assert_eq!(day(59), "1970-03-01");
+        assert_eq!(day(11016), "2000-02-29");
+        assert_eq!(day(19453), "2023-04-06");
+    }
+
+    // records each search and then fails; also used by the redis tests
+    #[derive(Default)]
+    pub(crate) struct FailingSink {
+        pub(crate) calls: std::sync::Mutex<Vec<(String, usize, bool)>>,
+    }
+
+    #[async_trait]
+    impl SearchAnalytics for FailingSink {
+        async fn record(&self, _dtype: &str, phrase: &str, hit_count: usize, from_cache: bool) -> Result<(), PachyDarn> {
+            self.calls.lock().unwrap().push((phrase.to_string(), hit_count, from_cache));
+            Err(PachyDarn::custom("analytics_down", "the sink is down"))
+        }
+    }
+
+    #[test]
+    fn failures_are_swallowed() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let sink = FailingSink::default();
+            record_search(Some(&sink), "animal", "fi", 0, false).await;
+            record_search(None, "animal", "fi", 0, false).await;
+            assert_eq!(sink.calls.lock().unwrap().len(), 1);
+        })
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn redis_counts_by_phrase() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = crate::testing::TestRedis::new().await.unwrap();
+            let counts = RedisSearchCounts::new(test_redis.pool().clone()).key_prefix(&test_redis.key("search_counts"));
+            counts.record("animal", "Fi", 3, false).await.unwrap();
+            counts.record("animal", "fi", 3, true).await.unwrap();
+            counts.record("animal", "zzz", 0, false).await.unwrap();
+            let today = utc_date(SystemTime::now());
+            let by_phrase = counts.counts("animal", &today).await.unwrap();
+            assert_eq!((by_phrase["fi"], by_phrase["zzz"]), (2, 1));
+            let zero_hits = counts.zero_hits("animal", &today).await.unwrap();
+            assert_eq!(zero_hits.into_iter().collect::<Vec<_>>(), vec![("zzz".to_string(), 1)]);
+            assert_eq!(counts.top_phrases("animal", 7, 5).await.unwrap(), vec!["fi", "zzz"]);
+            assert_eq!(counts.top_phrases("animal", 7, 1).await.unwrap(), vec!["fi"]);
+        })
+    }
+
+    // poll until the search_log has n rows, or give up after a second
+    async fn wait_for_rows(db: &TestDb, n: i64) -> i64 {
+        let client = db.client().await.unwrap();
+        let mut count = 0;
+        for _ in 0..20 {
+            count = client.query_one("SELECT COUNT(*) FROM search_log", &[]).await.unwrap().get(0);
+            if count >= n {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        count
+    }
+
+    #[test]
+    fn search_log_flushes_on_size() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(SEARCH_LOG_SQL).await.unwrap();
+            let config = SearchLogConfig{batch_size: 3, flush_interval: Duration::from_secs(3600), capacity: 10};
+            let log = PgSearchLog::spawn(db.pool().clone(), config);
+            for phrase in ["a", "b"] {
+                log.record("animal", phrase, 1, false).await.unwrap();
+            }
+            // a partial batch waits for the interval
+            assert_eq!(wait_for_rows(&db, 1).await, 0);
+            log.record("animal", "c", 0, true).await.unwrap();
+            assert_eq!(wait_for_rows(&db, 3).await, 3);
+            let client = db.client().await.unwrap();
+            let row = client.query_one("SELECT hit_count, from_cache FROM search_log WHERE phrase = 'c'", &[]).await.unwrap();
+            assert_eq!((row.get::<_, i32>(0), row.get::<_, bool>(1)), (0, true));
+        })
+    }
+
+    #[test]
+    fn search_log_flushes_on_interval() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(SEARCH_LOG_SQL).await.unwrap();
+            let config = SearchLogConfig{batch_size: 100, flush_interval: Duration::from_millis(100), capacity: 10};
+            let log = PgSearchLog::spawn(db.pool().clone(), config);
+            log.record("animal", "fi", 2, false).await.unwrap();
+            assert_eq!(wait_for_rows(&db, 1).await, 1);
+        })
+    }
+
+    #[test]
+    fn full_search_log_is_an_error() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(SEARCH_LOG_SQL).await.unwrap();
+            // on a single thread the task only runs once this yields, so the queue fills up first
+            let config = SearchLogConfig{batch_size: 100, flush_interval: Duration::from_secs(3600), capacity: 1};
+            let log = PgSearchLog::spawn(db.pool().clone(), config);
+            log.record("animal", "a", 1, false).await.unwrap();
+            match log.record("animal", "b", 1, false).await {
+                Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "search_log_full"),
+                other => panic!("expected search_log_full, got {:?}", other),
+            }
+            // and record_search only logs it
+            record_search(Some(&log), "animal", "c", 1, false).await;
+        })
+    }
+}
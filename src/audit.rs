@@ -0,0 +1,193 @@
+//! The audit module keeps audit columns (updated_at, updated_by etc.) current without repeating them in every write.
+//! Implement Audited for a type, write its statements with a {touch} placeholder where the SET clause continues,
+//! and run them with execute_audited or query_audited, which fill it in:
+//! ```ignore
+//! impl Audited for Post {}
+//! execute_audited::<Post>(&client, "UPDATE posts SET title = $1{touch} WHERE id = $2", &[&title, &id], Some(&user_id)).await?;
+//! // UPDATE posts SET title = $1, "updated_at" = now(), "updated_by" = $3 WHERE id = $2
+//! ```
+//! The actor (i.e. the ID of the user making the change) is bound as an extra parameter after the others, and
+//! an {actor} placeholder is replaced with it too, i.e. for a created_by column in an INSERT:
+//! ```ignore
+//! query_audited::<Post>(&client, "INSERT INTO posts (title, created_by) VALUES ($1, {actor}) RETURNING id", &[&title], Some(&user_id)).await?;
+//! ```
+//! Without an actor, {actor} becomes NULL and only the touch_columns() are set
+
+use tokio_postgres::types::ToSql;
+use crate::{client::PachyClient, connect::quote_ident, err::PachyDarn};
+
+
+/// A type whose table has audit columns. The defaults suit a table with updated_at and updated_by columns
+pub trait Audited {
+    /// Columns set to now() by {touch}
+    fn touch_columns() -> &'static [&'static str] {
+        &["updated_at"]
+    }
+
+    /// Columns set to the actor by {touch}, when there is one
+    fn actor_columns() -> &'static [&'static str] {
+        &["updated_by"]
+    }
+
+    /// The table and its pk column, for touch_by_pk (and borg::write_pg_audited), i.e. Some(("posts", "id"))
+    fn audited_table() -> Option<(&'static str, &'static str)> {
+        None
+    }
+}
+
+// the col = value assignments for the audit columns, with the actor bound to $actor_param
+fn assignments<T: Audited>(actor_param: Option<usize>) -> Vec<String> {
+    let mut assignments: Vec<String> = T::touch_columns().iter().map(|col| format!("{} = now()", quote_ident(col))).collect();
+    if let Some(n) = actor_param {
+        assignments.extend(T::actor_columns().iter().map(|col| format!("{} = ${}", quote_ident(col), n)));
+    }
+    assignments
+}
+
+/// Fill in the {touch} and {actor} placeholders of the query, with the actor bound to $actor_param (if there is one).
+/// A query with neither placeholder is an error of kind audit_placeholder, since it would silently skip the audit columns
+pub fn audited_query<T: Audited>(query: &str, actor_param: Option<usize>) -> Result<String, PachyDarn> {
+    if !query.contains("{touch}") && !query.contains("{actor}") {
+        return Err(PachyDarn::custom("audit_placeholder", format!("the audited query for {} has no {{touch}} or {{actor}} placeholder: \"{}\"", std::any::type_name::<T>(), query)))
+    }
+    let touch: String = assignments::<T>(actor_param).iter().map(|assignment| format!(", {}", assignment)).collect();
+    let actor = actor_param.map(|n| format!("${}", n)).unwrap_or_else(|| "NULL".to_string());
+    Ok(query.replace("{touch}", &touch).replace("{actor}", &actor))
+}
+
+// the params with the actor (if any) appended, and the position it was bound to
+fn with_actor<'a>(params: &[&'a (dyn ToSql + Sync)], actor: Option<&'a (dyn ToSql + Sync)>) -> (Vec<&'a (dyn ToSql + Sync)>, Option<usize>) {
+    let mut all = params.to_vec();
+    let actor_param = actor.map(|actor| {
+        all.push(actor);
+        all.len()
+    });
+    (all, actor_param)
+}
+
+/// Run an audited statement (see the module documentation), returning the number of rows affected
+pub async fn execute_audited<T: Audited>(client: &impl PachyClient, query: &str, params: &[&(dyn ToSql + Sync)], actor: Option<&(dyn ToSql + Sync)>) -> Result<u64, PachyDarn> {
+    let (params, actor_param) = with_actor(params, actor);
+    let query = audited_query::<T>(query, actor_param)?;
+    client.execute(&query, &params).await
+}
+
+/// Like execute_audited, but return the rows, i.e. of an INSERT ... RETURNING or an upsert
+pub async fn query_audited<T: Audited, C: PachyClient>(client: &C, query: &str, params: &[&(dyn ToSql + Sync)], actor: Option<&(dyn ToSql + Sync)>) -> Result<Vec<C::Row>, PachyDarn> {
+    let (params, actor_param) = with_actor(params, actor);
+    let query = audited_query::<T>(query, actor_param)?;
+    client.query(&query, &params).await
+}
+
+/// Set the audit columns of the row with the pk in T::audited_table(), returning the number of rows affected.
+/// This is for writes whose SQL can't take a {touch} placeholder. T::audited_table() being None is an error of kind audited_table,
+/// and so is having no columns to set (no touch_columns(), and no actor or actor_columns()), rather than sending an invalid UPDATE
+pub async fn touch_by_pk<T: Audited>(client: &impl PachyClient, pk: &(dyn ToSql + Sync), actor: Option<&(dyn ToSql + Sync)>) -> Result<u64, PachyDarn> {
+    let (table, pk_column) = T::audited_table()
+        .ok_or_else(|| PachyDarn::custom("audited_table", format!("audited_table() of {} is None", std::any::type_name::<T>())))?;
+    let (params, actor_param) = with_actor(&[pk], actor);
+    let assignments = assignments::<T>(actor_param);
+    if assignments.is_empty() {
+        return Err(PachyDarn::custom("audited_table", format!("{} has no audit columns to set on {}", std::any::type_name::<T>(), table)))
+    }
+    let query = format!("UPDATE {} SET {} WHERE {} = $1", quote_ident(table), assignments.join(", "), quote_ident(pk_column));
+    client.execute(&query, &params).await
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use tokio::runtime::Runtime;
+    use crate::testing::TestDb;
+    use super::*;
+
+    struct Post;
+
+    impl Audited for Post {
+        fn audited_table() -> Option<(&'static str, &'static str)> {
+            Some(("posts", "id"))
+        }
+    }
+
+    struct Tag;
+
+    impl Audited for Tag {
+        fn touch_columns() -> &'static [&'static str] {
+            &["modified", "checked"]
+        }
+        fn actor_columns() -> &'static [&'static str] {
+            &[]
+        }
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(audited_query::<Post>("UPDATE posts SET title = $1{touch} WHERE id = $2", Some(3)).unwrap(),
+            "UPDATE posts SET title = $1, \"updated_at\" = now(), \"updated_by\" = $3 WHERE id = $2");
+        assert_eq!(audited_query::<Post>("INSERT INTO posts (title, created_by) VALUES ($1, {actor})", None).unwrap(),
+            "INSERT INTO posts (title, created_by) VALUES ($1, NULL)");
+        assert_eq!(audited_query::<Tag>("UPDATE tags SET name = $1{touch}", Some(2)).unwrap(),
+            "UPDATE tags SET name = $1, \"modified\" = now(), \"checked\" = now()");
+        match audited_query::<Post>("UPDATE posts SET title = $1, updated_at = now()", None) {
+            Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "audit_placeholder"),
+            other => panic!("expected audit_placeholder, got {:?}", other),
+        }
+    }
+
+    struct Untracked;
+
+    impl Audited for Untracked {
+        fn touch_columns() -> &'static [&'static str] {
+            &[]
+        }
+        fn audited_table() -> Option<(&'static str, &'static str)> {
+            Some(("untracked", "id"))
+        }
+    }
+
+    #[test]
+    fn touching_nothing_is_an_error() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = crate::testing::MockClient::new();
+            match touch_by_pk::<Untracked>(&client, &1i32, None).await {
+                Err(PachyDarn::Custom{kind, message, ..}) => {
+                    assert_eq!(kind, "audited_table");
+                    assert!(message.ends_with("has no audit columns to set on untracked"), "{}", message);
+                },
+                other => panic!("expected an audited_table error, got {:?}", other),
+            }
+            assert!(client.calls().is_empty());
+        })
+    }
+
+    #[test]
+    fn writes_touch_and_reads_dont() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE posts (id SERIAL PRIMARY KEY, title VARCHAR NOT NULL, created_by INT,
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now(), updated_by INT);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let rows = query_audited::<Post, _>(&client, "INSERT INTO posts (title, created_by) VALUES ($1, {actor}) RETURNING id, updated_at",
+                &[&"draft"], Some(&7i32)).await.unwrap();
+            let (id, created): (i32, SystemTime) = (rows[0].get(0), rows[0].get(1));
+            let updated_at = || async {
+                let rows = client.query("SELECT updated_at, updated_by, created_by FROM posts WHERE id = $1", &[&id]).await.unwrap();
+                (rows[0].get::<_, SystemTime>(0), rows[0].get::<_, Option<i32>>(1), rows[0].get::<_, Option<i32>>(2))
+            };
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            // a read leaves it alone
+            assert_eq!(updated_at().await, (created, None, Some(7)));
+            let n = execute_audited::<Post>(&client, "UPDATE posts SET title = $1{touch} WHERE id = $2", &[&"final", &id], Some(&8i32)).await.unwrap();
+            assert_eq!(n, 1);
+            let (touched, updated_by, created_by) = updated_at().await;
+            assert!(touched > created);
+            assert_eq!((updated_by, created_by), (Some(8), Some(7)));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(touch_by_pk::<Post>(&client, &id, None).await.unwrap(), 1);
+            assert!(updated_at().await.0 > touched);
+        })
+    }
+}
@@ -3,13 +3,13 @@
 //! for a struct from a given table matching an autocomplete query 
 
 // standard library
-use std::vec::Vec;
+use std::{borrow::Cow, vec::Vec};
 // crates.io
 use async_trait::async_trait;
 use serde::{Serialize, Deserialize};
-use tokio_postgres::row::Row;
+use tokio_postgres::types::ToSql;
 use crate::err::PachyDarn;
-use crate::{connect::ClientNoTLS, fulltext::ts_expression};
+use crate::{client::{PachyClient, RowLike}, fulltext::{normalize_phrase, ts_expression_cfg}};
 
 
 
@@ -17,11 +17,33 @@ use crate::{connect::ClientNoTLS, fulltext::ts_expression};
 /// The WhoWhatWhere sruct is a reference to one item of a given type
 /// The generic PK field contains the primary key for the row in the table-
 /// be it an integer, a string, or a tuple etc.
+/// Build one with a struct literal, or with the WhoWhatWhere::new and with_score conveniences
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WhoWhatWhere<PK: Serialize+std::marker::Send > {
-    pub data_type: String,
+    /// Usually a constant, so it is borrowed rather than allocated for every row: WhoWhatWhere::new("animal", ..)
+    /// or Cow::Borrowed("animal"). An owned String works too, with .into(). Deserialized values are always owned
+    pub data_type: Cow<'static, str>,
     pub pk: PK,
-    pub name: String
+    pub name: String,
+    /// How well the item matched, i.e. its ts_rank, so a UI can sort or highlight the best matches.
+    /// None unless rowfunc_autocomp (or exec_autocomp_scored) sets it, and left out of the JSON when it is None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+}
+
+impl<PK: Serialize+std::marker::Send> WhoWhatWhere<PK> {
+    /// A hit with no score, i.e. WhoWhatWhere::new("animal", row.get(0), row.get(1)) in rowfunc_autocomp.
+    /// A &'static str data_type is borrowed, and a String is moved in
+    pub fn new(data_type: impl Into<Cow<'static, str>>, pk: PK, name: String) -> Self {
+        WhoWhatWhere{data_type: data_type.into(), pk, name, score: None}
+    }
+
+    /// Set the score, i.e. WhoWhatWhere::new("animal", row.get(0), row.get(1)).with_score(row.get(2)).
+    /// exec_autocomp_scored sets it from the query
+    pub fn with_score(mut self, score: f32) -> Self {
+        self.score = Some(score);
+        self
+    }
 }
 
 
@@ -57,26 +79,32 @@ pub struct WhoWhatWhere<PK: Serialize+std::marker::Send > {
 ///         ORDER BY LENGTH(name) ASC 
 ///         LIMIT 5;"
 ///     }
-///     fn rowfunc_autocomp(row: &tokio_postgres::Row) -> WhoWhatWhere<i32> {
-///         let data_type = "animal";
+///     fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
 ///         let id: i32 = row.get(0);
 ///         let name: String = row.get(1);
-///         WhoWhatWhere{data_type, pk: id, name}
+///         WhoWhatWhere::new("animal", id, name)
 ///     }
 /// }
 /// // You can then easily fetch autocomplete results like this:
 /// let hits = Animal::exec_autocomp(client, &phrase).await?;
 /// ```
+/// The client can be anything that implements PachyClient, so exec_autocomp can be unit tested with a testing::MockClient
 
 #[async_trait]
 pub trait AutoComp<PK: Serialize+std::marker::Send >: std::marker::Send {
     fn query_autocomp() -> &'static str;
-    fn rowfunc_autocomp(row: &Row) -> WhoWhatWhere<PK>;
-    async fn exec_autocomp(client: &ClientNoTLS, phrase: &str) -> Result<Vec<WhoWhatWhere<PK>>, PachyDarn> {
+    fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<PK>;
+    /// The text search configuration query_autocomp() uses in to_tsquery(...). 'simple' is usually right for
+    /// prefix matching, but override this if your query uses i.e. 'english' so the ts_expression matches (see ts_expression_cfg)
+    fn ts_config_autocomp() -> &'static str {
+        "simple"
+    }
+    async fn exec_autocomp<C: PachyClient>(client: &C, phrase: &str) -> Result<Vec<WhoWhatWhere<PK>>, PachyDarn> {
         let query = Self::query_autocomp();
-        let ts_expr = ts_expression(phrase);
+        let ts_expr = ts_expression_cfg(&normalize_phrase(phrase), Self::ts_config_autocomp());
+        let phrase = phrase.trim();
         let mut hits = Vec::new();
-        let rows = client.query(query,&[&ts_expr, &phrase]).await?;
+        let rows = client.query(query, &autocomp_params(query, &ts_expr, &phrase)).await?;
         for row in rows {
             let hit = Self::rowfunc_autocomp(&row);
             hits.push(hit);
@@ -85,11 +113,36 @@ pub trait AutoComp<PK: Serialize+std::marker::Send >: std::marker::Send {
     }
 }
 
-pub async fn exec_autocomp<PK: Serialize+std::marker::Send , T: AutoComp<PK>>(client: &ClientNoTLS, phrase: &str) -> Result<Vec<WhoWhatWhere<PK>>, PachyDarn> {
+// whether the query references $n itself, and not only i.e. $20
+fn references_param(query: &str, n: usize) -> bool {
+    let placeholder = format!("${}", n);
+    query.match_indices(&placeholder).any(|(i, _)| !query[i + placeholder.len()..].starts_with(|c: char| c.is_ascii_digit()))
+}
+
+/// The parameters an autocomplete query is run with: $1 is the ts_expression (of the phrase after normalize_phrase)
+/// and $2 the phrase as typed, only trimmed, so i.e. name LIKE $2 || '%' still sees its case and punctuation.
+/// Queries that don't reference $2 (i.e. ORDER BY LENGTH(name)) are only sent the ts_expression,
+/// since Postgres rejects a parameter the statement doesn't use
+pub(crate) fn autocomp_params<'a>(query: &str, ts_expr: &'a String, phrase: &'a &str) -> Vec<&'a (dyn ToSql + Sync)> {
+    if references_param(query, 2) {
+        vec![ts_expr, phrase]
+    } else {
+        vec![ts_expr]
+    }
+}
+
+pub async fn exec_autocomp<PK: Serialize+std::marker::Send , T: AutoComp<PK>>(client: &impl PachyClient, phrase: &str) -> Result<Vec<WhoWhatWhere<PK>>, PachyDarn> {
+    exec_autocomp_cfg::<PK, T>(client, phrase, T::ts_config_autocomp()).await
+}
+
+/// Like exec_autocomp, but the ts_expression is generated for the given text search configuration rather than
+/// T::ts_config_autocomp(), i.e. when one query_autocomp() serves several languages 
+pub async fn exec_autocomp_cfg<PK: Serialize+std::marker::Send , T: AutoComp<PK>>(client: &impl PachyClient, phrase: &str, ts_config: &str) -> Result<Vec<WhoWhatWhere<PK>>, PachyDarn> {
     let query = T::query_autocomp();
-    let ts_expr = ts_expression(phrase);
+    let ts_expr = ts_expression_cfg(&normalize_phrase(phrase), ts_config);
+    let phrase = phrase.trim();
     let mut hits = Vec::new();
-    let rows = client.query(query,&[&ts_expr, &phrase]).await?;
+    let rows = client.query(query, &autocomp_params(query, &ts_expr, &phrase)).await?;
     for row in rows {
         let hit = T::rowfunc_autocomp(&row);
         hits.push(hit);
@@ -97,3 +150,457 @@ pub async fn exec_autocomp<PK: Serialize+std::marker::Send , T: AutoComp<PK>>(cl
     Ok(hits)
 }
 
+
+/// Like exec_autocomp, but each hit's score is read from the third column of the row, which must be a ts_rank(...)
+/// (a real), i.e. SELECT id, name, ts_rank(autocomp_tsv, to_tsquery('simple', $1)) FROM animals WHERE ... ORDER BY 3 DESC.
+/// A query without the third column is an error of kind autocomp_score
+pub async fn exec_autocomp_scored<PK: Serialize+std::marker::Send , T: AutoComp<PK>>(client: &impl PachyClient, phrase: &str) -> Result<Vec<WhoWhatWhere<PK>>, PachyDarn> {
+    let query = T::query_autocomp();
+    let ts_expr = ts_expression_cfg(&normalize_phrase(phrase), T::ts_config_autocomp());
+    let phrase = phrase.trim();
+    let mut hits = Vec::new();
+    let rows = client.query(query, &autocomp_params(query, &ts_expr, &phrase)).await?;
+    for row in rows {
+        let score: f32 = row.try_get(2).map_err(|e| PachyDarn::custom("autocomp_score",
+            format!("exec_autocomp_scored::<{}> needs the ts_rank in the third column: {}", std::any::type_name::<T>(), e)))?;
+        hits.push(T::rowfunc_autocomp(&row).with_score(score));
+    }
+    Ok(hits)
+}
+
+
+/// Hand-writing query_autocomp() for many tables is error-prone: some queries order by length and some don't,
+/// some forget the LIMIT. AutocompSpec generates the query from the table's metadata instead, and the
+/// impl_autocomp! macro implements AutoComp with it:
+/// ```ignore
+/// impl_autocomp!(Animal, i32, AutocompSpec::new("animals").data_type("animal").order(OrderBy::NameLength).limit(5));
+/// // SELECT id, name FROM animals WHERE autocomp_tsv @@ to_tsquery('simple', $1) ORDER BY LENGTH(name) ASC LIMIT 5;
+/// impl_autocomp!(Food, String, AutocompSpec::new("foods").data_type("food").pk_columns(&["name"]).limit(10));
+/// // SELECT name FROM foods WHERE autocomp_tsv @@ to_tsquery('simple', $1) LIMIT 10;
+/// ```
+/// The PK columns are selected first, in order, followed by the name column (unless it is also a PK column).
+/// A composite PK is read into a tuple, i.e. impl_autocomp!(Membership, (i32, i32), spec.pk_columns(&["org_id", "user_id"]))
+pub mod builder {
+    use std::borrow::Cow;
+    use serde::Serialize;
+    use tokio_postgres::types::FromSqlOwned;
+    use crate::{client::RowLike, connect::quote_ident, primary_key::typed::TypedPK};
+    use super::WhoWhatWhere;
+
+    /// How autocomplete results are ordered
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum OrderBy {
+        /// No ORDER BY
+        None,
+        /// Shortest names first: ORDER BY LENGTH(name) ASC
+        NameLength,
+        /// Alphabetically: ORDER BY name ASC
+        Name,
+        /// Names starting with the phrase first, then the shortest: ORDER BY name ILIKE $2 || '%' DESC, LENGTH(name) ASC
+        PrefixFirst,
+    }
+
+    /// The metadata of a table queried for autocomplete results. See the module documentation
+    #[derive(Debug, Clone)]
+    pub struct AutocompSpec {
+        table: String,
+        data_type: Option<Cow<'static, str>>,
+        pk_columns: Vec<String>,
+        name_column: String,
+        tsv_column: String,
+        ts_config: String,
+        order: OrderBy,
+        limit: Option<u32>,
+    }
+
+    impl AutocompSpec {
+        /// A spec for the table with the defaults: PK column id, name column name, tsvector column autocomp_tsv,
+        /// text search configuration simple, no ORDER BY and no LIMIT
+        pub fn new(table: &str) -> Self {
+            AutocompSpec{
+                table: table.to_string(),
+                data_type: None,
+                pk_columns: vec!["id".to_string()],
+                name_column: "name".to_string(),
+                tsv_column: "autocomp_tsv".to_string(),
+                ts_config: "simple".to_string(),
+                order: OrderBy::None,
+                limit: None,
+            }
+        }
+
+        /// The data_type of each WhoWhatWhere. Defaults to the table name.
+        /// A &'static str is borrowed by every WhoWhatWhere, so rows don't allocate it
+        pub fn data_type(mut self, data_type: impl Into<Cow<'static, str>>) -> Self {
+            self.data_type = Some(data_type.into());
+            self
+        }
+
+        pub fn pk_columns(mut self, columns: &[&str]) -> Self {
+            self.pk_columns = columns.iter().map(|col| col.to_string()).collect();
+            self
+        }
+
+        pub fn name_column(mut self, column: &str) -> Self {
+            self.name_column = column.to_string();
+            self
+        }
+
+        pub fn tsv_column(mut self, column: &str) -> Self {
+            self.tsv_column = column.to_string();
+            self
+        }
+
+        /// The text search configuration passed to to_tsquery(...), and used as AutoComp::ts_config_autocomp()
+        pub fn ts_config(mut self, ts_config: &str) -> Self {
+            self.ts_config = ts_config.to_string();
+            self
+        }
+
+        pub fn order(mut self, order: OrderBy) -> Self {
+            self.order = order;
+            self
+        }
+
+        pub fn limit(mut self, limit: u32) -> Self {
+            self.limit = Some(limit);
+            self
+        }
+
+        /// Generate the query, one clause per line like a hand-written query_autocomp()
+        pub fn build(self) -> AutocompQuery {
+            let mut columns = self.pk_columns.clone();
+            let name_index = match columns.iter().position(|col| *col == self.name_column) {
+                Some(i) => i,
+                None => {
+                    columns.push(self.name_column.clone());
+                    columns.len() - 1
+                }
+            };
+            let select: Vec<String> = columns.iter().map(|col| ident(col)).collect();
+            let name = ident(&self.name_column);
+            let mut sql = format!("SELECT {}{}FROM {}{}WHERE {} @@ to_tsquery('{}', $1)", select.join(", "), CLAUSE_BREAK,
+                ident(&self.table), CLAUSE_BREAK, ident(&self.tsv_column), self.ts_config.replace('\'', "''"));
+            match self.order {
+                OrderBy::None => {},
+                OrderBy::NameLength => sql.push_str(&format!("{}ORDER BY LENGTH({}) ASC", CLAUSE_BREAK, name)),
+                OrderBy::Name => sql.push_str(&format!("{}ORDER BY {} ASC", CLAUSE_BREAK, name)),
+                OrderBy::PrefixFirst => sql.push_str(&format!("{}ORDER BY {} ILIKE $2 || '%' DESC, LENGTH({}) ASC", CLAUSE_BREAK, name, name)),
+            }
+            if let Some(limit) = self.limit {
+                sql.push_str(&format!("{}LIMIT {}", CLAUSE_BREAK, limit));
+            }
+            sql.push(';');
+            let data_type = self.data_type.unwrap_or_else(|| Cow::Owned(self.table.rsplit('.').next().unwrap_or(&self.table).to_string()));
+            AutocompQuery{sql, data_type, name_index, ts_config: self.ts_config}
+        }
+    }
+
+    // what each clause after the SELECT starts with, as in a query_autocomp() written in an impl
+    const CLAUSE_BREAK: &str = "\n        ";
+
+    // Quote an identifier (or each part of a schema-qualified one) only if it needs it, so the generated SQL 
+    // reads like hand-written SQL for ordinary lowercase names
+    fn ident(name: &str) -> String {
+        let needs_quotes = name.split('.').any(|part| {
+            let mut chars = part.chars();
+            let starts_ok = matches!(chars.next(), Some(c) if c.is_ascii_lowercase() || c == '_');
+            !starts_ok || !chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+                || RESERVED.contains(&part)
+        });
+        if needs_quotes { quote_ident(name) } else { name.to_string() }
+    }
+
+    // reserved words likely to be used as table or column names
+    const RESERVED: &[&str] = &["all", "and", "any", "array", "as", "asc", "case", "check", "column", "constraint", "create", 
+        "default", "desc", "distinct", "do", "else", "end", "for", "foreign", "from", "grant", "group", "having", "in", 
+        "into", "limit", "not", "null", "offset", "on", "only", "or", "order", "primary", "references", "select", "table", 
+        "then", "to", "union", "unique", "user", "using", "when", "where", "with"];
+
+    /// The query generated by AutocompSpec::build, and how to read its rows
+    #[derive(Debug, Clone)]
+    pub struct AutocompQuery {
+        sql: String,
+        data_type: Cow<'static, str>,
+        name_index: usize,
+        ts_config: String,
+    }
+
+    impl AutocompQuery {
+        pub fn sql(&self) -> &str {
+            &self.sql
+        }
+
+        pub fn data_type(&self) -> &str {
+            &self.data_type
+        }
+
+        pub fn ts_config(&self) -> &str {
+            &self.ts_config
+        }
+
+        /// Read a row of the query: the PK from the leading column(s), and the name
+        pub fn row_to_www<PK: PkColumns + Serialize + Send, R: RowLike>(&self, row: &R) -> WhoWhatWhere<PK> {
+            WhoWhatWhere::new(self.data_type.clone(), PK::from_columns(row, 0), row.get(self.name_index))
+        }
+    }
+
+    /// A primary key that can be read from one or more consecutive columns, starting at the column start
+    pub trait PkColumns: Sized {
+        fn from_columns<R: RowLike>(row: &R, start: usize) -> Self;
+    }
+
+    macro_rules! pk_column {
+        ($($t:ty),*) => {
+            $(
+                impl PkColumns for $t {
+                    fn from_columns<R: RowLike>(row: &R, start: usize) -> Self {
+                        row.get(start)
+                    }
+                }
+            )*
+        };
+    }
+
+    pk_column!(i16, i32, i64, String, bool);
+
+    #[cfg(feature = "uuid")]
+    pk_column!(uuid::Uuid);
+
+    impl<T: FromSqlOwned, const N: u64> PkColumns for TypedPK<T, N> {
+        fn from_columns<R: RowLike>(row: &R, start: usize) -> Self {
+            row.get(start)
+        }
+    }
+
+    impl<A: FromSqlOwned, B: FromSqlOwned> PkColumns for (A, B) {
+        fn from_columns<R: RowLike>(row: &R, start: usize) -> Self {
+            (row.get(start), row.get(start + 1))
+        }
+    }
+
+    impl<A: FromSqlOwned, B: FromSqlOwned, C: FromSqlOwned> PkColumns for (A, B, C) {
+        fn from_columns<R: RowLike>(row: &R, start: usize) -> Self {
+            (row.get(start), row.get(start + 1), row.get(start + 2))
+        }
+    }
+
+
+    /// Implement AutoComp for a type with a query generated from an AutocompSpec, i.e.
+    /// impl_autocomp!(Animal, i32, AutocompSpec::new("animals").data_type("animal").order(OrderBy::NameLength).limit(5));
+    /// The spec is built once, the first time the query is needed 
+    #[macro_export]
+    macro_rules! impl_autocomp {
+        ($t:ty, $pk:ty, $spec:expr) => {
+            const _: () = {
+                static QUERY: std::sync::OnceLock<$crate::autocomplete::builder::AutocompQuery> = std::sync::OnceLock::new();
+                fn query() -> &'static $crate::autocomplete::builder::AutocompQuery {
+                    QUERY.get_or_init(|| $spec.build())
+                }
+                impl $crate::autocomplete::AutoComp<$pk> for $t {
+                    fn query_autocomp() -> &'static str {
+                        query().sql()
+                    }
+                    fn rowfunc_autocomp<R: $crate::client::RowLike>(row: &R) -> $crate::autocomplete::WhoWhatWhere<$pk> {
+                        query().row_to_www(row)
+                    }
+                    fn ts_config_autocomp() -> &'static str {
+                        query().ts_config()
+                    }
+                }
+            };
+        };
+    }
+
+
+    #[cfg(test)]
+    mod tests {
+        use tokio::runtime::Runtime;
+        use tokio_postgres::types::Type;
+        use crate::{autocomplete::AutoComp, impl_autocomp, testing::{MockClient, MockRow}};
+        use super::*;
+
+        // the queries examples/api.rs used to write by hand (less their trailing spaces), which the specs below must reproduce
+        const ANIMAL_SQL: &str = "SELECT id, name
+        FROM animals
+        WHERE autocomp_tsv @@ to_tsquery('simple', $1)
+        ORDER BY LENGTH(name) ASC
+        LIMIT 5;";
+        const FOOD_SQL: &str = "SELECT name
+        FROM foods
+        WHERE autocomp_tsv @@ to_tsquery('simple', $1)
+        LIMIT 10;";
+
+        struct Animal;
+        impl_autocomp!(Animal, i32, AutocompSpec::new("animals").data_type("animal").order(OrderBy::NameLength).limit(5));
+
+        struct Food;
+        impl_autocomp!(Food, String, AutocompSpec::new("foods").data_type("food").pk_columns(&["name"]).limit(10));
+
+        #[test]
+        fn specs_reproduce_hand_written_queries() {
+            assert_eq!(Animal::query_autocomp(), ANIMAL_SQL);
+            assert_eq!(Food::query_autocomp(), FOOD_SQL);
+        }
+
+        #[test]
+        fn identifiers_and_composite_pks() {
+            let query = AutocompSpec::new("hr.Members")
+                .pk_columns(&["org_id", "user"])
+                .name_column("display name")
+                .ts_config("english")
+                .order(OrderBy::PrefixFirst)
+                .build();
+            assert_eq!(query.sql(), "SELECT org_id, \"user\", \"display name\"
+        FROM \"hr\".\"Members\"
+        WHERE autocomp_tsv @@ to_tsquery('english', $1)
+        ORDER BY \"display name\" ILIKE $2 || '%' DESC, LENGTH(\"display name\") ASC;");
+            assert_eq!(query.data_type(), "Members");
+            let row = MockRow::new().with("org_id", Type::INT4, &3i32).with("user", Type::INT8, &9i64).with("display name", Type::TEXT, &"Ada");
+            let www: WhoWhatWhere<(i32, i64)> = query.row_to_www(&row);
+            assert_eq!(www.pk, (3, 9));
+            assert_eq!(www.name, "Ada");
+        }
+
+        #[test]
+        fn generated_impl_executes() {
+            let rt = Runtime::new().unwrap();
+            rt.block_on(async {
+                let client = MockClient::new().with_rows(vec![MockRow::new().with("name", Type::TEXT, &"strawberry")]);
+                let hits = Food::exec_autocomp(&client, "str").await.unwrap();
+                assert_eq!((hits[0].data_type.as_ref(), hits[0].pk.as_str(), hits[0].name.as_str()), ("food", "strawberry", "strawberry"));
+                // the spec's data_type is borrowed by every row rather than allocated
+                assert!(matches!(hits[0].data_type, Cow::Borrowed("food")));
+                // the query doesn't reference $2, so only the ts_expression is bound
+                assert_eq!(client.calls()[0].params, vec!["\"str:*\"".to_string()]);
+            })
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use tokio_postgres::types::Type;
+    use crate::testing::{MockClient, MockRow};
+    use super::*;
+
+    struct Animal;
+
+    impl AutoComp<i32> for Animal {
+        fn query_autocomp() -> &'static str {
+            "SELECT id, name FROM animals WHERE autocomp_tsv @@ to_tsquery('simple', $1) ORDER BY name LIKE $2 || '%' DESC"
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            WhoWhatWhere::new("animal", row.get(0), row.get(1))
+        }
+    }
+
+    fn animal_row(id: i32, name: &str) -> MockRow {
+        MockRow::new().with("id", Type::INT4, &id).with("name", Type::TEXT, &name)
+    }
+
+    #[test]
+    fn exec_autocomp_with_mock_client() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = MockClient::new()
+                .with_rows(vec![animal_row(3, "fish"), animal_row(5, "finch")])
+                .with_rows(vec![]);
+            let hits = Animal::exec_autocomp(&client, " Fi ").await.unwrap();
+            let names: Vec<&str> = hits.iter().map(|hit| hit.name.as_str()).collect();
+            assert_eq!(names, vec!["fish", "finch"]);
+            // the data_type isn't allocated per row
+            assert!(hits.iter().all(|hit| matches!(hit.data_type, Cow::Borrowed("animal"))));
+            assert_eq!(hits[1].pk, 5);
+            // the ts_expression is $1 and the trimmed phrase is $2
+            assert_eq!(client.calls()[0].params, vec!["\"fi:*\"".to_string(), "\"Fi\"".to_string()]);
+            // the english configuration drops the stopwords from the ts_expression only
+            let hits = exec_autocomp_cfg::<i32, Animal>(&client, "the fi", "english").await.unwrap();
+            assert!(hits.is_empty());
+            assert_eq!(client.calls()[1].params, vec!["\"fi:*\"".to_string(), "\"the fi\"".to_string()]);
+        })
+    }
+
+    #[test]
+    fn params_follow_the_placeholders() {
+        assert!(references_param("SELECT $1 WHERE name ILIKE $2 || '%'", 2));
+        assert!(references_param("SELECT $20, $2", 2));
+        assert!(!references_param("SELECT $1, $20, $21", 2));
+        assert!(!references_param("SELECT $1 LIMIT 5", 2));
+    }
+
+    #[test]
+    fn exec_autocomp_scored_reads_rank() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = MockClient::new()
+                .with_rows(vec![animal_row(3, "fish").with("rank", Type::FLOAT4, &0.6f32)])
+                .with_rows(vec![animal_row(3, "fish")]);
+            let hits = exec_autocomp_scored::<i32, Animal>(&client, "fi").await.unwrap();
+            assert_eq!(hits[0].score, Some(0.6));
+            assert!(serde_json::to_string(&hits[0]).unwrap().contains("\"score\":0.6"));
+            // without the rank column
+            match exec_autocomp_scored::<i32, Animal>(&client, "fi").await {
+                Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "autocomp_score"),
+                other => panic!("expected autocomp_score, got {:?}", other),
+            }
+            // unscored hits serialize as before
+            let hits = exec_autocomp::<i32, Animal>(&MockClient::new().with_rows(vec![animal_row(3, "fish")]), "fi").await.unwrap();
+            assert_eq!(serde_json::to_string(&hits[0]).unwrap(), "{\"data_type\":\"animal\",\"pk\":3,\"name\":\"fish\"}");
+        })
+    }
+
+    #[test]
+    fn exec_autocomp_surfaces_errors() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // the client's errors are returned as-is
+            let client = MockClient::new().with_err(PachyDarn::custom("boom", "the database is on fire"));
+            let res = exec_autocomp::<i32, Animal>(&client, "fi").await;
+            assert!(matches!(res, Err(PachyDarn::Custom{kind, ..}) if kind == "boom"));
+        })
+    }
+
+    #[test]
+    fn data_type_is_borrowed_unless_owned() {
+        let borrowed: WhoWhatWhere<i32> = WhoWhatWhere::new("animal", 3, "fish".to_string());
+        // this stops compiling if the field goes back to a String, which would be allocated for every row
+        let data_type: &Cow<'static, str> = &borrowed.data_type;
+        assert!(matches!(data_type, Cow::Borrowed("animal")));
+        // a struct literal works as well as new
+        let literal: WhoWhatWhere<i32> = WhoWhatWhere{data_type: Cow::Borrowed("animal"), pk: 3, name: "fish".to_string(), score: None};
+        assert_eq!(serde_json::to_string(&literal).unwrap(), serde_json::to_string(&borrowed).unwrap());
+        // owned Strings still work, and serialize the same
+        let owned: WhoWhatWhere<i32> = WhoWhatWhere::new(String::from("animal"), 3, "fish".to_string());
+        assert!(matches!(owned.data_type, Cow::Owned(_)));
+        assert_eq!(serde_json::to_string(&borrowed).unwrap(), serde_json::to_string(&owned).unwrap());
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuid_pks_round_trip_json() {
+        use uuid::Uuid;
+        struct Gadget;
+        impl AutoComp<Uuid> for Gadget {
+            fn query_autocomp() -> &'static str {
+                "SELECT id, name FROM gadgets WHERE autocomp_tsv @@ to_tsquery('simple', $1)"
+            }
+            fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<Uuid> {
+                WhoWhatWhere::new("gadget", row.get(0), row.get(1))
+            }
+        }
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let id = Uuid::parse_str("67E55044-10B1-426F-9247-BB680E5FE0C8").unwrap();
+            let client = MockClient::new().with_rows(vec![MockRow::new().with("id", Type::UUID, &id).with("name", Type::TEXT, &"sprocket")]);
+            let hits = Gadget::exec_autocomp(&client, "spr").await.unwrap();
+            // the PK is serialized as a lowercase, hyphenated string
+            let jz = serde_json::to_string(&hits).unwrap();
+            assert_eq!(jz, r#"[{"data_type":"gadget","pk":"67e55044-10b1-426f-9247-bb680e5fe0c8","name":"sprocket"}]"#);
+            let back: Vec<WhoWhatWhere<Uuid>> = serde_json::from_str(&jz).unwrap();
+            assert_eq!((back[0].pk, back[0].data_type.as_ref()), (id, "gadget"));
+        })
+    }
+}
@@ -18,13 +18,18 @@
 //!    operations.
 //! 3) The .on_invocation(), .on_pk_sadd(), and .on_instantiation() optional methods make it
 //!    ergonomic to emit events (presumably via http call) at various point in instantiation.
+//! 4) borg_with_context() passes a request-scoped context (trace ID, user ID etc.) through to
+//!    .on_invocation_with_context() and .on_instantiation_with_context(), so it doesn't have to be
+//!    embedded in B or O just to be logged.
 
-use std::convert::From;
+use std::{collections::HashMap, convert::From, time::{Duration, Instant}};
 use async_recursion::async_recursion;
 use async_trait::async_trait;
+use futures_util::FutureExt;
 use serde::{Serialize, de::DeserializeOwned};
-use tokio_postgres::types::FromSqlOwned;
-use crate::{connect::ClientNoTLS, err::{PachyDarn, MissingRowError}, redis::{rediserde, RedisPool}};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+use tokio_postgres::types::{FromSqlOwned, ToSql};
+use crate::{audit::{touch_by_pk, Audited}, connect::ClientNoTLS, err::{PachyDarn, MissingRowError}, redis::{rediserde, RedisPool}, utils::{fnv1a_64, pachy_log, within_deadline, Deadline}};
 
 
 /// The Borg trait is intended as a fast, ergonomic way to build up complex types
@@ -69,6 +74,11 @@ pub trait Borg<B, O, R: Serialize + DeserializeOwned, G, E: std::error::Error +
         60*60*2 as usize // 2 hours 
     }
 
+    /// redis_expiry_r() as a Duration, which is what borg uses. Override this instead for a sub-second TTL
+    fn redis_ttl_r() -> Duration {
+        Duration::from_secs(Self::redis_expiry_r() as u64)
+    }
+
     /// Define a string unique to a given to a fully-specified innstance
     fn redis_pk_member(&self) -> String;
 
@@ -95,18 +105,103 @@ pub trait Borg<B, O, R: Serialize + DeserializeOwned, G, E: std::error::Error +
         Ok(())
     }
 
+    /// borg_with_context(...) calls this instead of on_invocation, with a reference to the context it was given.
+    /// Read the context through its BorgContext methods, i.e. context.trace_id().
+    /// borg(...) passes the unit type () as the context. By default this just calls on_invocation
+    async fn on_invocation_with_context(b: &B, o: &O, _context: &dyn BorgContext) -> Result<(), E> {
+        Self::on_invocation(b, o).await
+    }
+
     /// borg(...) will call on_pk_sadd AFTER instantiate(...) but BEFORE on_instantiation(...)
     /// IF the string returned by redis_pk_member was not present 
-    /// This is typically done to ensure a record exists in Postgres reflecting the new item
+    /// This is typically done to ensure a record exists in Postgres reflecting the new item.
+    /// It must be idempotent whatever redis_pk_sadd_mode() is, since the PK set is cleared when it grows past redis_pk_max_ct
     async fn on_pk_sadd<'a>(&'a self, _c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a B) -> Result<(), E> {
         Ok(())
     }
+
+    /// The most on_pk_sadd calls to make in any one-second window (a sliding window counted in Redis, so across every process), or None for no limit.
+    /// Over the limit, on_pk_sadd is deferred rather than dropped: the redis_pk_member is pushed onto a queue
+    /// and left out of the PK set, so on_pk_sadd is called again the next time it is borg'd. See next_deferred_pk_member
+    fn on_pk_sadd_rate_limit() -> Option<usize> {
+        None
+    }
+
+    /// How on_pk_sadd is guarded against several processes calling it for the same member at once. See PkSaddMode
+    fn redis_pk_sadd_mode() -> PkSaddMode {
+        PkSaddMode::AlwaysRun
+    }
+
+    /// How long PkSaddMode::AdvisoryLock waits for the lock before calling on_pk_sadd without it
+    fn redis_pk_sadd_lock_wait() -> Duration {
+        Duration::from_secs(5)
+    }
     
     /// borg(...) calls this method last thing, just after constructing self 
     /// and just before returning it. method is called last thing- just as instantiation finishes.
     async fn on_instantiation(&self) -> Result<(), E> {
         Ok(())
     }
+
+    /// borg_with_context(...) calls this instead of on_instantiation, with a reference to the context it was given.
+    /// By default this just calls on_instantiation
+    async fn on_instantiation_with_context(&self, _context: &dyn BorgContext) -> Result<(), E> {
+        self.on_instantiation().await
+    }
+}
+
+
+/// The request-scoped context borg_with_context(...) passes to the Borg hooks, which read it through these methods.
+/// Implement the ones your hooks need; the unit type () is the empty context borg(...) passes
+pub trait BorgContext: Send + Sync {
+    /// the ID of the trace the borg is part of
+    fn trace_id(&self) -> Option<&str> {
+        None
+    }
+
+    /// the ID of the user the borg is done for
+    fn user_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl BorgContext for () {}
+
+
+/// Before a redis_pk_member is in the PK set, every process that borgs it calls on_pk_sadd, so a member borg'd by several
+/// instances at once is written several times. Borg::redis_pk_sadd_mode() chooses how that is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PkSaddMode {
+    /// call on_pk_sadd without coordinating, the default
+    AlwaysRun,
+    /// hold a Postgres advisory lock keyed by a hash of the redis_prefix and redis_pk_member while calling on_pk_sadd
+    /// and adding the member to the PK set. A process that waited for the lock finds the member in the set and skips on_pk_sadd.
+    /// The wait is bounded by Borg::redis_pk_sadd_lock_wait(), after which on_pk_sadd is called anyway: the lock is held
+    /// by the session, so a borg dropped before unlocking leaves it with that pooled connection
+    AdvisoryLock,
+    /// on_pk_sadd is an upsert (i.e. INSERT ... ON CONFLICT) that is safe to run concurrently, so it is called without a lock
+    PgUpsert,
+}
+
+// the advisory lock key of a member, for PkSaddMode::AdvisoryLock
+fn pk_sadd_lock_key(redis_prefix: &str, member: &str) -> i64 {
+    fnv1a_64(format!("borg_pks_{}:{}", redis_prefix, member).as_bytes()) as i64
+}
+
+// take the advisory lock with pg_try_advisory_lock, retrying until wait has passed. Unlike pg_advisory_lock this can't
+// block forever on a lock that will never be released, returning false instead
+async fn try_pk_sadd_lock(c: &ClientNoTLS, lock_key: i64, wait: Duration) -> Result<bool, PachyDarn> {
+    let start = Instant::now();
+    loop {
+        let locked: bool = c.query_one("SELECT pg_try_advisory_lock($1)", &[&lock_key]).await?.get(0);
+        if locked {
+            return Ok(true)
+        }
+        if start.elapsed() >= wait {
+            return Ok(false)
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
 }
 
 
@@ -114,44 +209,131 @@ pub trait Borg<B, O, R: Serialize + DeserializeOwned, G, E: std::error::Error +
 /// TR. 
 /// The Borg::on_instantiation() method will be called automatically 
 pub async fn borg<B, O, R: Serialize + DeserializeOwned, G, E: std::error::Error + From<PachyDarn>, T: Borg<B, O, R, G, E>>(c: &ClientNoTLS, rpool: &RedisPool, b: &B, o: O) -> Result<T, E> {
+    borg_with_context(c, rpool, b, o, ()).await
+}
+
+
+/// Like borg(...), but passes a request-scoped context (i.e. a struct with a trace ID and user ID) to 
+/// Borg::on_invocation_with_context() and Borg::on_instantiation_with_context()
+pub async fn borg_with_context<B, O, R: Serialize + DeserializeOwned, G, E: std::error::Error + From<PachyDarn>, T: Borg<B, O, R, G, E>, C: BorgContext>(c: &ClientNoTLS, rpool: &RedisPool, b: &B, o: O, context: C) -> Result<T, E> {
+    borg_inner(c, rpool, b, o, context, None).await
+}
+
+
+/// Like borg(...), but each phase (the Redis lookups and the redis_value, generate and on_pk_sadd hooks) is given the
+/// remaining budget of the deadline, if there is one (see utils::Deadline). A hook that runs out is abandoned rather than
+/// cancelled, so a statement it started on c still finishes on the server
+pub async fn borg_within<B, O, R: Serialize + DeserializeOwned, G, E: std::error::Error + From<PachyDarn>, T: Borg<B, O, R, G, E>>(c: &ClientNoTLS, rpool: &RedisPool, b: &B, o: O, deadline: Option<Deadline>) -> Result<T, E> {
+    borg_inner(c, rpool, b, o, (), deadline).await
+}
+
+
+async fn borg_inner<B, O, R: Serialize + DeserializeOwned, G, E: std::error::Error + From<PachyDarn>, T: Borg<B, O, R, G, E>, C: BorgContext>(c: &ClientNoTLS, rpool: &RedisPool, b: &B, o: O, context: C, deadline: Option<Deadline>) -> Result<T, E> {
     // call on_invocation first- before any (other) error can be thrown 
-    let _x = <T as Borg<B, O, R, G, E>>::on_invocation(b, &o).await?;
+    let _x = <T as Borg<B, O, R, G, E>>::on_invocation_with_context(b, &o, &context).await?;
     // determine which Redis key should be used to SET/GET values for R
     let prefix = <T as Borg<B, O, R, G, E>>::redis_prefix();
     let suffix: String = <T as Borg<B, O, R, G, E>>::redis_suffix_r(&b, &o);
     let key_r = format!("borg_r_{}_{}", prefix, &suffix);
     let key_set_pks = format!("borg_pks_{}", prefix);
     // check to see if that key is set in Redis
-    let cached: Option<R> = rediserde::get(rpool, &key_r).await?;
+    // each phase is given the remaining budget of the deadline (if any)
+    let cached: Option<R> = within_deadline(deadline, "redis", rediserde::get(rpool, &key_r)).await?;
     let r: R = match cached {
         Some(val) => val,
         None => {
             // If the value has not been set in redis, generate it by calling redis_value(...)
-            let val: R = <T as Borg<B, O, R, G, E>>::redis_value(c, rpool, &b, &o).await?;
-            let _x = rediserde::set_ex(rpool, &key_r, &val, <T as Borg<B, O, R, G, E>>::redis_expiry_r()).await?;
+            let val: R = within_deadline(deadline, "borg_redis_value", <T as Borg<B, O, R, G, E>>::redis_value(c, rpool, &b, &o)).await?;
+            let _x = within_deadline(deadline, "redis", rediserde::set_ex(rpool, &key_r, &val, <T as Borg<B, O, R, G, E>>::redis_ttl_r())).await?;
             val
         }
     };
     // Consume the owned type O and the Redis type R to return a generated type G
-    let g: G = <T as Borg<B, O, R, G, E>>::generate(c, rpool, &b, o, r).await?;
+    let g: G = within_deadline(deadline, "borg_generate", <T as Borg<B, O, R, G, E>>::generate(c, rpool, &b, o, r)).await?;
     // instantiate the thing you want to return
     let inst = T::instantiate(&b, g);
     // if the PK for inst is not a member of the associated set in redis, call on_pk_sadd
     let member = inst.redis_pk_member();
     if ! rediserde::sismember_str(rpool, &key_set_pks, &member).await? {
-        let _x = inst.on_pk_sadd(c, rpool, &b).await?;
-        if <T as Borg<B, O, R, G, E>>::redis_pk_max_ct() < rediserde::scard(rpool, &key_set_pks).await? {
-            // too many old keys are cached! delete the set and start over 
-            let _x = rediserde::del(rpool, &key_set_pks).await?;
+        let over_limit = match <T as Borg<B, O, R, G, E>>::on_pk_sadd_rate_limit() {
+            Some(limit) => !pk_sadd_allowed(rpool, prefix, limit).await?,
+            None => false,
+        };
+        if over_limit {
+            // queue it for later, and leave it out of the set so the next borg(...) for it calls on_pk_sadd
+            pachy_log!(debug, "pachydurable::borg", "on_pk_sadd for {} is over its rate limit, deferring {}", prefix, member);
+            let _x = rediserde::rpush_str(rpool, &deferred_pk_queue_key(prefix), &member).await?;
+        } else {
+            match <T as Borg<B, O, R, G, E>>::redis_pk_sadd_mode() {
+                PkSaddMode::AlwaysRun | PkSaddMode::PgUpsert => pk_sadd::<B, O, R, G, E, T>(&inst, c, rpool, b, &key_set_pks, &member, deadline).await?,
+                PkSaddMode::AdvisoryLock => pk_sadd_locked::<B, O, R, G, E, T>(&inst, c, rpool, b, &key_set_pks, &member, deadline).await?,
+            }
         }
-        let _x = rediserde::sadd_str(rpool, &key_set_pks, &member).await?;
     }
     // finally, call on_instantiation if you want to emit an event or whatever
-    let _x = inst.on_instantiation().await?;
+    let _x = inst.on_instantiation_with_context(&context).await?;
     Ok(inst)
 }
 
 
+// call on_pk_sadd and add the member to the PK set
+async fn pk_sadd<B, O, R: Serialize + DeserializeOwned, G, E: std::error::Error + From<PachyDarn>, T: Borg<B, O, R, G, E>>(inst: &T, c: &ClientNoTLS, rpool: &RedisPool, b: &B, key_set_pks: &str, member: &str, deadline: Option<Deadline>) -> Result<(), E> {
+    let _x = within_deadline(deadline, "borg_on_pk_sadd", inst.on_pk_sadd(c, rpool, b)).await?;
+    if <T as Borg<B, O, R, G, E>>::redis_pk_max_ct() < rediserde::scard(rpool, key_set_pks).await? {
+        // too many old keys are cached! delete the set and start over 
+        let _x = rediserde::del(rpool, key_set_pks).await?;
+    }
+    let _x = rediserde::sadd_str(rpool, key_set_pks, member).await?;
+    Ok(())
+}
+
+// pk_sadd holding the advisory lock of the member, for PkSaddMode::AdvisoryLock
+async fn pk_sadd_locked<B, O, R: Serialize + DeserializeOwned, G, E: std::error::Error + From<PachyDarn>, T: Borg<B, O, R, G, E>>(inst: &T, c: &ClientNoTLS, rpool: &RedisPool, b: &B, key_set_pks: &str, member: &str, deadline: Option<Deadline>) -> Result<(), E> {
+    let lock_key = pk_sadd_lock_key(<T as Borg<B, O, R, G, E>>::redis_prefix(), member);
+    if !try_pk_sadd_lock(c, lock_key, <T as Borg<B, O, R, G, E>>::redis_pk_sadd_lock_wait()).await? {
+        // on_pk_sadd is idempotent, so calling it unlocked is better than failing the borg
+        pachy_log!(warn, "pachydurable::borg", "gave up waiting for the on_pk_sadd lock for {}, calling it without the lock", member);
+        return pk_sadd::<B, O, R, G, E, T>(inst, c, rpool, b, key_set_pks, member, deadline).await
+    }
+    // another process may have called on_pk_sadd while this one waited for the lock
+    let added = match rediserde::sismember_str(rpool, key_set_pks, member).await {
+        Ok(true) => Ok(()),
+        Ok(false) => pk_sadd::<B, O, R, G, E, T>(inst, c, rpool, b, key_set_pks, member, deadline).await,
+        Err(e) => Err(e.into()),
+    };
+    // the lock is held by the connection, so it has to be released even if on_pk_sadd failed
+    if let Err(e) = c.execute("SELECT pg_advisory_unlock($1)", &[&lock_key]).await {
+        pachy_log!(warn, "pachydurable::borg", "failed to release the on_pk_sadd lock for {}: {}", member, e);
+    }
+    added
+}
+
+// count an on_pk_sadd call in a sliding one-second window, returning false if it is over the limit
+async fn pk_sadd_allowed(rpool: &RedisPool, prefix: &str, limit: usize) -> Result<bool, PachyDarn> {
+    rediserde::sliding_window_hit(rpool, &format!("borg_rate_{}", prefix), limit, Duration::from_secs(1)).await
+}
+
+/// The Redis list holding the redis_pk_members whose on_pk_sadd was deferred by Borg::on_pk_sadd_rate_limit
+pub fn deferred_pk_queue_key(redis_prefix: &str) -> String {
+    format!("borg_deferred_{}", redis_prefix)
+}
+
+/// Pop the oldest redis_pk_member whose on_pk_sadd was deferred, or None if there are none.
+/// The expected consumer is a background task that pops members at (or under) the rate limit and calls borg(...) again
+/// for each one, which calls on_pk_sadd since the member was never added to the PK set:
+/// ```ignore
+/// while let Some(member) = next_deferred_pk_member(&rpool, Subdomain::redis_prefix()).await? {
+///     let (b, o) = inputs_for(&member);
+///     let _subdomain: Subdomain = borg(&client, &rpool, &b, o).await?;
+///     tokio::time::sleep(Duration::from_millis(1000 / LIMIT as u64)).await;
+/// }
+/// ```
+/// A member borg'd several times during a burst is queued several times, and later calls find it in the PK set,
+/// so on_pk_sadd should be idempotent (it must already be, since the PK set is cleared when it grows past redis_pk_max_ct)
+pub async fn next_deferred_pk_member(rpool: &RedisPool, redis_prefix: &str) -> Result<Option<String>, PachyDarn> {
+    rediserde::lpop_str(rpool, &deferred_pk_queue_key(redis_prefix)).await
+}
+
 
 /// The WritePG trait makes it easy to write things to Postgres
 /// The the type T that is returned can be set to the product PK or whatever else you prefer
@@ -160,11 +342,146 @@ pub trait WritePG<T: Send + Sync> {
     async fn write_pg(&self, c: &ClientNoTLS) -> Result<T, PachyDarn>;
 }
 
+/// Call write_pg, then set the audit columns (see audit::Audited) of the row with the PK it returned in W::audited_table(),
+/// with the actor (if any) as updated_by. The write and the touch are separate statements, so run it in a transaction
+/// if they must succeed together
+pub async fn write_pg_audited<T: ToSql + Send + Sync, W: WritePG<T> + Audited + Sync>(w: &W, c: &ClientNoTLS, actor: Option<&(dyn ToSql + Sync)>) -> Result<T, PachyDarn> {
+    let pk = w.write_pg(c).await?;
+    let _touched = touch_by_pk::<W>(c, &pk, actor).await?;
+    Ok(pk)
+}
+
+
+/// Options for import_ndjson
+#[derive(Debug, Clone)]
+pub struct ImportOpts {
+    /// How many items are written per transaction
+    pub batch_size: usize,
+    /// How many parsed batches may wait to be written before reading pauses, which bounds memory use
+    pub max_in_flight: usize,
+    /// How many lines may fail to parse before the import fails
+    pub max_errors: u64,
+}
+
+impl Default for ImportOpts {
+    fn default() -> Self {
+        ImportOpts{batch_size: 500, max_in_flight: 2, max_errors: 0}
+    }
+}
+
+/// A line import_ndjson couldn't parse
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImportLineError {
+    /// The line number, starting at 1
+    pub line: u64,
+    pub error: String,
+}
+
+/// What import_ndjson read and wrote
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// The lines read, including blank and malformed ones
+    pub lines: u64,
+    /// The items written with write_pg
+    pub written: u64,
+    /// The transactions committed
+    pub batches: u64,
+    pub errors: Vec<ImportLineError>,
+}
+
+// rolls back the transaction write_batch opened unless it was committed, including when the import is dropped part way
+// through a batch, so the pooled connection never goes back to the pool inside a transaction. Drop can't await, but
+// batch_execute sends its statement on the first poll, so polling it once queues the ROLLBACK ahead of whatever the
+// connection runs next (this is how tokio_postgres's own Transaction rolls back on drop)
+struct RollbackGuard<'a> {
+    client: &'a ClientNoTLS,
+    committed: bool,
+}
+
+impl Drop for RollbackGuard<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return
+        }
+        if let Some(Err(e)) = self.client.batch_execute("ROLLBACK").now_or_never() {
+            pachy_log!(error, "pachydurable::borg", "import_ndjson could not roll back a failed batch: {}", e);
+        }
+    }
+}
+
+// write_pg every item in one transaction on the client. write_pg takes the &ClientNoTLS itself, so the batch can't run
+// on a tokio_postgres Transaction and BEGIN/COMMIT are sent by hand, with a RollbackGuard for every other way out
+async fn write_batch<T: WritePG<R> + Sync, R: Send + Sync>(c: &ClientNoTLS, batch: &[T]) -> Result<(), PachyDarn> {
+    c.batch_execute("BEGIN").await?;
+    let mut guard = RollbackGuard{client: c, committed: false};
+    for item in batch {
+        item.write_pg(c).await?;
+    }
+    // a COMMIT that fails before reaching Postgres leaves the transaction open, so the guard still rolls it back
+    c.batch_execute("COMMIT").await?;
+    guard.committed = true;
+    Ok(())
+}
+
+/// Read newline-delimited JSON from reader, writing each item with write_pg in transactions of opts.batch_size items.
+/// Lines are read as they are needed: once opts.max_in_flight batches are waiting to be written, reading pauses.
+/// Blank lines are skipped, and lines that fail to parse are reported by line number without stopping the import
+/// unless there are more than opts.max_errors, in which case a Custom error of kind import_errors is returned.
+/// A failed write rolls back its batch and is returned as the error. Either way, batches already committed stay
+pub async fn import_ndjson<T, R, B>(c: &ClientNoTLS, reader: B, opts: ImportOpts) -> Result<ImportReport, PachyDarn>
+where T: DeserializeOwned + WritePG<R> + Send + Sync, R: Send + Sync, B: AsyncBufRead + Unpin + Send {
+    let batch_size = opts.batch_size.max(1);
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<T>>(opts.max_in_flight.max(1));
+    let parse = async move {
+        let mut lines = reader.lines();
+        let mut report = ImportReport::default();
+        let mut batch = Vec::with_capacity(batch_size);
+        while let Some(line) = lines.next_line().await? {
+            report.lines += 1;
+            if line.trim().is_empty() {
+                continue
+            }
+            match serde_json::from_str::<T>(&line) {
+                Ok(item) => batch.push(item),
+                Err(e) => {
+                    pachy_log!(warn, "pachydurable::borg", "import_ndjson skipped line {}: {}", report.lines, e);
+                    report.errors.push(ImportLineError{line: report.lines, error: e.to_string()});
+                    if report.errors.len() as u64 > opts.max_errors {
+                        return Err(PachyDarn::custom("import_errors", format!("{} lines failed to parse, more than max_errors={}; the last was line {}: {}", report.errors.len(), opts.max_errors, report.lines, e)))
+                    }
+                },
+            }
+            // a failed send means the writer stopped on an error, which it returns
+            if batch.len() >= batch_size && tx.send(std::mem::replace(&mut batch, Vec::with_capacity(batch_size))).await.is_err() {
+                return Ok(report)
+            }
+        }
+        if !batch.is_empty() {
+            let _sent = tx.send(batch).await;
+        }
+        Ok::<ImportReport, PachyDarn>(report)
+    };
+    let write = async move {
+        let (mut written, mut batches) = (0, 0);
+        while let Some(batch) = rx.recv().await {
+            write_batch(c, &batch).await?;
+            written += batch.len() as u64;
+            batches += 1;
+        }
+        Ok::<(u64, u64), PachyDarn>((written, batches))
+    };
+    let (parsed, wrote) = tokio::join!(parse, write);
+    let (written, batches) = wrote?;
+    Ok(ImportReport{written, batches, ..parsed?})
+}
+
 
 /// Several tables have an (integer) PK with a unique constraint on a VARCHAR value
 /// This function lets you provide the QUERY and INSERT statements to allow querying/insereting into those tables
 /// NOTE: This function is recursive becuae it contains logic to retry upon duplicate insert attempts
 /// This is only expected to occur if many inserts are being done at once 
+/// Duplicate inserts are detected with PachyDarn::is_unique_violation() (i.e. SQLSTATE 23505) rather than
+/// by matching on the error text- use the same pattern when classifying Postgres errors in your own code
 #[async_recursion]
 pub async fn get_string_id<'a, T: FromSqlOwned>(c: &'a ClientNoTLS, name: &'a str, query: &'a str, insert: &'a str) -> Result<T, PachyDarn> {
     let rows = c.query(query, &[&name]).await?;
@@ -180,20 +497,20 @@ pub async fn get_string_id<'a, T: FromSqlOwned>(c: &'a ClientNoTLS, name: &'a st
                             Ok(id)
                         },
                         // IDK how you would ever reach the code below, but it sounds bad
-                        None => Err(MissingRowError{message: "How on earth do you insert a row but not get it back?".to_string()}.into())
+                        None => Err(MissingRowError::from_str("How on earth do you insert a row but not get it back?").into())
                     }
                 },
                 Err(e) => {
-                    let errtext = e.to_string();
-                    if errtext.contains("duplicate key value violates unique constraint") {
+                    let err = PachyDarn::from(e);
+                    if err.is_unique_violation() {
                         // When many inserts are happening concurrently, this error can occur on occasion
                         // When two processes try to inset the same record at once.
                         // just pause for a few milliseconds and recurse
                         std::thread::sleep(std::time::Duration::from_millis(100));
-                        println!("   Warning - get_string_id is recursing- suspect concurrent inserts for '{}'", name);
+                        pachy_log!(warn, "pachydurable::borg", "get_string_id is recursing- suspect concurrent inserts for '{}'", name);
                         get_string_id(c, name, query, insert).await
                     } else {
-                        Err(e.into())
+                        Err(err)
                     }
                 },
             }
@@ -201,12 +518,458 @@ pub async fn get_string_id<'a, T: FromSqlOwned>(c: &'a ClientNoTLS, name: &'a st
     }
 }
 
+/// Like get_string_id for many names at once: one round-trip fetches the IDs that already exist, and only the missing names
+/// are inserted (one at a time), so seeding thousands of names costs thousands of inserts at most rather than thousands of lookups too.
+/// Unlike get_string_id, query takes an array of names and returns the name and the ID, i.e.
+/// SELECT name, id FROM tags WHERE name = ANY($1)
+/// while insert is the same single-name statement, i.e. INSERT INTO tags (name) VALUES ($1) RETURNING id.
+/// Duplicate inserts from concurrent writers are handled as in get_string_id, by looking the name up again
+pub async fn batch_get_string_id<T: FromSqlOwned>(c: &ClientNoTLS, names: &[&str], query: &str, insert: &str) -> Result<HashMap<String, T>, PachyDarn> {
+    let rows = c.query(query, &[&names]).await?;
+    let mut ids: HashMap<String, T> = rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+    for name in names.iter() {
+        while !ids.contains_key(*name) {
+            match c.query(insert, &[name]).await {
+                Ok(rows) => match rows.get(0) {
+                    Some(row) => { ids.insert(name.to_string(), row.get(0)); },
+                    None => return Err(MissingRowError::from_str("How on earth do you insert a row but not get it back?").into()),
+                },
+                Err(e) => {
+                    let err = PachyDarn::from(e);
+                    if !err.is_unique_violation() {
+                        return Err(err)
+                    }
+                    // another writer inserted the name after the lookup- pause and fetch its ID
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    pachy_log!(warn, "pachydurable::borg", "batch_get_string_id is retrying- suspect concurrent inserts for '{}'", name);
+                    let rows = c.query(query, &[&[*name].as_slice()]).await?;
+                    if let Some(row) = rows.get(0) {
+                        ids.insert(name.to_string(), row.get(1));
+                    }
+                },
+            }
+        }
+    }
+    Ok(ids)
+}
+
+
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{OnceLock, atomic::{AtomicUsize, Ordering}};
     use tokio::runtime::Runtime;
-    use crate::{connect::pool_no_tls_from_env, err::PachyDarn, redis};
+    use crate::{err::PachyDarn, testing::{TestDb, TestRedis}};
     use super::*;
-}
 
+    // the borg keys are named after redis_prefix(), which can't come from a TestRedis, so each test type's prefix
+    // is unique to the process instead, i.e. test_badge_4242, and its keys are deleted with del_borg_keys
+    fn process_prefix(cell: &'static OnceLock<String>, name: &str) -> &'static str {
+        cell.get_or_init(|| format!("{}_{}", name, std::process::id()))
+    }
+
+    async fn del_borg_keys(rpool: &RedisPool, prefix: &str) {
+        for kind in ["r", "pks", "rate", "deferred"] {
+            let _x = rediserde::delete_by_prefix(rpool, &format!("borg_{}_{}", kind, prefix)).await;
+        }
+    }
+
+    /// A greeting is built from a name (by reference) and a number of exclamation marks (owned)
+    struct Greeting {
+        text: String,
+    }
+
+    #[async_trait]
+    impl Borg<String, u32, String, String, PachyDarn> for Greeting {
+        fn redis_prefix() -> &'static str {
+            static PREFIX: OnceLock<String> = OnceLock::new();
+            process_prefix(&PREFIX, "test_greeting")
+        }
+        fn redis_suffix_r(b: &String, _o: &u32) -> String {
+            b.to_string()
+        }
+        fn redis_pk_member(&self) -> String {
+            self.text.clone()
+        }
+        async fn redis_value<'a>(_c: &'a ClientNoTLS, _rpool: &'a RedisPool, b: &'a String, _o: &'a u32) -> Result<String, PachyDarn> {
+            // a domain failure can be returned with PachyDarn::custom- no new error enum is needed 
+            if b == "mallory" {
+                return Err(PachyDarn::custom_with_status("banned_name", format!("{} is not welcome here", b), 403))
+            }
+            Ok(format!("Hello, {}", b))
+        }
+        async fn generate<'a>(_c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String, o: u32, r: String) -> Result<String, PachyDarn> {
+            Ok(format!("{}{}", r, "!".repeat(o as usize)))
+        }
+        fn instantiate(_b: &String, g: String) -> Self {
+            Greeting{text: g}
+        }
+        async fn on_instantiation_with_context(&self, context: &dyn BorgContext) -> Result<(), PachyDarn> {
+            match context.trace_id() {
+                Some("") => Err(PachyDarn::custom("missing_trace_id", format!("no trace ID for '{}'", self.text))),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// request-scoped context for borg_with_context
+    struct TraceContext {
+        trace_id: String,
+    }
+
+    impl BorgContext for TraceContext {
+        fn trace_id(&self) -> Option<&str> {
+            Some(&self.trace_id)
+        }
+    }
+
+    /// A badge is written to Postgres (here, counted) the first time each name is seen, at most 2 per second
+    struct Badge {
+        name: String,
+    }
+
+    static BADGES_WRITTEN: AtomicUsize = AtomicUsize::new(0);
+
+    #[async_trait]
+    impl Borg<String, (), (), (), PachyDarn> for Badge {
+        fn redis_prefix() -> &'static str {
+            static PREFIX: OnceLock<String> = OnceLock::new();
+            process_prefix(&PREFIX, "test_badge")
+        }
+        fn redis_suffix_r(_b: &String, _o: &()) -> String {
+            "all".to_string()
+        }
+        fn redis_pk_member(&self) -> String {
+            self.name.clone()
+        }
+        async fn redis_value<'a>(_c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String, _o: &'a ()) -> Result<(), PachyDarn> {
+            Ok(())
+        }
+        async fn generate<'a>(_c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String, _o: (), _r: ()) -> Result<(), PachyDarn> {
+            Ok(())
+        }
+        fn instantiate(b: &String, _g: ()) -> Self {
+            Badge{name: b.clone()}
+        }
+        async fn on_pk_sadd<'a>(&'a self, _c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String) -> Result<(), PachyDarn> {
+            BADGES_WRITTEN.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        fn on_pk_sadd_rate_limit() -> Option<usize> {
+            Some(2)
+        }
+    }
+
+    #[test]
+    fn borg_defers_pk_sadd_over_rate_limit() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let prefix = Badge::redis_prefix();
+            let names: Vec<String> = (0..5).map(|i| format!("badge_{}", i)).collect();
+            for name in &names {
+                let badge: Badge = borg(&client, rpool, name, ()).await.unwrap();
+                assert_eq!(&badge.name, name);
+            }
+            // even if the burst spans two windows, at most 4 of the 5 are written
+            let mut deferred = Vec::new();
+            while let Some(member) = next_deferred_pk_member(rpool, prefix).await.unwrap() {
+                deferred.push(member);
+            }
+            assert!(!deferred.is_empty());
+            assert_eq!(BADGES_WRITTEN.load(Ordering::SeqCst) + deferred.len(), names.len());
+            // the consumer borgs the deferred members again, which writes them
+            tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+            for member in &deferred[..1] {
+                let _badge: Badge = borg(&client, rpool, member, ()).await.unwrap();
+            }
+            assert_eq!(BADGES_WRITTEN.load(Ordering::SeqCst) + deferred.len() - 1, names.len());
+            del_borg_keys(rpool, prefix).await;
+        })
+    }
+
+    /// A stamp is written once per name, however many processes borg it at once
+    struct Stamp {
+        name: String,
+    }
+
+    static STAMPS_WRITTEN: AtomicUsize = AtomicUsize::new(0);
+
+    #[async_trait]
+    impl Borg<String, (), (), (), PachyDarn> for Stamp {
+        fn redis_prefix() -> &'static str {
+            static PREFIX: OnceLock<String> = OnceLock::new();
+            process_prefix(&PREFIX, "test_stamp")
+        }
+        fn redis_suffix_r(_b: &String, _o: &()) -> String {
+            "all".to_string()
+        }
+        fn redis_pk_member(&self) -> String {
+            self.name.clone()
+        }
+        async fn redis_value<'a>(_c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String, _o: &'a ()) -> Result<(), PachyDarn> {
+            Ok(())
+        }
+        async fn generate<'a>(_c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String, _o: (), _r: ()) -> Result<(), PachyDarn> {
+            Ok(())
+        }
+        fn instantiate(b: &String, _g: ()) -> Self {
+            Stamp{name: b.clone()}
+        }
+        async fn on_pk_sadd<'a>(&'a self, _c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String) -> Result<(), PachyDarn> {
+            // slow enough that the other borg is waiting for the lock
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            STAMPS_WRITTEN.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        fn redis_pk_sadd_mode() -> PkSaddMode {
+            PkSaddMode::AdvisoryLock
+        }
+    }
+
+    #[test]
+    fn advisory_lock_dedupes_pk_sadd() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let (client1, client2) = (db.client().await.unwrap(), db.client().await.unwrap());
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let name = "stamp_0".to_string();
+            let (first, second) = tokio::join!(
+                borg::<String, (), (), (), PachyDarn, Stamp>(&client1, rpool, &name, ()),
+                borg::<String, (), (), (), PachyDarn, Stamp>(&client2, rpool, &name, ()),
+            );
+            assert_eq!((first.unwrap().name, second.unwrap().name), (name.clone(), name.clone()));
+            assert_eq!(STAMPS_WRITTEN.load(Ordering::SeqCst), 1);
+            // the lock was released, so the connections can take it again
+            let lock_key = pk_sadd_lock_key(Stamp::redis_prefix(), &name);
+            let locked: bool = client2.query_one("SELECT pg_try_advisory_lock($1)", &[&lock_key]).await.unwrap().get(0);
+            assert!(locked);
+            client2.execute("SELECT pg_advisory_unlock($1)", &[&lock_key]).await.unwrap();
+            del_borg_keys(rpool, Stamp::redis_prefix()).await;
+        })
+    }
+
+    /// Like a Stamp, but gives up on the lock quickly
+    struct Seal {
+        name: String,
+    }
+
+    static SEALS_WRITTEN: AtomicUsize = AtomicUsize::new(0);
+
+    #[async_trait]
+    impl Borg<String, (), (), (), PachyDarn> for Seal {
+        fn redis_prefix() -> &'static str {
+            static PREFIX: OnceLock<String> = OnceLock::new();
+            process_prefix(&PREFIX, "test_seal")
+        }
+        fn redis_suffix_r(_b: &String, _o: &()) -> String {
+            "all".to_string()
+        }
+        fn redis_pk_member(&self) -> String {
+            self.name.clone()
+        }
+        async fn redis_value<'a>(_c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String, _o: &'a ()) -> Result<(), PachyDarn> {
+            Ok(())
+        }
+        async fn generate<'a>(_c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String, _o: (), _r: ()) -> Result<(), PachyDarn> {
+            Ok(())
+        }
+        fn instantiate(b: &String, _g: ()) -> Self {
+            Seal{name: b.clone()}
+        }
+        async fn on_pk_sadd<'a>(&'a self, _c: &'a ClientNoTLS, _rpool: &'a RedisPool, _b: &'a String) -> Result<(), PachyDarn> {
+            SEALS_WRITTEN.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        fn redis_pk_sadd_mode() -> PkSaddMode {
+            PkSaddMode::AdvisoryLock
+        }
+        fn redis_pk_sadd_lock_wait() -> Duration {
+            Duration::from_millis(200)
+        }
+    }
+
+    #[test]
+    fn abandoned_advisory_lock_does_not_block_borg() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let (client, holder) = (db.client().await.unwrap(), db.client().await.unwrap());
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let prefix = Seal::redis_prefix();
+            // the lock is held by a connection that never releases it, as if a borg was dropped while holding it
+            let name = "seal_0".to_string();
+            let lock_key = pk_sadd_lock_key(prefix, &name);
+            holder.execute("SELECT pg_advisory_lock($1)", &[&lock_key]).await.unwrap();
+            let start = std::time::Instant::now();
+            let seal: Seal = borg(&client, rpool, &name, ()).await.unwrap();
+            assert_eq!(seal.name, name);
+            assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+            assert_eq!(SEALS_WRITTEN.load(Ordering::SeqCst), 1);
+            assert!(rediserde::sismember_str(rpool, &format!("borg_pks_{}", prefix), &name).await.unwrap());
+            holder.execute("SELECT pg_advisory_unlock($1)", &[&lock_key]).await.unwrap();
+            del_borg_keys(rpool, prefix).await;
+        })
+    }
+
+    #[test]
+    fn batch_string_ids() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE batch_tags (id SERIAL PRIMARY KEY, name VARCHAR UNIQUE NOT NULL);
+                INSERT INTO batch_tags (name) VALUES ('red');").await.unwrap();
+            let client = db.client().await.unwrap();
+            let query = "SELECT name, id FROM batch_tags WHERE name = ANY($1)";
+            let insert = "INSERT INTO batch_tags (name) VALUES ($1) RETURNING id";
+            let red: i32 = get_string_id(&client, "red", "SELECT id FROM batch_tags WHERE name = $1", insert).await.unwrap();
+            let ids: HashMap<String, i32> = batch_get_string_id(&client, &["red", "green", "blue", "green"], query, insert).await.unwrap();
+            assert_eq!(ids.len(), 3);
+            assert_eq!(ids["red"], red);
+            let again: HashMap<String, i32> = batch_get_string_id(&client, &["blue", "green"], query, insert).await.unwrap();
+            assert_eq!(again["blue"], ids["blue"]);
+            assert_eq!(again["green"], ids["green"]);
+            let count: i64 = crate::connect::get_scalar(&client, "SELECT COUNT(*) FROM batch_tags", &[]).await.unwrap();
+            assert_eq!(count, 3);
+        })
+    }
 
+    #[test]
+    fn borg_custom_error() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let greeting: Greeting = borg(&client, rpool, &"alice".to_string(), 2).await.unwrap();
+            assert_eq!(greeting.text, "Hello, alice!!");
+            let res: Result<Greeting, PachyDarn> = borg(&client, rpool, &"mallory".to_string(), 2).await;
+            match res {
+                Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "banned_name"),
+                _ => panic!("expected the banned_name custom error"),
+            }
+            del_borg_keys(rpool, Greeting::redis_prefix()).await;
+        })
+    }
+
+    #[test]
+    fn borg_passes_context() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let ctx = TraceContext{trace_id: "3f9a01c2".to_string()};
+            let greeting: Greeting = borg_with_context(&client, rpool, &"alice".to_string(), 1, ctx).await.unwrap();
+            assert_eq!(greeting.text, "Hello, alice!");
+            let ctx = TraceContext{trace_id: String::new()};
+            let res: Result<Greeting, PachyDarn> = borg_with_context(&client, rpool, &"alice".to_string(), 1, ctx).await;
+            match res {
+                Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "missing_trace_id"),
+                _ => panic!("expected the missing_trace_id custom error"),
+            }
+            // borg passes () as the context, which the hook ignores
+            let greeting: Greeting = borg(&client, rpool, &"alice".to_string(), 1).await.unwrap();
+            assert_eq!(greeting.text, "Hello, alice!");
+            del_borg_keys(rpool, Greeting::redis_prefix()).await;
+        })
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Parcel {
+        sku: String,
+        grams: i32,
+    }
+
+    #[async_trait]
+    impl WritePG<i32> for Parcel {
+        async fn write_pg(&self, c: &ClientNoTLS) -> Result<i32, PachyDarn> {
+            let row = c.query_one("INSERT INTO parcels (sku, grams) VALUES ($1, $2) RETURNING id", &[&self.sku, &self.grams]).await?;
+            Ok(row.get(0))
+        }
+    }
+
+    async fn open(path: &std::path::Path) -> tokio::io::BufReader<tokio::fs::File> {
+        tokio::io::BufReader::new(tokio::fs::File::open(path).await.unwrap())
+    }
+
+    #[test]
+    fn import_ndjson_skips_malformed_lines() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE parcels (id SERIAL PRIMARY KEY, sku VARCHAR NOT NULL, grams INT NOT NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            // 1,000 good lines, with malformed ones at lines 101 and 502
+            let mut ndjson = String::new();
+            for n in 0..1000 {
+                ndjson.push_str(&format!("{{\"sku\": \"sku-{}\", \"grams\": {}}}\n", n, n));
+                match n {
+                    99 => ndjson.push_str("{\"sku\": \"truncated\", \"gra\n"),
+                    499 => ndjson.push_str("{\"sku\": \"heavy\", \"grams\": \"a lot\"}\n"),
+                    _ => {},
+                }
+            }
+            let path = std::env::temp_dir().join(format!("pachy_import_{}.ndjson", std::process::id()));
+            tokio::fs::write(&path, &ndjson).await.unwrap();
+            let opts = ImportOpts{batch_size: 64, max_in_flight: 2, max_errors: 2};
+            let report = import_ndjson::<Parcel, i32, _>(&client, open(&path).await, opts).await.unwrap();
+            assert_eq!((report.lines, report.written, report.batches), (1002, 1000, 16));
+            let lines: Vec<u64> = report.errors.iter().map(|e| e.line).collect();
+            assert_eq!(lines, vec![101, 502]);
+            let (count, total): (i64, i64) = {
+                let row = client.query_one("SELECT count(*), sum(grams) FROM parcels", &[]).await.unwrap();
+                (row.get(0), row.get(1))
+            };
+            assert_eq!((count, total), (1000, 499_500));
+            // with a smaller error budget the import fails at the second malformed line
+            let opts = ImportOpts{max_errors: 1, ..Default::default()};
+            match import_ndjson::<Parcel, i32, _>(&client, open(&path).await, opts).await {
+                Err(PachyDarn::Custom{kind, message, ..}) => {
+                    assert_eq!(kind, "import_errors");
+                    assert!(message.contains("line 502"), "{}", message);
+                },
+                other => panic!("expected an import_errors error, got {:?}", other.map(|report| report.lines)),
+            }
+            tokio::fs::remove_file(&path).await.unwrap();
+        })
+    }
+
+    // a Parcel whose write stays in flight for a second after the insert
+    struct SlowParcel(Parcel);
+
+    #[async_trait]
+    impl WritePG<i32> for SlowParcel {
+        async fn write_pg(&self, c: &ClientNoTLS) -> Result<i32, PachyDarn> {
+            let id = self.0.write_pg(c).await?;
+            c.batch_execute("SELECT pg_sleep(1)").await?;
+            Ok(id)
+        }
+    }
+
+    #[test]
+    fn write_batch_rolls_back_when_dropped() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE parcels (id SERIAL PRIMARY KEY, sku VARCHAR NOT NULL, grams INT NOT NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let batch = vec![SlowParcel(Parcel{sku: "sku-1".to_string(), grams: 1})];
+            // the timeout drops write_batch after the insert but before the COMMIT
+            let res = tokio::time::timeout(Duration::from_millis(200), write_batch(&client, &batch)).await;
+            assert!(res.is_err());
+            // the insert was rolled back, and the next query runs in its own transaction (now() is only the
+            // statement timestamp for the first statement of a transaction)
+            let row = client.query_one("SELECT count(*), now() = statement_timestamp() FROM parcels", &[]).await.unwrap();
+            assert_eq!((row.get::<_, i64>(0), row.get::<_, bool>(1)), (0, true));
+            write_batch(&client, &[Parcel{sku: "sku-2".to_string(), grams: 2}]).await.unwrap();
+            let count: i64 = client.query_one("SELECT count(*) FROM parcels", &[]).await.unwrap().get(0);
+            assert_eq!(count, 1);
+        })
+    }
+}
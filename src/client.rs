@@ -0,0 +1,295 @@
+//! The client module introduces the PachyClient and RowLike traits, which let the execution functions
+//! (AutoComp::exec_autocomp, exec_fulltext, get_by_pk, cached_or_cache etc.) run against anything that can
+//! answer a query- a pooled ClientNoTLS, a tokio_postgres::Client, a Transaction, or the testing::MockClient.
+//!
+//! tokio_postgres::GenericClient can't be used for this, since it is sealed and its rows are tokio_postgres::Row,
+//! which can only be constructed by tokio_postgres itself. Instead, the rowfuncs of the traits read columns through
+//! RowLike, which is implemented for Row and for testing::MockRow:
+//! ```ignore
+//! impl FullText for Animal {
+//!     fn query_fulltext() -> &'static str {
+//!         "SELECT id, name FROM animals WHERE fulltext_tsv @@ to_tsquery('english', $1)"
+//!     }
+//!     fn rowfunc_fulltext<R: RowLike>(row: &R) -> Self {
+//!         Animal{id: row.get(0), name: row.get("name")}
+//!     }
+//! }
+//! ```
+//!
+//! PgPoolLike does the same for pools: it is implemented for the mobc ConnPoolNoTLS and, with the "deadpool" feature,
+//! for deadpool_postgres::Pool, so code that checks out its own clients works with either.
+
+use std::fmt;
+use async_trait::async_trait;
+use tokio_postgres::{Client, GenericClient, Transaction, row::{Row, RowIndex}, types::{FromSql, ToSql}};
+use crate::{connect::{ClientNoTLS, ConnPoolNoTLS, SchemaScopedClient, TenantGuard}, err::PachyDarn};
+
+
+/// A column index for RowLike::get: either the position of the column (usize) or its name (&str)
+pub trait ColumnIdx: RowIndex + fmt::Display {
+    /// The position of the column among the named columns of a row, if there is one
+    fn position(&self, names: &[String]) -> Option<usize>;
+}
+
+impl ColumnIdx for usize {
+    fn position(&self, names: &[String]) -> Option<usize> {
+        if *self < names.len() { Some(*self) } else { None }
+    }
+}
+
+impl ColumnIdx for str {
+    fn position(&self, names: &[String]) -> Option<usize> {
+        // like tokio_postgres, an exact match wins over a case-insensitive one
+        names.iter().position(|name| name == self)
+            .or_else(|| names.iter().position(|name| name.eq_ignore_ascii_case(self)))
+    }
+}
+
+impl<'b, T: ColumnIdx + ?Sized> ColumnIdx for &'b T {
+    fn position(&self, names: &[String]) -> Option<usize> {
+        T::position(*self, names)
+    }
+}
+
+
+/// A row that columns can be read from, i.e. tokio_postgres::Row or testing::MockRow.
+/// get and try_get have the same signatures as the methods on Row, so a rowfunc body doesn't change
+pub trait RowLike {
+    /// The number of columns in the row
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Deserialize a value from the row, returning an error if the index is out of range
+    /// or the column can't be converted to T
+    fn try_get<'a, I: ColumnIdx, T: FromSql<'a>>(&'a self, idx: I) -> Result<T, PachyDarn>;
+
+    /// Like try_get, but panics on error just like Row::get
+    fn get<'a, I: ColumnIdx, T: FromSql<'a>>(&'a self, idx: I) -> T {
+        let label = idx.to_string();
+        match self.try_get(idx) {
+            Ok(val) => val,
+            Err(e) => panic!("error retrieving column {}: {}", label, e),
+        }
+    }
+}
+
+impl RowLike for Row {
+    fn len(&self) -> usize {
+        Row::len(self)
+    }
+
+    fn try_get<'a, I: ColumnIdx, T: FromSql<'a>>(&'a self, idx: I) -> Result<T, PachyDarn> {
+        Ok(Row::try_get(self, idx)?)
+    }
+}
+
+
+/// Anything the execution functions can send a query to. Row is the type of row it returns
+#[async_trait]
+pub trait PachyClient: Sync {
+    type Row: RowLike + Send;
+
+    /// Run a query, returning the resulting rows
+    async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Self::Row>, PachyDarn>;
+
+    /// Run a statement, returning the number of rows modified
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PachyDarn>;
+
+    /// Run the statement once for each set of params, returning the total number of rows affected.
+    /// Postgres clients prepare the statement once and reuse it for every set
+    async fn execute_many(&self, sql: &str, param_sets: &[&[&(dyn ToSql + Sync)]]) -> Result<u64, PachyDarn> {
+        let mut total = 0;
+        for params in param_sets {
+            total += self.execute(sql, params).await?;
+        }
+        Ok(total)
+    }
+}
+
+// prepare the statement once and execute it for each set of params, for the execute_many of Postgres clients
+async fn execute_prepared<C: GenericClient + Sync>(client: &C, sql: &str, param_sets: &[&[&(dyn ToSql + Sync)]]) -> Result<u64, PachyDarn> {
+    let statement = client.prepare(sql).await?;
+    let mut total = 0;
+    for params in param_sets {
+        total += client.execute(&statement, params).await?;
+    }
+    Ok(total)
+}
+
+// the inherent methods are called with their full path, since self.query(...) would resolve to the trait method
+#[async_trait]
+impl PachyClient for Client {
+    type Row = Row;
+
+    async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, PachyDarn> {
+        Ok(Client::query(self, sql, params).await?)
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PachyDarn> {
+        Ok(Client::execute(self, sql, params).await?)
+    }
+
+    async fn execute_many(&self, sql: &str, param_sets: &[&[&(dyn ToSql + Sync)]]) -> Result<u64, PachyDarn> {
+        execute_prepared::<Client>(self, sql, param_sets).await
+    }
+}
+
+#[async_trait]
+impl PachyClient for ClientNoTLS {
+    type Row = Row;
+
+    async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, PachyDarn> {
+        Ok(Client::query(self, sql, params).await?)
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PachyDarn> {
+        Ok(Client::execute(self, sql, params).await?)
+    }
+
+    async fn execute_many(&self, sql: &str, param_sets: &[&[&(dyn ToSql + Sync)]]) -> Result<u64, PachyDarn> {
+        execute_prepared::<Client>(self, sql, param_sets).await
+    }
+}
+
+#[async_trait]
+impl<'t> PachyClient for Transaction<'t> {
+    type Row = Row;
+
+    async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, PachyDarn> {
+        Ok(Transaction::query(self, sql, params).await?)
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PachyDarn> {
+        Ok(Transaction::execute(self, sql, params).await?)
+    }
+
+    async fn execute_many(&self, sql: &str, param_sets: &[&[&(dyn ToSql + Sync)]]) -> Result<u64, PachyDarn> {
+        execute_prepared::<Transaction>(self, sql, param_sets).await
+    }
+}
+
+#[async_trait]
+impl PachyClient for SchemaScopedClient {
+    type Row = Row;
+
+    async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, PachyDarn> {
+        Ok(Client::query(self, sql, params).await?)
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PachyDarn> {
+        Ok(Client::execute(self, sql, params).await?)
+    }
+
+    async fn execute_many(&self, sql: &str, param_sets: &[&[&(dyn ToSql + Sync)]]) -> Result<u64, PachyDarn> {
+        PachyClient::execute_many(&**self, sql, param_sets).await
+    }
+}
+
+#[async_trait]
+impl<'a> PachyClient for TenantGuard<'a> {
+    type Row = Row;
+
+    async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, PachyDarn> {
+        Ok(Transaction::query(self, sql, params).await?)
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PachyDarn> {
+        Ok(Transaction::execute(self, sql, params).await?)
+    }
+
+    async fn execute_many(&self, sql: &str, param_sets: &[&[&(dyn ToSql + Sync)]]) -> Result<u64, PachyDarn> {
+        PachyClient::execute_many(&**self, sql, param_sets).await
+    }
+}
+
+#[cfg(feature = "deadpool")]
+#[async_trait]
+impl PachyClient for deadpool_postgres::Client {
+    type Row = Row;
+
+    async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, PachyDarn> {
+        Ok(Client::query(self, sql, params).await?)
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PachyDarn> {
+        Ok(Client::execute(self, sql, params).await?)
+    }
+
+    async fn execute_many(&self, sql: &str, param_sets: &[&[&(dyn ToSql + Sync)]]) -> Result<u64, PachyDarn> {
+        execute_prepared::<Client>(self, sql, param_sets).await
+    }
+}
+
+
+/// A Postgres connection pool: the mobc ConnPoolNoTLS, or deadpool_postgres::Pool with the "deadpool" feature.
+/// Pool errors are returned as the MobcPG variant for either, so is_transient_pg_error and http_status() treat them alike
+#[async_trait]
+pub trait PgPoolLike: Sync {
+    type Client: PachyClient<Row = Row> + Send;
+
+    /// Check a client out of the pool 
+    async fn client(&self) -> Result<Self::Client, PachyDarn>;
+}
+
+#[async_trait]
+impl PgPoolLike for ConnPoolNoTLS {
+    type Client = ClientNoTLS;
+
+    async fn client(&self) -> Result<ClientNoTLS, PachyDarn> {
+        Ok(self.get().await?)
+    }
+}
+
+#[cfg(feature = "deadpool")]
+#[async_trait]
+impl PgPoolLike for deadpool_postgres::Pool {
+    type Client = deadpool_postgres::Client;
+
+    async fn client(&self) -> Result<deadpool_postgres::Client, PachyDarn> {
+        Ok(self.get().await?)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::testing::TestDb;
+    use super::*;
+
+    // only compiles if C's rows can be read through RowLike
+    async fn first_text<C: PachyClient>(client: &C, sql: &str) -> String {
+        let rows = client.query(sql, &[]).await.unwrap();
+        rows[0].get("greeting")
+    }
+
+    #[test]
+    fn column_positions() {
+        let names = vec!["id".to_string(), "Name".to_string()];
+        assert_eq!(1usize.position(&names), Some(1));
+        assert_eq!(2usize.position(&names), None);
+        assert_eq!("Name".position(&names), Some(1));
+        assert_eq!("name".position(&names), Some(1));
+        assert_eq!("color".position(&names), None);
+    }
+
+    #[test]
+    fn clients_and_transactions() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let mut client = db.client().await.unwrap();
+            assert_eq!(first_text(&client, "SELECT 'hello' AS greeting").await, "hello");
+            let tx = client.transaction().await.unwrap();
+            assert_eq!(first_text(&tx, "SELECT 'hi' AS greeting").await, "hi");
+            let rows = PachyClient::query(&tx, "SELECT 'hi' AS greeting", &[]).await.unwrap();
+            let err = rows[0].try_get::<_, i32>(0).unwrap_err();
+            assert!(matches!(err, PachyDarn::Postgres(_)));
+            tx.rollback().await.unwrap();
+        })
+    }
+}
@@ -1,10 +1,17 @@
-use std::{env, vec::Vec, marker::Sync};
-pub use tokio_postgres::{Config, NoTls, row::Row, Error as ErrorTKPG};
-use tokio_postgres::{types::ToSql}; // can't pub use ToSql as it is private
+use std::{error::Error, fmt, future::Future, vec::Vec, marker::Sync, path::Path, pin::Pin, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}};
+use bytes::{Bytes, BytesMut};
+use futures_util::{future::BoxFuture, Stream, StreamExt};
+use postgres_protocol::types::{array_to_sql, ArrayDimension};
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+pub use tokio_postgres::{Config, NoTls, row::Row, Error as ErrorTKPG, config::TargetSessionAttrs};
+use tokio_postgres::{Client, Transaction, types::{FromSqlOwned, ToSql, Type, Kind, IsNull, to_sql_checked}}; // can't pub use ToSql as it is private
 pub use tokio_postgres::GenericClient;
 pub use mobc::{self, Pool};
 pub use mobc_postgres::PgConnectionManager;
-use crate::err::{PachyDarn, MissingRowError};
+use crate::client::{PachyClient, PgPoolLike};
+use crate::err::{debug_summary, PachyDarn, PachyContext, MissingRowError, UnexpectedMultipleRowsError};
+use crate::utils::{config_error, current_request_id, Deadline, env_opt, env_parse, pachy_log, redact_config, REDACTED};
 
 
 /// The ConnPoolNoTLS a common connector used for various applications
@@ -13,11 +20,44 @@ use crate::err::{PachyDarn, MissingRowError};
 pub type ConnPoolNoTLS = Pool<PgConnectionManager<NoTls>>;
 /// The client is also notls and should be changed in the future
 pub type ClientNoTLS = mobc::Connection<PgConnectionManager<NoTls>>;
+/// The stream of rows (converted to T) returned by get_stream
+pub type PachyStream<T> = Pin<Box<dyn Stream<Item = Result<T, PachyDarn>> + Send>>;
+
+
+/// The line logged for a query that took at least PSQL_SLOW_QUERY_MS, including the request ID if there is one
+pub fn slow_query_line(query: &str, elapsed: Duration) -> String {
+    let request_id = current_request_id().map(|id| id.to_string()).unwrap_or_else(|| "-".to_string());
+    format!("slow query request_id={} elapsed_ms={} query={}", request_id, elapsed.as_millis(), query.split_whitespace().collect::<Vec<&str>>().join(" "))
+}
+
+// run the query, logging it if the PSQL_SLOW_QUERY_MS environment variable is set and it took at least that long
+async fn timed_query(client: &impl PachyClient<Row = Row>, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, PachyDarn> {
+    let start = Instant::now();
+    let rows = client.query(query, params).await;
+    log_if_slow(query, start);
+    Ok(rows?)
+}
+
+fn log_if_slow(query: &str, start: Instant) {
+    // an invalid PSQL_SLOW_QUERY_MS shouldn't make every query fail, so it's treated as unset
+    if let Ok(Some(threshold_ms)) = env_opt::<u128>("PSQL_SLOW_QUERY_MS") {
+        let elapsed = start.elapsed();
+        if elapsed.as_millis() >= threshold_ms {
+            pachy_log!(warn, "pachydurable::connect", "{}", slow_query_line(query, elapsed));
+        }
+    }
+}
+
+
+/// A compact rendering of bound params for error messages, i.e. [42, "kiwi"]. Long params are truncated
+pub(crate) fn params_summary(params: &[&(dyn ToSql + Sync)]) -> String {
+    debug_summary(params)
+}
 
 
 /// return an option<T>
-pub async fn get_opt<'a, T>(client: &'a ClientNoTLS, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params: &'a [&'a (dyn ToSql + Sync)]) -> Result<Option<T>, PachyDarn> {
-    let rows = client.query(query, params).await?;
+pub async fn get_opt<'a, T>(client: &'a impl PachyClient<Row = Row>, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params: &'a [&'a (dyn ToSql + Sync)]) -> Result<Option<T>, PachyDarn> {
+    let rows = timed_query(client, query, params).await?;
     match rows.get(0) {
         None => Ok(None),
         Some(row) => Ok(Some(rowfunc(row))) // see https://users.rust-lang.org/t/how-to-store-function-pointers-in-struct-and-call-them/51348
@@ -25,18 +65,159 @@ pub async fn get_opt<'a, T>(client: &'a ClientNoTLS, query: &'static str, rowfun
 }
 
 /// return T
-pub async fn get_one<'a, T>(client: &'a ClientNoTLS, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params:&'a [&'a (dyn ToSql + Sync)]) -> Result<T, PachyDarn> {
+pub async fn get_one<'a, T>(client: &'a impl PachyClient<Row = Row>, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params:&'a [&'a (dyn ToSql + Sync)]) -> Result<T, PachyDarn> {
     let t: T = match get_opt(client, query, rowfunc, params).await? {
         Some(t) => t,
-        None => return Err(MissingRowError{message: format!("No row found for query \"{}\"", query)}.into())
+        None => return Err(MissingRowError::for_query_summary(query, params_summary(params)))
+            .with_context(|| format!("get_one::<{}> failed", std::any::type_name::<T>()))
     };
     Ok(t)
 }
 
 
+/// return exactly one row: MissingRowError if there are none and UnexpectedMultipleRowsError if there are more than one.
+/// This is safer than .get(0) for lookups on unique-constrained columns, where getting >1 row indicates a schema problem
+pub async fn query_one(client: &impl PachyClient<Row = Row>, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Row, PachyDarn> {
+    let mut rows = timed_query(client, query, params).await?;
+    match rows.len() {
+        0 => Err(MissingRowError::for_query_summary(query, params_summary(params)).into()),
+        1 => Ok(rows.remove(0)),
+        row_count => Err(UnexpectedMultipleRowsError{message: format!("query \"{}\"", query), row_count}.into()),
+    }
+}
+
+
+/// Like get_vec, but if the query hasn't finished after timeout_ms PachyDarn::StatementTimeout is returned,
+/// i.e. for autocomplete that should rather return nothing than be slow. Unlike setting statement_timeout for the session
+/// this only applies to this query: it runs through with_statement_timeout, so Postgres stops it too
+pub async fn query_with_timeout<'a, T>(client: &'a mut Client, query: &str, rowfunc: &'a dyn Fn(&Row) -> T, params: &'a [&'a (dyn ToSql + Sync)], timeout_ms: u64) -> Result<Vec<T>, PachyDarn> {
+    let rows = with_statement_timeout(client, Duration::from_millis(timeout_ms), |tx| async move {
+        let rows = timed_query(&tx, query, params).await?;
+        Ok((tx, rows))
+    }).await?;
+    match rows {
+        Some(rows) => Ok(rows.iter().map(rowfunc).collect()),
+        None => Err(PachyDarn::StatementTimeout{timeout_ms}),
+    }
+}
+
+/// Like get_vec, but within the remaining budget of the deadline (if there is one): PachyDarn::DeadlineExceeded
+/// with the phase "postgres" is returned if it runs out, and Postgres stops the query (see with_statement_timeout)
+pub async fn get_vec_within<'a, T>(client: &'a mut Client, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params: &'a [&'a (dyn ToSql + Sync)], deadline: Option<Deadline>) -> Result<Vec<T>, PachyDarn> {
+    let rows = match deadline {
+        None => return get_vec(&*client, query, rowfunc, params).await,
+        Some(deadline) => statement_within_deadline(client, deadline, |tx| async move {
+            let rows = timed_query(&tx, query, params).await?;
+            Ok((tx, rows))
+        }).await?,
+    };
+    Ok(rows.iter().map(rowfunc).collect())
+}
+
+// run f through with_statement_timeout with the remaining budget of the deadline, the "postgres" phase
+pub(crate) async fn statement_within_deadline<'c, T, F, Fut>(client: &'c mut Client, deadline: Deadline, f: F) -> Result<T, PachyDarn>
+where
+    F: FnOnce(Transaction<'c>) -> Fut,
+    Fut: Future<Output = Result<(Transaction<'c>, T), PachyDarn>>,
+{
+    if deadline.is_expired() {
+        return Err(deadline.exceeded("postgres"))
+    }
+    with_statement_timeout(client, deadline.remaining(), f).await?.ok_or_else(|| deadline.exceeded("postgres"))
+}
+
+/// Run the statements of f on a transaction with SET LOCAL statement_timeout, so Postgres itself stops one still running
+/// after timeout, and commit it. f is given the transaction and hands it back with its result.
+/// None is returned if the timeout passed (on the server, or waiting for it), in which case the transaction is rolled back.
+/// No cancel request is sent: one could arrive after the query finished and cancel the next statement on the connection
+/// instead. If the caller gives up on the future, dropping the Transaction rolls it back, so the connection is left clean
+pub async fn with_statement_timeout<'c, T, F, Fut>(client: &'c mut Client, timeout: Duration, f: F) -> Result<Option<T>, PachyDarn>
+where
+    F: FnOnce(Transaction<'c>) -> Fut,
+    Fut: Future<Output = Result<(Transaction<'c>, T), PachyDarn>>,
+{
+    // a statement_timeout of 0 would disable it
+    let timeout_ms = timeout.as_millis().clamp(1, i32::MAX as u128);
+    let run = async move {
+        let tx = client.transaction().await?;
+        tx.batch_execute(&format!("SET LOCAL statement_timeout = {}", timeout_ms)).await?;
+        let (tx, val) = f(tx).await?;
+        tx.commit().await?;
+        Ok::<T, PachyDarn>(val)
+    };
+    match tokio::time::timeout(timeout, run).await {
+        Ok(Ok(val)) => Ok(Some(val)),
+        Ok(Err(e)) if e.is_query_canceled() => Ok(None),
+        Ok(Err(e)) => Err(e),
+        Err(_elapsed) => Ok(None),
+    }
+}
+
+
+/// return the first column of exactly one row, i.e. get_scalar::<i64>(&client, "SELECT COUNT(*) FROM animals", &[])
+/// or get_scalar::<DateTime<Utc>>(&client, "SELECT now()", &[]) in a health check
+pub async fn get_scalar<T: FromSqlOwned>(client: &impl PachyClient<Row = Row>, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<T, PachyDarn> {
+    let row = query_one(client, query, params).await?;
+    Ok(row.try_get(0)?)
+}
+
+/// Whether the table exists in the schema, i.e. to refuse to start with a clear message before migrations have run
+pub async fn ensure_table_exists(client: &impl PachyClient<Row = Row>, schema: &str, table: &str) -> Result<bool, PachyDarn> {
+    get_scalar(client, "SELECT EXISTS (SELECT 1 FROM information_schema.tables WHERE table_schema = $1 AND table_name = $2)", &[&schema, &table]).await
+}
+
+/// The columns the table in the schema is missing, in the order given (so all of them if the table doesn't exist)
+pub async fn ensure_columns_exist(client: &impl PachyClient<Row = Row>, schema: &str, table: &str, columns: &[&str]) -> Result<Vec<String>, PachyDarn> {
+    let rows = timed_query(client, "SELECT column_name::text FROM information_schema.columns WHERE table_schema = $1 AND table_name = $2", &[&schema, &table]).await?;
+    let existing: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+    Ok(columns.iter().filter(|col| !existing.iter().any(|existing| existing == *col)).map(|col| col.to_string()).collect())
+}
+
+
+/// run an INSERT/UPDATE/DELETE etc., returning the number of rows affected.
+/// Like the query functions, it is logged if it took at least PSQL_SLOW_QUERY_MS
+pub async fn execute(client: &impl PachyClient, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PachyDarn> {
+    let start = Instant::now();
+    let affected = client.execute(query, params).await;
+    log_if_slow(query, start);
+    affected
+}
+
+
+/// Like execute, but an error of kind unexpected_affected_rows if the statement didn't affect exactly expected rows,
+/// i.e. execute_expecting(&tx, "UPDATE animals SET name = $1 WHERE id = $2", &[&name, &id], 1) to update exactly one row by PK.
+/// Run it in a transaction to roll back a statement that affected the wrong number of rows
+pub async fn execute_expecting(client: &impl PachyClient, query: &str, params: &[&(dyn ToSql + Sync)], expected: u64) -> Result<(), PachyDarn> {
+    let affected = execute(client, query, params).await?;
+    if affected != expected {
+        return Err(PachyDarn::custom("unexpected_affected_rows", format!("expected {} affected row(s), got {}: query \"{}\"", expected, affected, query)))
+    }
+    Ok(())
+}
+
+
+/// Run the statement once for each set of params, preparing it only once, and return the total number of rows affected.
+/// Stops at the first error, so run it in a transaction to make it all or nothing
+pub async fn execute_many(client: &impl PachyClient, query: &str, param_sets: &[&[&(dyn ToSql + Sync)]]) -> Result<u64, PachyDarn> {
+    client.execute_many(query, param_sets).await
+}
+
+/// Run an INSERT ... ON CONFLICT ... RETURNING upsert and map the first returned row, i.e.
+/// upsert_returning(&client, "INSERT INTO tags (name) VALUES ($1) ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name RETURNING id", &|row| row.get::<_, i32>(0), &[&name])
+/// returns the tag's id whether or not it already existed. Unlike get_string_id, it takes one round-trip and doesn't race with
+/// concurrent inserts. A query that returns no rows (i.e. ON CONFLICT DO NOTHING) is a MissingRowError
+pub async fn upsert_returning<T>(client: &impl PachyClient<Row = Row>, query: &str, rowfunc: &dyn Fn(&Row) -> T, params: &[&(dyn ToSql + Sync)]) -> Result<T, PachyDarn> {
+    let rows = timed_query(client, query, params).await?;
+    match rows.get(0) {
+        Some(row) => Ok(rowfunc(row)),
+        None => Err(MissingRowError::from_str(&format!("No row returned by upsert \"{}\"", query)))
+            .with_context(|| format!("upsert_returning::<{}> failed", std::any::type_name::<T>())),
+    }
+}
+
 /// This cool function takes a references to a pool and a query and returns a vec of results
-pub async fn get_vec<'a, T>(client: &'a ClientNoTLS, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params:&'a[&'a(dyn ToSql + Sync)]) -> Result<Vec<T>, PachyDarn> {
-    let rows = client.query(query, params).await?;
+pub async fn get_vec<'a, T>(client: &'a impl PachyClient<Row = Row>, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params:&'a[&'a(dyn ToSql + Sync)]) -> Result<Vec<T>, PachyDarn> {
+    let rows = timed_query(client, query, params).await?;
     let mut vt = Vec::new();
     for row in rows {
         let t = rowfunc(&row);
@@ -45,29 +226,706 @@ pub async fn get_vec<'a, T>(client: &'a ClientNoTLS, query: &'static str, rowfun
     Ok(vt)
 }
 
+/// Like get_vec, but the rows are sorted by order_column ASC/DESC, i.e. to sort by a query param. The ORDER BY is appended
+/// to the query (a trailing ; is dropped), so the query must not have its own ORDER BY, LIMIT or OFFSET. The order_column
+/// may name a column through its table, i.e. "a.name", and must be one of the allowed_columns, or it is an error of kind
+/// invalid_order_column with status 400, so a column name from a request can't inject SQL
+pub async fn get_vec_ordered<'a, T>(client: &'a impl PachyClient<Row = Row>, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params: &'a[&'a(dyn ToSql + Sync)],
+    order_column: &str, descending: bool, allowed_columns: &[&str]) -> Result<Vec<T>, PachyDarn> {
+    if !allowed_columns.contains(&order_column) {
+        return Err(PachyDarn::custom_with_status("invalid_order_column", format!("can't order by \"{}\", expected one of {:?}", order_column, allowed_columns), 400))
+    }
+    let base = query.trim().trim_end_matches(';');
+    let query = format!("{} ORDER BY {} {}", base, quote_ident(order_column), if descending { "DESC" } else { "ASC" });
+    let rows = timed_query(client, &query, params).await?;
+    Ok(rows.iter().map(rowfunc).collect())
+}
+
+
+/// Like get_vec, but rows are converted to T as they arrive from Postgres instead of being collected first,
+/// so memory stays flat for large result sets (i.e. pair it with http_server::stream_json_array).
+/// The stream does not borrow the client, so it can outlive the request handler
+pub async fn get_stream<T: Send + 'static>(client: &ClientNoTLS, query: &str, rowfunc: fn(&Row) -> T, params: &[&(dyn ToSql + Sync)]) -> Result<PachyStream<T>, PachyDarn> {
+    let rows = client.query_raw(query, params.iter().copied()).await?;
+    Ok(Box::pin(rows.map(move |row| Ok(rowfunc(&row?)))))
+}
+
+
+/// Run a COPY ... TO STDOUT statement, i.e. "COPY animals TO STDOUT (FORMAT csv, HEADER)", streaming the bytes
+/// as Postgres sends them (in whatever format the statement asked for) without loading the rows into Rust structs
+pub async fn copy_out(client: &ClientNoTLS, query: &str) -> Result<PachyStream<Bytes>, PachyDarn> {
+    let stream = client.copy_out(query).await?;
+    Ok(Box::pin(stream.map(|chunk| Ok(chunk?))))
+}
+
+/// Like copy_out, but the bytes are written to a file at path (created or truncated). Returns the number of bytes written
+pub async fn copy_out_to_file(client: &ClientNoTLS, query: &str, path: &Path) -> Result<u64, PachyDarn> {
+    let mut stream = copy_out(client, query).await?;
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut written: u64 = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+    file.flush().await?;
+    Ok(written)
+}
+
+
+/// Options for export_ndjson
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    /// How many rows may fail to convert (or serialize) and be skipped before the export fails
+    pub max_errors: u64,
+    /// Lines are buffered and written (then flushed) once at least this many bytes are waiting
+    pub chunk_bytes: usize,
+    /// Wrap the writer in a gzip encoder. Without the "gzip" feature, setting it is an error
+    pub gzip: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions{
+            max_errors: 0,
+            chunk_bytes: 64 * 1024,
+            gzip: false,
+        }
+    }
+}
+
+/// What export_ndjson wrote
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportStats {
+    /// The lines written
+    pub rows: u64,
+    /// The rows skipped because they failed to convert or serialize
+    pub errors: u64,
+    /// The bytes of NDJSON written, before any gzip compression
+    pub bytes: u64,
+}
+
+// stream the rows into writer as NDJSON, leaving it flushed but not shut down
+async fn write_ndjson<T: Serialize, W: AsyncWrite + Unpin>(client: &ClientNoTLS, query: &str, rowfunc: &dyn Fn(&Row) -> Result<T, PachyDarn>, params: &[&(dyn ToSql + Sync)], writer: &mut W, options: &ExportOptions) -> Result<ExportStats, PachyDarn> {
+    let mut rows = client.query_raw(query, params.iter().copied()).await?;
+    futures_util::pin_mut!(rows);
+    let mut stats = ExportStats::default();
+    let mut buf: Vec<u8> = Vec::with_capacity(options.chunk_bytes);
+    while let Some(row) = rows.next().await {
+        // Postgres errors end the export, but a row that fails to convert or serialize can be skipped
+        let line = rowfunc(&row?).and_then(|val| Ok(serde_json::to_vec(&val)?));
+        match line {
+            Ok(line) => {
+                buf.extend_from_slice(&line);
+                buf.push(b'\n');
+                stats.rows += 1;
+            },
+            Err(e) => {
+                stats.errors += 1;
+                if stats.errors > options.max_errors {
+                    return Err(PachyDarn::custom("export_errors", format!("{} rows failed to convert, more than max_errors={}; the last: {}", stats.errors, options.max_errors, e)))
+                }
+                pachy_log!(warn, "pachydurable::connect", "export_ndjson skipped a row: {}", e);
+            },
+        }
+        if buf.len() >= options.chunk_bytes {
+            writer.write_all(&buf).await?;
+            writer.flush().await?;
+            stats.bytes += buf.len() as u64;
+            buf.clear();
+        }
+    }
+    writer.write_all(&buf).await?;
+    writer.flush().await?;
+    stats.bytes += buf.len() as u64;
+    Ok(stats)
+}
+
+/// Stream the rows of a query to writer as newline-delimited JSON, one line per row, without collecting them first.
+/// Unlike get_stream the rowfunc returns a Result (i.e. using row.try_get), so up to options.max_errors rows that
+/// fail to convert can be skipped and counted in the ExportStats. The writer is flushed but not shut down,
+/// unless options.gzip is set, in which case the gzip stream is finished so the output is complete. Setting options.gzip
+/// without the "gzip" feature is a Custom error of kind gzip_unavailable, returned before anything is written
+pub async fn export_ndjson<T: Serialize, W: AsyncWrite + Unpin>(client: &ClientNoTLS, query: &str, rowfunc: &dyn Fn(&Row) -> Result<T, PachyDarn>, params: &[&(dyn ToSql + Sync)], mut writer: W, options: &ExportOptions) -> Result<ExportStats, PachyDarn> {
+    #[cfg(feature = "gzip")]
+    if options.gzip {
+        let mut encoder = async_compression::tokio::write::GzipEncoder::new(writer);
+        let stats = write_ndjson(client, query, rowfunc, params, &mut encoder, options).await?;
+        encoder.shutdown().await?;
+        return Ok(stats)
+    }
+    #[cfg(not(feature = "gzip"))]
+    if options.gzip {
+        return Err(PachyDarn::custom("gzip_unavailable", "ExportOptions::gzip is set, but pachydurable was built without the \"gzip\" feature"))
+    }
+    write_ndjson(client, query, rowfunc, params, &mut writer, options).await
+}
+
+
+// used by the multiquery! macro so callers don't need tokio's "macros" feature themselves
+#[doc(hidden)]
+pub use tokio::join as __tokio_join;
+
+
+/// Run several independent queries concurrently on one client (tokio-postgres pipelines them),
+/// rather than awaiting each in turn. Each query is a (query, params, rowfunc) triple as for get_vec,
+/// and the result is a tuple with one Result<Vec<T>, PachyDarn> per query, so each keeps its own type:
+/// ```ignore
+/// let (counts, recent) = multiquery!(&client,
+///     ("SELECT COUNT(*) FROM animals", &[], |row: &Row| row.get::<_, i64>(0)),
+///     ("SELECT id, name FROM animals ORDER BY id DESC LIMIT $1", &[&10i64], animal_from_row),
+/// );
+/// ```
+/// The macro expands to tokio::join!, so use it in an async context without .await
+#[macro_export]
+macro_rules! multiquery {
+    ($client:expr, $(($query:expr, $params:expr, $rowfunc:expr)),+ $(,)?) => {{
+        let client: &$crate::connect::ClientNoTLS = $client;
+        $crate::connect::__tokio_join!($($crate::connect::get_vec(client, $query, &$rowfunc, $params)),+)
+    }};
+}
+
+
+/// Bulk insert (or update etc.) thousands of rows in one round trip using UNNEST.
+/// Each Vec in columns holds the values for one column, and is bound to one array parameter, i.e.
+/// ```ignore
+/// let ids: Vec<Box<dyn ToSql + Sync>> = vec![Box::new(1i32), Box::new(2i32)];
+/// let names: Vec<Box<dyn ToSql + Sync>> = vec![Box::new("cat"), Box::new("dog")];
+/// let n = execute_unnest(&client, 
+///     "INSERT INTO animals (id, name) SELECT * FROM UNNEST($1::int[], $2::text[])", 
+///     &[ids, names]).await?;
+/// ```
+/// Every column must have the same number of values. Returns the number of rows affected
+pub async fn execute_unnest(client: &impl PachyClient, query: &str, columns: &[Vec<Box<dyn ToSql + Sync>>]) -> Result<u64, PachyDarn> {
+    if let Some(first) = columns.first() {
+        if columns.iter().any(|col| col.len() != first.len()) {
+            return Err(PachyDarn::custom("unnest_column_lengths", "every column passed to execute_unnest must have the same number of values"))
+        }
+    }
+    let arrays: Vec<UnnestColumn> = columns.iter().map(|col| UnnestColumn(col)).collect();
+    let params: Vec<&(dyn ToSql + Sync)> = arrays.iter().map(|arr| arr as &(dyn ToSql + Sync)).collect();
+    let n = client.execute(query, &params).await?;
+    Ok(n)
+}
+
+
+/// Encodes a column of boxed values as a one-dimensional Postgres array.
+/// Each value is type-checked against the array's element type as it is written
+#[derive(Debug)]
+struct UnnestColumn<'a>(&'a [Box<dyn ToSql + Sync>]);
+
+impl<'a> ToSql for UnnestColumn<'a> {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let member_type = match ty.kind() {
+            Kind::Array(member) => member,
+            _ => return Err(format!("execute_unnest expected an array parameter, got {}", ty).into()),
+        };
+        let dimension = ArrayDimension{len: i32::try_from(self.0.len())?, lower_bound: 1};
+        array_to_sql(Some(dimension), member_type.oid(), self.0.iter(), |val, out| {
+            match val.to_sql_checked(member_type, out)? {
+                IsNull::No => Ok(postgres_protocol::IsNull::No),
+                IsNull::Yes => Ok(postgres_protocol::IsNull::Yes),
+            }
+        }, out)?;
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(ty.kind(), Kind::Array(_))
+    }
+
+    to_sql_checked!();
+}
+
+
+// a WHERE condition added to a QueryBuilder
+enum Condition {
+    Eq(String),
+    In(String, usize),
+}
+
+/// Assembles a parameterized SELECT so the $1, $2 ... placeholders don't have to be counted by hand:
+/// ```ignore
+/// let (query, params) = QueryBuilder::select("animals", &["id", "name"])
+///     .where_eq("owner_id", 7i32)
+///     .where_in("species", vec!["cat", "dog"])
+///     .order_by("name", false)
+///     .limit(20)
+///     .build()?;
+/// // SELECT "id", "name" FROM "animals" WHERE "owner_id" = $1 AND "species" IN ($2, $3) ORDER BY "name" ASC LIMIT $4
+/// let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref()).collect();
+/// let rows = client.query(query.as_str(), &params).await?;
+/// ```
+/// Table and column names are quoted (a.b becomes "a"."b"), so they can't inject SQL, but they should still
+/// come from your code rather than from user input. This is not an ORM- write anything more involved by hand 
+pub struct QueryBuilder {
+    table: String,
+    columns: Vec<String>,
+    conditions: Vec<Condition>,
+    params: Vec<Box<dyn ToSql + Sync>>,
+    order_by: Vec<(String, bool)>,
+    limit: Option<i64>,
+}
+
+impl QueryBuilder {
+    /// Start a SELECT of the columns from the table 
+    pub fn select(table: &str, columns: &[&str]) -> Self {
+        QueryBuilder{
+            table: table.to_string(),
+            columns: columns.iter().map(|col| col.to_string()).collect(),
+            conditions: Vec::new(),
+            params: Vec::new(),
+            order_by: Vec::new(),
+            limit: None,
+        }
+    }
+
+    /// AND column = value
+    pub fn where_eq<T: ToSql + Sync + 'static>(mut self, column: &str, value: T) -> Self {
+        self.conditions.push(Condition::Eq(column.to_string()));
+        self.params.push(Box::new(value));
+        self
+    }
+
+    /// AND column IN (values...), with one placeholder per value
+    pub fn where_in<T: ToSql + Sync + 'static>(mut self, column: &str, values: Vec<T>) -> Self {
+        self.conditions.push(Condition::In(column.to_string(), values.len()));
+        for value in values {
+            self.params.push(Box::new(value));
+        }
+        self
+    }
+
+    /// Sort by the column. Call again to break ties with another column 
+    pub fn order_by(mut self, column: &str, desc: bool) -> Self {
+        self.order_by.push((column.to_string(), desc));
+        self
+    }
+
+    pub fn limit(mut self, n: i64) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    /// The query and its parameters, in placeholder order. Fails if there are no columns or a where_in has no values
+    pub fn build(mut self) -> Result<(String, Vec<Box<dyn ToSql + Sync>>), PachyDarn> {
+        if self.columns.is_empty() {
+            return Err(PachyDarn::custom("query_builder", format!("no columns were selected from {}", self.table)))
+        }
+        let columns: Vec<String> = self.columns.iter().map(|col| quote_ident(col)).collect();
+        let mut query = format!("SELECT {} FROM {}", columns.join(", "), quote_ident(&self.table));
+        let mut n = 0;
+        let mut clauses = Vec::new();
+        for condition in &self.conditions {
+            match condition {
+                Condition::Eq(col) => {
+                    n += 1;
+                    clauses.push(format!("{} = ${}", quote_ident(col), n));
+                },
+                Condition::In(col, 0) => {
+                    return Err(PachyDarn::custom("query_builder", format!("where_in({}) needs at least one value", col)))
+                },
+                Condition::In(col, count) => {
+                    let placeholders: Vec<String> = (n+1..=n+count).map(|i| format!("${}", i)).collect();
+                    n += count;
+                    clauses.push(format!("{} IN ({})", quote_ident(col), placeholders.join(", ")));
+                },
+            }
+        }
+        if !clauses.is_empty() {
+            query.push_str(&format!(" WHERE {}", clauses.join(" AND ")));
+        }
+        if !self.order_by.is_empty() {
+            let sorts: Vec<String> = self.order_by.iter()
+                .map(|(col, desc)| format!("{} {}", quote_ident(col), if *desc { "DESC" } else { "ASC" }))
+                .collect();
+            query.push_str(&format!(" ORDER BY {}", sorts.join(", ")));
+        }
+        if let Some(limit) = self.limit {
+            n += 1;
+            query.push_str(&format!(" LIMIT ${}", n));
+            self.params.push(Box::new(limit));
+        }
+        Ok((query, self.params))
+    }
+}
+
+// quote each part of a possibly schema-qualified name, i.e. public.animals becomes "public"."animals"
+pub(crate) fn quote_ident(name: &str) -> String {
+    name.split('.').map(|part| format!("\"{}\"", part.replace('"', "\"\""))).collect::<Vec<String>>().join(".")
+}
+
 
 /// create a new Pool from environment variables
 pub async fn pool_no_tls_from_env() -> Result<ConnPoolNoTLS, PachyDarn> {
-    let config = SimpleConfig::new_from_env();
+    let config = SimpleConfig::try_new_from_env()?;
     pool_no_tls_from_config(&config).await
 }
 
+/// Load .env and .env.{profile} (see utils::load_env) and then create a new Pool from environment variables
+#[cfg(feature = "dotenvy")]
+pub async fn pool_no_tls_from_env_with_profile(profile: Option<&str>) -> Result<ConnPoolNoTLS, PachyDarn> {
+    let report = crate::utils::load_env(profile)?;
+    pachy_log!(info, "pachydurable::connect", "loaded environment variables {:?}", report.sources);
+    pool_no_tls_from_env().await
+}
+
 /// create a new Pool from a SimpleConfig
+/// The config (with the password redacted) is logged at the debug level before connecting
 pub async fn pool_no_tls_from_config(config: &SimpleConfig) -> Result<ConnPoolNoTLS, PachyDarn> {
+    pachy_log!(debug, "pachydurable::connect", "connecting with {}", redact_config(config));
+    pool_no_tls_from_pg_config(pg_config_from(config), config).await
+}
+
+// the tokio_postgres Config for a SimpleConfig 
+pub(crate) fn pg_config_from(config: &SimpleConfig) -> Config {
     let mut pg_config = Config::new();
     pg_config.user(&config.user);
     pg_config.password(&config.password);
     pg_config.dbname(&config.database);
     pg_config.host(&config.host);
     pg_config.port(config.port);
+    for (host, port) in &config.failover_hosts {
+        pg_config.host(host);
+        pg_config.port(*port);
+    }
+    pg_config.target_session_attrs(config.target_session_attrs);
+    if !config.schema_search_path.is_empty() {
+        // the names were validated when the config was loaded, so they need no escaping
+        pg_config.options(&format!("-c search_path={}", config.schema_search_path.join(",")));
+    }
+    pg_config
+}
+
+// create a new Pool from a tokio_postgres Config, i.e. one with extra options, using the pool settings of the SimpleConfig
+pub(crate) async fn pool_no_tls_from_pg_config(pg_config: Config, config: &SimpleConfig) -> Result<ConnPoolNoTLS, PachyDarn> {
     // instantiate a manager and a pool
     let manager = PgConnectionManager::new(pg_config, NoTls);
-    let pool = Pool::builder().max_open(20).max_idle(5).build(manager);
+    let pool = Pool::builder()
+        .max_open(u64::from(config.max_connections))
+        .max_idle(5)
+        .max_lifetime(config.idle_timeout_secs.map(Duration::from_secs))
+        .build(manager);
     // ensure you can connect now instead of throwing an 
-    let _client: ClientNoTLS = pool.get().await?; // No ensure you can connect
+    let client: ClientNoTLS = pool.get().await?; // No ensure you can connect
+    // with failover hosts, it's worth knowing which one was picked
+    let rows = client.query("SELECT host(inet_server_addr()), inet_server_port()", &[]).await?;
+    if let Some(row) = rows.get(0) {
+        let (addr, port): (Option<String>, Option<i32>) = (row.try_get(0)?, row.try_get(1)?);
+        match (addr, port) {
+            (Some(addr), Some(port)) => pachy_log!(info, "pachydurable::connect", "connected to {}:{}", addr, port),
+            _ => pachy_log!(info, "pachydurable::connect", "connected over a unix socket"),
+        }
+    }
+    Ok(pool)
+}
+
+
+/// Like pool_no_tls_from_env, but the pool is a deadpool_postgres::Pool (see client::PgPoolLike)
+#[cfg(feature = "deadpool")]
+pub async fn deadpool_from_env() -> Result<deadpool_postgres::Pool, PachyDarn> {
+    let config = SimpleConfig::try_new_from_env()?;
+    deadpool_from_config(&config).await
+}
+
+/// Like pool_no_tls_from_config, but the pool is a deadpool_postgres::Pool.
+/// deadpool has no maximum connection lifetime, so idle_timeout_secs is not used: connections are checked
+/// before they are handed out instead (RecyclingMethod::Fast)
+#[cfg(feature = "deadpool")]
+pub async fn deadpool_from_config(config: &SimpleConfig) -> Result<deadpool_postgres::Pool, PachyDarn> {
+    use deadpool_postgres::{Manager, ManagerConfig, RecyclingMethod};
+    pachy_log!(debug, "pachydurable::connect", "connecting with {}", redact_config(config));
+    let manager = Manager::from_config(pg_config_from(config), NoTls, ManagerConfig{recycling_method: RecyclingMethod::Fast});
+    let pool = deadpool_postgres::Pool::builder(manager)
+        .max_size(config.max_connections as usize)
+        .build()
+        .map_err(PachyDarn::boxed)?;
+    // ensure you can connect now, like pool_no_tls_from_pg_config
+    let _client = pool.get().await?;
     Ok(pool)
 }
 
+/// SQLSTATEs that mean Postgres could not (yet) serve the connection, rather than that the query was wrong:
+/// too many connections, server shutting down or starting up, and the connection exception class
+const TRANSIENT_SQLSTATES: [&str; 8] = ["53300", "57P01", "57P03", "08000", "08001", "08003", "08004", "08006"];
+
+/// true for errors worth retrying: pool-level errors (the MobcPG variant), Postgres errors with a
+/// SQLSTATE like 53300 (too many connections), and connections closed under the client (i.e. connection reset by peer)
+pub fn is_transient_pg_error(e: &PachyDarn) -> bool {
+    match e.root() {
+        PachyDarn::MobcPG(_) => true,
+        PachyDarn::Postgres(err) => {
+            let transient_state = e.sqlstate().map(|state| TRANSIENT_SQLSTATES.contains(&state)).unwrap_or(false);
+            let io_error = err.source().map(|source| source.is::<std::io::Error>()).unwrap_or(false);
+            transient_state || err.is_closed() || io_error
+        },
+        _ => false,
+    }
+}
+
+/// Call f until it succeeds, up to max_attempts times in total, sleeping delay_ms between attempts.
+/// Only errors for which is_transient_pg_error is true are retried- others are returned immediately,
+/// and once the attempts are exhausted the last error is returned:
+/// ```ignore
+/// let rows = with_retry(|| async { Ok(pool.get().await?.query("SELECT 1", &[]).await?) }, 3, 250).await?;
+/// ```
+pub async fn with_retry<T, F, Fut>(f: F, max_attempts: u32, delay_ms: u64) -> Result<T, PachyDarn>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, PachyDarn>>,
+{
+    let mut attempt: u32 = 1;
+    loop {
+        match f().await {
+            Ok(t) => return Ok(t),
+            Err(e) if attempt < max_attempts && is_transient_pg_error(&e) => {
+                pachy_log!(warn, "pachydurable::connect", "attempt {} of {} failed, retrying in {} ms: {}", attempt, max_attempts, delay_ms, e);
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                attempt += 1;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+
+// the SQLSTATEs of a connection that is gone: admin_shutdown (i.e. pg_terminate_backend), crash_shutdown,
+// connection_does_not_exist and connection_failure
+const BROKEN_CONNECTION_SQLSTATES: [&str; 4] = ["57P01", "57P02", "08003", "08006"];
+
+/// true if the connection a statement ran on is gone (closed, reset, or terminated by the server), so the same statement
+/// might succeed on a fresh connection. Unlike is_transient_pg_error, pool errors and overload (i.e. too many connections) are false,
+/// as are errors the statement itself caused, like constraint violations
+pub fn is_broken_connection_error(e: &PachyDarn) -> bool {
+    match e.root() {
+        PachyDarn::Postgres(err) => {
+            let broken_state = e.sqlstate().map(|state| BROKEN_CONNECTION_SQLSTATES.contains(&state)).unwrap_or(false);
+            let broken_io = err.source().and_then(|source| source.downcast_ref::<std::io::Error>()).map(|io| matches!(io.kind(),
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionRefused
+                | std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::UnexpectedEof)).unwrap_or(false);
+            broken_state || err.is_closed() || broken_io
+        },
+        _ => false,
+    }
+}
+
+/// Wraps a closure that is safe to run twice, i.e. a read or an upsert, for with_reconnect.
+/// Writes are never retried unless they are wrapped in this
+pub struct Idempotent<F>(pub F);
+
+/// Run f with a client from the pool, and if it fails because the connection was dead (see is_broken_connection_error),
+/// run it once more with a fresh client. This covers the stale connections left in a pool after Postgres restarts or fails over:
+/// the dead client is dropped (the pool discards closed connections) before the retry checks out another
+/// ```ignore
+/// let n: i64 = with_reconnect(&pool, Idempotent(|client| async move {
+///     get_scalar(&client, "SELECT COUNT(*) FROM animals", &[]).await
+/// })).await?;
+/// ```
+pub async fn with_reconnect<P, T, F, Fut>(pool: &P, f: Idempotent<F>) -> Result<T, PachyDarn>
+where
+    P: PgPoolLike,
+    F: Fn(P::Client) -> Fut,
+    Fut: Future<Output = Result<T, PachyDarn>>,
+{
+    let Idempotent(f) = f;
+    match f(pool.client().await?).await {
+        Err(e) if is_broken_connection_error(&e) => {
+            pachy_log!(warn, "pachydurable::connect", "retrying on a fresh connection after the connection was lost: {}", e);
+            f(pool.client().await?).await
+        },
+        res => res,
+    }
+}
+
+/// get_opt with a client from the pool, retried once on a fresh connection if the first was dead (see with_reconnect).
+/// Only pass it reads, since it doesn't check
+pub async fn get_opt_retrying<'a, P: PgPoolLike, T>(pool: &P, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params: &'a [&'a (dyn ToSql + Sync)]) -> Result<Option<T>, PachyDarn> {
+    with_reconnect(pool, Idempotent(|client| async move { get_opt(&client, query, rowfunc, params).await })).await
+}
+
+/// get_one with a client from the pool, retried once on a fresh connection if the first was dead. Only pass it reads
+pub async fn get_one_retrying<'a, P: PgPoolLike, T>(pool: &P, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params: &'a [&'a (dyn ToSql + Sync)]) -> Result<T, PachyDarn> {
+    with_reconnect(pool, Idempotent(|client| async move { get_one(&client, query, rowfunc, params).await })).await
+}
+
+/// get_vec with a client from the pool, retried once on a fresh connection if the first was dead. Only pass it reads
+pub async fn get_vec_retrying<'a, P: PgPoolLike, T>(pool: &P, query: &'static str, rowfunc: &'a dyn Fn(&Row) -> T, params: &'a [&'a (dyn ToSql + Sync)]) -> Result<Vec<T>, PachyDarn> {
+    with_reconnect(pool, Idempotent(|client| async move { get_vec(&client, query, rowfunc, params).await })).await
+}
+
+
+/// Run f with the client's search_path set to the schema, i.e. for a multi-tenant database with a schema per tenant:
+/// ```ignore
+/// let animals = with_schema_path(pool.get().await?, &tenant, |client| async move {
+///     get_vec(&client, "SELECT id, name FROM animals", &rowfunc, &[]).await
+/// }).await?;
+/// ```
+/// The search_path lasts for the session, so f is given a SchemaScopedClient rather than the ClientNoTLS itself:
+/// when it is dropped, the search_path is reset before the connection goes back to the pool, so the next request
+/// to check it out doesn't see this tenant's tables. A schema that doesn't exist is an error of kind unknown_schema (404)
+pub async fn with_schema_path<T, F, Fut>(client: ClientNoTLS, schema: &str, f: F) -> Result<T, PachyDarn>
+where
+    F: FnOnce(SchemaScopedClient) -> Fut,
+    Fut: Future<Output = Result<T, PachyDarn>>,
+{
+    // scoped before the search_path is set, so it is reset even if this future is dropped while setting it
+    let client = SchemaScopedClient{client: Some(client)};
+    let rows = client.query("SELECT set_config('search_path', $2, false) FROM pg_namespace WHERE nspname = $1", &[&schema, &quote_ident(schema)]).await?;
+    if rows.is_empty() {
+        return Err(PachyDarn::custom_with_status("unknown_schema", format!("there is no schema named {}", schema), 404))
+    }
+    f(client).await
+}
+
+/// A pooled client whose search_path was set by with_schema_path. It derefs to the ClientNoTLS and implements
+/// PachyClient, and resets the search_path when it is dropped
+pub struct SchemaScopedClient {
+    client: Option<ClientNoTLS>,
+}
+
+impl std::ops::Deref for SchemaScopedClient {
+    type Target = ClientNoTLS;
+
+    fn deref(&self) -> &ClientNoTLS {
+        // only None once it is being dropped
+        self.client.as_ref().expect("SchemaScopedClient used after drop")
+    }
+}
+
+impl Drop for SchemaScopedClient {
+    fn drop(&mut self) {
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => return,
+        };
+        // the client is only returned to the pool once the task finishes, so no one else can check it out first
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(e) = client.batch_execute("RESET search_path").await {
+                        pachy_log!(warn, "pachydurable::connect", "failed to reset the search_path of a pooled connection: {}", e);
+                    }
+                });
+            },
+            Err(_) => pachy_log!(warn, "pachydurable::connect", "a SchemaScopedClient was dropped outside of a tokio runtime, so its search_path was not reset"),
+        }
+    }
+}
+
+/// Like with_schema_path, but the name must match [a-zA-Z_][a-zA-Z0-9_]*, or an error of kind invalid_schema (400)
+/// is returned without running f, i.e. for a tenant's schema:
+/// ```ignore
+/// let animals = with_schema(pool.get().await?, &tenant, |client| async move {
+///     get_vec(&client, "SELECT id, name FROM animals", &rowfunc, &[]).await
+/// }).await?;
+/// ```
+/// f is given a SchemaScopedClient, so the search_path is reset (to SimpleConfig::schema_search_path if it was set)
+/// before the connection goes back to the pool, even if f fails, panics, or the future is dropped part way through
+pub async fn with_schema<T, F, Fut>(client: ClientNoTLS, schema_name: &str, f: F) -> Result<T, PachyDarn>
+where
+    F: FnOnce(SchemaScopedClient) -> Fut,
+    Fut: Future<Output = Result<T, PachyDarn>>,
+{
+    if !is_schema_name(schema_name) {
+        return Err(PachyDarn::custom_with_status("invalid_schema", format!("\"{}\" is not a valid schema name", schema_name), 400))
+    }
+    with_schema_path(client, schema_name, f).await
+}
+
+// whether the name matches [a-zA-Z_][a-zA-Z0-9_]*, so it can go into SQL as an identifier
+fn is_schema_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => (first.is_ascii_alphabetic() || first == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        None => false,
+    }
+}
+
+/// Begin a transaction on the client and set each (name, value) in it with set_config(name, value, true),
+/// i.e. for row-level security policies keyed off current_setting('app.tenant_id'):
+/// ```ignore
+/// let mut client = pool.get().await?;
+/// let guard = with_tenant(&mut client, &[("app.tenant_id", &tenant_id)]).await?;
+/// let notes: Vec<Note> = exec_fulltext(&guard, &phrase).await?;
+/// ```
+/// The TenantGuard implements PachyClient, so exec_autocomp, exec_fulltext, get_by_pk etc. all take it.
+/// The settings are local to the transaction, so they can't outlive the guard: dropping it (i.e. when a handler
+/// returns early with an error) rolls the transaction back, and commit() keeps writes made through it.
+/// Either way the connection goes back to the pool without the settings. The client is borrowed mutably
+/// so it can't be used around the guard while it is alive
+pub async fn with_tenant<'a>(client: &'a mut ClientNoTLS, settings: &[(&str, &str)]) -> Result<TenantGuard<'a>, PachyDarn> {
+    let tx = client.transaction().await?;
+    for (name, value) in settings {
+        // if this fails, dropping tx rolls back the settings made so far
+        tx.query("SELECT set_config($1, $2, true)", &[name, value]).await?;
+    }
+    Ok(TenantGuard{tx})
+}
+
+/// A transaction with tenant settings, returned by with_tenant. It derefs to the Transaction
+pub struct TenantGuard<'a> {
+    tx: Transaction<'a>,
+}
+
+impl<'a> TenantGuard<'a> {
+    /// Commit writes made through the guard. The settings end with the transaction
+    pub async fn commit(self) -> Result<(), PachyDarn> {
+        Ok(self.tx.commit().await?)
+    }
+
+    /// Roll back, as dropping the guard does, but wait for Postgres to confirm it
+    pub async fn rollback(self) -> Result<(), PachyDarn> {
+        Ok(self.tx.rollback().await?)
+    }
+}
+
+impl<'a> std::ops::Deref for TenantGuard<'a> {
+    type Target = Transaction<'a>;
+
+    fn deref(&self) -> &Transaction<'a> {
+        &self.tx
+    }
+}
+
+
+// makes each savepoint name unique, so nested savepoints can share a name
+static SAVEPOINT_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Run f inside a SAVEPOINT of the transaction: if f succeeds the savepoint is released, and if it fails
+/// the transaction is rolled back to the savepoint and f's error returned, so the outer transaction can carry on
+/// (i.e. to retry or skip a step) and later commit the work done around it:
+/// ```ignore
+/// let mut tx = client.transaction().await?;
+/// let res = with_savepoint(&mut tx, "import_row", |sp| Box::pin(async move {
+///     execute(sp, "INSERT INTO animals (name) VALUES ($1)", &[&name]).await
+/// })).await;
+/// tx.commit().await?;
+/// ```
+/// f is given the savepoint as a &mut Transaction, so it can call with_savepoint again to nest them.
+/// Only a Transaction can be passed in, so a savepoint outside a transaction doesn't compile.
+/// The name is only a prefix: non-alphanumeric characters become _ and a sequence number is appended
+pub async fn with_savepoint<'t, T, F>(tx: &mut Transaction<'t>, name: &str, f: F) -> Result<T, PachyDarn>
+where
+    F: for<'s, 'x> FnOnce(&'s mut Transaction<'x>) -> BoxFuture<'s, Result<T, PachyDarn>>,
+{
+    let prefix: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+    let name = format!("{}_{}", prefix, SAVEPOINT_SEQ.fetch_add(1, Ordering::Relaxed));
+    let mut savepoint = tx.savepoint(name.clone()).await?;
+    match f(&mut savepoint).await {
+        Ok(t) => {
+            savepoint.commit().await?; // RELEASE SAVEPOINT
+            Ok(t)
+        },
+        Err(e) => {
+            if let Err(rollback_err) = savepoint.rollback().await {
+                pachy_log!(warn, "pachydurable::connect", "failed to roll back to savepoint {}: {}", name, rollback_err);
+            }
+            Err(e)
+        },
+    }
+}
+
+
 /// This struct describes how to connect to an instance using host/port/passwords etc.
 pub struct SimpleConfig {
     pub host: String,
@@ -75,49 +933,146 @@ pub struct SimpleConfig {
     pub user: String,
     pub password: String,
     pub database: String,
+    /// Connections older than this are closed and replaced by the pool, so a connection that
+    /// Postgres has already dropped server-side isn't handed out. None keeps connections indefinitely
+    pub idle_timeout_secs: Option<u64>,
+    /// The most connections the pool opens, for the mobc pool and deadpool alike
+    pub max_connections: u32,
+    /// Hosts and ports tried in order after host:port, i.e. the standby of a primary.
+    /// Each new connection goes to the first one that accepts it and matches target_session_attrs
+    pub failover_hosts: Vec<(String, u16)>,
+    /// ReadWrite skips hosts that are read-only, so after a failover new connections land on the promoted standby
+    /// (while connections to the old primary error and are replaced). Any takes the first host that accepts the connection
+    pub target_session_attrs: TargetSessionAttrs,
+    /// The search_path new connections start with, i.e. ["tenant_a", "public"]. with_schema overrides it for one call
+    /// and then resets back to it. Empty leaves the server's default
+    pub schema_search_path: Vec<String>,
+}
+
+/// The default for SimpleConfig.idle_timeout_secs if PSQL_IDLE_TIMEOUT_SECS is not set 
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 300;
+
+/// The default for SimpleConfig.max_connections if PSQL_MAX_CONNECTIONS is not set
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 20;
+
+// the idle timeout the variable sets: DEFAULT_IDLE_TIMEOUT_SECS if it is unset, None if it is 0 (which disables the timeout),
+// and an error naming the variable rather than a panic if it isn't a number
+fn idle_timeout_secs_from_env(name: &str) -> Result<Option<u64>, PachyDarn> {
+    match env_parse::<u64>(name, DEFAULT_IDLE_TIMEOUT_SECS)? {
+        0 => Ok(None),
+        secs => Ok(Some(secs)),
+    }
 }
 
 impl SimpleConfig {
 
     /// Instantiate a new SimpleConfig from a provided database and user name,
-    /// Sourcing other parameters from environment variables
+    /// Sourcing other parameters from environment variables.
+    /// This panics if a variable is set to an invalid value- see try_new_from_db_user_env 
     pub fn new_from_db_user_env(database: &str, user: &str) -> Self {
-        let host = match env::var("PSQL_HOST") {
-            Ok(var) => var,
-            Err(_) => "127.0.0.1".to_string(),
-        };
-        let port = match env::var("PSQL_PORT") {
-            Ok(var) => var,
-            Err(_) => "5432".to_string(),
+        SimpleConfig::try_new_from_db_user_env(database, user).unwrap()
+    }
+
+    /// Like new_from_db_user_env, but an invalid PSQL_PORT, PSQL_IDLE_TIMEOUT_SECS or PSQL_MAX_CONNECTIONS is returned as an error naming the variable.
+    /// If PSQL_HOSTS (i.e. db-a:5432,db-b:5432) is set, its first host is used instead of PSQL_HOST and the rest are failover_hosts
+    pub fn try_new_from_db_user_env(database: &str, user: &str) -> Result<Self, PachyDarn> {
+        let idle_timeout_secs = idle_timeout_secs_from_env("PSQL_IDLE_TIMEOUT_SECS")?;
+        let port = env_parse("PSQL_PORT", 5432)?;
+        let max_connections = env_parse("PSQL_MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS)?;
+        if max_connections == 0 {
+            return Err(config_error("PSQL_MAX_CONNECTIONS", "must be at least 1"))
+        }
+        let mut hosts = match env_opt::<String>("PSQL_HOSTS")? {
+            Some(list) => parse_hosts(&list, port).map_err(|problem| config_error("PSQL_HOSTS", problem))?,
+            None => vec![(env_parse("PSQL_HOST", "127.0.0.1".to_string())?, port)],
         };
-        let password = match env::var("PSQL_PW") {
-            Ok(var) => var,
-            Err(_) => "".to_string(),
+        let (host, port) = hosts.remove(0);
+        let target_session_attrs = match env_opt::<String>("PSQL_TARGET_SESSION_ATTRS")?.as_deref() {
+            None | Some("any") => TargetSessionAttrs::Any,
+            Some("read-write") => TargetSessionAttrs::ReadWrite,
+            Some(other) => return Err(config_error("PSQL_TARGET_SESSION_ATTRS", format!("has invalid value '{}', expected read-write or any", other))),
         };
-        SimpleConfig {
-            host: host,
-            port: port.parse::<u16>().unwrap(),
+        // PSQL_SEARCH_PATH is a comma-separated list of schemas, i.e. tenant_a,public
+        let mut schema_search_path = Vec::new();
+        for schema in env_opt::<String>("PSQL_SEARCH_PATH")?.unwrap_or_default().split(',').map(str::trim).filter(|schema| !schema.is_empty()) {
+            if !is_schema_name(schema) {
+                return Err(config_error("PSQL_SEARCH_PATH", format!("has invalid schema name '{}'", schema)))
+            }
+            schema_search_path.push(schema.to_string());
+        }
+        Ok(SimpleConfig {
+            host,
+            port,
             user: user.to_string(),
-            password: password,
+            password: env_parse("PSQL_PW", String::new())?,
             database: database.to_string(),
-        }
+            idle_timeout_secs: idle_timeout_secs,
+            max_connections,
+            failover_hosts: hosts,
+            target_session_attrs,
+            schema_search_path,
+        })
     }
 
 
-    /// Instantiate a new SimpleConfig purely from environment variables
+    /// Instantiate a new SimpleConfig purely from environment variables.
+    /// This panics if a variable is set to an invalid value- see try_new_from_env 
     pub fn new_from_env() -> Self {
-        let user = match env::var("PSQL_USER") {
-            Ok(var) => var,
-            Err(_) => "postgres".to_string(),
-        };
-        let database = match env::var("PSQL_DB") {
-            Ok(var) => var,
-            Err(_) => "postgres".to_string(),
+        SimpleConfig::try_new_from_env().unwrap()
+    }
+
+    /// Like new_from_env, but invalid values are returned as an error naming the variable
+    pub fn try_new_from_env() -> Result<Self, PachyDarn> {
+        let user: String = env_parse("PSQL_USER", "postgres".to_string())?;
+        let database: String = env_parse("PSQL_DB", "postgres".to_string())?;
+        SimpleConfig::try_new_from_db_user_env(&database, &user)
+    }
+}
+
+// parse a comma-separated list of host:port pairs, where the port defaults to default_port
+fn parse_hosts(list: &str, default_port: u16) -> Result<Vec<(String, u16)>, String> {
+    let mut hosts = Vec::new();
+    for entry in list.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+        let host = match entry.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), port.parse().map_err(|_| format!("has an invalid port in '{}'", entry))?),
+            None => (entry.to_string(), default_port),
         };
-        SimpleConfig::new_from_db_user_env(&database, &user)
+        hosts.push(host);
+    }
+    if hosts.is_empty() {
+        return Err("has no hosts".to_string())
     }
+    Ok(hosts)
 }
 
+/// Like redact_config, the password is printed as [REDACTED], so a SimpleConfig in a panic message or log line doesn't leak it
+impl fmt::Debug for SimpleConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimpleConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("password", &format_args!("{}", REDACTED))
+            .field("database", &self.database)
+            .field("idle_timeout_secs", &self.idle_timeout_secs)
+            .field("max_connections", &self.max_connections)
+            .field("failover_hosts", &self.failover_hosts)
+            .field("target_session_attrs", &self.target_session_attrs)
+            .field("schema_search_path", &self.schema_search_path)
+            .finish()
+    }
+}
+
+/// A connection summary without credentials, i.e. postgres://postgres@127.0.0.1:5432/animals
+impl fmt::Display for SimpleConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "postgres://{}@{}:{}", self.user, self.host, self.port)?;
+        for (host, port) in &self.failover_hosts {
+            write!(f, ",{}:{}", host, port)?;
+        }
+        write!(f, "/{}", self.database)
+    }
+}
 
 pub fn ts_expression(phrase: &str) -> String {
     // Given a phrase like "crimson thread", convert it to a TS expression
@@ -132,4 +1087,757 @@ pub fn ts_expression(phrase: &str) -> String {
 }
 
 
+/// Convert a row to a JSON object keyed by column name without defining a struct, for debugging during development.
+/// bool, integer, float, and text columns (and arrays of them) are supported, and NULLs become null.
+/// Columns of any other type also become null, with a warning logged. Only compiled with the "dev" feature
+#[cfg(feature = "dev")]
+pub fn row_to_json(row: &Row) -> Result<serde_json::Value, PachyDarn> {
+    Ok(serde_json::Value::Object(row_to_json_map(row)?))
+}
+
+// the JSON object for row_to_json and unsafe_dynamic_sql::get_json_rows
+#[cfg(any(feature = "dev", feature = "dynamic-query"))]
+fn row_to_json_map(row: &Row) -> Result<serde_json::Map<String, serde_json::Value>, PachyDarn> {
+    let mut obj = serde_json::Map::new();
+    for (idx, col) in row.columns().iter().enumerate() {
+        let ty = col.type_();
+        // Option<Vec<Option<T>>> covers both NULL arrays and NULL elements
+        macro_rules! json_of {
+            ($t:ty) => { serde_json::to_value(row.try_get::<_, Option<$t>>(idx)?)? };
+        }
+        let val = match *ty {
+            Type::BOOL => json_of!(bool),
+            Type::INT2 => json_of!(i16),
+            Type::INT4 => json_of!(i32),
+            Type::INT8 => json_of!(i64),
+            Type::FLOAT4 => json_of!(f32),
+            Type::FLOAT8 => json_of!(f64),
+            Type::TEXT | Type::VARCHAR | Type::BPCHAR | Type::NAME => json_of!(String),
+            Type::BOOL_ARRAY => json_of!(Vec<Option<bool>>),
+            Type::INT2_ARRAY => json_of!(Vec<Option<i16>>),
+            Type::INT4_ARRAY => json_of!(Vec<Option<i32>>),
+            Type::INT8_ARRAY => json_of!(Vec<Option<i64>>),
+            Type::FLOAT4_ARRAY => json_of!(Vec<Option<f32>>),
+            Type::FLOAT8_ARRAY => json_of!(Vec<Option<f64>>),
+            Type::TEXT_ARRAY | Type::VARCHAR_ARRAY | Type::BPCHAR_ARRAY | Type::NAME_ARRAY => json_of!(Vec<Option<String>>),
+            _ => {
+                pachy_log!(warn, "pachydurable::connect", "row_to_json: column {} has unsupported type {}, using null", col.name(), ty);
+                serde_json::Value::Null
+            },
+        };
+        obj.insert(col.name().to_string(), val);
+    }
+    Ok(obj)
+}
+
+
+/// SELECTs built at runtime, i.e. for an admin panel listing whatever columns of whatever table it is asked for.
+/// The table and column names are checked against information_schema and quoted, but the WHERE clause is
+/// passed through as SQL, so it must never contain user input (bind values with params instead).
+/// Only compiled with the "dynamic-query" feature
+#[cfg(feature = "dynamic-query")]
+pub mod unsafe_dynamic_sql {
+    use std::{collections::HashMap, sync::{OnceLock, RwLock}};
+    use tokio_postgres::types::ToSql;
+    use crate::{client::PachyClient, err::PachyDarn};
+    use super::{quote_ident, row_to_json_map, Row};
+
+    type ColumnCache = RwLock<HashMap<(String, String), Vec<String>>>;
+
+    // the columns of each (schema, table) in ordinal order, shared by every thread
+    static TABLE_COLUMNS: OnceLock<ColumnCache> = OnceLock::new();
+
+    fn column_cache() -> &'static ColumnCache {
+        TABLE_COLUMNS.get_or_init(|| RwLock::new(HashMap::new()))
+    }
+
+    // the columns of the table, read from information_schema the first time the process sees the table.
+    // A poisoned lock only means another thread panicked mid-insert, so the map is still used
+    async fn table_columns(client: &impl PachyClient<Row = Row>, schema: &str, table: &str) -> Result<Vec<String>, PachyDarn> {
+        let key = (schema.to_string(), table.to_string());
+        let cached = column_cache().read().unwrap_or_else(|e| e.into_inner()).get(&key).cloned();
+        if let Some(columns) = cached {
+            return Ok(columns)
+        }
+        let rows = client.query("SELECT column_name::text FROM information_schema.columns
+            WHERE table_schema = $1 AND table_name = $2 ORDER BY ordinal_position", &[&schema, &table]).await?;
+        let columns: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+        // a table that doesn't exist (yet) isn't cached, so it is found once it is created
+        if columns.is_empty() {
+            return Err(PachyDarn::custom_with_status("unknown_table", format!("there is no table {}", table), 400))
+        }
+        column_cache().write().unwrap_or_else(|e| e.into_inner()).insert(key, columns.clone());
+        Ok(columns)
+    }
+
+    /// Forget the columns cached for every table, i.e. after a migration adds or drops columns
+    pub fn clear_column_cache() {
+        column_cache().write().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// SELECT the columns (all of them, in order, if columns is empty) of table ("zoo.animals", or "animals" in the current
+    /// schema, which costs a round-trip to look up) WHERE where_clause (if any) with params bound, returning each row as a JSON object (see
+    /// connect::row_to_json for the supported types). Unknown tables and columns are 400 errors, named unknown_table
+    /// and unknown_column. The columns of a table are cached for the process, so call clear_column_cache after migrations
+    pub async fn get_json_rows(client: &impl PachyClient<Row = Row>, table: &str, columns: &[&str], where_clause: Option<&str>, params: &[&(dyn ToSql + Sync)])
+    -> Result<Vec<serde_json::Map<String, serde_json::Value>>, PachyDarn> {
+        // an unqualified table is found through the search_path, which can differ between clients (i.e. with_schema)
+        let (schema, table_name) = match table.split_once('.') {
+            Some((schema, table_name)) => (schema.to_string(), table_name),
+            None => (super::get_scalar::<String>(client, "SELECT current_schema()::text", &[]).await?, table),
+        };
+        let known = table_columns(client, &schema, table_name).await?;
+        let selected: Vec<String> = match columns.is_empty() {
+            true => known.clone(),
+            false => columns.iter().map(|col| col.to_string()).collect(),
+        };
+        if let Some(unknown) = selected.iter().find(|col| !known.contains(col)) {
+            return Err(PachyDarn::custom_with_status("unknown_column", format!("table {} has no column {}", table, unknown), 400))
+        }
+        let select: Vec<String> = selected.iter().map(|col| format!("\"{}\"", col.replace('"', "\"\""))).collect();
+        let mut query = format!("SELECT {} FROM {}", select.join(", "), quote_ident(table));
+        if let Some(where_clause) = where_clause {
+            query = format!("{} WHERE {}", query, where_clause);
+        }
+        let rows = client.query(&query, params).await?;
+        rows.iter().map(row_to_json_map).collect()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::testing::TestDb;
+    use super::*;
+
+    #[cfg(feature = "dev")]
+    #[test]
+    fn row_to_json_types() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = db.pool();
+            let client = pool.get().await.unwrap();
+            let row = client.query_one("SELECT true AS b, 7::INT4 AS i, 8::INT8 AS l, 1.5::FLOAT8 AS f, 'emu'::VARCHAR AS s, 
+                NULL::TEXT AS n, ARRAY[1, NULL]::INT4[] AS a, NOW() AS t", &[]).await.unwrap();
+            let val = row_to_json(&row).unwrap();
+            assert_eq!(val, serde_json::json!({"b": true, "i": 7, "l": 8, "f": 1.5, "s": "emu", "n": null, "a": [1, null], "t": null}));
+        })
+    }
+
+    #[cfg(feature = "dynamic-query")]
+    #[test]
+    fn dynamic_json_rows() {
+        use unsafe_dynamic_sql::get_json_rows;
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE critters (id INT PRIMARY KEY, name VARCHAR NOT NULL, legs INT);
+                INSERT INTO critters VALUES (1, 'emu', 2), (2, 'ant', 6), (3, 'eel', NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let rows = get_json_rows(&client, "critters", &[], Some("legs > $1 ORDER BY id"), &[&1i32]).await.unwrap();
+            let rows: Vec<serde_json::Value> = rows.into_iter().map(serde_json::Value::Object).collect();
+            assert_eq!(rows, vec![serde_json::json!({"id": 1, "name": "emu", "legs": 2}), serde_json::json!({"id": 2, "name": "ant", "legs": 6})]);
+            let table = format!("{}.critters", db.schema());
+            let rows = get_json_rows(&client, &table, &["name"], None, &[]).await.unwrap();
+            assert_eq!(rows.len(), 3);
+            assert_eq!(rows[0].keys().collect::<Vec<&String>>(), vec!["name"]);
+            for (table, columns) in [("critters", vec!["name", "name\" FROM pg_user --"]), ("no_such_table", vec![])] {
+                match get_json_rows(&client, table, &columns, None, &[]).await {
+                    Err(e) => assert_eq!(e.http_status(), 400, "{}", e),
+                    Ok(rows) => panic!("expected an error, got {:?}", rows),
+                }
+            }
+        })
+    }
 
+    #[test]
+    fn query_builder_numbers_placeholders() {
+        let (query, params) = QueryBuilder::select("public.animals", &["id", "name"])
+            .where_eq("owner_id", 7i32)
+            .where_in("species", vec!["cat", "dog"])
+            .order_by("name", false)
+            .order_by("id", true)
+            .limit(20)
+            .build().unwrap();
+        assert_eq!(query, r#"SELECT "id", "name" FROM "public"."animals" WHERE "owner_id" = $1 AND "species" IN ($2, $3) ORDER BY "name" ASC, "id" DESC LIMIT $4"#);
+        let params: Vec<String> = params.iter().map(|p| format!("{:?}", p)).collect();
+        assert_eq!(params, vec!["7", "\"cat\"", "\"dog\"", "20"]);
+        let (query, params) = QueryBuilder::select("animals", &["na\"me"]).build().unwrap();
+        assert_eq!((query.as_str(), params.len()), (r#"SELECT "na""me" FROM "animals""#, 0));
+        assert!(QueryBuilder::select("animals", &[]).build().is_err());
+        assert!(QueryBuilder::select("animals", &["id"]).where_in("id", Vec::<i32>::new()).build().is_err());
+    }
+
+    #[test]
+    fn query_builder_runs() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(crate::testing::DEMO_SCHEMA_SQL).await.unwrap();
+            let client = db.client().await.unwrap();
+            let (query, params) = QueryBuilder::select("animals", &["name"])
+                .where_in("name", vec!["cat".to_string(), "dog".to_string(), "emu".to_string()])
+                .order_by("name", true)
+                .limit(2)
+                .build().unwrap();
+            let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|p| p.as_ref()).collect();
+            let rows = client.query(query.as_str(), &params).await.unwrap();
+            let names: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+            assert_eq!(names, vec!["emu", "dog"]);
+        })
+    }
+
+    // the same checks for every PgPoolLike backend 
+    async fn shared_suite<P: crate::client::PgPoolLike>(pool: &P) {
+        let client = pool.client().await.unwrap();
+        let int_of = |row: &Row| row.get::<_, i32>(0);
+        assert_eq!(get_one(&client, "SELECT 7::INT4", &int_of, &[]).await.unwrap(), 7);
+        assert_eq!(get_opt(&client, "SELECT 7::INT4 WHERE false", &int_of, &[]).await.unwrap(), None);
+        let n: i32 = 3;
+        // the missing row error keeps the bound params out of its message
+        let err = get_one(&client, "SELECT $1::INT4 WHERE false", &int_of, &[&n]).await.unwrap_err();
+        assert!(err.to_string().ends_with("No row found for query \"SELECT $1::INT4 WHERE false\""), "{}", err);
+        assert_eq!(get_vec(&client, "SELECT generate_series(1, $1)", &int_of, &[&n]).await.unwrap(), vec![1, 2, 3]);
+        let series = "SELECT * FROM generate_series(1, $1) AS n";
+        assert_eq!(get_vec_ordered(&client, series, &int_of, &[&n], "n", true, &["n"]).await.unwrap(), vec![3, 2, 1]);
+        assert_eq!(get_vec_ordered(&client, series, &int_of, &[&n], "n", false, &["n"]).await.unwrap(), vec![1, 2, 3]);
+        // a trailing semicolon still leaves valid SQL, and a column can be named through its table
+        let joined = "SELECT a.n FROM generate_series(1, $1) AS a(n) JOIN generate_series(2, 3) AS b(n) ON a.n = b.n; ";
+        assert_eq!(get_vec_ordered(&client, joined, &int_of, &[&n], "a.n", true, &["a.n"]).await.unwrap(), vec![3, 2]);
+        let err = get_vec_ordered(&client, series, &int_of, &[&n], "n; DROP TABLE animals", false, &["n"]).await.unwrap_err();
+        assert_eq!(err.http_status(), 400);
+        let err = query_one(&client, "SELECT generate_series(1, 2)", &[]).await.unwrap_err();
+        assert!(matches!(err, PachyDarn::UnexpectedMultipleRows(_)));
+        assert_eq!(client.execute("SELECT 1", &[]).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn shared_suite_mobc() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            shared_suite(db.pool()).await;
+        })
+    }
+
+    #[cfg(feature = "deadpool")]
+    #[test]
+    fn shared_suite_deadpool() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            shared_suite(&deadpool_from_env().await.unwrap()).await;
+        })
+    }
+
+    #[test]
+    fn copy_out_csv() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(crate::testing::DEMO_SCHEMA_SQL).await.unwrap();
+            let client = db.client().await.unwrap();
+            let query = "COPY (SELECT name FROM animals ORDER BY name) TO STDOUT (FORMAT csv, HEADER)";
+            let chunks: Vec<Bytes> = copy_out(&client, query).await.unwrap().map(|chunk| chunk.unwrap()).collect().await;
+            let csv: Vec<u8> = chunks.concat();
+            assert_eq!(String::from_utf8(csv.clone()).unwrap(), "name\ncat\ndog\nemu\nfish\n");
+            let path = std::env::temp_dir().join(format!("pachy_copy_out_{}.csv", std::process::id()));
+            assert_eq!(copy_out_to_file(&client, query, &path).await.unwrap(), csv.len() as u64);
+            assert_eq!(std::fs::read(&path).unwrap(), csv);
+            std::fs::remove_file(&path).unwrap();
+            // a query that isn't COPY ... TO STDOUT is an error
+            assert!(copy_out(&client, "SELECT 1").await.is_err());
+        })
+    }
+
+    // its own variables, since tests run concurrently and TestDb reads the PSQL_ ones
+    #[test]
+    fn idle_timeouts_from_env() {
+        std::env::set_var("_PACHY_TEST_IDLE_TIMEOUT", "60");
+        std::env::set_var("_PACHY_TEST_IDLE_TIMEOUT_OFF", "0");
+        std::env::set_var("_PACHY_TEST_IDLE_TIMEOUT_BAD", "soon");
+        assert_eq!(idle_timeout_secs_from_env("_PACHY_TEST_IDLE_TIMEOUT").unwrap(), Some(60));
+        assert_eq!(idle_timeout_secs_from_env("_PACHY_TEST_IDLE_TIMEOUT_OFF").unwrap(), None);
+        assert_eq!(idle_timeout_secs_from_env("_PACHY_TEST_IDLE_TIMEOUT_UNSET").unwrap(), Some(DEFAULT_IDLE_TIMEOUT_SECS));
+        let err = idle_timeout_secs_from_env("_PACHY_TEST_IDLE_TIMEOUT_BAD").unwrap_err();
+        assert!(err.to_string().contains("_PACHY_TEST_IDLE_TIMEOUT_BAD"), "{}", err);
+    }
+
+    #[test]
+    fn config_formatting_hides_password() {
+        let mut config = SimpleConfig{host: "db.internal".to_string(), port: 5433, user: "app".to_string(), 
+            password: "hunter2".to_string(), database: "animals".to_string(), idle_timeout_secs: None, max_connections: 20,
+            failover_hosts: vec![], target_session_attrs: TargetSessionAttrs::Any, schema_search_path: vec![]};
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert_eq!(debug, "SimpleConfig { host: \"db.internal\", port: 5433, user: \"app\", password: [REDACTED], database: \"animals\", idle_timeout_secs: None, max_connections: 20, failover_hosts: [], target_session_attrs: Any, schema_search_path: [] }");
+        assert_eq!(config.to_string(), "postgres://app@db.internal:5433/animals");
+        config.failover_hosts.push(("db-standby.internal".to_string(), 5432));
+        assert_eq!(config.to_string(), "postgres://app@db.internal:5433,db-standby.internal:5432/animals");
+    }
+
+    #[test]
+    fn failover_hosts() {
+        use tokio_postgres::config::Host;
+        assert_eq!(parse_hosts("db-a:5433, db-b", 5432).unwrap(), vec![("db-a".to_string(), 5433), ("db-b".to_string(), 5432)]);
+        assert!(parse_hosts("db-a:primary", 5432).is_err());
+        assert!(parse_hosts(" , ", 5432).is_err());
+        let mut config = SimpleConfig::try_new_from_env().unwrap();
+        let real = (config.host.clone(), config.port);
+        // nothing listens on port 1, so every connection has to fail over
+        config.host = "127.0.0.1".to_string();
+        config.port = 1;
+        config.failover_hosts = vec![real.clone()];
+        config.target_session_attrs = TargetSessionAttrs::ReadWrite;
+        let pg_config = pg_config_from(&config);
+        assert_eq!(pg_config.get_hosts(), &[Host::Tcp("127.0.0.1".to_string()), Host::Tcp(real.0.clone())]);
+        assert_eq!(pg_config.get_ports(), &[1, real.1]);
+        assert_eq!(pg_config.get_target_session_attrs(), TargetSessionAttrs::ReadWrite);
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = pool_no_tls_from_config(&config).await.unwrap();
+            let client = pool.get().await.unwrap();
+            assert_eq!(get_scalar::<i32>(&client, "SELECT 1", &[]).await.unwrap(), 1);
+        })
+    }
+
+    #[test]
+    fn scalars() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = db.pool();
+            let client = pool.get().await.unwrap();
+            assert_eq!(get_scalar::<i64>(&client, "SELECT COUNT(*) FROM generate_series(1, $1::INT)", &[&3i32]).await.unwrap(), 3);
+            #[cfg(feature = "chrono")]
+            {
+                let now = get_scalar::<chrono::DateTime<chrono::Utc>>(&client, "SELECT now()", &[]).await.unwrap();
+                assert!((chrono::Utc::now() - now).num_seconds().abs() < 60);
+            }
+            assert!(get_scalar::<i64>(&client, "SELECT 1::INT8 WHERE false", &[]).await.is_err());
+        })
+    }
+
+    #[test]
+    fn tables_and_columns_exist() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE pets (id INT PRIMARY KEY, name VARCHAR NOT NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            assert!(ensure_table_exists(&client, db.schema(), "pets").await.unwrap());
+            assert!(!ensure_table_exists(&client, db.schema(), "owners").await.unwrap());
+            let missing = ensure_columns_exist(&client, db.schema(), "pets", &["id", "species", "name", "owner_id"]).await.unwrap();
+            assert_eq!(missing, vec!["species", "owner_id"]);
+            assert_eq!(ensure_columns_exist(&client, db.schema(), "owners", &["id"]).await.unwrap(), vec!["id"]);
+        })
+    }
+
+    // hands out a client whose backend was terminated, and then healthy ones
+    struct DeadFirst {
+        dead: std::sync::Mutex<Option<ClientNoTLS>>,
+        db: TestDb,
+        handed_out: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl PgPoolLike for DeadFirst {
+        type Client = ClientNoTLS;
+
+        async fn client(&self) -> Result<ClientNoTLS, PachyDarn> {
+            self.handed_out.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let dead = self.dead.lock().unwrap().take();
+            match dead {
+                Some(client) => Ok(client),
+                None => Ok(self.db.client().await?),
+            }
+        }
+    }
+
+    async fn dead_first() -> DeadFirst {
+        let db = TestDb::new("").await.unwrap();
+        let pool = db.pool();
+        let victim = pool.get().await.unwrap();
+        let pid: i32 = get_scalar(&victim, "SELECT pg_backend_pid()", &[]).await.unwrap();
+        let killer = pool.get().await.unwrap();
+        assert!(get_scalar::<bool>(&killer, "SELECT pg_terminate_backend($1)", &[&pid]).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        DeadFirst{dead: std::sync::Mutex::new(Some(victim)), db, handed_out: Default::default()}
+    }
+
+    #[test]
+    fn reads_retry_on_a_fresh_connection() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let pool = dead_first().await;
+            let int_of = |row: &Row| row.get::<_, i32>(0);
+            let n: i32 = 3;
+            assert_eq!(get_vec_retrying(&pool, "SELECT generate_series(1, $1)", &int_of, &[&n]).await.unwrap(), vec![1, 2, 3]);
+            assert_eq!(pool.handed_out.load(std::sync::atomic::Ordering::SeqCst), 2);
+            // the dead connection's error is classified as broken
+            let pool = dead_first().await;
+            let victim = pool.client().await.unwrap();
+            let err = get_scalar::<i32>(&victim, "SELECT 1", &[]).await.unwrap_err();
+            assert!(is_broken_connection_error(&err), "{:?}", err);
+        })
+    }
+
+    #[test]
+    fn statement_errors_are_not_retried() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = db.pool();
+            let attempts = std::sync::atomic::AtomicUsize::new(0);
+            let res = with_reconnect(pool, Idempotent(|client| {
+                attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async move { get_scalar::<i32>(&client, "SELECT 1 / 0", &[]).await }
+            })).await;
+            assert!(!is_broken_connection_error(&res.unwrap_err()));
+            assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 1);
+        })
+    }
+
+    #[test]
+    fn slow_queries_time_out_and_are_cancelled() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = db.pool();
+            let (mut client, observer) = (pool.get().await.unwrap(), pool.get().await.unwrap());
+            let pid = get_scalar::<i32>(&client, "SELECT pg_backend_pid()", &[]).await.unwrap();
+            let int_of = |row: &Row| row.get::<_, i32>(0);
+            let start = Instant::now();
+            let err = query_with_timeout(&mut client, "SELECT 1 FROM pg_sleep(5)", &int_of, &[], 50).await.unwrap_err();
+            assert!(start.elapsed() < Duration::from_secs(1));
+            assert!(matches!(err, PachyDarn::StatementTimeout{timeout_ms: 50}));
+            assert_eq!(err.http_status(), 503);
+            // the statement_timeout stops the server working on the query
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let state = get_scalar::<Option<String>>(&observer, "SELECT state FROM pg_stat_activity WHERE pid = $1", &[&pid]).await.unwrap();
+            assert_ne!(state.as_deref(), Some("active"));
+            // and the client can be used again straight away
+            let n: i32 = 3;
+            let rows = query_with_timeout(&mut client, "SELECT generate_series(1, $1)", &int_of, &[&n], 1000).await.unwrap();
+            assert_eq!(rows, vec![1, 2, 3]);
+            // nothing is left behind: a later statement isn't cancelled, and the statement_timeout only applied to the transaction
+            let setting = "SELECT current_setting('statement_timeout') FROM pg_sleep(0.1)";
+            assert_eq!(get_scalar::<String>(&client, setting, &[]).await.unwrap(), get_scalar::<String>(&observer, setting, &[]).await.unwrap());
+        })
+    }
+
+    #[test]
+    fn upserts_return_the_row() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE tags (id SERIAL PRIMARY KEY, name VARCHAR UNIQUE NOT NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let upsert = "INSERT INTO tags (name) VALUES ($1) ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name RETURNING id";
+            let id_of = |row: &Row| row.get::<_, i32>(0);
+            let red = upsert_returning(&client, upsert, &id_of, &[&"red"]).await.unwrap();
+            let blue = upsert_returning(&client, upsert, &id_of, &[&"blue"]).await.unwrap();
+            assert_ne!(red, blue);
+            assert_eq!(upsert_returning(&client, upsert, &id_of, &[&"red"]).await.unwrap(), red);
+            let res = upsert_returning(&client, "INSERT INTO tags (name) VALUES ($1) ON CONFLICT DO NOTHING RETURNING id", &id_of, &[&"red"]).await;
+            assert!(matches!(res.unwrap_err().root(), PachyDarn::MissingRow(_)));
+        })
+    }
+
+    #[test]
+    fn execute_affected_rows() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE pets (id INT PRIMARY KEY, name VARCHAR NOT NULL);").await.unwrap();
+            let mut client = db.client().await.unwrap();
+            let tx = client.transaction().await.unwrap();
+            // execute_many prepares the INSERT once and runs it for each pet
+            let inserted = execute_many(&tx, "INSERT INTO pets (id, name) VALUES ($1, $2)", &[&[&1i32, &"cat"], &[&2i32, &"dog"], &[&3i32, &"dog"]]).await.unwrap();
+            assert_eq!(inserted, 3);
+            assert_eq!(execute(&tx, "UPDATE pets SET name = 'hound' WHERE name = $1", &[&"dog"]).await.unwrap(), 2);
+            execute_expecting(&tx, "UPDATE pets SET name = 'tabby' WHERE id = $1", &[&1i32], 1).await.unwrap();
+            match execute_expecting(&tx, "DELETE FROM pets WHERE name = $1", &[&"hound"], 1).await {
+                Err(PachyDarn::Custom{kind, message, ..}) => {
+                    assert_eq!(kind, "unexpected_affected_rows");
+                    assert!(message.contains("expected 1 affected row(s), got 2"));
+                },
+                other => panic!("expected unexpected_affected_rows, got {:?}", other),
+            }
+            tx.commit().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn execute_many_without_prepare() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // clients that can't prepare statements run each set in turn
+            let client = crate::testing::MockClient::new().with_affected(1).with_affected(1);
+            let n = execute_many(&client, "DELETE FROM pets WHERE id = $1", &[&[&1i32], &[&2i32]]).await.unwrap();
+            assert_eq!(n, 2);
+            assert_eq!(client.calls().len(), 2);
+        })
+    }
+
+    #[test]
+    fn schema_scoped_clients() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let tenant_a = TestDb::new("CREATE TABLE tenant (name VARCHAR); INSERT INTO tenant VALUES ('a');").await.unwrap();
+            let tenant_b = TestDb::new("CREATE TABLE tenant (name VARCHAR); INSERT INTO tenant VALUES ('b');").await.unwrap();
+            let home = TestDb::new("").await.unwrap();
+            let pool = home.pool();
+            for (db, expected) in [(&tenant_a, "a"), (&tenant_b, "b")] {
+                let name = with_schema_path(pool.get().await.unwrap(), db.schema(), |client| async move {
+                    get_scalar::<String>(&client, "SELECT name FROM tenant", &[]).await
+                }).await.unwrap();
+                assert_eq!(name, expected);
+            }
+            // the reset runs as the client is returned to the pool
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let client = pool.get().await.unwrap();
+            let search_path = get_scalar::<String>(&client, "SHOW search_path", &[]).await.unwrap();
+            assert!(search_path.contains(home.schema()), "search_path was not reset: {}", search_path);
+            drop(client);
+            let res = with_schema_path(pool.get().await.unwrap(), "no_such_tenant", |_client| async move { Ok(()) }).await;
+            assert_eq!(res.unwrap_err().http_status(), 404);
+        })
+    }
+
+    #[test]
+    fn with_schema_resets_to_the_config_default() {
+        async fn tenant_name(client: &ClientNoTLS) -> Result<String, PachyDarn> {
+            get_scalar(client, "SELECT name FROM tenant", &[]).await
+        }
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let tenant_a = TestDb::new("CREATE TABLE tenant (name VARCHAR); INSERT INTO tenant VALUES ('a');").await.unwrap();
+            let tenant_b = TestDb::new("CREATE TABLE tenant (name VARCHAR); INSERT INTO tenant VALUES ('b');").await.unwrap();
+            let mut config = SimpleConfig::try_new_from_env().unwrap();
+            config.schema_search_path = vec![tenant_a.schema().to_string()];
+            // one connection, so each checkout gets the connection the last one used
+            let pool: ConnPoolNoTLS = Pool::builder().max_open(1).build(PgConnectionManager::new(pg_config_from(&config), NoTls));
+            assert_eq!(tenant_name(&pool.get().await.unwrap()).await.unwrap(), "a");
+            let name = with_schema(pool.get().await.unwrap(), tenant_b.schema(), |client| async move { tenant_name(&client).await }).await.unwrap();
+            assert_eq!(name, "b");
+            assert_eq!(tenant_name(&pool.get().await.unwrap()).await.unwrap(), "a");
+            // the search_path is reset when f fails too
+            let res: Result<(), PachyDarn> = with_schema(pool.get().await.unwrap(), tenant_b.schema(), |client| async move {
+                client.batch_execute("SELECT * FROM no_such_table").await?;
+                Ok(())
+            }).await;
+            assert!(res.is_err());
+            assert_eq!(tenant_name(&pool.get().await.unwrap()).await.unwrap(), "a");
+            // and when the future is dropped part way through
+            let abandoned = with_schema(pool.get().await.unwrap(), tenant_b.schema(), |_client| async move {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            });
+            assert!(tokio::time::timeout(Duration::from_millis(100), abandoned).await.is_err());
+            assert_eq!(tenant_name(&pool.get().await.unwrap()).await.unwrap(), "a");
+            let err = with_schema(pool.get().await.unwrap(), "public; DROP TABLE tenant", |_client| async move { Ok(()) }).await.unwrap_err();
+            assert_eq!(err.http_status(), 400);
+            assert!(!is_schema_name("9lives") && !is_schema_name("") && is_schema_name("_tenant_9"));
+        })
+    }
+
+    #[test]
+    fn tenant_settings_end_with_the_guard() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE notes (tenant_id INT NOT NULL, body VARCHAR NOT NULL);").await.unwrap();
+            let mut client = db.client().await.unwrap();
+            let setting = "SELECT current_setting('app.tenant_id', true)";
+            {
+                let guard = with_tenant(&mut client, &[("app.tenant_id", "42"), ("app.user_id", "7")]).await.unwrap();
+                assert_eq!(get_scalar::<Option<String>>(&guard, setting, &[]).await.unwrap().as_deref(), Some("42"));
+                execute(&guard, "INSERT INTO notes VALUES (current_setting('app.tenant_id')::int, 'dropped')", &[]).await.unwrap();
+                // dropped without commit
+            }
+            let leaked = get_scalar::<Option<String>>(&client, setting, &[]).await.unwrap();
+            assert!(leaked.as_deref().unwrap_or("").is_empty(), "app.tenant_id leaked: {:?}", leaked);
+            assert_eq!(get_scalar::<i64>(&client, "SELECT COUNT(*) FROM notes", &[]).await.unwrap(), 0);
+            let guard = with_tenant(&mut client, &[("app.tenant_id", "43")]).await.unwrap();
+            execute(&guard, "INSERT INTO notes VALUES (current_setting('app.tenant_id')::int, 'kept')", &[]).await.unwrap();
+            guard.commit().await.unwrap();
+            assert!(get_scalar::<Option<String>>(&client, setting, &[]).await.unwrap().as_deref().unwrap_or("").is_empty());
+            assert_eq!(get_scalar::<i32>(&client, "SELECT tenant_id FROM notes", &[]).await.unwrap(), 43);
+        })
+    }
+
+    #[test]
+    fn savepoints_keep_the_outer_transaction() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE steps (id INT PRIMARY KEY);").await.unwrap();
+            let mut client = db.client().await.unwrap();
+            let insert = "INSERT INTO steps (id) VALUES ($1)";
+            let mut tx = client.transaction().await.unwrap();
+            let failed = with_savepoint(&mut tx, "first", |sp| Box::pin(async move {
+                execute(&*sp, insert, &[&1i32]).await?;
+                Err::<(), _>(PachyDarn::custom("step_failed", "the first step failed"))
+            })).await;
+            match failed {
+                Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "step_failed"),
+                other => panic!("expected step_failed, got {:?}", other),
+            }
+            with_savepoint(&mut tx, "second", |sp| Box::pin(async move {
+                execute(&*sp, insert, &[&2i32]).await?;
+                // the nested savepoint fails on a duplicate key, which only undoes its own insert
+                let nested = with_savepoint(sp, "second", |inner| Box::pin(async move {
+                    execute(&*inner, insert, &[&3i32]).await?;
+                    execute(&*inner, insert, &[&2i32]).await
+                })).await;
+                assert!(nested.unwrap_err().is_unique_violation());
+                execute(&*sp, insert, &[&4i32]).await
+            })).await.unwrap();
+            execute(&tx, insert, &[&5i32]).await.unwrap();
+            tx.commit().await.unwrap();
+            let rows = client.query("SELECT id FROM steps ORDER BY id", &[]).await.unwrap();
+            let ids: Vec<i32> = rows.iter().map(|row| row.get(0)).collect();
+            assert_eq!(ids, vec![2, 4, 5]);
+        })
+    }
+
+    #[test]
+    fn stream_rows() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = db.pool();
+            let client = pool.get().await.unwrap();
+            let stream = get_stream(&client, "SELECT generate_series(1, $1::INT)", |row| row.get::<_, i32>(0), &[&5i32]).await.unwrap();
+            drop(client); // the stream does not borrow the client
+            let vals: Vec<i32> = stream.map(|val| val.unwrap()).collect().await;
+            assert_eq!(vals, vec![1, 2, 3, 4, 5]);
+        })
+    }
+
+    #[test]
+    fn export_ndjson_round_trips() {
+        #[derive(Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct Line {
+            n: i32,
+            label: String,
+        }
+        fn rowfunc(row: &Row) -> Result<Line, PachyDarn> {
+            Ok(Line{n: row.try_get(0)?, label: row.try_get(1)?})
+        }
+        // every thousandth label is NULL, so 10 of the 10,000 rows fail to convert
+        const QUERY: &str = "SELECT n, CASE WHEN n % 1000 = 0 THEN NULL ELSE 'row ' || n END FROM generate_series(1, 10000) n";
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = db.pool();
+            let client = pool.get().await.unwrap();
+            let options = ExportOptions{max_errors: 10, chunk_bytes: 4096, ..Default::default()};
+            let mut out: Vec<u8> = Vec::new();
+            let stats = export_ndjson(&client, QUERY, &rowfunc, &[], &mut out, &options).await.unwrap();
+            assert_eq!(stats, ExportStats{rows: 9990, errors: 10, bytes: out.len() as u64});
+            let lines: Vec<Line> = out.split(|b| *b == b'\n').filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice(line).unwrap()).collect();
+            assert_eq!(lines.len(), 9990);
+            assert_eq!(lines[0], Line{n: 1, label: "row 1".to_string()});
+            assert!(lines.iter().all(|line| line.n % 1000 != 0 && line.label == format!("row {}", line.n)));
+            // one more error than the budget fails the export
+            let options = ExportOptions{max_errors: 9, ..Default::default()};
+            match export_ndjson(&client, QUERY, &rowfunc, &[], Vec::new(), &options).await {
+                Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "export_errors"),
+                other => panic!("expected an export_errors error, got {:?}", other),
+            }
+            #[cfg(feature = "gzip")]
+            {
+                use tokio::io::AsyncReadExt;
+                let options = ExportOptions{max_errors: 10, gzip: true, ..Default::default()};
+                let mut gzipped: Vec<u8> = Vec::new();
+                let gz_stats = export_ndjson(&client, QUERY, &rowfunc, &[], &mut gzipped, &options).await.unwrap();
+                assert_eq!(gz_stats, stats);
+                let mut decoded = Vec::new();
+                async_compression::tokio::bufread::GzipDecoder::new(&gzipped[..]).read_to_end(&mut decoded).await.unwrap();
+                assert_eq!(decoded, out);
+            }
+            #[cfg(not(feature = "gzip"))]
+            {
+                let options = ExportOptions{gzip: true, ..Default::default()};
+                match export_ndjson(&client, QUERY, &rowfunc, &[], Vec::new(), &options).await {
+                    Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "gzip_unavailable"),
+                    other => panic!("expected a gzip_unavailable error, got {:?}", other),
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn multiquery_tuple() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = db.pool();
+            let client = pool.get().await.unwrap();
+            let (counts, names) = crate::multiquery!(&client,
+                ("SELECT generate_series(1, 3)", &[], |row: &Row| row.get::<_, i32>(0)),
+                ("SELECT $1::TEXT || generate_series(1, 2)", &[&"emu"], |row: &Row| row.get::<_, String>(0)),
+            );
+            assert_eq!(counts.unwrap(), vec![1, 2, 3]);
+            assert_eq!(names.unwrap(), vec!["emu1".to_string(), "emu2".to_string()]);
+            let (missing,) = crate::multiquery!(&client, ("SELECT * FROM no_such_table", &[], |row: &Row| row.len()));
+            assert!(missing.unwrap_err().sqlstate().is_some());
+        })
+    }
+
+    #[test]
+    fn retry_transient_only() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use crate::err::MobcErr;
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // a pool timeout twice, then success
+            let calls = AtomicU32::new(0);
+            let res = with_retry(|| async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(PachyDarn::MobcPG(MobcErr::Timeout)),
+                    n => Ok(n),
+                }
+            }, 5, 1).await;
+            assert_eq!(res.unwrap(), 2);
+            // exhausted: the last error is returned after max_attempts calls 
+            let calls = AtomicU32::new(0);
+            let res: Result<(), PachyDarn> = with_retry(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(PachyDarn::MobcPG(MobcErr::BadConn))
+            }, 3, 1).await;
+            assert!(matches!(res, Err(PachyDarn::MobcPG(MobcErr::BadConn))));
+            assert_eq!(calls.load(Ordering::SeqCst), 3);
+            // a syntax error is not transient, so it is not retried 
+            let db = TestDb::new("").await.unwrap();
+            let pool = db.pool();
+            let calls = AtomicU32::new(0);
+            let res = with_retry(|| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let client = pool.get().await?;
+                Ok(client.query("SELEC 1", &[]).await?)
+            }, 3, 1).await;
+            assert_eq!(res.unwrap_err().sqlstate(), Some("42601"));
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+        })
+    }
+
+    #[test]
+    fn unnest_insert() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = db.pool();
+            let client = pool.get().await.unwrap();
+            client.batch_execute("CREATE TEMP TABLE pachy_unnest (id INT PRIMARY KEY, name VARCHAR);").await.unwrap();
+            let ids: Vec<Box<dyn ToSql + Sync>> = vec![Box::new(1i32), Box::new(2i32), Box::new(3i32)];
+            let names: Vec<Box<dyn ToSql + Sync>> = vec![Box::new("cat"), Box::new("dog"), Box::new(None::<String>)];
+            let query = "INSERT INTO pachy_unnest (id, name) SELECT * FROM UNNEST($1::int[], $2::varchar[])";
+            let n = execute_unnest(&client, query, &[ids, names]).await.unwrap();
+            assert_eq!(n, 3);
+            let row = client.query_one("SELECT COUNT(*) FROM pachy_unnest WHERE name IS NULL", &[]).await.unwrap();
+            assert_eq!(row.get::<_, i64>(0), 1);
+            // columns of different lengths are rejected before anything is sent
+            let ids: Vec<Box<dyn ToSql + Sync>> = vec![Box::new(4i32)];
+            let names: Vec<Box<dyn ToSql + Sync>> = vec![];
+            assert!(execute_unnest(&client, query, &[ids, names]).await.is_err());
+        })
+    }
+}
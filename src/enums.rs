@@ -0,0 +1,208 @@
+//! The enums module maps Postgres enum types (i.e. CREATE TYPE order_status AS ENUM ('pending', 'shipped')) to Rust enums.
+//! The pg_enum! macro declares the Rust enum along with ToSql and FromSql impls, so it can be passed as a param
+//! (to get_by_pk, cached_or_cache etc.) and read in a rowfunc like any other column:
+//! ```ignore
+//! pg_enum!(pub enum OrderStatus: "order_status" {
+//!     Pending = "pending",
+//!     Shipped = "shipped",
+//! });
+//! let status: OrderStatus = row.get("status");
+//! let shipped = count_by_fk::<Order>(&client, &[&OrderStatus::Shipped]).await?;
+//! ```
+//! Each enum also gets as_str(), FromStr, Display, and variants(), which lists every variant in order.
+//! A database value the Rust enum doesn't know returns an error of kind unknown_enum_value. If the database
+//! may get new values before the code does, add the lenient keyword and they are read into an Other(String) variant:
+//! ```ignore
+//! pg_enum!(pub enum AnimalClass: "animal_class" lenient { Mammal = "mammal", Bird = "bird" });
+//! assert_eq!("reptile".parse::<AnimalClass>()?, AnimalClass::Other("reptile".to_string()));
+//! ```
+//! Values are accepted as either the named enum type or text/varchar, so they can also be compared with text columns
+
+use crate::err::PachyDarn;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use bytes::BytesMut;
+    pub use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+}
+
+
+/// The error for a value that isn't one of the variants of a (strict) pg_enum!
+pub fn unknown_value(pg_type: &str, value: &str) -> PachyDarn {
+    PachyDarn::custom("unknown_enum_value", format!("'{}' is not a known value of {}", value, pg_type))
+}
+
+
+/// Declare a Rust enum mapped to a Postgres enum type. See the module documentation
+#[macro_export]
+macro_rules! pg_enum {
+    ($(#[$meta:meta])* $vis:vis enum $name:ident : $pg_type:literal { $($variant:ident = $value:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        $vis enum $name {
+            $($variant),+
+        }
+
+        impl $name {
+            /// The value of the variant in Postgres
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $($name::$variant => $value),+
+                }
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = $crate::err::PachyDarn;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($value => Ok($name::$variant),)+
+                    _ => Err($crate::enums::unknown_value($pg_type, s)),
+                }
+            }
+        }
+
+        $crate::pg_enum!(@common $name, $pg_type, $($variant),+);
+    };
+
+    ($(#[$meta:meta])* $vis:vis enum $name:ident : $pg_type:literal lenient { $($variant:ident = $value:literal),+ $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        $vis enum $name {
+            $($variant,)+
+            /// A value this enum doesn't know (yet)
+            Other(String),
+        }
+
+        impl $name {
+            /// The value of the variant in Postgres
+            pub fn as_str(&self) -> &str {
+                match self {
+                    $($name::$variant => $value,)+
+                    $name::Other(value) => value.as_str(),
+                }
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = $crate::err::PachyDarn;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($value => Ok($name::$variant),)+
+                    _ => Ok($name::Other(s.to_string())),
+                }
+            }
+        }
+
+        $crate::pg_enum!(@common $name, $pg_type, $($variant),+);
+    };
+
+    (@common $name:ident, $pg_type:literal, $($variant:ident),+) => {
+        #[allow(dead_code)]
+        impl $name {
+            /// The name of the Postgres enum type
+            pub const PG_TYPE: &'static str = $pg_type;
+
+            /// Every variant, in the order they were declared (without Other)
+            pub fn variants() -> &'static [$name] {
+                const VARIANTS: &[$name] = &[$($name::$variant),+];
+                VARIANTS
+            }
+
+            fn accepts_type(ty: &$crate::enums::__private::Type) -> bool {
+                use $crate::enums::__private::Type;
+                ty.name() == $pg_type || *ty == Type::TEXT || *ty == Type::VARCHAR
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl $crate::enums::__private::ToSql for $name {
+            fn to_sql(&self, _ty: &$crate::enums::__private::Type, out: &mut $crate::enums::__private::BytesMut)
+                -> Result<$crate::enums::__private::IsNull, Box<dyn std::error::Error + Sync + Send>> {
+                out.extend_from_slice(self.as_str().as_bytes());
+                Ok($crate::enums::__private::IsNull::No)
+            }
+
+            fn accepts(ty: &$crate::enums::__private::Type) -> bool {
+                $name::accepts_type(ty)
+            }
+
+            $crate::enums::__private::to_sql_checked!();
+        }
+
+        impl<'a> $crate::enums::__private::FromSql<'a> for $name {
+            fn from_sql(_ty: &$crate::enums::__private::Type, raw: &'a [u8]) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+                let s = std::str::from_utf8(raw)?;
+                Ok(s.parse::<$name>()?)
+            }
+
+            fn accepts(ty: &$crate::enums::__private::Type) -> bool {
+                $name::accepts_type(ty)
+            }
+        }
+    };
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::{client::PachyClient, pg_enum, testing::TestDb};
+    use super::*;
+
+    pg_enum!(
+        /// the class of a creature, without reptiles
+        enum AnimalClass: "animal_class" {
+            Mammal = "mammal",
+            Bird = "bird",
+        }
+    );
+
+    pg_enum!(enum LenientClass: "animal_class" lenient { Mammal = "mammal", Bird = "bird" });
+
+    const SCHEMA_SQL: &str = "CREATE TYPE animal_class AS ENUM ('mammal', 'bird', 'reptile');
+        CREATE TABLE creatures (id SERIAL PRIMARY KEY, class animal_class NOT NULL);";
+
+    #[test]
+    fn strings_and_variants() {
+        assert_eq!(AnimalClass::variants(), &[AnimalClass::Mammal, AnimalClass::Bird]);
+        assert_eq!(AnimalClass::Bird.as_str(), "bird");
+        assert_eq!("mammal".parse::<AnimalClass>().unwrap(), AnimalClass::Mammal);
+        match "reptile".parse::<AnimalClass>() {
+            Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "unknown_enum_value"),
+            other => panic!("expected unknown_enum_value, got {:?}", other),
+        }
+        assert_eq!("reptile".parse::<LenientClass>().unwrap(), LenientClass::Other("reptile".to_string()));
+        assert_eq!(LenientClass::Other("reptile".to_string()).to_string(), "reptile");
+    }
+
+    #[test]
+    fn round_trip() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(SCHEMA_SQL).await.unwrap();
+            let client = db.client().await.unwrap();
+            for class in AnimalClass::variants() {
+                let rows = PachyClient::query(&client, "INSERT INTO creatures (class) VALUES ($1) RETURNING class", &[class]).await.unwrap();
+                assert_eq!(rows[0].get::<_, AnimalClass>(0), *class);
+            }
+            // the enum can be compared against text too
+            let rows = PachyClient::query(&client, "SELECT COUNT(*) FROM creatures WHERE class::text = $1", &[&AnimalClass::Bird]).await.unwrap();
+            assert_eq!(rows[0].get::<_, i64>(0), 1);
+            // a value only the database knows is an error, unless the enum is lenient
+            client.batch_execute("INSERT INTO creatures (class) VALUES ('reptile');").await.unwrap();
+            let rows = PachyClient::query(&client, "SELECT class FROM creatures ORDER BY id", &[]).await.unwrap();
+            assert!(rows[2].try_get::<_, AnimalClass>(0).is_err());
+            let classes: Vec<LenientClass> = rows.iter().map(|row| row.get(0)).collect();
+            assert_eq!(classes, vec![LenientClass::Mammal, LenientClass::Bird, LenientClass::Other("reptile".to_string())]);
+        })
+    }
+}
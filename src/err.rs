@@ -1,11 +1,16 @@
-use std::{error::Error, fmt};
+use std::{error::Error, fmt, time::Duration};
 use mobc;
+#[cfg(feature = "redis")]
 use redis;
 use serde_json;
+use tokio_postgres::error::SqlState;
+/// GenericError is kept so downstream code that still names it compiles. New code should use PachyDarn:
+/// a GenericError converts into PachyDarn (via the Boxed variant) and PachyDarn converts into a GenericError
+#[deprecated(note = "use PachyDarn instead; it converts to and from a boxed error")]
 pub type GenericError = Box<dyn std::error::Error + Send + Sync>;
 
 
-/// This captures non-tokio_postgres error variants from MOBC 
+/// This captures non-tokio_postgres error variants from MOBC (and deadpool, with the "deadpool" feature)
 #[derive(Debug)]
 pub enum MobcErr {
     Other(String),
@@ -20,21 +25,261 @@ pub enum MobcErr {
 pub enum PachyDarn {
     Postgres(tokio_postgres::Error),
     MobcPG(MobcErr),
+    #[cfg(feature = "redis")]
     MobcRedis(MobcErr),
     MissingRow(MissingRowError),
+    /// A lookup by key (i.e. cached_or_cache_f) found nothing. type_name is the Rust type that was looked up
+    /// and key identifies the instance, so HTTP layers can report which resource is missing
+    NotFound { type_name: String, key: String },
+    UnexpectedMultipleRows(UnexpectedMultipleRowsError),
+    /// A query didn't finish within the timeout given to connect::query_with_timeout, so Postgres was told to stop it
+    StatementTimeout { timeout_ms: u64 },
+    /// The budget of a utils::Deadline ran out during phase (i.e. "redis" or "postgres"), elapsed after it was created
+    DeadlineExceeded { phase: &'static str, elapsed: Duration },
+    #[cfg(feature = "redis")]
     Redis(redis::RedisError),
     SerdeJSON(serde_json::Error),
+    Boxed(Box<dyn Error + Send + Sync>),
+    Io(std::io::Error),
+    ParseInt(std::num::ParseIntError),
+    /// Use this variant to return your own domain errors through functions that return PachyDarn
+    /// (i.e. Borg::redis_value) without defining a whole new error enum. The status is an optional
+    /// hint used by PachyDarn::http_status()
+    Custom { kind: &'static str, message: String, status: Option<u16> },
+    /// A message describing what was being attempted when the wrapped error occured.
+    /// See the PachyContext trait for an ergonomic way to add context
+    Context { message: String, source: Box<PachyDarn> },
 }
 
-impl Error for PachyDarn {}
+impl Error for PachyDarn {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PachyDarn::Postgres(err) => Some(err),
+            PachyDarn::MissingRow(err) => Some(err),
+            PachyDarn::UnexpectedMultipleRows(err) => Some(err),
+            #[cfg(feature = "redis")]
+            PachyDarn::Redis(err) => Some(err),
+            PachyDarn::SerdeJSON(err) => Some(err),
+            PachyDarn::Io(err) => Some(err),
+            PachyDarn::ParseInt(err) => Some(err),
+            PachyDarn::Boxed(err) => Some(err.as_ref()),
+            PachyDarn::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+
+/// These methods dig into the tokio_postgres::error::DbError (if there is one) so callers
+/// can classify Postgres errors by their SQLSTATE instead of matching on error text
+impl PachyDarn {
+
+    /// Instantiate the Custom variant, i.e. PachyDarn::custom("banned_name", "mallory is not welcome here")
+    pub fn custom(kind: &'static str, message: impl Into<String>) -> Self {
+        PachyDarn::Custom{kind, message: message.into(), status: None}
+    }
+
+    /// Like custom(), but with a hint for the HTTP status code the error should map to
+    pub fn custom_with_status(kind: &'static str, message: impl Into<String>, status: u16) -> Self {
+        PachyDarn::Custom{kind, message: message.into(), status: Some(status)}
+    }
+
+    /// Instantiate the NotFound variant for type T, i.e. PachyDarn::not_found::<Animal>("cacheable_animal_42")
+    pub fn not_found<T: ?Sized>(key: impl Into<String>) -> Self {
+        PachyDarn::NotFound{type_name: std::any::type_name::<T>().to_string(), key: key.into()}
+    }
+
+    /// Box up any other error so it can be returned as a PachyDarn
+    pub fn boxed(err: impl Error + Send + Sync + 'static) -> Self {
+        PachyDarn::Boxed(Box::new(err))
+    }
+
+    /// The HTTP status code that best describes this error
+    pub fn http_status(&self) -> u16 {
+        match self {
+            PachyDarn::Context { source, .. } => source.http_status(),
+            PachyDarn::Custom { status, .. } => status.unwrap_or(500),
+            PachyDarn::MissingRow(_) | PachyDarn::NotFound { .. } => 404,
+            PachyDarn::ParseInt(_) => 400,
+            PachyDarn::MobcPG(MobcErr::Timeout) | PachyDarn::StatementTimeout { .. } | PachyDarn::DeadlineExceeded { .. } => 503,
+            #[cfg(feature = "redis")]
+            PachyDarn::MobcRedis(MobcErr::Timeout) => 503,
+            PachyDarn::Postgres(_) if self.is_unique_violation() || self.is_foreign_key_violation() => 409,
+            _ => 500,
+        }
+    }
+
+    /// A short machine-readable code for the error that started the chain, i.e. "missing_row" or "redis_timeout",
+    /// for API responses like {"code": "missing_row", "message": "..."}. Postgres errors are classified by SQLSTATE
+    /// where pachydurable has a predicate for it ("unique_violation" etc.), and a Custom error's code is its kind
+    pub fn error_code(&self) -> &'static str {
+        match self.root() {
+            PachyDarn::Postgres(_) if self.is_unique_violation() => "unique_violation",
+            PachyDarn::Postgres(_) if self.is_foreign_key_violation() => "foreign_key_violation",
+            PachyDarn::Postgres(_) if self.is_serialization_failure() => "serialization_failure",
+            PachyDarn::Postgres(_) => "postgres_error",
+            PachyDarn::MobcPG(MobcErr::Timeout) => "postgres_timeout",
+            PachyDarn::MobcPG(_) => "postgres_pool_error",
+            #[cfg(feature = "redis")]
+            PachyDarn::MobcRedis(MobcErr::Timeout) => "redis_timeout",
+            #[cfg(feature = "redis")]
+            PachyDarn::MobcRedis(_) => "redis_pool_error",
+            #[cfg(feature = "redis")]
+            PachyDarn::Redis(_) => "redis_error",
+            PachyDarn::MissingRow(_) => "missing_row",
+            PachyDarn::NotFound { .. } => "not_found",
+            PachyDarn::UnexpectedMultipleRows(_) => "unexpected_multiple_rows",
+            PachyDarn::StatementTimeout { .. } => "statement_timeout",
+            PachyDarn::DeadlineExceeded { .. } => "deadline_exceeded",
+            PachyDarn::SerdeJSON(_) => "serialization_error",
+            PachyDarn::Boxed(_) => "internal_error",
+            PachyDarn::Io(_) => "io_error",
+            PachyDarn::ParseInt(_) => "invalid_integer",
+            PachyDarn::Custom { kind, .. } => kind,
+            // root() never returns a Context
+            PachyDarn::Context { .. } => "internal_error",
+        }
+    }
+
+    /// A human-readable message to pair with error_code() in API responses. Custom and client (4xx) errors
+    /// return the message of the error that started the chain, without any Context layers. Server (5xx) errors
+    /// return a generic message, so log the full error server-side if you need the details
+    pub fn error_message(&self) -> String {
+        let status = self.http_status();
+        match self.root() {
+            PachyDarn::Custom { message, .. } => message.clone(),
+            // i.e. "duplicate key value violates unique constraint ..." without the "db error: ERROR: " prefix
+            PachyDarn::Postgres(err) if status < 500 => match err.as_db_error() {
+                Some(db_err) => db_err.message().to_string(),
+                None => err.to_string(),
+            },
+            root if status < 500 => root.to_string(),
+            _ => generic_message(status).to_string(),
+        }
+    }
+
+    /// Walk past any Context layers and return the error that started the chain
+    pub fn root(&self) -> &PachyDarn {
+        match self {
+            PachyDarn::Context { source, .. } => source.root(),
+            _ => self,
+        }
+    }
+
+    /// true if this error came from Redis or the Redis connection pool (always false without the redis feature)
+    #[cfg(feature = "redis")]
+    pub fn is_redis_error(&self) -> bool {
+        matches!(self.root(), PachyDarn::Redis(_) | PachyDarn::MobcRedis(_))
+    }
+
+    /// true if this error came from Redis or the Redis connection pool (always false without the redis feature)
+    #[cfg(not(feature = "redis"))]
+    pub fn is_redis_error(&self) -> bool {
+        false
+    }
+
+    /// return the underlying tokio_postgres::Error, if this is a Postgres error
+    fn pg_error(&self) -> Option<&tokio_postgres::Error> {
+        match self.root() {
+            PachyDarn::Postgres(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// return the five-character SQLSTATE code (i.e. "23505") reported by Postgres, if any
+    pub fn sqlstate(&self) -> Option<&str> {
+        self.pg_error()?.code().map(|state| state.code())
+    }
+
+    /// true if Postgres rejected a write for violating a unique constraint (SQLSTATE 23505)
+    pub fn is_unique_violation(&self) -> bool {
+        self.sqlstate() == Some(SqlState::UNIQUE_VIOLATION.code())
+    }
+
+    /// true if Postgres rejected a write for violating a foreign key constraint (SQLSTATE 23503)
+    pub fn is_foreign_key_violation(&self) -> bool {
+        self.sqlstate() == Some(SqlState::FOREIGN_KEY_VIOLATION.code())
+    }
+
+    /// true if a serializable transaction could not be committed and should be retried (SQLSTATE 40001)
+    pub fn is_serialization_failure(&self) -> bool {
+        self.sqlstate() == Some(SqlState::T_R_SERIALIZATION_FAILURE.code())
+    }
+
+    /// true if Postgres cancelled the statement, i.e. because it ran past its statement_timeout (SQLSTATE 57014)
+    pub fn is_query_canceled(&self) -> bool {
+        self.sqlstate() == Some(SqlState::QUERY_CANCELED.code())
+    }
+
+    /// return the name of the constraint that was violated, if Postgres reported one
+    pub fn constraint_name(&self) -> Option<&str> {
+        self.pg_error()?.as_db_error()?.constraint()
+    }
+}
+
+// the message PachyDarn::error_message sends to clients for a server (5xx) error
+fn generic_message(status: u16) -> &'static str {
+    match status {
+        503 => "the service is temporarily unavailable, please retry",
+        _ => "an internal error occurred",
+    }
+}
 
 impl fmt::Display for PachyDarn {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            // i.e. "failed to hydrate Animal 42: MissingRowError: ..."
+            PachyDarn::Context { message, source } => write!(f, "{}: {}", message, source),
+            PachyDarn::MissingRow(err) => write!(f, "{}", err),
+            PachyDarn::NotFound { type_name, key } => write!(f, "NotFound: no {} at key {}", type_name, key),
+            PachyDarn::UnexpectedMultipleRows(err) => write!(f, "{}", err),
+            PachyDarn::StatementTimeout { timeout_ms } => write!(f, "StatementTimeout: the query did not finish within {}ms", timeout_ms),
+            PachyDarn::DeadlineExceeded { phase, elapsed } => write!(f, "DeadlineExceeded: the deadline passed during {} after {}ms", phase, elapsed.as_millis()),
+            PachyDarn::Custom { kind, message, .. } => write!(f, "{}: {}", kind, message),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+
+/// The PachyContext trait lets you name the operation that failed as an error bubbles up:
+/// ```ignore
+/// let animal: Animal = get_by_pk(&client, &[&42]).await.context("failed to hydrate Animal 42")?;
+/// ```
+/// The original error is kept as the source, so PachyDarn::root() still returns the original variant
+pub trait PachyContext<T> {
+    /// wrap an error in the Context variant with the provided message
+    fn context(self, message: impl Into<String>) -> Result<T, PachyDarn>;
+    /// like context, but the message is only built if there is an error
+    fn with_context<F: FnOnce() -> String>(self, f: F) -> Result<T, PachyDarn>;
+}
+
+impl<T, E: Into<PachyDarn>> PachyContext<T> for Result<T, E> {
+    fn context(self, message: impl Into<String>) -> Result<T, PachyDarn> {
+        self.map_err(|err| PachyDarn::Context{message: message.into(), source: Box::new(err.into())})
+    }
+
+    fn with_context<F: FnOnce() -> String>(self, f: F) -> Result<T, PachyDarn> {
+        self.map_err(|err| PachyDarn::Context{message: f(), source: Box::new(err.into())})
+    }
+}
+
+
+#[cfg(feature = "deadpool")]
+impl From<deadpool_postgres::PoolError> for PachyDarn {
+    fn from(err: deadpool_postgres::PoolError) -> Self {
+        // deadpool's pool errors map to the same variants as mobc's, so callers handle both pools alike
+        match err {
+            deadpool_postgres::PoolError::Backend(tpg) => PachyDarn::Postgres(tpg),
+            deadpool_postgres::PoolError::Timeout(_) => PachyDarn::MobcPG(MobcErr::Timeout),
+            deadpool_postgres::PoolError::Closed => PachyDarn::MobcPG(MobcErr::PoolClosed),
+            other => PachyDarn::MobcPG(MobcErr::Other(other.to_string())),
+        }
     }
 }
 
 
+#[cfg(feature = "redis")]
 impl From<redis::RedisError> for PachyDarn {
     fn from(err: redis::RedisError) -> Self {
         PachyDarn::Redis(err)
@@ -67,6 +312,7 @@ impl From<mobc::Error<tokio_postgres::Error>> for PachyDarn {
 }
 
 
+#[cfg(feature = "redis")]
 impl From<mobc::Error<redis::RedisError>> for PachyDarn {
     fn from(err: mobc::Error<redis::RedisError>) -> Self {
         match err {
@@ -79,6 +325,7 @@ impl From<mobc::Error<redis::RedisError>> for PachyDarn {
 }
 
 
+#[cfg(feature = "redis")]
 impl From<mobc_redis::redis::RedisError> for PachyDarn {
     fn from(err: mobc_redis::redis::RedisError) -> Self {
         let msg = format!("{:?}", err);
@@ -87,6 +334,7 @@ impl From<mobc_redis::redis::RedisError> for PachyDarn {
 }
 
 
+#[cfg(feature = "redis")]
 impl From<mobc::Error<mobc_redis::redis::RedisError>> for PachyDarn {
     fn from(err: mobc::Error<mobc_redis::redis::RedisError>) -> Self {
         match err {
@@ -100,6 +348,28 @@ impl From<mobc::Error<mobc_redis::redis::RedisError>> for PachyDarn {
     }
 }
 
+impl From<Box<dyn Error + Send + Sync>> for PachyDarn {
+    fn from(err: Box<dyn Error + Send + Sync>) -> Self {
+        // If a PachyDarn was boxed up along the way, unbox it so the typed variant is preserved
+        match err.downcast::<PachyDarn>() {
+            Ok(darn) => *darn,
+            Err(other) => PachyDarn::Boxed(other),
+        }
+    }
+}
+
+impl From<std::io::Error> for PachyDarn {
+    fn from(err: std::io::Error) -> Self {
+        PachyDarn::Io(err)
+    }
+}
+
+impl From<std::num::ParseIntError> for PachyDarn {
+    fn from(err: std::num::ParseIntError) -> Self {
+        PachyDarn::ParseInt(err)
+    }
+}
+
 impl From<MissingRowError> for PachyDarn {
     fn from(err: MissingRowError) -> Self {
         PachyDarn::MissingRow(err)
@@ -112,6 +382,10 @@ impl From<MissingRowError> for PachyDarn {
 #[derive(Debug)]
 pub struct MissingRowError {
     pub message: String,
+    /// The table the query read FROM, if it could be found (see for_query)
+    pub table: Option<String>,
+    /// The query's params, i.e. [42, "kiwi"]
+    pub params_debug: Option<String>,
 }
 
 impl Error for MissingRowError {}
@@ -125,8 +399,252 @@ impl fmt::Display for MissingRowError {
 impl MissingRowError {
     pub fn from_str(message: &str) -> Self {
         MissingRowError{
-            message: message.to_string()
+            message: message.to_string(),
+            table: None,
+            params_debug: None,
         }
     }
+
+    /// The error for a query that returned no rows. The table it reads FROM and its params are kept as fields, not in the message
+    pub fn for_query(query: &str, params: &[&dyn fmt::Debug]) -> Self {
+        MissingRowError::for_query_summary(query, debug_summary(params))
+    }
+
+    // for_query with the params already rendered, since a slice of ToSql params can't be passed as &[&dyn Debug]
+    pub(crate) fn for_query_summary(query: &str, params_debug: String) -> Self {
+        MissingRowError{
+            message: format!("No row found for query \"{}\"", query),
+            table: table_of_query(query),
+            params_debug: Some(params_debug),
+        }
+    }
+}
+
+/// A compact rendering of params for error messages, i.e. [42, "kiwi"]. Long params are truncated
+pub(crate) fn debug_summary<D: fmt::Debug + ?Sized>(params: &[&D]) -> String {
+    const MAX_PARAM_CHARS: usize = 40;
+    let rendered: Vec<String> = params.iter().map(|param| {
+        let debug = format!("{:?}", param);
+        match debug.char_indices().nth(MAX_PARAM_CHARS) {
+            Some((end, _)) => format!("{}...", &debug[..end]),
+            None => debug,
+        }
+    }).collect();
+    format!("[{}]", rendered.join(", "))
 }
 
+// the table after the first top-level FROM of a query, i.e. "zoo.animals" for SELECT * FROM zoo.animals WHERE id = $1.
+// FROMs inside parentheses (EXTRACT(YEAR FROM born), subqueries) and in IS DISTINCT FROM are skipped.
+// None if there is no FROM, it reads from a subquery or function (i.e. FROM generate_series(1, 3)) or from a CTE
+fn table_of_query(query: &str) -> Option<String> {
+    let tokens = top_level_tokens(query);
+    let from = tokens.iter().enumerate().position(|(i, token)| {
+        token.eq_ignore_ascii_case("from") && !(i > 0 && tokens[i-1].eq_ignore_ascii_case("distinct"))
+    })?;
+    let table = tokens.get(from+1)?.trim_end_matches(|c| c == ';' || c == ',');
+    if table.is_empty() || table.contains('(') {
+        return None
+    }
+    // WITH x AS (...) SELECT * FROM x reads from the CTE, not a table
+    let is_cte = tokens[0].eq_ignore_ascii_case("with") && tokens.windows(2).any(|pair| {
+        pair[0] == table && pair[1].eq_ignore_ascii_case("as")
+    });
+    if is_cte {
+        return None
+    }
+    Some(table.replace('"', ""))
+}
+
+// the whitespace-separated words of a query outside of parentheses and string literals.
+// A parenthesized group is kept as a "(" token, or appended to the word it follows, i.e. "generate_series("
+fn top_level_tokens(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut depth = 0usize;
+    let mut in_string = false;
+    for c in query.chars() {
+        match c {
+            '\'' => in_string = !in_string,
+            _ if in_string => {},
+            '(' => {
+                if depth == 0 {
+                    word.push('(');
+                    tokens.push(std::mem::take(&mut word));
+                }
+                depth += 1;
+            },
+            ')' => depth = depth.saturating_sub(1),
+            _ if depth > 0 => {},
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    tokens.push(std::mem::take(&mut word));
+                }
+            },
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+
+/// Use this struct when you expect exactly one row but there are several,
+/// i.e. a lookup on a column that should have a unique constraint
+#[derive(Debug)]
+pub struct UnexpectedMultipleRowsError {
+    pub message: String,
+    pub row_count: usize,
+}
+
+impl Error for UnexpectedMultipleRowsError {}
+
+impl fmt::Display for UnexpectedMultipleRowsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UnexpectedMultipleRowsError: expected 1 row, got {}: {}", self.row_count, self.message)
+    }
+}
+
+impl From<UnexpectedMultipleRowsError> for PachyDarn {
+    fn from(err: UnexpectedMultipleRowsError) -> Self {
+        PachyDarn::UnexpectedMultipleRows(err)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::testing::TestDb;
+    use super::*;
+
+    #[test]
+    fn classify_constraint_violations() {
+        // trigger real 23505 and 23503 errors and ensure they are classified
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("
+                CREATE TABLE pachy_err_parent (id INT PRIMARY KEY);
+                CREATE TABLE pachy_err_child (
+                    id INT PRIMARY KEY,
+                    parent_id INT CONSTRAINT pachy_err_child_fk REFERENCES pachy_err_parent(id)
+                );
+                INSERT INTO pachy_err_parent (id) VALUES (1);").await.unwrap();
+            let client = db.client().await.unwrap();
+            // inserting the same PK twice is a unique violation
+            let err = PachyDarn::from(client.execute("INSERT INTO pachy_err_parent (id) VALUES (1)", &[]).await.unwrap_err());
+            assert_eq!(err.sqlstate(), Some("23505"));
+            assert!(err.is_unique_violation());
+            assert!(!err.is_foreign_key_violation());
+            assert!(!err.is_serialization_failure());
+            assert_eq!(err.constraint_name(), Some("pachy_err_parent_pkey"));
+            assert_eq!(err.error_code(), "unique_violation");
+            // referencing a parent that does not exist is a foreign key violation
+            let err = PachyDarn::from(client.execute("INSERT INTO pachy_err_child (id, parent_id) VALUES (1, 2)", &[]).await.unwrap_err());
+            assert_eq!(err.sqlstate(), Some("23503"));
+            assert!(err.is_foreign_key_violation());
+            assert!(!err.is_unique_violation());
+            assert_eq!(err.constraint_name(), Some("pachy_err_child_fk"));
+        })
+    }
+
+    #[test]
+    fn boxed_round_trip_preserves_variant() {
+        // PachyDarn -> boxed error -> PachyDarn should come back as the same variant
+        let err = PachyDarn::from(MissingRowError::from_str("round trip"));
+        let boxed: Box<dyn Error + Send + Sync> = err.into();
+        assert!(boxed.downcast_ref::<PachyDarn>().is_some());
+        match PachyDarn::from(boxed) {
+            PachyDarn::MissingRow(mre) => assert_eq!(mre.message, "round trip"),
+            other => panic!("expected MissingRow, got {:?}", other),
+        }
+        // any other boxed error lands in the Boxed variant
+        let boxed: Box<dyn Error + Send + Sync> = Box::new(std::fmt::Error);
+        assert!(matches!(PachyDarn::from(boxed), PachyDarn::Boxed(_)));
+    }
+
+    #[test]
+    fn context_chain() {
+        let res: Result<(), MissingRowError> = Err(MissingRowError::from_str("no animal with id 42"));
+        let err = res.context("failed to hydrate Animal 42").with_context(|| "failed to build zoo".to_string()).unwrap_err();
+        assert_eq!(err.to_string(), "failed to build zoo: failed to hydrate Animal 42: MissingRowError: no animal with id 42");
+        // the root variant can still be matched
+        match err.root() {
+            PachyDarn::MissingRow(mre) => assert_eq!(mre.message, "no animal with id 42"),
+            other => panic!("expected MissingRow, got {:?}", other),
+        }
+        // and the source chain can be walked with the std Error trait
+        let inner = err.source().unwrap().downcast_ref::<PachyDarn>().unwrap();
+        assert!(matches!(inner, PachyDarn::Context{..}));
+    }
+
+    #[test]
+    fn custom_errors_and_status() {
+        let err = PachyDarn::custom("banned_name", "mallory is not welcome here");
+        assert_eq!(err.to_string(), "banned_name: mallory is not welcome here");
+        assert_eq!(err.http_status(), 500);
+        let err = PachyDarn::custom_with_status("banned_name", "mallory is not welcome here", 403);
+        assert_eq!(err.http_status(), 403);
+        // the status hint survives being wrapped in context
+        let res: Result<(), PachyDarn> = Err(err);
+        assert_eq!(res.context("failed to greet").unwrap_err().http_status(), 403);
+        let err = PachyDarn::from("abc".parse::<i32>().unwrap_err());
+        assert_eq!(err.http_status(), 400);
+        assert_eq!(PachyDarn::from(MissingRowError::from_str("gone")).http_status(), 404);
+        let err = PachyDarn::not_found::<MissingRowError>("cacheable_gone_1");
+        assert_eq!(err.to_string(), "NotFound: no pachydurable::err::MissingRowError at key cacheable_gone_1");
+        assert_eq!(err.http_status(), 404);
+        let err = PachyDarn::from(std::io::Error::new(std::io::ErrorKind::NotFound, "no config file"));
+        assert!(matches!(err, PachyDarn::Io(_)));
+        assert!(matches!(PachyDarn::boxed(std::fmt::Error), PachyDarn::Boxed(_)));
+    }
+
+    #[test]
+    fn error_codes() {
+        let res: Result<(), MissingRowError> = Err(MissingRowError::from_str("no animal with id 42"));
+        let err = res.context("failed to hydrate Animal 42").unwrap_err();
+        // the code and message come from the root, leaving out the context
+        assert_eq!(err.error_code(), "missing_row");
+        assert_eq!(err.error_message(), "MissingRowError: no animal with id 42");
+        assert_eq!(PachyDarn::custom_with_status("banned_name", "mallory is not welcome here", 400).error_message(), "mallory is not welcome here");
+        assert_eq!(PachyDarn::custom("banned_name", "mallory is not welcome here").error_message(), "mallory is not welcome here");
+        assert_eq!(PachyDarn::boxed(std::fmt::Error).error_message(), "an internal error occurred");
+        assert_eq!(PachyDarn::StatementTimeout{timeout_ms: 50}.error_message(), "the service is temporarily unavailable, please retry");
+        assert_eq!(PachyDarn::custom("banned_name", "mallory is not welcome here").error_code(), "banned_name");
+        assert_eq!(PachyDarn::not_found::<MissingRowError>("gone").error_code(), "not_found");
+        assert_eq!(PachyDarn::StatementTimeout{timeout_ms: 50}.error_code(), "statement_timeout");
+        assert_eq!(PachyDarn::MobcPG(MobcErr::Timeout).error_code(), "postgres_timeout");
+        #[cfg(feature = "redis")]
+        assert_eq!(PachyDarn::MobcRedis(MobcErr::Timeout).error_code(), "redis_timeout");
+        assert_eq!(PachyDarn::from(serde_json::from_str::<i32>("kiwi").unwrap_err()).error_code(), "serialization_error");
+        assert_eq!(PachyDarn::from("abc".parse::<i32>().unwrap_err()).error_code(), "invalid_integer");
+    }
+
+    #[test]
+    fn missing_rows_name_the_query() {
+        let err = MissingRowError::for_query("SELECT id, name FROM zoo.animals WHERE id = $1 AND name = $2", &[&42, &"kiwi"]);
+        assert_eq!(err.table.as_deref(), Some("zoo.animals"));
+        assert_eq!(err.params_debug.as_deref(), Some("[42, \"kiwi\"]"));
+        assert_eq!(err.to_string(), "MissingRowError: No row found for query \"SELECT id, name FROM zoo.animals WHERE id = $1 AND name = $2\"");
+        assert_eq!(table_of_query("select *\n  from \"animals\";"), Some("animals".to_string()));
+        assert_eq!(table_of_query("SELECT n FROM generate_series(1, 3) AS n"), None);
+        assert_eq!(table_of_query("SELECT 1"), None);
+        assert_eq!(table_of_query("SELECT EXTRACT(YEAR FROM born) FROM animals"), Some("animals".to_string()));
+        assert_eq!(table_of_query("SELECT a IS DISTINCT FROM b FROM pairs WHERE note = 'from x'"), Some("pairs".to_string()));
+        assert_eq!(table_of_query("SELECT * FROM (SELECT id FROM animals) AS sub"), None);
+        assert_eq!(table_of_query("WITH recent AS (SELECT * FROM animals) SELECT * FROM recent"), None);
+        assert_eq!(table_of_query("WITH recent AS (SELECT 1) SELECT * FROM animals, recent"), Some("animals".to_string()));
+        let long = "x".repeat(60);
+        assert!(MissingRowError::for_query("SELECT 1", &[&long]).params_debug.unwrap().ends_with("...]"));
+        assert!(MissingRowError::from_str("gone").table.is_none());
+    }
+
+    #[test]
+    fn non_postgres_errors_have_no_sqlstate() {
+        let err = PachyDarn::from(MissingRowError::from_str("nothing here"));
+        assert!(err.sqlstate().is_none());
+        assert!(err.constraint_name().is_none());
+        assert!(!err.is_unique_violation());
+    }
+}
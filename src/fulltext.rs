@@ -4,10 +4,12 @@
 //! 
 
 // standard library
-use std::vec::Vec;
+use std::{collections::HashSet, fmt, hash::Hash, str::FromStr, vec::Vec};
 // crates.io
-use tokio_postgres::row::Row;
-use crate::{err::PachyDarn, connect::ClientNoTLS, utils::print_if_env_eq};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Serialize, Deserialize};
+use tokio_postgres::{error::SqlState, types::ToSql};
+use crate::{err::PachyDarn, client::{PachyClient, RowLike}, connect::quote_ident, primary_key::{GetByPK, PkFromRow}, utils::{fnv1a_64, pachy_log}};
 
 
 
@@ -26,7 +28,7 @@ use crate::{err::PachyDarn, connect::ClientNoTLS, utils::print_if_env_eq};
 /// // CREATE INDEX fulltext_animals ON animals USING GIN(fulltext_tsv);
 /// // 
 /// // You could create an Animal struct and implement AutoComp like so:
-/// use tokio_postgres::row::Row;
+/// use pachydurable::client::RowLike;
 /// 
 /// #[derive(Serialize)]
 /// struct Animal {
@@ -42,7 +44,7 @@ use crate::{err::PachyDarn, connect::ClientNoTLS, utils::print_if_env_eq};
 ///         WHERE fulltext_tsv @@ to_tsquery('english', $1)
 ///         LIMIT 10;"
 ///     }
-///     fn rowfunc_fulltext(row: &Row) -> Self {
+///     fn rowfunc_fulltext<R: RowLike>(row: &R) -> Self {
 ///         let id: i32 = row.get(0);
 ///         let name: String = row.get(1);
 ///         let description: Option<String> = row.get(2);
@@ -54,16 +56,27 @@ use crate::{err::PachyDarn, connect::ClientNoTLS, utils::print_if_env_eq};
 /// ```
 pub trait FullText {
     fn query_fulltext() -> &'static str;
-    fn rowfunc_fulltext(row: &Row) -> Self;
+    fn rowfunc_fulltext<R: RowLike>(row: &R) -> Self;
+    /// the text search configuration query_fulltext passes to to_tsquery. english stopwords are only dropped
+    /// from the phrase for 'english' (see sanitize_tsquery_cfg)
+    fn ts_config_fulltext() -> &'static str {
+        "english"
+    }
 }
 
 
 /// call this function with an explicit type hint for Vec<T>, where T implements the FullText trait
-pub async fn exec_fulltext<T: FullText>(client: &ClientNoTLS, phrase: &str) -> Result<Vec<T>, PachyDarn> {
-    let query = T::query_fulltext();
-    let ts_expr = ts_expression(phrase);
+/// Phrases that are empty (or contain only stopwords/punctuation) return an empty Vec without querying Postgres
+pub async fn exec_fulltext<T: FullText>(client: &impl PachyClient, phrase: &str) -> Result<Vec<T>, PachyDarn> {
+    exec_fulltext_or_empty(client, phrase).await
+}
+
+
+/// Sanitize the phrase with sanitize_tsquery_cfg, returning Ok(vec![]) if nothing is left,
+/// since an empty or all-stopword phrase would otherwise make to_tsquery(...) fail or match nothing
+pub async fn exec_fulltext_or_empty<T: FullText>(client: &impl PachyClient, phrase: &str) -> Result<Vec<T>, PachyDarn> {
     let mut hits = Vec::new();
-    let rows = client.query(query,&[&ts_expr]).await?;
+    let rows = fulltext_rows::<T, _>(client, phrase).await?;
     for row in rows {
         let hit = T::rowfunc_fulltext(&row);
         hits.push(hit);
@@ -71,6 +84,326 @@ pub async fn exec_fulltext<T: FullText>(client: &ClientNoTLS, phrase: &str) -> R
     Ok(hits)
 }
 
+// the rows of T::query_fulltext() for the sanitized phrase, or none without querying if nothing is left of it
+async fn fulltext_rows<T: FullText, C: PachyClient>(client: &C, phrase: &str) -> Result<Vec<C::Row>, PachyDarn> {
+    let sanitized = sanitize_tsquery_cfg(phrase, T::ts_config_fulltext());
+    if sanitized.is_empty() {
+        return Ok(Vec::new())
+    }
+    let query = T::query_fulltext();
+    let ts_expr = ts_expression(&sanitized);
+    client.query(query,&[&ts_expr]).await
+}
+
+
+/// Like exec_fulltext, but each row is only returned once, i.e. when the query joins or unions the fields it searches
+/// so a row matching on several of them comes back several times. Hits are deduplicated by T::pk_from_row,
+/// keeping the first (highest ranked, if the query orders by rank) in their original order
+pub async fn exec_fulltext_deduped<T: FullText + GetByPK + PkFromRow<PK>, PK: Eq + Hash>(client: &impl PachyClient, phrase: &str) -> Result<Vec<T>, PachyDarn> {
+    let rows = fulltext_rows::<T, _>(client, phrase).await?;
+    let mut seen = HashSet::new();
+    let mut hits = Vec::new();
+    for row in rows {
+        if seen.insert(T::pk_from_row(&row)) {
+            hits.push(T::rowfunc_fulltext(&row));
+        }
+    }
+    Ok(hits)
+}
+
+
+/// Searches several tsvector columns at once with a weight each, i.e. a title_tsv that counts for more than a body_tsv.
+/// The query is generated from the table, the columns rowfunc_fulltext_multi reads, and the fields:
+/// ```ignore
+/// impl FullTextMulti for Article {
+///     fn rowfunc_fulltext_multi<R: RowLike>(row: &R) -> Self { Article{id: row.get("id"), title: row.get("title")} }
+///     fn fulltext_table() -> &'static str { "articles" }
+///     fn fulltext_select() -> &'static str { "id, title" }
+///     fn query_fulltext_fields() -> Vec<(&'static str, f32)> { vec![("title_tsv", 1.0), ("body_tsv", 0.2)] }
+/// }
+/// let hits: Vec<(Article, f64)> = exec_fulltext_multi(&client, "rust async").await?;
+/// ```
+/// Each field is labelled with setweight (A for the first, B for the second etc.), so there can be at most 4,
+/// and the label's weight is passed to ts_rank. It doesn't need a FullText impl, so there is no query_fulltext() to write
+pub trait FullTextMulti {
+    /// convert a row of fulltext_select() into Self
+    fn rowfunc_fulltext_multi<R: RowLike>(row: &R) -> Self;
+    /// the configuration of the tsvector columns
+    fn ts_config_fulltext_multi() -> &'static str {
+        "english"
+    }
+    /// the table (or view) to search
+    fn fulltext_table() -> &'static str;
+    /// the columns rowfunc_fulltext_multi reads, in order
+    fn fulltext_select() -> &'static str;
+    /// each tsvector column with its weight, usually between 0 and 1
+    fn query_fulltext_fields() -> Vec<(&'static str, f32)>;
+    /// the most hits to return, or None for all of them
+    fn fulltext_multi_limit() -> Option<u32> {
+        None
+    }
+}
+
+// the setweight labels, in the order the fields are labelled
+const WEIGHT_LABELS: [char; 4] = ['A', 'B', 'C', 'D'];
+
+// the query exec_fulltext_multi runs, with the combined rank as __pachy_rank
+fn fulltext_multi_query<T: FullTextMulti>() -> Result<String, PachyDarn> {
+    let fields = T::query_fulltext_fields();
+    if fields.is_empty() || fields.len() > WEIGHT_LABELS.len() {
+        return Err(PachyDarn::custom("fulltext_fields", format!("query_fulltext_fields() of {} must have 1 to 4 fields, not {}", std::any::type_name::<T>(), fields.len())))
+    }
+    let vectors: Vec<String> = fields.iter().zip(WEIGHT_LABELS)
+        .map(|((column, _), label)| format!("setweight(coalesce({}, ''::tsvector), '{}')", quote_ident(column), label))
+        .collect();
+    // ts_rank takes the weights of the labels in the order D, C, B, A
+    let mut weights = [0f32; 4];
+    for (i, (_, weight)) in fields.iter().enumerate() {
+        weights[3 - i] = *weight;
+    }
+    let weights: Vec<String> = weights.iter().map(|weight| weight.to_string()).collect();
+    let mut query = format!("SELECT {}, ts_rank('{{{}}}'::float4[], __pachy_tsv, __pachy_query)::float8 AS __pachy_rank
+        FROM (SELECT *, {} AS __pachy_tsv FROM {}) AS __pachy_fields, to_tsquery('{}', $1) AS __pachy_query
+        WHERE __pachy_tsv @@ __pachy_query ORDER BY __pachy_rank DESC",
+        T::fulltext_select(), weights.join(","), vectors.join(" || "), quote_ident(T::fulltext_table()), T::ts_config_fulltext_multi().replace('\'', "''"));
+    if let Some(limit) = T::fulltext_multi_limit() {
+        query.push_str(&format!(" LIMIT {}", limit));
+    }
+    Ok(query)
+}
+
+/// Return the hits for the phrase across every field of T::query_fulltext_fields(), with their combined rank, best first.
+/// Like exec_fulltext, a phrase with nothing left after sanitize_tsquery returns no hits without querying
+pub async fn exec_fulltext_multi<T: FullTextMulti>(client: &impl PachyClient, phrase: &str) -> Result<Vec<(T, f64)>, PachyDarn> {
+    let query = fulltext_multi_query::<T>()?;
+    let sanitized = sanitize_tsquery_cfg(phrase, T::ts_config_fulltext_multi());
+    if sanitized.is_empty() {
+        return Ok(Vec::new())
+    }
+    let ts_expr = ts_expression(&sanitized);
+    let rows = client.query(&query, &[&ts_expr]).await?;
+    rows.iter().map(|row| Ok((T::rowfunc_fulltext_multi(row), row.try_get("__pachy_rank")?))).collect()
+}
+
+
+/// OFFSET pagination over ranked results is slow and shows duplicate or missing rows when rows are added between pages.
+/// FullTextCursor pages with a keyset instead: each page ends with a Cursor holding the cursor_columns of its last row,
+/// and the next page only returns rows that sort after it:
+/// ```ignore
+/// impl FullTextCursor for Animal {
+///     fn query_fulltext_cursor() -> &'static str {
+///         "SELECT id, name, description, ts_rank(fulltext_tsv, to_tsquery('english', $1)) AS rank
+///         FROM animals WHERE fulltext_tsv @@ to_tsquery('english', $1)"
+///     }
+///     fn cursor_columns() -> &'static str {
+///         "rank, id"
+///     }
+/// }
+/// let (page, next) = exec_fulltext_cursor::<Animal>(&client, "swims", None, 20).await?;
+/// let (page2, next) = exec_fulltext_cursor::<Animal>(&client, "swims", next, 20).await?;
+/// ```
+pub trait FullTextCursor: FullText {
+    /// Like query_fulltext, but it must select the cursor_columns and must not have an ORDER BY or LIMIT,
+    /// as exec_fulltext_cursor adds those. Its rows are read with rowfunc_fulltext
+    fn query_fulltext_cursor() -> &'static str;
+
+    /// The columns rows are ordered by (descending), i.e. "rank, id": first the rank, then column(s) that make each row unique.
+    /// The rank is compared as a float8 and the other columns as text
+    fn cursor_columns() -> &'static str;
+}
+
+
+/// An opaque position in a paged fulltext search: URL-safe base64 of the rank and keys of the last row of a page,
+/// and a hash of the phrase, so a cursor is only accepted for the search it came from.
+/// Parse one from a query parameter with .parse::<Cursor>() or get_query_param_opt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor(String);
+
+#[derive(Serialize, Deserialize)]
+struct CursorPosition {
+    #[serde(with = "rank_bits")]
+    rank: f64,
+    keys: Vec<String>,
+    phrase_hash: u32,
+}
+
+// the rank is stored as its bits, since serde_json (without float_roundtrip) may not parse a float back to the exact
+// same value, and the next page would then skip or repeat rows ranked next to the boundary
+mod rank_bits {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(rank: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(rank.to_bits())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        Ok(f64::from_bits(u64::deserialize(deserializer)?))
+    }
+}
+
+// an error for a cursor that can't be used, which maps to a 400
+fn invalid_cursor(message: &str) -> PachyDarn {
+    PachyDarn::custom_with_status("invalid_cursor", message, 400)
+}
+
+impl Cursor {
+    fn encode(position: &CursorPosition) -> Result<Self, PachyDarn> {
+        Ok(Cursor(URL_SAFE_NO_PAD.encode(serde_json::to_vec(position)?)))
+    }
+
+    fn decode(&self) -> Result<CursorPosition, PachyDarn> {
+        let bytes = URL_SAFE_NO_PAD.decode(&self.0).map_err(|_| invalid_cursor("the cursor is not valid base64"))?;
+        let position: CursorPosition = serde_json::from_slice(&bytes).map_err(|_| invalid_cursor("the cursor could not be decoded"))?;
+        if !position.rank.is_finite() {
+            return Err(invalid_cursor("the cursor has an invalid rank"))
+        }
+        Ok(position)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = PachyDarn;
+
+    /// Checks the cursor decodes, so a mangled cursor is rejected as it is read from the request
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cursor = Cursor(s.to_string());
+        cursor.decode()?;
+        Ok(cursor)
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+
+/// Return a page of at most page_size hits for the phrase, and the Cursor for the next page (None after the last page).
+/// Pass None as the cursor for the first page. A cursor that doesn't decode, or came from a different phrase, is a
+/// Custom error of kind invalid_cursor with status 400. Like exec_fulltext, an empty phrase returns no hits
+pub async fn exec_fulltext_cursor<T: FullTextCursor>(client: &impl PachyClient, phrase: &str, cursor: Option<Cursor>, page_size: usize) -> Result<(Vec<T>, Option<Cursor>), PachyDarn> {
+    if page_size == 0 {
+        return Err(PachyDarn::custom_with_status("invalid_page_size", "the page size must be at least 1", 400))
+    }
+    let sanitized = sanitize_tsquery_cfg(phrase, T::ts_config_fulltext());
+    if sanitized.is_empty() {
+        return Ok((Vec::new(), None))
+    }
+    let phrase_hash = (fnv1a_64(sanitized.as_bytes()) >> 32) as u32;
+    let columns: Vec<&str> = T::cursor_columns().split(',').map(|col| col.trim()).filter(|col| !col.is_empty()).collect();
+    if columns.len() < 2 {
+        return Err(PachyDarn::custom("cursor_columns", format!("cursor_columns() of {} must name a rank column and at least one key column", std::any::type_name::<T>())))
+    }
+    let position = match &cursor {
+        Some(cursor) => {
+            let position = cursor.decode()?;
+            if position.phrase_hash != phrase_hash || position.keys.len() != columns.len() - 1 {
+                return Err(invalid_cursor("the cursor is from a different search"))
+            }
+            Some(position)
+        },
+        None => None,
+    };
+    // the rank as float8 and the keys as text, both to select and to compare against the cursor
+    let exprs: Vec<String> = columns.iter().enumerate()
+        .map(|(i, col)| if i == 0 { format!("({})::float8", col) } else { format!("({})::text", col) })
+        .collect();
+    let selected: Vec<String> = exprs.iter().enumerate().map(|(i, expr)| format!("{} AS __pachy_cursor_{}", expr, i)).collect();
+    let base = T::query_fulltext_cursor().trim().trim_end_matches(';');
+    let mut query = format!("SELECT __pachy_page.*, {} FROM ({}) AS __pachy_page", selected.join(", "), base);
+    let ts_expr = ts_expression(&sanitized);
+    let mut params: Vec<&(dyn ToSql + Sync)> = vec![&ts_expr];
+    if let Some(position) = &position {
+        let placeholders: Vec<String> = (0..exprs.len()).map(|i| format!("${}", i + 2)).collect();
+        query.push_str(&format!(" WHERE ({}) < ({})", exprs.join(", "), placeholders.join(", ")));
+        params.push(&position.rank);
+        for key in &position.keys {
+            params.push(key);
+        }
+    }
+    let order: Vec<String> = exprs.iter().map(|expr| format!("{} DESC", expr)).collect();
+    // one extra row shows whether there is another page
+    query.push_str(&format!(" ORDER BY {} LIMIT {}", order.join(", "), page_size + 1));
+    let mut rows = client.query(&query, &params).await?;
+    let more = rows.len() > page_size;
+    rows.truncate(page_size);
+    let next = match (more, rows.last()) {
+        (true, Some(last)) => {
+            let first = last.len() - exprs.len();
+            let rank: f64 = last.try_get(first)?;
+            let keys = (first + 1..last.len()).map(|i| last.try_get::<_, Option<String>>(i).map(|key| key.unwrap_or_default()))
+                .collect::<Result<Vec<String>, PachyDarn>>()?;
+            Some(Cursor::encode(&CursorPosition{rank, keys, phrase_hash})?)
+        },
+        _ => None,
+    };
+    let hits = rows.iter().map(|row| T::rowfunc_fulltext(row)).collect();
+    Ok((hits, next))
+}
+
+
+/// LIMIT/OFFSET pagination with a total, i.e. for page controls showing "page 2 of 7". The total comes from a separate
+/// count query rather than COUNT(*) OVER (), so it can be tuned on its own (i.e. to use a simpler covering index):
+/// ```ignore
+/// impl FullTextPaged for Animal {
+///     fn query_fulltext_count() -> &'static str {
+///         "SELECT COUNT(*) FROM animals WHERE fulltext_tsv @@ to_tsquery('english', $1)"
+///     }
+/// }
+/// let page: Page<Animal> = exec_fulltext_paged_v2(&client, "swims", 20, 40).await?;
+/// ```
+/// exec_fulltext_paged_v2 appends LIMIT $2 OFFSET $3 to query_fulltext(), so that must not have a LIMIT of its own,
+/// and should have an ORDER BY so the pages are stable
+pub trait FullTextPaged: FullText {
+    /// counts the rows query_fulltext() matches for the same $1 ts_expression
+    fn query_fulltext_count() -> &'static str;
+}
+
+/// One page of results and the total across all pages
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub limit: i64,
+    pub offset: i64,
+    pub total: i64,
+}
+
+/// Return at most limit hits for the phrase starting at offset, along with the total number of hits. The page and the count
+/// are queried concurrently. A negative limit or offset is a Custom error of kind invalid_page with status 400, and like
+/// exec_fulltext, a phrase with nothing left after sanitize_tsquery returns an empty page without querying
+pub async fn exec_fulltext_paged_v2<T: FullTextPaged>(client: &impl PachyClient, phrase: &str, limit: i64, offset: i64) -> Result<Page<T>, PachyDarn> {
+    if limit < 0 || offset < 0 {
+        return Err(PachyDarn::custom_with_status("invalid_page", "the limit and offset must not be negative", 400))
+    }
+    let sanitized = sanitize_tsquery_cfg(phrase, T::ts_config_fulltext());
+    if sanitized.is_empty() {
+        return Ok(Page{items: Vec::new(), limit, offset, total: 0})
+    }
+    let ts_expr = ts_expression(&sanitized);
+    let query = format!("{} LIMIT $2 OFFSET $3", T::query_fulltext().trim().trim_end_matches(';'));
+    let (rows, count_rows) = tokio::join!(
+        client.query(&query, &[&ts_expr, &limit, &offset]),
+        client.query(T::query_fulltext_count(), &[&ts_expr]),
+    );
+    let items = rows?.iter().map(|row| T::rowfunc_fulltext(row)).collect();
+    let total: i64 = match count_rows?.get(0) {
+        Some(row) => row.try_get(0)?,
+        None => 0,
+    };
+    Ok(Page{items, limit, offset, total})
+}
+
 
 /// Convert a phrase to a postgres ts_expression
 pub fn ts_expression(phrase: &str) -> String {
@@ -82,7 +415,395 @@ pub fn ts_expression(phrase: &str) -> String {
         prefixes.push(prefix);
     }
     let ts_expression = prefixes.join(" & ");
-    print_if_env_eq("DEBUG_TSEX", "1", &format!("ts_expression={}", &ts_expression));
+    pachy_log!(debug, "pachydurable::fulltext", "ts_expression={}", &ts_expression);
     ts_expression
 }
 
+
+/// Like ts_expression, but aware of the text search configuration the expression will be used with:
+/// for 'english' the stopwords are dropped first, since Postgres ignores them there (even as prefixes, i.e. the:*).
+/// Other configurations, including 'simple', get the same expression as ts_expression 
+pub fn ts_expression_cfg(phrase: &str, ts_config: &str) -> String {
+    match ts_config {
+        "english" => {
+            let words: Vec<&str> = phrase.split_whitespace()
+                .filter(|word| !ENGLISH_STOPWORDS.contains(&word.to_lowercase().as_str()))
+                .collect();
+            ts_expression(&words.join(" "))
+        },
+        _ => ts_expression(phrase),
+    }
+}
+
+
+/// The text search configurations built into Postgres (see SELECT cfgname FROM pg_ts_config)
+pub const BUILTIN_TS_CONFIGS: &[&str] = &[
+    "simple", "arabic", "armenian", "basque", "catalan", "danish", "dutch", "english", "finnish", "french", "german",
+    "greek", "hindi", "hungarian", "indonesian", "irish", "italian", "lithuanian", "nepali", "norwegian", "portuguese",
+    "romanian", "russian", "serbian", "spanish", "swedish", "tamil", "turkish", "yiddish",
+];
+
+/// true if name is one of BUILTIN_TS_CONFIGS. As a const fn it can catch typos like 'engish' at compile time:
+/// const _: () = assert!(is_known_tsconfig("english"));
+/// Custom configurations (CREATE TEXT SEARCH CONFIGURATION ...) aren't known here- check those with validate_ts_config
+pub const fn is_known_tsconfig(name: &str) -> bool {
+    let name = name.as_bytes();
+    let mut i = 0;
+    while i < BUILTIN_TS_CONFIGS.len() {
+        let known = BUILTIN_TS_CONFIGS[i].as_bytes();
+        if known.len() == name.len() {
+            let mut j = 0;
+            while j < known.len() && known[j] == name[j] {
+                j += 1;
+            }
+            if j == known.len() {
+                return true
+            }
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Check the database has a text search configuration with this name by running to_tsquery with it, i.e. 
+/// during integration test setup. An unknown name returns a Custom error of kind unknown_ts_config 
+pub async fn validate_ts_config(client: &impl PachyClient, config_name: &str) -> Result<(), PachyDarn> {
+    match client.query("SELECT to_tsquery($1::text::regconfig, 'test')", &[&config_name]).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.sqlstate() == Some(SqlState::UNDEFINED_OBJECT.code()) => {
+            Err(PachyDarn::custom("unknown_ts_config", format!("there is no text search configuration named '{}'", config_name)))
+        },
+        Err(e) => Err(e),
+    }
+}
+
+
+/// These english stopwords are dropped by sanitize_tsquery_cfg for 'english', as Postgres ignores them in to_tsquery('english', ...)
+const ENGLISH_STOPWORDS: &[&str] = &[
+    "i", "me", "my", "myself", "we", "our", "ours", "ourselves", "you", "your", "yours", "yourself", "yourselves",
+    "he", "him", "his", "himself", "she", "her", "hers", "herself", "it", "its", "itself", "they", "them", "their",
+    "theirs", "themselves", "what", "which", "who", "whom", "this", "that", "these", "those", "am", "is", "are", "was",
+    "were", "be", "been", "being", "have", "has", "had", "having", "do", "does", "did", "doing", "a", "an", "the", "and",
+    "but", "if", "or", "because", "as", "until", "while", "of", "at", "by", "for", "with", "about", "against", "between",
+    "into", "through", "during", "before", "after", "above", "below", "to", "from", "up", "down", "in", "out", "on", "off",
+    "over", "under", "again", "further", "then", "once", "here", "there", "when", "where", "why", "how", "all", "any",
+    "both", "each", "few", "more", "most", "other", "some", "such", "no", "nor", "not", "only", "own", "same", "so",
+    "than", "too", "very", "s", "t", "can", "will", "just", "don", "should", "now",
+];
+
+
+/// Trim, lowercase and collapse the whitespace of a phrase, replacing characters with a meaning in tsquery syntax
+/// (i.e. & | ! ( ) : * ' < >) with spaces, so " Dog  FOOD!" becomes "dog food". Autocomplete normalizes
+/// phrases with this before building the ts_expression and the Redis key, so phrases differing only in these ways
+/// share one cache entry. Call it yourself to key anything else by phrase (i.e. a rate limit) the same way
+pub fn normalize_phrase(phrase: &str) -> String {
+    let cleaned: String = phrase.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { ' ' })
+        .collect();
+    cleaned.to_lowercase().split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Remove everything from a phrase that could make to_tsquery('english', ...) fail: the phrase is normalized with
+/// normalize_phrase and english stopwords are dropped, so "The fish & Chips!" becomes "fish chips"
+pub fn sanitize_tsquery(phrase: &str) -> String {
+    sanitize_tsquery_cfg(phrase, "english")
+}
+
+/// Like sanitize_tsquery, for the text search configuration the phrase will be used with: the english stopwords
+/// are only dropped for 'english', so "The Who" stays "the who" for 'simple'
+pub fn sanitize_tsquery_cfg(phrase: &str, ts_config: &str) -> String {
+    let normalized = normalize_phrase(phrase);
+    match ts_config {
+        "english" => {
+            let words: Vec<&str> = normalized
+                .split_whitespace()
+                .filter(|word| !ENGLISH_STOPWORDS.contains(word))
+                .collect();
+            words.join(" ")
+        },
+        _ => normalized,
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio_postgres::types::Type;
+    use crate::testing::{MockClient, MockRow, TestDb};
+    use super::*;
+
+    #[test]
+    fn sanitize_tsquery_drops_stopwords_and_operators() {
+        assert_eq!(sanitize_tsquery("the a an is"), "");
+        assert_eq!(sanitize_tsquery("   "), "");
+        assert_eq!(sanitize_tsquery("The fish & Chips!"), "fish chips");
+        assert_eq!(sanitize_tsquery("cat:* | !(dog)"), "cat dog");
+        assert_eq!(sanitize_tsquery("o'brien"), "o brien");
+        // only the english configuration drops the english stopwords
+        assert_eq!(sanitize_tsquery_cfg("The Who!", "simple"), "the who");
+        assert_eq!(sanitize_tsquery_cfg("The Who!", "english"), "");
+        // normalize_phrase keeps the stopwords
+        assert_eq!(normalize_phrase("  The   fish & Chips! "), "the fish chips");
+        assert_eq!(normalize_phrase(" :* "), "");
+    }
+
+    #[test]
+    fn ts_expression_prefixes() {
+        assert_eq!(ts_expression("crimson thread"), "crimson:* & thread:*");
+    }
+
+    #[test]
+    fn ts_expression_per_config() {
+        assert_eq!(ts_expression_cfg("the crimson thread", "simple"), "the:* & crimson:* & thread:*");
+        assert_eq!(ts_expression_cfg("The crimson thread", "english"), "crimson:* & thread:*");
+        assert_eq!(ts_expression_cfg("la ficelle", "french"), "la:* & ficelle:*");
+    }
+
+    const _: () = assert!(is_known_tsconfig("english") && is_known_tsconfig("simple"));
+
+    #[test]
+    fn known_tsconfigs() {
+        assert!(is_known_tsconfig("french"));
+        assert!(!is_known_tsconfig("engish"));
+        assert!(!is_known_tsconfig("englis"));
+        assert!(!is_known_tsconfig(""));
+    }
+
+    #[test]
+    fn validate_ts_configs() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let client = db.client().await.unwrap();
+            validate_ts_config(&client, "english").await.unwrap();
+            match validate_ts_config(&client, "engish").await {
+                Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "unknown_ts_config"),
+                other => panic!("expected unknown_ts_config, got {:?}", other),
+            }
+        })
+    }
+
+    struct Animal {
+        id: i32,
+        name: String,
+    }
+
+    impl FullText for Animal {
+        fn query_fulltext() -> &'static str {
+            "SELECT id, name FROM animals WHERE fulltext_tsv @@ to_tsquery('english', $1)"
+        }
+        fn rowfunc_fulltext<R: RowLike>(row: &R) -> Self {
+            Animal{id: row.get("id"), name: row.get("name")}
+        }
+    }
+
+    #[test]
+    fn exec_fulltext_with_mock_client() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = MockClient::new()
+                .with_rows(vec![MockRow::new().with("id", Type::INT4, &3i32).with("name", Type::TEXT, &"fish")]);
+            // a phrase of only stopwords never reaches Postgres
+            let hits: Vec<Animal> = exec_fulltext(&client, "The & of").await.unwrap();
+            assert!(hits.is_empty());
+            assert!(client.calls().is_empty());
+            let hits: Vec<Animal> = exec_fulltext(&client, "Swims in the sea!").await.unwrap();
+            assert_eq!((hits[0].id, hits[0].name.as_str()), (3, "fish"));
+            assert_eq!(client.calls()[0].params, vec!["\"swims:* & sea:*\"".to_string()]);
+        })
+    }
+
+    impl GetByPK for Animal {
+        fn query_get_by_pk() -> &'static str {
+            "SELECT id, name FROM animals WHERE id = $1"
+        }
+        fn rowfunc_get_by_pk<R: RowLike>(row: &R) -> Self {
+            Animal::rowfunc_fulltext(row)
+        }
+    }
+
+    impl PkFromRow<i32> for Animal {
+        fn pk_from_row<R: RowLike>(row: &R) -> i32 {
+            row.get("id")
+        }
+    }
+
+    #[test]
+    fn exec_fulltext_deduped_keeps_first_hits() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let row = |id: i32, name: &str| MockRow::new().with("id", Type::INT4, &id).with("name", Type::TEXT, &name);
+            let client = MockClient::new()
+                .with_rows(vec![row(3, "fish"), row(4, "eel"), row(3, "fish"), row(5, "crab"), row(4, "eel")]);
+            let hits: Vec<Animal> = exec_fulltext_deduped(&client, "swims").await.unwrap();
+            let ids: Vec<i32> = hits.iter().map(|hit| hit.id).collect();
+            assert_eq!(ids, vec![3, 4, 5]);
+        })
+    }
+    impl FullTextCursor for Animal {
+        fn query_fulltext_cursor() -> &'static str {
+            "SELECT id, name, ts_rank(fulltext_tsv, to_tsquery('english', $1)) AS rank
+            FROM animals WHERE fulltext_tsv @@ to_tsquery('english', $1)"
+        }
+        fn cursor_columns() -> &'static str {
+            "rank, id"
+        }
+    }
+
+    impl FullTextPaged for Animal {
+        fn query_fulltext_count() -> &'static str {
+            "SELECT COUNT(*) FROM animals WHERE fulltext_tsv @@ to_tsquery('english', $1)"
+        }
+    }
+
+    const CURSOR_SCHEMA_SQL: &str = "CREATE TABLE animals (
+        id SERIAL PRIMARY KEY,
+        name VARCHAR NOT NULL,
+        fulltext_tsv tsvector GENERATED ALWAYS AS (to_tsvector('english', name)) STORED
+    );
+    INSERT INTO animals (name) VALUES ('otter swims'), ('seal swims'), ('duck swims'), ('swan swims'),
+        ('swims and swims'), ('frog swims'), ('newt swims'), ('orca swims'), ('eel swims'), ('cat sleeps');";
+
+    struct Article {
+        title: String,
+    }
+
+    impl FullTextMulti for Article {
+        fn rowfunc_fulltext_multi<R: RowLike>(row: &R) -> Self { Article{title: row.get("title")} }
+        fn fulltext_table() -> &'static str { "articles" }
+        fn fulltext_select() -> &'static str { "title" }
+        fn query_fulltext_fields() -> Vec<(&'static str, f32)> { vec![("title_tsv", 1.0), ("body_tsv", 0.1)] }
+    }
+
+    // the same fields, weighted the other way around
+    struct BodyFirst(Article);
+
+    impl FullTextMulti for BodyFirst {
+        fn rowfunc_fulltext_multi<R: RowLike>(row: &R) -> Self { BodyFirst(Article::rowfunc_fulltext_multi(row)) }
+        fn fulltext_table() -> &'static str { "articles" }
+        fn fulltext_select() -> &'static str { "title" }
+        fn query_fulltext_fields() -> Vec<(&'static str, f32)> { vec![("title_tsv", 0.1), ("body_tsv", 1.0)] }
+    }
+
+    #[test]
+    fn weighted_fields_rank_hits() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE articles (
+                id SERIAL PRIMARY KEY,
+                title VARCHAR NOT NULL,
+                body VARCHAR,
+                title_tsv tsvector GENERATED ALWAYS AS (to_tsvector('english', title)) STORED,
+                body_tsv tsvector GENERATED ALWAYS AS (to_tsvector('english', body)) STORED
+            );
+            INSERT INTO articles (title, body) VALUES ('Otters at play', 'a day by the river'),
+                ('A day by the river', 'otters at play'), ('Seals', NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let hits: Vec<(Article, f64)> = exec_fulltext_multi(&client, "otters").await.unwrap();
+            let titles: Vec<&str> = hits.iter().map(|(article, _)| article.title.as_str()).collect();
+            assert_eq!(titles, vec!["Otters at play", "A day by the river"]);
+            assert!(hits[0].1 > hits[1].1);
+            let hits: Vec<(BodyFirst, f64)> = exec_fulltext_multi(&client, "otters").await.unwrap();
+            assert_eq!(hits[0].0.0.title, "A day by the river");
+            // a NULL tsvector doesn't hide the row's other fields
+            let hits: Vec<(Article, f64)> = exec_fulltext_multi(&client, "seals").await.unwrap();
+            assert_eq!(hits.len(), 1);
+        })
+    }
+
+    #[test]
+    fn fulltext_fields_are_limited() {
+        struct TooMany;
+        impl FullTextMulti for TooMany {
+            fn rowfunc_fulltext_multi<R: RowLike>(_row: &R) -> Self { TooMany }
+            fn fulltext_table() -> &'static str { "t" }
+            fn fulltext_select() -> &'static str { "id" }
+            fn query_fulltext_fields() -> Vec<(&'static str, f32)> { vec![("a", 1.0), ("b", 1.0), ("c", 1.0), ("d", 1.0), ("e", 1.0)] }
+        }
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = MockClient::new();
+            match exec_fulltext_multi::<TooMany>(&client, "fish").await {
+                Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "fulltext_fields"),
+                other => panic!("expected fulltext_fields, got {:?}", other.map(|hits| hits.len())),
+            }
+            assert!(client.calls().is_empty());
+        });
+        // the labels' weights are passed in the order D, C, B, A
+        let query = fulltext_multi_query::<Article>().unwrap();
+        assert!(query.contains("'{0,0,0.1,1}'::float4[]"), "{}", query);
+        assert!(query.contains(r#"setweight(coalesce("title_tsv", ''::tsvector), 'A') || setweight(coalesce("body_tsv", ''::tsvector), 'B')"#));
+    }
+
+    #[test]
+    fn offset_pages_with_totals() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(CURSOR_SCHEMA_SQL).await.unwrap();
+            let client = db.client().await.unwrap();
+            let first: Page<Animal> = exec_fulltext_paged_v2(&client, "swims", 4, 0).await.unwrap();
+            assert_eq!((first.items.len(), first.total), (4, 9));
+            let last: Page<Animal> = exec_fulltext_paged_v2(&client, "swims", 4, 8).await.unwrap();
+            assert_eq!((last.items.len(), last.offset, last.total), (1, 8, 9));
+            let none: Page<Animal> = exec_fulltext_paged_v2(&client, "walrus", 4, 0).await.unwrap();
+            assert_eq!((none.items.len(), none.total), (0, 0));
+            let res: Result<Page<Animal>, PachyDarn> = exec_fulltext_paged_v2(&client, "swims", -1, 0).await;
+            assert_eq!(res.unwrap_err().http_status(), 400);
+        })
+    }
+
+    #[test]
+    fn cursor_pages_without_duplicates() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(CURSOR_SCHEMA_SQL).await.unwrap();
+            let client = db.client().await.unwrap();
+            let mut seen = Vec::new();
+            let mut cursor = None;
+            for page in 0..3 {
+                let (hits, next) = exec_fulltext_cursor::<Animal>(&client, "swims", cursor, 3).await.unwrap();
+                assert_eq!(hits.len(), 3);
+                seen.extend(hits.into_iter().map(|hit| hit.id));
+                if page == 0 {
+                    // a new row, ranked above everything, shouldn't shift the later pages
+                    client.batch_execute("INSERT INTO animals (name) VALUES ('swims swims swims')").await.unwrap();
+                }
+                cursor = next;
+            }
+            assert!(cursor.is_none());
+            let mut unique = seen.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), seen.len());
+            assert_eq!(unique, (1..=9).collect::<Vec<i32>>());
+            // the same search gives the same cursor
+            let (_, first) = exec_fulltext_cursor::<Animal>(&client, "swims", None, 3).await.unwrap();
+            let (_, again) = exec_fulltext_cursor::<Animal>(&client, "swims", None, 3).await.unwrap();
+            assert_eq!(first, again);
+        })
+    }
+
+    #[test]
+    fn invalid_cursors_are_bad_requests() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = MockClient::new();
+            let other_search = Cursor::encode(&CursorPosition{rank: 0.5, keys: vec!["3".to_string()], phrase_hash: 0}).unwrap();
+            let garbage = Cursor("not a cursor!".to_string());
+            for cursor in [other_search, garbage] {
+                match exec_fulltext_cursor::<Animal>(&client, "swims", Some(cursor), 3).await {
+                    Err(PachyDarn::Custom{kind, status, ..}) => assert_eq!((kind, status), ("invalid_cursor", Some(400))),
+                    other => panic!("expected invalid_cursor, got {:?}", other.map(|(hits, _)| hits.len())),
+                }
+            }
+            assert!("%%%".parse::<Cursor>().is_err());
+            assert!(client.calls().is_empty());
+            // the rank decodes to exactly the value encoded
+            for rank in [0.1 + 0.2, 0.060_792_710_739_374_16, f64::MIN_POSITIVE, 1e-310] {
+                let cursor = Cursor::encode(&CursorPosition{rank, keys: vec!["3".to_string()], phrase_hash: 0}).unwrap();
+                assert_eq!(cursor.decode().unwrap().rank.to_bits(), rank.to_bits());
+            }
+        })
+    }
+}
@@ -0,0 +1,126 @@
+//! The health module checks Postgres and Redis for a /health endpoint, returning a HealthCheck that serializes
+//! directly as the response body:
+//! ```ignore
+//! let health = health_check(&pg_pool, Some(&redis_pool), Duration::from_secs(2)).await;
+//! let status = if health.ok() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+//! ```
+
+use std::{future::Future, time::{Duration, Instant}};
+use serde::Serialize;
+use crate::{connect::{get_scalar, ConnPoolNoTLS}, err::PachyDarn, preflight::PreflightRedis, utils::pachy_log};
+
+
+/// Whether Postgres and Redis answered, and how long they took. A latency is None if the check failed or,
+/// for Redis, if no pool was given, in which case redis_ok is true as there is nothing to be down
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub postgres_ok: bool,
+    pub redis_ok: bool,
+    pub postgres_latency_ms: Option<u64>,
+    pub redis_latency_ms: Option<u64>,
+}
+
+impl HealthCheck {
+    /// true if every checked dependency is ok
+    pub fn ok(&self) -> bool {
+        self.postgres_ok && self.redis_ok
+    }
+}
+
+
+/// The health of one dependency (i.e. Postgres or Redis) as reported by http_server::health_handler
+#[derive(Serialize, Debug)]
+pub struct DependencyHealth {
+    pub name: &'static str,
+    pub ok: bool,
+    pub latency_ms: u64,
+    /// why the check failed, if it did
+    pub error: Option<String>,
+    /// connections open in the pool
+    pub open_connections: u64,
+    /// connections currently checked out of the pool
+    pub in_use: u64,
+}
+
+// run one check, enforcing the timeout so a hung dependency can't hang the probe
+pub(crate) async fn check_dependency<F: Future<Output = Result<(), PachyDarn>>>(name: &'static str, timeout: Duration, check: F) -> DependencyHealth {
+    let start = Instant::now();
+    let error = match tokio::time::timeout(timeout, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("timed out after {}ms", timeout.as_millis())),
+    };
+    DependencyHealth{name, ok: error.is_none(), latency_ms: start.elapsed().as_millis() as u64, error, open_connections: 0, in_use: 0}
+}
+
+// the latency of a dependency if it is ok, logging why it isn't otherwise
+fn latency(dependency: DependencyHealth) -> Option<u64> {
+    match dependency.error {
+        None => Some(dependency.latency_ms),
+        Some(error) => {
+            pachy_log!(warn, "pachydurable::health", "{} health check failed: {}", dependency.name, error);
+            None
+        },
+    }
+}
+
+#[cfg(feature = "redis")]
+async fn redis_latency(redis_pool: &PreflightRedis, timeout: Duration) -> Option<u64> {
+    latency(check_dependency("redis", timeout, async { crate::preflight::redis_ping(redis_pool).await.map(|_pong| ()) }).await)
+}
+
+#[cfg(not(feature = "redis"))]
+async fn redis_latency(redis_pool: &PreflightRedis, _timeout: Duration) -> Option<u64> {
+    match *redis_pool {}
+}
+
+
+/// Time SELECT 1 on Postgres and, if a Redis pool is given, PING on Redis, concurrently. A check that takes
+/// longer than timeout fails, so a hung dependency can't hang the endpoint
+pub async fn health_check(pg_pool: &ConnPoolNoTLS, redis_pool: Option<&PreflightRedis>, timeout: Duration) -> HealthCheck {
+    let postgres = check_dependency("postgres", timeout, async {
+        let _one: i32 = get_scalar(&pg_pool.get().await?, "SELECT 1", &[]).await?;
+        Ok(())
+    });
+    let redis = async {
+        match redis_pool {
+            Some(redis_pool) => redis_latency(redis_pool, timeout).await,
+            None => None,
+        }
+    };
+    let (postgres, redis_latency_ms) = tokio::join!(postgres, redis);
+    let postgres_latency_ms = latency(postgres);
+    let redis_ok = redis_pool.is_none() || redis_latency_ms.is_some();
+    HealthCheck{postgres_ok: postgres_latency_ms.is_some(), redis_ok, postgres_latency_ms, redis_latency_ms}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::testing::TestDb;
+    use super::*;
+
+    #[test]
+    fn health_check_times_each_dependency() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pg = db.pool();
+            let health = health_check(pg, None, Duration::from_secs(2)).await;
+            assert!(health.ok() && health.postgres_ok && health.redis_ok);
+            assert!(health.postgres_latency_ms.is_some());
+            assert_eq!(health.redis_latency_ms, None);
+            #[cfg(feature = "redis")]
+            {
+                let test_redis = crate::testing::TestRedis::new().await.unwrap();
+                let health = health_check(pg, Some(test_redis.pool()), Duration::from_secs(2)).await;
+                assert!(health.ok() && health.redis_latency_ms.is_some(), "{:?}", health);
+            }
+            let json = serde_json::to_value(&health).unwrap();
+            assert_eq!(json["postgres_ok"], true);
+            assert_eq!(json["redis_latency_ms"], serde_json::Value::Null);
+        })
+    }
+}
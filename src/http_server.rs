@@ -0,0 +1,1096 @@
+//! The http_server module contains helpers for serving pachydurable queries with hyper.
+//! It is only compiled when the "hyper" feature is enabled.
+//!
+//! The switch_psql_handler function takes care of the boilerplate common to many endpoints:
+//! reading the data_type= and q= query parameters, awaiting a switcher that matches the data_type
+//! to a Postgres query, and translating any PachyDarn into a response via PachyDarn::http_status().
+//!
+//! The AutocompRegistry, cached_autocomp_handler, and health_handler need Redis, so they also require the "redis" feature.
+
+// standard library
+use std::{convert::Infallible, future::Future, pin::Pin, str::FromStr, sync::Arc, time::{Duration, Instant}};
+// crates.io
+use futures_util::{Stream, StreamExt, stream};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode, header, header::HeaderValue, body::{Bytes, HttpBody}, http::request::Parts};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::{connect::{ClientNoTLS, ConnPoolNoTLS}, err::PachyDarn, health::check_dependency, utils::{RequestId, REQUEST_ID, pachy_log, strong_etag}};
+pub use crate::health::DependencyHealth;
+#[cfg(feature = "redis")]
+use std::collections::HashMap;
+#[cfg(feature = "redis")]
+use mobc_redis::redis;
+#[cfg(feature = "redis")]
+use crate::redis::{CacheEnvelope, CachedAutoComp, RedisPool, cached_autocomp_envelope_pooled};
+
+
+/// The future returned by a switcher
+pub type SwitchFuture<'a> = Pin<Box<dyn Future<Output = Result<Response<Body>, PachyDarn>> + Send + 'a>>;
+
+/// A switcher takes the data_type, the parsed q= parameter, and a client, and returns a future
+/// resolving to a response. Return unknown_data_type(data_type) for data types it does not handle.
+pub type Switcher<PK> = for<'a> fn(&'a str, &'a PK, &'a ClientNoTLS) -> SwitchFuture<'a>;
+
+
+/// Get a query parameter from a request and parse it to T.
+/// A missing or unparseable parameter yields an error that maps to 400 and names the parameter
+pub fn get_query_param<T: FromStr>(req: &Request<Body>, name: &str) -> Result<T, PachyDarn> {
+    match get_query_param_opt(req, name)? {
+        Some(val) => Ok(val),
+        None => Err(PachyDarn::custom_with_status("missing_param", format!("missing query parameter {}", name), 400)),
+    }
+}
+
+/// Like get_query_param, but a missing parameter is the None variant rather than an error
+pub fn get_query_param_opt<T: FromStr>(req: &Request<Body>, name: &str) -> Result<Option<T>, PachyDarn> {
+    let query = req.uri().query().unwrap_or("");
+    let raw = form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, val)| val.into_owned());
+    match raw {
+        Some(val) => match val.parse::<T>() {
+            Ok(t) => Ok(Some(t)),
+            Err(_) => Err(PachyDarn::custom_with_status("invalid_param", format!("could not parse query parameter {}={}", name, val), 400)),
+        },
+        None => Ok(None),
+    }
+}
+
+
+/// Parse the path segment at position index (counting from 0, ignoring empty segments) to T, i.e. for /gadgets/{id}
+/// get_path_param::<Uuid>(&req, 1) parses the id. Like get_query_param, a missing or unparseable segment maps to 400
+pub fn get_path_param<T: FromStr>(req: &Request<Body>, index: usize) -> Result<T, PachyDarn> {
+    let segment = req.uri().path().split('/').filter(|seg| !seg.is_empty()).nth(index)
+        .ok_or_else(|| PachyDarn::custom_with_status("missing_param", format!("missing path segment {}", index), 400))?;
+    segment.parse::<T>()
+        .map_err(|_| PachyDarn::custom_with_status("invalid_param", format!("could not parse path segment {}={}", index, segment), 400))
+}
+
+/// The defaults and limits applied by PageParams::from_request
+pub struct PageDefaults {
+    /// the limit if none is provided
+    pub limit: u32,
+    /// larger limits are clamped to this
+    pub max_limit: u32,
+    /// the sort column if none is provided
+    pub sort: Option<&'static str>,
+    /// the sort direction if none is provided
+    pub desc: bool,
+    /// only these values are accepted for sort=, since it gets spliced into SQL
+    pub sort_allowlist: &'static [&'static str],
+}
+
+impl Default for PageDefaults {
+    fn default() -> Self {
+        PageDefaults{limit: 20, max_limit: 100, sort: None, desc: false, sort_allowlist: &[]}
+    }
+}
+
+
+/// Pagination and sorting parameters read from the limit=, offset=, sort=, and dir= (asc or desc) query parameters
+#[derive(Debug, PartialEq)]
+pub struct PageParams {
+    pub limit: u32,
+    pub offset: u32,
+    pub sort: Option<String>,
+    pub desc: bool,
+}
+
+impl PageParams {
+
+    /// Read the page parameters from a request. Errors map to 400:
+    /// negative or overflowing numbers, a dir other than asc/desc, or a sort column not in the allowlist.
+    /// A limit larger than defaults.max_limit is clamped rather than rejected
+    pub fn from_request(req: &Request<Body>, defaults: PageDefaults) -> Result<Self, PachyDarn> {
+        let limit: u32 = get_query_param_opt(req, "limit")?.unwrap_or(defaults.limit).clamp(1, defaults.max_limit.max(1));
+        let offset: u32 = get_query_param_opt(req, "offset")?.unwrap_or(0);
+        let desc = match get_query_param_opt::<String>(req, "dir")? {
+            None => defaults.desc,
+            Some(dir) => match dir.to_lowercase().as_ref() {
+                "asc" => false,
+                "desc" => true,
+                _ => return Err(PachyDarn::custom_with_status("invalid_param", format!("dir must be asc or desc, not {}", dir), 400)),
+            },
+        };
+        let sort = match get_query_param_opt::<String>(req, "sort")? {
+            None => defaults.sort.map(|col| col.to_string()),
+            Some(col) => match defaults.sort_allowlist.contains(&col.as_str()) {
+                true => Some(col),
+                false => return Err(PachyDarn::custom_with_status("invalid_param", format!("cannot sort by {}", col), 400)),
+            },
+        };
+        Ok(PageParams{limit, offset, sort, desc})
+    }
+
+    fn order_by(&self) -> String {
+        match &self.sort {
+            Some(col) => format!("ORDER BY \"{}\" {} ", col, if self.desc { "DESC" } else { "ASC" }),
+            None => "".to_string(),
+        }
+    }
+
+    /// i.e. ORDER BY "name" DESC LIMIT 20 OFFSET 40
+    /// The sort column was checked against the allowlist and the numbers were parsed, so this is safe to splice into SQL
+    pub fn to_sql_suffix(&self) -> String {
+        format!("{}LIMIT {} OFFSET {}", self.order_by(), self.limit, self.offset)
+    }
+
+    /// i.e. ORDER BY "name" DESC LIMIT $3 OFFSET $4 if next_param is 3.
+    /// Bind limit_param() and offset_param() to those parameters
+    pub fn to_sql_suffix_bound(&self, next_param: usize) -> String {
+        format!("{}LIMIT ${} OFFSET ${}", self.order_by(), next_param, next_param + 1)
+    }
+
+    /// the limit as a BIGINT parameter
+    pub fn limit_param(&self) -> i64 {
+        self.limit as i64
+    }
+
+    /// the offset as a BIGINT parameter
+    pub fn offset_param(&self) -> i64 {
+        self.offset as i64
+    }
+}
+
+
+/// Return this from a switcher when it does not recognize the data type. It maps to 404
+pub fn unknown_data_type(data_type: &str) -> PachyDarn {
+    PachyDarn::custom_with_status("unknown_data_type", format!("Unknown data type {}", data_type), 404)
+}
+
+
+/// Serialize a value to JSON and return it as a 200 response
+pub fn build_response_json<T: Serialize>(value: &T) -> Result<Response<Body>, PachyDarn> {
+    let jz = serde_json::to_string(value)?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(jz))
+        .map_err(PachyDarn::boxed)?)
+}
+
+
+/// The Cache-Control header set by build_response_json_etag: clients may store the response,
+/// but must revalidate it with If-None-Match before reusing it
+pub const DEFAULT_ETAG_CACHE_CONTROL: &str = "no-cache";
+
+
+/// Return true if any If-None-Match header matches the etag (or is "*").
+/// If-None-Match uses weak comparison, so a W/"..." validator from a proxy matches the strong ETag it was derived from
+pub fn if_none_match(req_headers: &HeaderMap, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    req_headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|val| val.to_str().ok())
+        .flat_map(|val| val.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+
+/// Return already serialized JSON with its ETag and the given Cache-Control header,
+/// or 304 with an empty body if the request's If-None-Match matches the ETag
+pub fn build_response_etag(jz: String, etag: &str, req_headers: &HeaderMap, cache_control: &str) -> Result<Response<Body>, PachyDarn> {
+    let builder = Response::builder()
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, cache_control);
+    let resp = match if_none_match(req_headers, etag) {
+        true => builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()),
+        false => builder.status(StatusCode::OK).header(header::CONTENT_TYPE, "application/json").body(Body::from(jz)),
+    };
+    resp.map_err(PachyDarn::boxed)
+}
+
+
+/// Like build_response_json, but sets a strong ETag (the hash of the JSON) and answers a matching If-None-Match with 304.
+/// The Cache-Control header is DEFAULT_ETAG_CACHE_CONTROL
+pub fn build_response_json_etag<T: Serialize>(value: &T, req_headers: &HeaderMap) -> Result<Response<Body>, PachyDarn> {
+    build_response_json_etag_with_cache_control(value, req_headers, DEFAULT_ETAG_CACHE_CONTROL)
+}
+
+
+/// build_response_json_etag with a custom Cache-Control header, i.e. "max-age=60"
+pub fn build_response_json_etag_with_cache_control<T: Serialize>(value: &T, req_headers: &HeaderMap, cache_control: &str) -> Result<Response<Body>, PachyDarn> {
+    let jz = serde_json::to_string(value)?;
+    let etag = strong_etag(jz.as_bytes());
+    build_response_etag(jz, &etag, req_headers, cache_control)
+}
+
+
+/// The key of the object stream_json_array appends if the source stream fails part way through
+pub const STREAM_ERROR_KEY: &str = "_stream_error";
+
+
+// the message sent to the client for an error (see PachyDarn::error_message). Server errors only
+// get a generic message, so the full error is logged here
+fn client_message(err: &PachyDarn) -> String {
+    if err.http_status() >= 500 {
+        pachy_log!(error, "pachydurable::http_server", "{}: {}", err.error_code(), err);
+    }
+    err.error_message()
+}
+
+
+// close the array with an element describing the error, i.e. ,{"_stream_error":{"status":500,"message":"..."}}]
+fn stream_error_chunk(separator: &str, err: &PachyDarn) -> Bytes {
+    let element = serde_json::json!({STREAM_ERROR_KEY: {"status": err.http_status(), "message": client_message(err)}});
+    Bytes::from(format!("{}{}]", separator, element))
+}
+
+
+/// Return a 200 response whose body is a JSON array written one element at a time as the items stream in,
+/// so large result sets never need to be collected into a Vec or one giant String. Pair it with connect::get_stream:
+/// ```ignore
+/// let hits = get_stream(&client, "SELECT id, name FROM animals", |row| Animal{id: row.get(0), name: row.get(1)}, &[]).await?;
+/// Ok(stream_json_array(hits))
+/// ```
+/// The status has already been sent by the time a mid-stream error occurs, so the convention is that the body
+/// stays valid JSON: the array ends with one last element {"_stream_error": {"status": ..., "message": ...}}
+/// (see STREAM_ERROR_KEY) and nothing after the error is sent. Clients should check the last element for that key 
+pub fn stream_json_array<T: Serialize + Send + 'static>(items: impl Stream<Item = Result<T, PachyDarn>> + Send + 'static) -> Response<Body> {
+    // the state is (items, whether no element has been written yet, whether the array is closed)
+    let chunks = stream::unfold((Box::pin(items), true, false), |(mut items, first, closed)| async move {
+        if closed {
+            return None
+        }
+        let separator = if first { "[" } else { "," };
+        let (chunk, closed) = match items.next().await {
+            None => (Bytes::from(if first { "[]" } else { "]" }), true),
+            Some(Ok(item)) => match serde_json::to_string(&item) {
+                Ok(jz) => (Bytes::from(format!("{}{}", separator, jz)), false),
+                Err(e) => (stream_error_chunk(separator, &e.into()), true),
+            },
+            Some(Err(e)) => (stream_error_chunk(separator, &e), true),
+        };
+        Some((Ok::<Bytes, Infallible>(chunk), (items, false, closed)))
+    });
+    let mut resp = Response::new(Body::wrap_stream(chunks));
+    resp.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    resp
+}
+
+
+/// Serialize a value to JSON and return it as a 201 response, i.e. after a POST creates something
+pub fn write_json_created<T: Serialize>(value: &T) -> Response<Body> {
+    match build_response_json(value) {
+        Ok(mut resp) => {
+            *resp.status_mut() = StatusCode::CREATED;
+            resp
+        },
+        Err(e) => error_response(&e),
+    }
+}
+
+
+/// Read a JSON request body and deserialize it to T, returning the request parts so headers remain accessible.
+/// The errors all map to an appropriate status via PachyDarn::http_status():
+/// 415 if the Content-Type is not application/json,
+/// 413 if the body is larger than max_bytes (it is rejected as soon as that is known- not buffered in full),
+/// 422 if the body is not valid JSON for T (the serde message is included)
+pub async fn read_json_body<T: DeserializeOwned>(req: Request<Body>, max_bytes: usize) -> Result<(T, Parts), PachyDarn> {
+    let (parts, mut body) = req.into_parts();
+    let content_type = parts.headers.get(header::CONTENT_TYPE).and_then(|val| val.to_str().ok()).unwrap_or("");
+    if !content_type.to_lowercase().starts_with("application/json") {
+        return Err(PachyDarn::custom_with_status("unsupported_media_type", format!("expected Content-Type application/json, got '{}'", content_type), 415))
+    }
+    let too_large = || PachyDarn::custom_with_status("payload_too_large", format!("request body exceeds {} bytes", max_bytes), 413);
+    // reject early if the client already told us the body is too big 
+    let content_length = parts.headers.get(header::CONTENT_LENGTH)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| val.parse::<usize>().ok());
+    if let Some(length) = content_length {
+        if length > max_bytes {
+            return Err(too_large())
+        }
+    }
+    // otherwise read chunk by chunk, stopping as soon as the limit is passed
+    let mut buf: Vec<u8> = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(PachyDarn::boxed)?;
+        if buf.len() + chunk.len() > max_bytes {
+            return Err(too_large())
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    let t: T = serde_json::from_slice(&buf)
+        .map_err(|e| PachyDarn::custom_with_status("invalid_json", e.to_string(), 422))?;
+    Ok((t, parts))
+}
+
+
+/// The future returned by an AutocompRegistry entry: the JSON serialized hits, and their ETag from the cache
+#[cfg(feature = "redis")]
+type AutocompFuture<'a> = Pin<Box<dyn Future<Output = Result<CacheEnvelope<String>, PachyDarn>> + Send + 'a>>;
+
+/// An AutocompRegistry entry: the function to call plus the CachedAutoComp::ttl() of the type 
+#[cfg(feature = "redis")]
+struct AutocompEntry {
+    func: for<'a> fn(&'a RedisPool, &'a ConnPoolNoTLS, &'a str) -> AutocompFuture<'a>,
+    ttl: Duration,
+}
+
+#[cfg(feature = "redis")]
+fn autocomp_json<'a, PKC: Serialize+DeserializeOwned+Send+Sync+'static, T: CachedAutoComp<PKC>+'static>(rpool: &'a RedisPool, pg: &'a ConnPoolNoTLS, phrase: &'a str) -> AutocompFuture<'a> {
+    Box::pin(async move {
+        let envelope = cached_autocomp_envelope_pooled::<PKC, T>(rpool, pg, phrase).await?;
+        Ok(CacheEnvelope{etag: envelope.etag, value: serde_json::to_string(&envelope.value)?})
+    })
+}
+
+
+/// The AutocompRegistry maps each data_type to a type implementing CachedAutoComp, so one handler
+/// (see cached_autocomp_handler) can serve cached autocomplete results for every type:
+/// ```ignore
+/// let registry = AutocompRegistry::new()
+///     .register::<i32, Animal>("animal")
+///     .register::<String, Food>("food");
+/// ```
+#[cfg(feature = "redis")]
+pub struct AutocompRegistry {
+    entries: HashMap<String, AutocompEntry>,
+}
+
+#[cfg(feature = "redis")]
+impl Default for AutocompRegistry {
+    fn default() -> Self {
+        AutocompRegistry{entries: HashMap::new()}
+    }
+}
+
+#[cfg(feature = "redis")]
+impl AutocompRegistry {
+    pub fn new() -> Self {
+        AutocompRegistry::default()
+    }
+
+    /// register T so requests with data_type= this data_type are answered with cached_autocomp::<PKC, T>
+    pub fn register<PKC: Serialize+DeserializeOwned+Send+Sync+'static, T: CachedAutoComp<PKC>+'static>(mut self, data_type: &str) -> Self {
+        let entry = AutocompEntry{func: autocomp_json::<PKC, T>, ttl: <T as CachedAutoComp<PKC>>::ttl()};
+        self.entries.insert(data_type.to_string(), entry);
+        self
+    }
+
+    /// return the JSON response of cached autocomplete hits for the phrase, with a Cache-Control max-age matching the
+    /// ttl() of the registered type and the ETag cached alongside the hits. 
+    /// A matching If-None-Match in req_headers yields 304, and an unregistered data_type maps to 404.
+    /// A Postgres client is only checked out of pg on a cache miss
+    pub async fn autocomp(&self, rpool: &RedisPool, pg: &ConnPoolNoTLS, data_type: &str, phrase: &str, req_headers: &HeaderMap) -> Result<Response<Body>, PachyDarn> {
+        let entry = self.entries.get(data_type).ok_or_else(|| unknown_data_type(data_type))?;
+        let envelope = (entry.func)(rpool, pg, phrase).await?;
+        let cache_control = format!("max-age={}", entry.ttl.as_secs());
+        build_response_etag(envelope.value, &envelope.etag, req_headers, &cache_control)
+    }
+}
+
+
+/// Answer GET /autocomp?data_type=X&q=Y with cached autocomplete hits for any type in the registry.
+/// Requests with a matching If-None-Match yield 304, unknown data types yield 404, and if Redis is down the hits come from Postgres (see cached_autocomp_degraded).
+/// It takes the Postgres pool rather than a client, so a cache hit never touches Postgres
+#[cfg(feature = "redis")]
+pub async fn cached_autocomp_handler(req: &Request<Body>, registry: &AutocompRegistry, rpool: &RedisPool, pg: &ConnPoolNoTLS) -> Response<Body> {
+    let res = async {
+        let data_type: String = get_query_param(req, "data_type")?;
+        let phrase: String = get_query_param(req, "q")?;
+        registry.autocomp(rpool, pg, &data_type, &phrase, req.headers()).await
+    };
+    match res.await {
+        Ok(resp) => resp,
+        Err(e) => error_response(&e),
+    }
+}
+
+
+/// The JSON body returned by health_handler
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub dependencies: Vec<DependencyHealth>,
+}
+
+
+// check Postgres, including the state of the pool
+async fn postgres_health(pg: &ConnPoolNoTLS, timeout: Duration) -> DependencyHealth {
+    let mut postgres = check_dependency("postgres", timeout, async {
+        let client = pg.get().await?;
+        let _row = client.query_one("SELECT 1", &[]).await?;
+        Ok::<(), PachyDarn>(())
+    }).await;
+    let state = pg.state().await;
+    postgres.open_connections = state.connections;
+    postgres.in_use = state.in_use;
+    postgres
+}
+
+// check Redis, including the state of the pool
+#[cfg(feature = "redis")]
+async fn redis_health(rpool: &RedisPool, timeout: Duration) -> DependencyHealth {
+    let mut redis_health = check_dependency("redis", timeout, async {
+        let mut rconn = rpool.get().await?;
+        let _pong: String = redis::cmd("PING").query_async(&mut *rconn).await?;
+        Ok::<(), PachyDarn>(())
+    }).await;
+    let state = rpool.state().await;
+    redis_health.open_connections = state.connections;
+    redis_health.in_use = state.in_use;
+    redis_health
+}
+
+// the HealthReport of the dependencies as JSON: 200 if they are all ok and 503 otherwise
+fn health_response(dependencies: Vec<DependencyHealth>) -> Response<Body> {
+    let report = HealthReport{ok: dependencies.iter().all(|dep| dep.ok), dependencies};
+    let status = if report.ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    match build_response_json(&report) {
+        Ok(mut resp) => {
+            *resp.status_mut() = status;
+            resp
+        },
+        Err(e) => error_response(&e),
+    }
+}
+
+
+/// A readiness probe (i.e. /readyz) for services without Redis: like health_handler, but only checks Postgres
+pub async fn pg_health_handler(pg: Arc<ConnPoolNoTLS>, timeout: Duration) -> Response<Body> {
+    health_response(vec![postgres_health(&pg, timeout).await])
+}
+
+
+/// A readiness probe (i.e. /readyz): checks that Postgres (and Redis, if provided) can be reached,
+/// each within its own timeout. Returns 200 with a JSON HealthReport, or 503 naming the failing dependency
+#[cfg(feature = "redis")]
+pub async fn health_handler(pg: Arc<ConnPoolNoTLS>, rpool: Option<Arc<RedisPool>>, timeout: Duration) -> Response<Body> {
+    // the checks run concurrently, so a slow dependency doesn't add its latency to the other's
+    let (postgres, redis) = tokio::join!(postgres_health(&pg, timeout), async {
+        match rpool {
+            Some(rpool) => Some(redis_health(&rpool, timeout).await),
+            None => None,
+        }
+    });
+    let mut dependencies = vec![postgres];
+    dependencies.extend(redis);
+    health_response(dependencies)
+}
+
+
+/// A liveness probe (i.e. /healthz): if the server can answer at all it is alive, so this never touches Postgres or Redis
+pub fn liveness_handler() -> Response<Body> {
+    Response::new(Body::from("ok"))
+}
+
+
+/// Which cross-origin requests browsers should allow. Origins may be exact (https://app.example.com),
+/// the wildcard *, or a subdomain pattern (https://*.example.com, which does not match https://example.com itself):
+/// ```ignore
+/// let cors = CorsPolicy::new(
+///     vec!["https://app.example.com".to_string(), "https://*.example.org".to_string()],
+///     vec![Method::GET, Method::POST],
+///     vec!["content-type".to_string()],
+///     true,
+///     Some(Duration::from_secs(3600)),
+/// )?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<Method>,
+    allowed_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl CorsPolicy {
+    /// Create a policy, returning an error if an origin pattern is malformed or if credentials are allowed
+    /// with the wildcard origin (browsers refuse that combination, and it would expose credentialed responses to any site)
+    pub fn new(allowed_origins: Vec<String>, allowed_methods: Vec<Method>, allowed_headers: Vec<String>, allow_credentials: bool, max_age: Option<Duration>) -> Result<Self, PachyDarn> {
+        let invalid = |message: String| PachyDarn::custom("invalid_cors_policy", message);
+        for origin in allowed_origins.iter() {
+            if origin == "*" {
+                if allow_credentials {
+                    return Err(invalid("allow_credentials cannot be combined with the wildcard origin *".to_string()))
+                }
+                continue
+            }
+            let stars = origin.matches('*').count();
+            if stars > 1 || (stars == 1 && !origin.contains("://*.")) {
+                return Err(invalid(format!("origin pattern {} must be exact or of the form scheme://*.domain", origin)))
+            }
+        }
+        Ok(CorsPolicy{allowed_origins, allowed_methods, allowed_headers, allow_credentials, max_age})
+    }
+
+    // true if the policy allows any origin 
+    fn is_wildcard(&self) -> bool {
+        self.allowed_origins.iter().any(|origin| origin == "*")
+    }
+
+    /// Return true if the origin matches an exact origin or subdomain pattern of the policy (or the policy has the wildcard)
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|allowed| {
+            match allowed.split_once("://*.") {
+                None => allowed == "*" || allowed.eq_ignore_ascii_case(origin),
+                Some((scheme, domain)) => {
+                    let origin = origin.to_ascii_lowercase();
+                    let prefix = format!("{}://", scheme.to_ascii_lowercase());
+                    let suffix = format!(".{}", domain.to_ascii_lowercase());
+                    match origin.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(&suffix)) {
+                        // the subdomain part can't smuggle in a different host, i.e. https://evil.com/.example.com
+                        Some(sub) => !sub.is_empty() && sub.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'),
+                        None => false,
+                    }
+                },
+            }
+        })
+    }
+
+    /// Add the Access-Control-Allow-Origin (and if enabled Access-Control-Allow-Credentials) headers to a response
+    /// if the request's Origin is allowed. Unless the policy is the wildcard, Vary: Origin is always added so caches
+    /// don't serve a response allowed for one origin to another
+    pub fn apply(&self, req_origin: Option<&HeaderValue>, resp: &mut Response<Body>) {
+        let headers = resp.headers_mut();
+        if self.is_wildcard() {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+            return
+        }
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        let origin = match req_origin {
+            Some(origin) => origin,
+            None => return,
+        };
+        if origin.to_str().map(|val| self.allows_origin(val)).unwrap_or(false) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            if self.allow_credentials {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+            }
+        }
+    }
+
+    /// Answer a preflight (OPTIONS) request with 204. If the Origin is allowed the response lists the allowed methods,
+    /// headers, and max age; if not, the CORS headers are left out and the browser blocks the actual request
+    pub fn preflight(&self, req: &Request<Body>) -> Response<Body> {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NO_CONTENT;
+        let req_origin = req.headers().get(header::ORIGIN);
+        self.apply(req_origin, &mut resp);
+        if !resp.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return resp
+        }
+        let methods: Vec<&str> = self.allowed_methods.iter().map(|method| method.as_str()).collect();
+        let mut values = vec![(header::ACCESS_CONTROL_ALLOW_METHODS, methods.join(", "))];
+        if !self.allowed_headers.is_empty() {
+            values.push((header::ACCESS_CONTROL_ALLOW_HEADERS, self.allowed_headers.join(", ")));
+        }
+        if let Some(max_age) = self.max_age {
+            values.push((header::ACCESS_CONTROL_MAX_AGE, max_age.as_secs().to_string()));
+        }
+        for (name, value) in values {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                resp.headers_mut().insert(name, value);
+            }
+        }
+        resp
+    }
+}
+
+
+/// Like build_response_json, but with the CORS headers the policy allows for the request's Origin
+pub fn build_response_json_cors<T: Serialize>(value: &T, req: &Request<Body>, policy: &CorsPolicy) -> Result<Response<Body>, PachyDarn> {
+    let mut resp = build_response_json(value)?;
+    policy.apply(req.headers().get(header::ORIGIN), &mut resp);
+    Ok(resp)
+}
+
+
+/// The header a request ID is read from, and echoed back in
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+
+/// Read the request ID from the X-Request-Id header, or generate a UUID if there is none (or it is empty,
+/// longer than 128 characters, or not printable ASCII). The header is set on the returned request either way
+pub fn with_request_id(mut req: Request<Body>) -> (Request<Body>, RequestId) {
+    let from_header = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|val| val.to_str().ok())
+        .filter(|val| !val.is_empty() && val.len() <= 128 && val.chars().all(|c| c.is_ascii_graphic()))
+        .map(|val| val.to_string());
+    let id = match from_header {
+        Some(id) => id,
+        None => {
+            let id = uuid::Uuid::new_v4().to_string();
+            if let Ok(val) = HeaderValue::from_str(&id) {
+                req.headers_mut().insert(REQUEST_ID_HEADER, val);
+            }
+            id
+        },
+    };
+    (req, RequestId(id))
+}
+
+
+/// Log one line per request: the request ID, method, path, status (from PachyDarn::http_status() for errors), and elapsed time
+pub fn log_request(outcome: &Result<Response<Body>, PachyDarn>, id: &RequestId, method: &Method, path: &str, elapsed: Duration) {
+    let ms = elapsed.as_millis();
+    match outcome {
+        Ok(resp) => pachy_log!(info, "pachydurable::http_server", "request_id={} {} {} status={} elapsed_ms={}", id, method, path, resp.status().as_u16(), ms),
+        Err(e) => pachy_log!(info, "pachydurable::http_server", "request_id={} {} {} status={} elapsed_ms={} error={}", id, method, path, e.http_status(), ms, e),
+    }
+}
+
+
+/// Wrap a handler so that the request has an ID (see with_request_id) which is
+/// 1) visible to the query layer (i.e. the slow query log) via utils::current_request_id() while the handler runs,
+/// 2) logged along with the outcome by log_request, and
+/// 3) echoed back in the X-Request-Id response header. Errors become responses via error_response:
+/// ```ignore
+/// let resp = with_request_logging(req, |req| async move { my_handler(req, &client).await }).await;
+/// ```
+pub async fn with_request_logging<F, Fut>(req: Request<Body>, handler: F) -> Response<Body>
+where
+    F: FnOnce(Request<Body>) -> Fut,
+    Fut: Future<Output = Result<Response<Body>, PachyDarn>>,
+{
+    let (req, id) = with_request_id(req);
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let start = Instant::now();
+    let outcome = REQUEST_ID.scope(id.clone(), handler(req)).await;
+    log_request(&outcome, &id, &method, &path, start.elapsed());
+    let mut resp = match outcome {
+        Ok(resp) => resp,
+        Err(e) => error_response(&e),
+    };
+    if let Ok(val) = HeaderValue::from_str(&id.0) {
+        resp.headers_mut().insert(REQUEST_ID_HEADER, val);
+    }
+    resp
+}
+
+
+/// Translate a PachyDarn into a plain text response with the status from PachyDarn::http_status()
+/// and the body from PachyDarn::error_message(), so server errors never leak SQL or connection details
+pub fn error_response(err: &PachyDarn) -> Response<Body> {
+    let status = StatusCode::from_u16(err.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut resp = Response::new(Body::from(client_message(err)));
+    *resp.status_mut() = status;
+    resp
+}
+
+
+/// Read the data_type= and q= query parameters, parse q to PK, and await the switcher.
+/// Errors are translated to responses, so this always returns a Response:
+/// a missing parameter yields 400 naming the parameter and an unknown data_type yields 404
+pub async fn switch_psql_handler<PK: FromStr>(req: &Request<Body>, client: &ClientNoTLS, switcher: Switcher<PK>) -> Response<Body> {
+    let data_type: String = match get_query_param(req, "data_type") {
+        Ok(val) => val,
+        Err(e) => return error_response(&e),
+    };
+    let pk: PK = match get_query_param(req, "q") {
+        Ok(val) => val,
+        Err(e) => return error_response(&e),
+    };
+    match switcher(&data_type, &pk, client).await {
+        Ok(resp) => resp,
+        Err(e) => error_response(&e),
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::testing::TestDb;
+    #[cfg(feature = "redis")]
+    use crate::testing::{TestRedis, DEMO_SCHEMA_SQL};
+    use super::*;
+
+    fn echo_switcher<'a>(data_type: &'a str, q: &'a String, _client: &'a ClientNoTLS) -> SwitchFuture<'a> {
+        Box::pin(async move {
+            match data_type {
+                "echo" => build_response_json(q),
+                _ => Err(unknown_data_type(data_type)),
+            }
+        })
+    }
+
+    async fn call(uri: &str) -> (StatusCode, String) {
+        let db = TestDb::new("").await.unwrap();
+        let client = db.client().await.unwrap();
+        let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let resp = switch_psql_handler(&req, &client, echo_switcher).await;
+        let status = resp.status();
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[derive(serde::Deserialize, Serialize)]
+    struct NewAnimal {
+        name: String,
+    }
+
+    fn json_request(content_type: &str, body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .uri("http://localhost/animals")
+            .header(header::CONTENT_TYPE, content_type)
+            .header("x-zoo", "bronx")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[test]
+    fn read_json_body_checks() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // happy path: the value is parsed and the headers are still accessible 
+            let req = json_request("application/json; charset=utf-8", r#"{"name": "emu"}"#);
+            let (animal, parts) = read_json_body::<NewAnimal>(req, 1024).await.unwrap();
+            assert_eq!(animal.name, "emu");
+            assert_eq!(parts.headers.get("x-zoo").unwrap(), "bronx");
+            // wrong content type
+            let req = json_request("text/plain", r#"{"name": "emu"}"#);
+            let err = read_json_body::<NewAnimal>(req, 1024).await.err().unwrap();
+            assert_eq!(err.http_status(), 415);
+            // oversize
+            let req = json_request("application/json", r#"{"name": "a very long name for an emu"}"#);
+            let err = read_json_body::<NewAnimal>(req, 8).await.err().unwrap();
+            assert_eq!(err.http_status(), 413);
+            // malformed
+            let req = json_request("application/json", r#"{"name": "#);
+            let err = read_json_body::<NewAnimal>(req, 1024).await.err().unwrap();
+            assert_eq!(err.http_status(), 422);
+            assert!(err.to_string().contains("EOF"));
+            // and the companion 201 response
+            let resp = write_json_created(&NewAnimal{name: "emu".to_string()});
+            assert_eq!(resp.status(), StatusCode::CREATED);
+        })
+    }
+
+    fn page_params(uri: &str) -> Result<PageParams, PachyDarn> {
+        let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        PageParams::from_request(&req, PageDefaults{limit: 10, max_limit: 50, sort: Some("name"), desc: false, sort_allowlist: &["name", "id"]})
+    }
+
+    #[test]
+    fn page_params_defaults_and_clamping() {
+        let page = page_params("http://localhost/animals").unwrap();
+        assert_eq!(page, PageParams{limit: 10, offset: 0, sort: Some("name".to_string()), desc: false});
+        assert_eq!(page.to_sql_suffix(), "ORDER BY \"name\" ASC LIMIT 10 OFFSET 0");
+        let page = page_params("http://localhost/animals?limit=500&offset=20&sort=id&dir=DESC").unwrap();
+        assert_eq!(page, PageParams{limit: 50, offset: 20, sort: Some("id".to_string()), desc: true});
+        assert_eq!(page.to_sql_suffix_bound(2), "ORDER BY \"id\" DESC LIMIT $2 OFFSET $3");
+    }
+
+    #[test]
+    fn page_params_rejections() {
+        for uri in [
+            "http://localhost/animals?limit=-1",
+            "http://localhost/animals?offset=99999999999",
+            "http://localhost/animals?dir=sideways",
+            "http://localhost/animals?sort=name;DROP%20TABLE%20animals",
+        ] {
+            assert_eq!(page_params(uri).unwrap_err().http_status(), 400, "{}", uri);
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    struct AnimalHit;
+
+    #[cfg(feature = "redis")]
+    impl crate::autocomplete::AutoComp<i32> for AnimalHit {
+        fn query_autocomp() -> &'static str {
+            "SELECT id, name FROM animals WHERE autocomp_tsv @@ to_tsquery('simple', $1) ORDER BY name LIKE $2 || '%' DESC, name LIMIT 5;"
+        }
+        fn rowfunc_autocomp<R: crate::client::RowLike>(row: &R) -> crate::autocomplete::WhoWhatWhere<i32> {
+            crate::autocomplete::WhoWhatWhere::new("animal", row.get(0), row.get(1))
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    impl CachedAutoComp<i32> for AnimalHit {
+        // the cache keys are named after the dtype, so it is unique to the process rather than shared by test runs
+        fn dtype() -> &'static str {
+            static NAME: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+            NAME.get_or_init(|| format!("test_handler_animal_{}", std::process::id()))
+        }
+        fn seconds_expiry() -> usize {
+            90
+        }
+        fn prewarm_depth() -> crate::redis::PreWarmDepth {
+            crate::redis::PreWarmDepth::Char1
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn cached_autocomp_handler_statuses() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(DEMO_SCHEMA_SQL).await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let registry = AutocompRegistry::new().register::<i32, AnimalHit>("animal");
+            let req = Request::builder().uri("http://localhost/autocomp?data_type=animal&q=fi").body(Body::empty()).unwrap();
+            let resp = cached_autocomp_handler(&req, &registry, rpool, db.pool()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), "max-age=90");
+            // revalidating with the ETag from the cache yields 304 
+            let etag = resp.headers().get(header::ETAG).unwrap().clone();
+            let req = Request::builder().uri("http://localhost/autocomp?data_type=animal&q=fi")
+                .header(header::IF_NONE_MATCH, etag).body(Body::empty()).unwrap();
+            let resp = cached_autocomp_handler(&req, &registry, rpool, db.pool()).await;
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+            let req = Request::builder().uri("http://localhost/autocomp?data_type=mineral&q=qu").body(Body::empty()).unwrap();
+            let resp = cached_autocomp_handler(&req, &registry, rpool, db.pool()).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+            let _x = crate::redis::rediserde::delete_by_prefix(rpool, &format!("autocomp_{}_", AnimalHit::dtype())).await;
+        })
+    }
+
+    fn if_none_match_headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for val in values {
+            headers.append(header::IF_NONE_MATCH, val.parse().unwrap());
+        }
+        headers
+    }
+
+    async fn etag_call(headers: &HeaderMap) -> (StatusCode, String) {
+        let resp = build_response_json_etag(&vec!["emu", "ibis"], headers).unwrap();
+        assert_eq!(resp.headers().get(header::CACHE_CONTROL).unwrap(), DEFAULT_ETAG_CACHE_CONTROL);
+        let status = resp.status();
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn build_response_json_etag_validators() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let etag = strong_etag(br#"["emu","ibis"]"#);
+            // no validator: the full body
+            let (status, body) = etag_call(&HeaderMap::new()).await;
+            assert_eq!((status, body.as_str()), (StatusCode::OK, r#"["emu","ibis"]"#));
+            // matching validator: 304 with an empty body
+            let (status, body) = etag_call(&if_none_match_headers(&[&etag])).await;
+            assert_eq!((status, body.as_str()), (StatusCode::NOT_MODIFIED, ""));
+            // mismatched validator
+            let (status, _body) = etag_call(&if_none_match_headers(&["\"0000000000000000\""])).await;
+            assert_eq!(status, StatusCode::OK);
+            // weak validators, lists, repeated headers, and * all match 
+            for values in [
+                vec![format!("W/{}", etag)],
+                vec![format!("\"abc\", W/{} ", etag)],
+                vec!["\"abc\"".to_string(), etag.clone()],
+                vec!["*".to_string()],
+            ] {
+                let values: Vec<&str> = values.iter().map(|val| val.as_str()).collect();
+                let (status, _body) = etag_call(&if_none_match_headers(&values)).await;
+                assert_eq!(status, StatusCode::NOT_MODIFIED, "{:?}", values);
+            }
+        })
+    }
+
+    fn cors_policy(origins: &[&str], allow_credentials: bool) -> Result<CorsPolicy, PachyDarn> {
+        let origins = origins.iter().map(|origin| origin.to_string()).collect();
+        CorsPolicy::new(origins, vec![Method::GET, Method::POST], vec!["content-type".to_string()], allow_credentials, Some(Duration::from_secs(600)))
+    }
+
+    fn cors_request(method: Method, origin: &str) -> Request<Body> {
+        Request::builder().method(method).uri("http://localhost/animals").header(header::ORIGIN, origin).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn cors_policy_construction() {
+        assert!(cors_policy(&["*"], false).is_ok());
+        assert!(cors_policy(&["https://app.example.com", "https://*.example.org"], true).is_ok());
+        // credentials + wildcard, and malformed patterns
+        for (origins, allow_credentials) in [(vec!["*"], true), (vec!["https://*example.com"], false), (vec!["https://*.*.example.com"], false)] {
+            assert!(cors_policy(&origins, allow_credentials).is_err(), "{:?}", origins);
+        }
+    }
+
+    #[test]
+    fn cors_policy_origins() {
+        let policy = cors_policy(&["https://app.example.com", "https://*.example.org"], true).unwrap();
+        for origin in ["https://app.example.com", "https://APP.example.com", "https://a.example.org", "https://a.b.example.org"] {
+            let resp = build_response_json_cors(&1, &cors_request(Method::GET, origin), &policy).unwrap();
+            assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), origin);
+            assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+            assert_eq!(resp.headers().get(header::VARY).unwrap(), "Origin");
+        }
+        for origin in ["http://app.example.com", "https://example.org", "https://evil.com", "https://evil.com/.example.org", "https://app.example.com.evil.com", "null"] {
+            let resp = build_response_json_cors(&1, &cors_request(Method::GET, origin), &policy).unwrap();
+            assert!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none(), "{}", origin);
+            assert_eq!(resp.headers().get(header::VARY).unwrap(), "Origin");
+        }
+        let wildcard = cors_policy(&["*"], false).unwrap();
+        let resp = build_response_json_cors(&1, &cors_request(Method::GET, "https://anywhere.io"), &wildcard).unwrap();
+        assert_eq!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+    }
+
+    #[test]
+    fn cors_policy_preflight() {
+        let policy = cors_policy(&["https://app.example.com"], false).unwrap();
+        let resp = policy.preflight(&cors_request(Method::OPTIONS, "https://app.example.com"));
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let headers = resp.headers();
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "GET, POST");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), "content-type");
+        assert_eq!(headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+        // a blocked origin gets none of the CORS headers
+        let resp = policy.preflight(&cors_request(Method::OPTIONS, "https://evil.com"));
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert!(resp.headers().get(header::ACCESS_CONTROL_ALLOW_METHODS).is_none());
+    }
+
+    async fn streamed(items: Vec<Result<i32, PachyDarn>>) -> serde_json::Value {
+        let resp = stream_json_array(stream::iter(items));
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).expect("stream_json_array wrote invalid JSON")
+    }
+
+    #[test]
+    fn stream_json_array_stays_valid() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            assert_eq!(streamed(vec![]).await, serde_json::json!([]));
+            assert_eq!(streamed(vec![Ok(1), Ok(2), Ok(3)]).await, serde_json::json!([1, 2, 3]));
+            // an error halfway through ends the array with the error object, and nothing after it is sent
+            let err = PachyDarn::custom_with_status("lost_connection", "the database went away", 503);
+            let vals = streamed(vec![Ok(1), Ok(2), Err(err), Ok(4)]).await;
+            let vals = vals.as_array().unwrap();
+            assert_eq!(vals.len(), 3);
+            assert_eq!(vals[2][STREAM_ERROR_KEY]["status"], 503);
+            assert_eq!(vals[2][STREAM_ERROR_KEY]["message"], "the database went away");
+            // an error before any element 
+            let vals = streamed(vec![Err(PachyDarn::custom("oops", "first"))]).await;
+            assert!(vals[0][STREAM_ERROR_KEY]["message"].as_str().unwrap().contains("first"));
+        })
+    }
+
+    #[test]
+    fn request_id_round_trip() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let client = db.client().await.unwrap();
+            // borrow the client so the handler is Copy and can be passed to both calls
+            let client = &client;
+            let handler = |req: Request<Body>| async move {
+                // the task-local is visible inside get_vec, i.e. to its rowfunc 
+                let seen = crate::connect::get_vec(client, "SELECT 1", &|_row| crate::utils::current_request_id(), &[]).await?;
+                assert_eq!(seen[0].as_ref().unwrap().0, req.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap());
+                build_response_json(&seen[0].as_ref().map(|id| id.to_string()))
+            };
+            // the client's ID is echoed back
+            let req = Request::builder().uri("http://localhost/animals").header(REQUEST_ID_HEADER, "abc-123").body(Body::empty()).unwrap();
+            let resp = with_request_logging(req, handler).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-123");
+            // otherwise a UUID is generated 
+            let req = Request::builder().uri("http://localhost/animals").body(Body::empty()).unwrap();
+            let resp = with_request_logging(req, handler).await;
+            let id = resp.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap();
+            assert!(uuid::Uuid::parse_str(id).is_ok(), "{}", id);
+            // errors are echoed too
+            let req = Request::builder().uri("http://localhost/animals").header(REQUEST_ID_HEADER, "def-456").body(Body::empty()).unwrap();
+            let resp = with_request_logging(req, |_req| async { Err(unknown_data_type("mineral")) }).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+            assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "def-456");
+            // and outside the scope there is no request ID
+            assert!(crate::utils::current_request_id().is_none());
+        })
+    }
+
+    #[test]
+    fn pg_health_handler_reports_postgres() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = Arc::new(db.pool().clone());
+            let resp = pg_health_handler(pool, Duration::from_secs(2)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let report: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(report["dependencies"].as_array().unwrap().len(), 1);
+            assert_eq!(report["dependencies"][0]["name"], "postgres");
+        })
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn health_handler_names_failing_dependency() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pool = Arc::new(db.pool().clone());
+            let healthy = health_handler(pool.clone(), None, Duration::from_secs(2)).await;
+            assert_eq!(healthy.status(), StatusCode::OK);
+            // nothing is listening on port 1, so this Redis is "down"
+            let client = crate::redis::new_client("redis", "127.0.0.1:1", "").unwrap();
+            let down = Arc::new(mobc::Pool::builder().build(mobc_redis::RedisConnectionManager::new(client)));
+            let resp = health_handler(pool, Some(down), Duration::from_millis(500)).await;
+            assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+            let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let report: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(report["dependencies"][0]["ok"], true);
+            assert_eq!(report["dependencies"][1]["name"], "redis");
+            assert_eq!(report["dependencies"][1]["ok"], false);
+        })
+    }
+
+    #[test]
+    fn switch_psql_handler_statuses() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (status, body) = call("http://localhost/autocomp?data_type=echo&q=fi%20sh").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, "\"fi sh\"");
+            let (status, body) = call("http://localhost/autocomp?data_type=echo").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body, "missing query parameter q");
+            let (status, body) = call("http://localhost/autocomp?q=fish").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body, "missing query parameter data_type");
+            let (status, body) = call("http://localhost/autocomp?data_type=mineral&q=quartz").await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+            assert_eq!(body, "Unknown data type mineral");
+        })
+    }
+
+    #[cfg(feature = "uuid")]
+    fn uuid_switcher<'a>(data_type: &'a str, q: &'a uuid::Uuid, _client: &'a ClientNoTLS) -> SwitchFuture<'a> {
+        Box::pin(async move {
+            match data_type {
+                "gadget" => build_response_json(q),
+                _ => Err(unknown_data_type(data_type)),
+            }
+        })
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuid_params() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let client = db.client().await.unwrap();
+            // Uuid's FromStr accepts uppercase, and the response has the canonical form
+            let req = Request::builder().uri("http://localhost/autocomp?data_type=gadget&q=67E55044-10B1-426F-9247-BB680E5FE0C8").body(Body::empty()).unwrap();
+            let resp = switch_psql_handler::<uuid::Uuid>(&req, &client, uuid_switcher).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            assert_eq!(&bytes[..], b"\"67e55044-10b1-426f-9247-bb680e5fe0c8\"");
+            let req = Request::builder().uri("http://localhost/autocomp?data_type=gadget&q=not-a-uuid").body(Body::empty()).unwrap();
+            let resp = switch_psql_handler::<uuid::Uuid>(&req, &client, uuid_switcher).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        });
+        let req = Request::builder().uri("http://localhost/gadgets/67e55044-10b1-426f-9247-bb680e5fe0c8/").body(Body::empty()).unwrap();
+        let id: uuid::Uuid = get_path_param(&req, 1).unwrap();
+        assert_eq!(id.to_string(), "67e55044-10b1-426f-9247-bb680e5fe0c8");
+        assert_eq!(get_path_param::<uuid::Uuid>(&req, 0).unwrap_err().http_status(), 400);
+        assert_eq!(get_path_param::<uuid::Uuid>(&req, 2).unwrap_err().http_status(), 400);
+    }
+}
@@ -2,12 +2,34 @@
 //! The durability provided by Postgres is used in a very wide variety of applications.
 //! The pachydurable library is intended to make using Postgres in the Rust/tokio/hyper ecosystem more ergonomic. 
 
+pub mod analytics;
+pub mod audit;
 pub mod autocomplete;
+#[cfg(feature = "redis")]
 pub mod borg;
+pub mod client;
 pub mod connect;
+pub mod enums;
 pub mod err;
 pub mod fulltext;
+pub mod health;
+#[cfg(feature = "hyper")]
+pub mod http_server;
+#[cfg(feature = "redis")]
+pub mod listen;
+pub mod migrate;
+pub mod plan;
+pub mod preflight;
 pub mod primary_key;
+#[cfg(feature = "redis")]
 pub mod redis;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod utils;
+pub mod validate;
+
+pub use preflight::preflight;
+
+#[cfg(feature = "chrono")]
+pub use chrono;
 
@@ -0,0 +1,230 @@
+//! The listen module keeps Redis caches fresh when Postgres data changes, instead of waiting for them to expire.
+//! A trigger (see invalidation_trigger_sql) NOTIFYs a channel with the cache key of each changed row,
+//! and a ListenInvalidator LISTENs on the channel and deletes those keys from Redis:
+//! ```ignore
+//! client.batch_execute(&invalidation_trigger_sql::<Animal>("animals", "id", "cache_invalidation")).await?;
+//! let invalidator = ListenInvalidator::new(&SimpleConfig::try_new_from_env()?, rpool.clone(), "cache_invalidation");
+//! tokio::spawn(async move { invalidator.run().await });
+//! ```
+//! The next cached_or_cache::<Animal> for a changed row then misses and reads the new row from Postgres.
+//! Notifications sent while the invalidator isn't connected are lost, so keep a seconds_expiry() as a backstop
+//! The same invalidator clears the phrases a prefix_monotone CachedAutoComp had no hits for, with a trigger from empty_prefixes_trigger_sql
+
+use futures_util::{stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_postgres::{AsyncMessage, Config, NoTls};
+use tokio_util::sync::CancellationToken;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::{connect::{pg_config_from, quote_ident, SimpleConfig}, err::PachyDarn, redis::{empty_prefixes_key, rediserde, Cacheable, CachedAutoComp, RedisPool}, utils::pachy_log};
+
+
+/// The keys a ListenInvalidator deletes start with one of these: Cacheable keys, and the sets of empty autocomplete prefixes
+pub const INVALIDATABLE_PREFIXES: &[&str] = &["cacheable_", "autocomp_empty_"];
+
+/// Deletes the Redis keys NOTIFYd on a Postgres channel. See the module documentation
+pub struct ListenInvalidator {
+    pg_config: Config,
+    redis_pool: RedisPool,
+    channel: String,
+}
+
+impl ListenInvalidator {
+    /// Notifications are only delivered to the connection that LISTENs, and a pooled connection doesn't surface them,
+    /// so the invalidator opens its own connection with the pg_config (i.e. the one the pool was created from)
+    pub fn new(pg_config: &SimpleConfig, redis_pool: RedisPool, channel: &str) -> Self {
+        ListenInvalidator{pg_config: pg_config_from(pg_config), redis_pool, channel: channel.to_string()}
+    }
+
+    /// LISTEN on the channel and delete each key NOTIFYd on it. This only returns on an error,
+    /// i.e. the connection to Postgres was lost, so the caller can reconnect by calling run again
+    pub async fn run(&self) -> Result<u64, PachyDarn> {
+        self.run_cancellable(CancellationToken::new()).await
+    }
+
+    /// Like run, but it stops when the token is cancelled, returning how many keys were deleted
+    pub async fn run_cancellable(&self, token: CancellationToken) -> Result<u64, PachyDarn> {
+        let (client, mut connection) = self.pg_config.connect(NoTls).await?;
+        // the connection has to be polled for notifications to arrive, so forward them to a channel
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut messages = Box::pin(stream::poll_fn(move |cx| connection.poll_message(cx)));
+        let forward = tokio::spawn(async move {
+            while let Some(message) = messages.next().await {
+                let closed = message.is_err();
+                if tx.send(message).is_err() || closed {
+                    break
+                }
+            }
+        });
+        client.batch_execute(&format!("LISTEN {};", quote_ident(&self.channel))).await?;
+        pachy_log!(info, "pachydurable::listen", "listening for cache invalidations on {}", self.channel);
+        let mut invalidated = 0;
+        let result = loop {
+            let message = tokio::select! {
+                _ = token.cancelled() => break Ok(invalidated),
+                message = rx.recv() => message,
+            };
+            let notification = match message {
+                Some(Ok(AsyncMessage::Notification(notification))) => notification,
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => break Err(e.into()),
+                None => break Err(PachyDarn::custom("listen_connection_closed", format!("the connection listening on {} was closed", self.channel))),
+            };
+            if notification.channel() != self.channel {
+                continue
+            }
+            let key = notification.payload();
+            // only cache keys can be deleted, so a stray NOTIFY can't remove anything else
+            if !INVALIDATABLE_PREFIXES.iter().any(|prefix| key.starts_with(prefix)) {
+                pachy_log!(warn, "pachydurable::listen", "ignoring notification on {} that isn't a cache key: {}", self.channel, key);
+                continue
+            }
+            match rediserde::del(&self.redis_pool, key).await {
+                Ok(()) => invalidated += 1,
+                Err(e) => pachy_log!(warn, "pachydurable::listen", "failed to invalidate {}: {}", key, e),
+            }
+        };
+        forward.abort();
+        result
+    }
+}
+
+
+/// The SQL for a trigger on the table that NOTIFYs the channel with T's cache key for each inserted, updated or deleted row,
+/// i.e. cacheable_animal_3 for a change to the animals row with id 3. This assumes T is cached by the pk_column alone,
+/// i.e. cached_or_cache::<T>(&client, &rpool, &[&id]). If the pk_column of a row changes, both keys are sent
+pub fn invalidation_trigger_sql<T: Cacheable>(table: &str, pk_column: &str, channel: &str) -> String {
+    let prefix = format!("{}_", T::redis_key(&[]));
+    let name: String = table.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+    let function = quote_ident(&format!("pachy_invalidate_{}", name));
+    let (table, pk, channel, prefix) = (quote_ident(table), quote_ident(pk_column), literal(channel), literal(&prefix));
+    format!("CREATE OR REPLACE FUNCTION {function}() RETURNS trigger AS $pachy$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        PERFORM pg_notify({channel}, {prefix} || OLD.{pk}::text);
+    END IF;
+    IF TG_OP = 'INSERT' OR (TG_OP = 'UPDATE' AND NEW.{pk} IS DISTINCT FROM OLD.{pk}) THEN
+        PERFORM pg_notify({channel}, {prefix} || NEW.{pk}::text);
+    END IF;
+    RETURN NULL;
+END;
+$pachy$ LANGUAGE plpgsql;
+DROP TRIGGER IF EXISTS {function} ON {table};
+CREATE TRIGGER {function} AFTER INSERT OR UPDATE OR DELETE ON {table} FOR EACH ROW EXECUTE FUNCTION {function}();")
+}
+
+/// The SQL for a trigger on the table that NOTIFYs the channel with T's empty_prefixes_key after rows are inserted or updated,
+/// so a ListenInvalidator clears the phrases known to have no hits (see CachedAutoComp::prefix_monotone) once new rows might match them.
+/// The key is computed when the SQL is generated, so for a T with a cache_namespace() it only clears that namespace
+pub fn empty_prefixes_trigger_sql<PKC: Serialize + DeserializeOwned + Send, T: CachedAutoComp<PKC>>(table: &str, channel: &str) -> String {
+    let name: String = table.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+    let function = quote_ident(&format!("pachy_empty_prefixes_{}", name));
+    let (table, channel, key) = (quote_ident(table), literal(channel), literal(&empty_prefixes_key::<PKC, T>()));
+    format!("CREATE OR REPLACE FUNCTION {function}() RETURNS trigger AS $pachy$
+BEGIN
+    PERFORM pg_notify({channel}, {key});
+    RETURN NULL;
+END;
+$pachy$ LANGUAGE plpgsql;
+DROP TRIGGER IF EXISTS {function} ON {table};
+CREATE TRIGGER {function} AFTER INSERT OR UPDATE ON {table} FOR EACH STATEMENT EXECUTE FUNCTION {function}();")
+}
+
+// a SQL string literal
+fn literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use serde::{Serialize, Deserialize};
+    use tokio::runtime::Runtime;
+    use crate::{client::RowLike, redis::cached_or_cache_f, testing::{TestDb, TestRedis}};
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Plant {
+        id: i32,
+        name: String,
+    }
+
+    impl Cacheable for Plant {
+        fn key_prefix() -> &'static str { "plant" }
+        fn seconds_expiry() -> usize { 60 }
+        fn query() -> &'static str { "SELECT id, name FROM plants WHERE id = $1" }
+        fn from_row<R: RowLike>(row: &R) -> Self { Plant{id: row.get(0), name: row.get(1)} }
+    }
+
+    #[test]
+    fn trigger_sql() {
+        let sql = invalidation_trigger_sql::<Plant>("garden.plants", "id", "cache_invalidation");
+        assert!(sql.contains("CREATE TRIGGER \"pachy_invalidate_garden_plants\" AFTER INSERT OR UPDATE OR DELETE ON \"garden\".\"plants\""));
+        assert!(sql.contains("pg_notify('cache_invalidation', 'cacheable_plant_' || OLD.\"id\"::text)"));
+    }
+
+    struct PlantAutoComp;
+
+    impl crate::autocomplete::AutoComp<i32> for PlantAutoComp {
+        fn query_autocomp() -> &'static str { "SELECT id, name FROM plants WHERE name LIKE $2 || '%'" }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> crate::autocomplete::WhoWhatWhere<i32> {
+            crate::autocomplete::WhoWhatWhere::new("plant", row.get(0), row.get(1))
+        }
+    }
+
+    impl CachedAutoComp<i32> for PlantAutoComp {
+        fn dtype() -> &'static str { "plant" }
+        fn seconds_expiry() -> usize { 60 }
+        fn prewarm_depth() -> crate::redis::PreWarmDepth { crate::redis::PreWarmDepth::Char1 }
+        fn prefix_monotone() -> bool { true }
+    }
+
+    #[test]
+    fn empty_prefixes_trigger() {
+        let sql = empty_prefixes_trigger_sql::<i32, PlantAutoComp>("plants", "cache_invalidation");
+        assert!(sql.contains("PERFORM pg_notify('cache_invalidation', 'autocomp_empty_plant');"));
+        assert!(sql.contains("AFTER INSERT OR UPDATE ON \"plants\" FOR EACH STATEMENT"));
+        assert!(INVALIDATABLE_PREFIXES.iter().any(|prefix| empty_prefixes_key::<i32, PlantAutoComp>().starts_with(prefix)));
+    }
+
+    #[test]
+    fn changed_rows_are_invalidated() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let channel = test_redis.key("invalidation");
+            let db = TestDb::new("CREATE TABLE plants (id INT PRIMARY KEY, name VARCHAR NOT NULL);
+                INSERT INTO plants (id, name) VALUES (1, 'fern');").await.unwrap();
+            let client = db.client().await.unwrap();
+            client.batch_execute(&invalidation_trigger_sql::<Plant>("plants", "id", &channel)).await.unwrap();
+            let rpool = test_redis.pool().clone();
+            let key = Plant::redis_key(&[&1i32]);
+            let _x = rediserde::del(&rpool, &key).await;
+            let plant: Plant = cached_or_cache_f(&client, &rpool, &[&1i32]).await.unwrap();
+            assert_eq!(plant.name, "fern");
+            let invalidator = ListenInvalidator::new(&SimpleConfig::try_new_from_env().unwrap(), rpool.clone(), &channel);
+            let token = CancellationToken::new();
+            let task = tokio::spawn({
+                let token = token.clone();
+                async move { invalidator.run_cancellable(token).await }
+            });
+            // the invalidator may not be listening yet, so keep updating until the key is gone
+            let mut deleted = false;
+            for _ in 0..50 {
+                client.execute("UPDATE plants SET name = 'bracken' WHERE id = 1", &[]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                if rediserde::get::<Plant>(&rpool, &key).await.unwrap().is_none() {
+                    deleted = true;
+                    break
+                }
+            }
+            assert!(deleted);
+            let plant: Plant = cached_or_cache_f(&client, &rpool, &[&1i32]).await.unwrap();
+            assert_eq!(plant.name, "bracken");
+            token.cancel();
+            assert!(task.await.unwrap().unwrap() >= 1);
+            let _x = rediserde::del(&rpool, &key).await;
+        })
+    }
+}
@@ -0,0 +1,248 @@
+//! The migrate module creates and evolves the tables the AutoComp and FullText traits query.
+//! Each Migration has a version, and run_migrations applies the ones the database hasn't seen yet, in order,
+//! each in its own transaction. Applied versions are recorded in the _pachy_migrations table along with a
+//! checksum of their SQL, so editing a migration that has already run is detected and refused:
+//! ```ignore
+//! const MIGRATIONS: &[Migration] = &[
+//!     Migration{version: 1, name: "animals", up_sql: "CREATE TABLE animals (id SERIAL PRIMARY KEY, name VARCHAR NOT NULL);"},
+//!     Migration{version: 2, name: "animals_autocomp", up_sql: r#"
+//!         ALTER TABLE "animals" ADD COLUMN IF NOT EXISTS "autocomp_tsv" tsvector GENERATED ALWAYS AS (to_tsvector('simple', coalesce("name", ''))) STORED;
+//!         CREATE INDEX IF NOT EXISTS "animals_autocomp_tsv_gin" ON "animals" USING GIN ("autocomp_tsv");"#},
+//! ];
+//! let report = run_migrations(&mut client, MIGRATIONS).await?;
+//! ```
+//! The SQL in migration 2 is what tsv_column_sql and gin_index_sql generate, so the columns are added consistently
+
+use serde::Serialize;
+use crate::{connect::{ClientNoTLS, quote_ident}, err::PachyDarn, utils::{fnv1a_64, pachy_log, DryRun}};
+
+
+/// The table run_migrations records applied migrations in
+pub const MIGRATIONS_TABLE: &str = "_pachy_migrations";
+
+/// One step in the evolution of a schema. Once a migration has been applied, never edit it- add another
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up_sql: &'static str,
+}
+
+impl Migration {
+    /// The checksum recorded when the migration is applied: the FNV-1a hash of up_sql, in hex
+    pub fn checksum(&self) -> String {
+        format!("{:016x}", fnv1a_64(self.up_sql.as_bytes()))
+    }
+}
+
+/// What run_migrations did, by version
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    /// applied by this call
+    pub applied: Vec<i64>,
+    /// already applied before this call
+    pub skipped: Vec<i64>,
+    /// not applied yet, and would be applied by an Execute (only set by a Preview)
+    pub pending: Vec<i64>,
+}
+
+
+// an error for a migration whose recorded checksum doesn't match its SQL
+fn checksum_error(migration: &Migration, recorded: &str) -> PachyDarn {
+    PachyDarn::custom("migration_checksum", format!("migration {} ({}) was modified after it was applied: recorded checksum {}, current checksum {}",
+        migration.version, migration.name, recorded, migration.checksum()))
+}
+
+
+/// Apply the migrations that haven't been applied yet, in order of version. The versions must be unique and ascending.
+/// Before anything is applied, every migration that has already been applied is checked against its recorded checksum, 
+/// and a mismatch is returned as a Custom error of kind migration_checksum.
+/// Each migration runs in its own transaction with the migrations table locked, so concurrent runners (i.e. several
+/// instances starting at once) apply each migration exactly once. If one fails, the earlier ones stay applied
+pub async fn run_migrations(client: &mut ClientNoTLS, migrations: &[Migration]) -> Result<MigrationReport, PachyDarn> {
+    run_migrations_with(client, migrations, DryRun::Execute).await
+}
+
+/// Like run_migrations, but with DryRun::Preview nothing is written (not even the migrations table):
+/// the checksums are verified and the report lists the pending and skipped versions instead.
+/// Another runner may apply migrations in between, so the plan is only what would happen now
+pub async fn run_migrations_with(client: &mut ClientNoTLS, migrations: &[Migration], dry_run: DryRun) -> Result<MigrationReport, PachyDarn> {
+    if let Some(pair) = migrations.windows(2).find(|pair| pair[0].version >= pair[1].version) {
+        return Err(PachyDarn::custom("migration_order", format!("migration versions must be unique and ascending, but {} is followed by {}", pair[0].version, pair[1].version)))
+    }
+    if dry_run == DryRun::Preview {
+        return preview_migrations(client, migrations).await
+    }
+    client.batch_execute(&format!("CREATE TABLE IF NOT EXISTS {} (
+        version BIGINT NOT NULL PRIMARY KEY,
+        name VARCHAR NOT NULL,
+        checksum VARCHAR NOT NULL,
+        applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+    );", MIGRATIONS_TABLE)).await?;
+    let select = format!("SELECT checksum FROM {} WHERE version = $1", MIGRATIONS_TABLE);
+    // refuse to apply anything if a historical migration was modified
+    for migration in migrations {
+        if let Some(row) = client.query_opt(select.as_str(), &[&migration.version]).await? {
+            let recorded: String = row.get(0);
+            if recorded != migration.checksum() {
+                return Err(checksum_error(migration, &recorded))
+            }
+        }
+    }
+    let mut report = MigrationReport::default();
+    for migration in migrations {
+        let tx = client.transaction().await?;
+        tx.batch_execute(&format!("LOCK TABLE {} IN EXCLUSIVE MODE;", MIGRATIONS_TABLE)).await?;
+        // check again now the table is locked, in case another runner got here first
+        if let Some(row) = tx.query_opt(select.as_str(), &[&migration.version]).await? {
+            let recorded: String = row.get(0);
+            if recorded != migration.checksum() {
+                return Err(checksum_error(migration, &recorded))
+            }
+            report.skipped.push(migration.version);
+            continue
+        }
+        tx.batch_execute(migration.up_sql).await?;
+        let insert = format!("INSERT INTO {} (version, name, checksum) VALUES ($1, $2, $3)", MIGRATIONS_TABLE);
+        tx.execute(insert.as_str(), &[&migration.version, &migration.name, &migration.checksum()]).await?;
+        tx.commit().await?;
+        pachy_log!(info, "pachydurable::migrate", "applied migration {} ({})", migration.version, migration.name);
+        report.applied.push(migration.version);
+    }
+    Ok(report)
+}
+
+// the report of a Preview: which migrations are recorded as applied and which are pending, checking the recorded checksums
+async fn preview_migrations(client: &ClientNoTLS, migrations: &[Migration]) -> Result<MigrationReport, PachyDarn> {
+    let exists = client.query_one("SELECT EXISTS (SELECT 1 FROM information_schema.tables WHERE table_name = $1 AND table_schema = ANY(current_schemas(false)))",
+        &[&MIGRATIONS_TABLE]).await?;
+    let mut report = MigrationReport::default();
+    if !exists.get::<_, bool>(0) {
+        report.pending = migrations.iter().map(|migration| migration.version).collect();
+        return Ok(report)
+    }
+    let select = format!("SELECT checksum FROM {} WHERE version = $1", MIGRATIONS_TABLE);
+    for migration in migrations {
+        match client.query_opt(select.as_str(), &[&migration.version]).await? {
+            Some(row) => {
+                let recorded: String = row.get(0);
+                if recorded != migration.checksum() {
+                    return Err(checksum_error(migration, &recorded))
+                }
+                report.skipped.push(migration.version);
+            },
+            None => report.pending.push(migration.version),
+        }
+    }
+    Ok(report)
+}
+
+
+// a SQL string literal, i.e. 'simple'
+fn quote_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// The SQL adding a generated tsvector column like those the AutoComp and FullText docs describe, i.e. for
+/// tsv_column_sql("animals", &["name", "description"], "english", "fulltext_tsv"):
+/// ALTER TABLE "animals" ADD COLUMN IF NOT EXISTS "fulltext_tsv" tsvector GENERATED ALWAYS AS
+/// (to_tsvector('english', coalesce("name", '') || ' ' || coalesce("description", ''))) STORED;
+/// The source columns are coalesced, so a NULL in one of them doesn't make the whole tsvector NULL
+pub fn tsv_column_sql(table: &str, source_cols: &[&str], config: &str, col_name: &str) -> String {
+    let sources: Vec<String> = source_cols.iter().map(|col| format!("coalesce({}, '')", quote_ident(col))).collect();
+    format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} tsvector GENERATED ALWAYS AS (to_tsvector({}, {})) STORED;",
+        quote_ident(table), quote_ident(col_name), quote_literal(config), sources.join(" || ' ' || "))
+}
+
+/// The SQL creating a GIN index on a (tsvector) column, named {table}_{col_name}_gin, i.e. for gin_index_sql("animals", "fulltext_tsv"):
+/// CREATE INDEX IF NOT EXISTS "animals_fulltext_tsv_gin" ON "animals" USING GIN ("fulltext_tsv");
+pub fn gin_index_sql(table: &str, col_name: &str) -> String {
+    // the index lives in the table's schema, so only the table name goes into the index name 
+    let table_name = table.rsplit('.').next().unwrap_or(table);
+    format!("CREATE INDEX IF NOT EXISTS {} ON {} USING GIN ({});",
+        quote_ident(&format!("{}_{}_gin", table_name, col_name)), quote_ident(table), quote_ident(col_name))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::testing::TestDb;
+    use super::*;
+
+    #[test]
+    fn generated_sql() {
+        assert_eq!(tsv_column_sql("animals", &["name", "description"], "english", "fulltext_tsv"),
+            r#"ALTER TABLE "animals" ADD COLUMN IF NOT EXISTS "fulltext_tsv" tsvector GENERATED ALWAYS AS (to_tsvector('english', coalesce("name", '') || ' ' || coalesce("description", ''))) STORED;"#);
+        assert_eq!(gin_index_sql("zoo.animals", "fulltext_tsv"),
+            r#"CREATE INDEX IF NOT EXISTS "animals_fulltext_tsv_gin" ON "zoo"."animals" USING GIN ("fulltext_tsv");"#);
+    }
+
+    // the generated SQL has to be 'static to be used in a Migration
+    fn leak(sql: String) -> &'static str {
+        Box::leak(sql.into_boxed_str())
+    }
+
+    fn animal_migrations() -> Vec<Migration> {
+        vec![
+            Migration{version: 1, name: "animals", up_sql: "CREATE TABLE animals (id SERIAL PRIMARY KEY, name VARCHAR NOT NULL, description VARCHAR);"},
+            Migration{version: 2, name: "animals_fulltext", up_sql: leak(format!("{} {}",
+                tsv_column_sql("animals", &["name", "description"], "english", "fulltext_tsv"), gin_index_sql("animals", "fulltext_tsv")))},
+            Migration{version: 3, name: "animals_seed", up_sql: "INSERT INTO animals (name) VALUES ('emu');"},
+        ]
+    }
+
+    #[test]
+    fn migrations_apply_once_and_detect_tampering() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let mut client = db.client().await.unwrap();
+            let report = run_migrations(&mut client, &animal_migrations()).await.unwrap();
+            assert_eq!(report, MigrationReport{applied: vec![1, 2, 3], skipped: vec![], pending: vec![]});
+            // running them again is a no-op
+            let report = run_migrations(&mut client, &animal_migrations()).await.unwrap();
+            assert_eq!(report, MigrationReport{applied: vec![], skipped: vec![1, 2, 3], pending: vec![]});
+            let row = client.query_one("SELECT COUNT(*) FROM animals WHERE fulltext_tsv @@ to_tsquery('english', 'emu')", &[]).await.unwrap();
+            assert_eq!(row.get::<_, i64>(0), 1);
+            // editing migration 2 is refused, before the new migration 4 is applied
+            let mut tampered = animal_migrations();
+            tampered[1].up_sql = "SELECT 1;";
+            tampered.push(Migration{version: 4, name: "more_seed", up_sql: "INSERT INTO animals (name) VALUES ('kea');"});
+            match run_migrations(&mut client, &tampered).await {
+                Err(PachyDarn::Custom{kind, message, ..}) => {
+                    assert_eq!(kind, "migration_checksum");
+                    assert!(message.contains("migration 2"), "{}", message);
+                },
+                other => panic!("expected a checksum error, got {:?}", other),
+            }
+            let row = client.query_one("SELECT COUNT(*) FROM _pachy_migrations", &[]).await.unwrap();
+            assert_eq!(row.get::<_, i64>(0), 3);
+            // versions out of order are rejected outright
+            let mut shuffled = animal_migrations();
+            shuffled.swap(0, 1);
+            assert!(run_migrations(&mut client, &shuffled).await.is_err());
+        })
+    }
+
+    #[test]
+    fn preview_writes_nothing() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let mut client = db.client().await.unwrap();
+            let tables = "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = current_schema()";
+            let preview = run_migrations_with(&mut client, &animal_migrations(), DryRun::Preview).await.unwrap();
+            assert_eq!(preview, MigrationReport{applied: vec![], skipped: vec![], pending: vec![1, 2, 3]});
+            assert_eq!(client.query_one(tables, &[]).await.unwrap().get::<_, i64>(0), 0);
+            // execute carries out the previewed plan
+            let report = run_migrations_with(&mut client, &animal_migrations()[..2], DryRun::Execute).await.unwrap();
+            assert_eq!(report.applied, vec![1, 2]);
+            let preview = run_migrations_with(&mut client, &animal_migrations(), DryRun::Preview).await.unwrap();
+            assert_eq!(preview, MigrationReport{applied: vec![], skipped: vec![1, 2], pending: vec![3]});
+            assert_eq!(client.query_one("SELECT COUNT(*) FROM animals", &[]).await.unwrap().get::<_, i64>(0), 0);
+            let report = run_migrations(&mut client, &animal_migrations()).await.unwrap();
+            assert_eq!(report.applied, preview.pending);
+        })
+    }
+}
@@ -0,0 +1,295 @@
+//! The plan module EXPLAINs AutoComp and FullText queries to check they use a GIN index, so a migration that drops
+//! or renames the index fails a deployment instead of turning every search into a sequential scan:
+//! ```ignore
+//! let checks = PlanChecks::new()
+//!     .autocomp::<i32, Animal>()
+//!     .fulltext::<Animal>();
+//! // refuse to roll out if any query would scan its table
+//! check_all(&mut client, &checks).await?;
+//! ```
+//! Plans are taken with enable_seqscan off, in a transaction that is rolled back. Otherwise the planner prefers a
+//! sequential scan for small tables (i.e. in staging) whether or not there is an index, so the check would depend on
+//! the data rather than the schema
+
+use std::{any::type_name, error::Error, future::Future, pin::Pin};
+use serde::Serialize;
+use serde_json::Value;
+use tokio_postgres::types::{FromSql, ToSql, Type};
+use crate::{autocomplete::{autocomp_params, AutoComp}, connect::ClientNoTLS, err::{PachyDarn, PachyContext}, utils::pachy_log};
+use crate::{fulltext::{FullText, ts_expression, ts_expression_cfg}, validate::PROBE_PHRASE};
+
+
+/// What the plan of a query says about how it finds rows
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PlanReport {
+    /// The implementing type, i.e. "my_crate::models::Animal"
+    pub type_name: &'static str,
+    /// The trait whose query was EXPLAINed: "autocomp" or "fulltext"
+    pub check: &'static str,
+    /// true if an index or bitmap scan uses a GIN index
+    pub uses_gin_index: bool,
+    /// The GIN indexes scanned, i.e. ["animals_autocomp_tsv_idx"]
+    pub gin_indexes: Vec<String>,
+    /// The tables scanned sequentially
+    pub seq_scans: Vec<String>,
+    /// true if the plan still needs a disabled node, i.e. a sequential scan as there is no usable index
+    pub uses_disabled_node: bool,
+    /// The planner's estimated total cost
+    pub total_cost: f64,
+    /// Why the plan is a problem, empty if it isn't
+    pub warnings: Vec<String>,
+}
+
+
+// the json EXPLAIN (FORMAT JSON) returns, which tokio_postgres can't read as a String
+struct JsonText(String);
+
+impl<'a> FromSql<'a> for JsonText {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        Ok(JsonText(std::str::from_utf8(raw)?.to_string()))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::JSON
+    }
+}
+
+
+// the scans found walking a plan
+#[derive(Default)]
+struct Scans {
+    // (schema, index name)
+    indexes: Vec<(String, String)>,
+    seq_scans: Vec<String>,
+    // nodes marked "Disabled": true (PostgreSQL 18+)
+    disabled: usize,
+}
+
+// collect the index names and sequentially scanned tables of a plan node and its children. A Bitmap Index Scan has
+// no Schema of its own, so it takes the one of the Bitmap Heap Scan above it
+fn walk_plan(node: &Value, schema: Option<&str>, scans: &mut Scans) {
+    let field = |name: &str| node.get(name).and_then(Value::as_str).map(str::to_string);
+    let schema = node.get("Schema").and_then(Value::as_str).or(schema);
+    match field("Node Type").as_deref() {
+        Some("Seq Scan") => scans.seq_scans.extend(field("Relation Name")),
+        Some("Index Scan") | Some("Index Only Scan") | Some("Bitmap Index Scan") => {
+            scans.indexes.extend(field("Index Name").map(|index| (schema.unwrap_or_default().to_string(), index)))
+        },
+        _ => {},
+    }
+    if node.get("Disabled").and_then(Value::as_bool) == Some(true) {
+        scans.disabled += 1;
+    }
+    for child in node.get("Plans").and_then(Value::as_array).into_iter().flatten() {
+        walk_plan(child, schema, scans);
+    }
+}
+
+// whether the plan needs a node its settings disabled. PostgreSQL 18 counts them in "Disabled Nodes" (or marks each
+// one "Disabled") and leaves the cost alone, while earlier versions add 10^10 to the cost of each disabled node
+fn uses_disabled_node(plan: &Value, scans: &Scans) -> bool {
+    if let Some(disabled_nodes) = plan.get("Disabled Nodes").and_then(Value::as_u64) {
+        return disabled_nodes > 0
+    }
+    scans.disabled > 0 || plan["Total Cost"].as_f64().unwrap_or_default() >= 1e10
+}
+
+// EXPLAIN the query with sequential scans disabled and report on the plan
+async fn analyze<T>(client: &mut ClientNoTLS, check: &'static str, query: &str, params: &[&(dyn ToSql + Sync)]) -> Result<PlanReport, PachyDarn> {
+    let context = || format!("failed to EXPLAIN the {} query of {}", check, type_name::<T>());
+    let tx = client.transaction().await?;
+    tx.batch_execute("SET LOCAL enable_seqscan = off").await?;
+    // VERBOSE names the schema of each scanned relation, so the indexes are looked up in the right one
+    let rows = tx.query(&format!("EXPLAIN (FORMAT JSON, VERBOSE) {}", query), params).await.with_context(context)?;
+    let explained: JsonText = rows.get(0).map(|row| row.try_get(0)).transpose()?.ok_or_else(|| PachyDarn::custom("query_plan", "EXPLAIN returned no rows"))?;
+    // the GIN indexes among those scanned
+    let mut scans = Scans::default();
+    let explained: Value = serde_json::from_str(&explained.0)?;
+    let plan = &explained[0]["Plan"];
+    walk_plan(plan, None, &mut scans);
+    let (schemas, indexes): (Vec<String>, Vec<String>) = scans.indexes.iter().cloned().unzip();
+    let gin_rows = tx.query("SELECT c.relname::text FROM pg_class c JOIN pg_am am ON am.oid = c.relam JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE am.amname = 'gin' AND (n.nspname::text, c.relname::text) IN (SELECT * FROM UNNEST($1::text[], $2::text[]))", &[&schemas, &indexes]).await?;
+    tx.rollback().await?;
+    let mut gin_indexes: Vec<String> = gin_rows.iter().map(|row| row.get(0)).collect();
+    gin_indexes.sort();
+    gin_indexes.dedup();
+    let mut warnings: Vec<String> = scans.seq_scans.iter().map(|table| format!("Seq Scan on {}", table)).collect();
+    if gin_indexes.is_empty() {
+        warnings.push("no GIN index is used".to_string());
+    }
+    Ok(PlanReport{
+        type_name: type_name::<T>(),
+        check,
+        uses_gin_index: !gin_indexes.is_empty(),
+        gin_indexes,
+        uses_disabled_node: uses_disabled_node(plan, &scans),
+        seq_scans: scans.seq_scans,
+        total_cost: plan["Total Cost"].as_f64().unwrap_or_default(),
+        warnings,
+    })
+}
+
+
+/// EXPLAIN T::query_autocomp() with the parameters exec_autocomp binds for a short phrase
+pub async fn analyze_query_plan<PK: Serialize + Send, T: AutoComp<PK>>(client: &mut ClientNoTLS) -> Result<PlanReport, PachyDarn> {
+    let (ts_expr, phrase) = (ts_expression_cfg(PROBE_PHRASE, T::ts_config_autocomp()), PROBE_PHRASE);
+    let params = autocomp_params(T::query_autocomp(), &ts_expr, &phrase);
+    analyze::<T>(client, "autocomp", T::query_autocomp(), &params).await
+}
+
+/// EXPLAIN T::query_fulltext() with the ts_expression exec_fulltext binds for a short phrase
+pub async fn analyze_fulltext_plan<T: FullText>(client: &mut ClientNoTLS) -> Result<PlanReport, PachyDarn> {
+    let ts_expr = ts_expression(PROBE_PHRASE);
+    analyze::<T>(client, "fulltext", T::query_fulltext(), &[&ts_expr]).await
+}
+
+
+/// The future a registered plan check returns
+pub type PlanFuture<'a> = Pin<Box<dyn Future<Output = Result<PlanReport, PachyDarn>> + Send + 'a>>;
+
+type PlanFn = Box<dyn for<'a> Fn(&'a mut ClientNoTLS) -> PlanFuture<'a> + Send + Sync>;
+
+/// A registry of queries for check_all to EXPLAIN, like validate::Validators
+#[derive(Default)]
+pub struct PlanChecks {
+    checks: Vec<PlanFn>,
+}
+
+impl PlanChecks {
+    pub fn new() -> Self {
+        PlanChecks::default()
+    }
+
+    /// Register any check, i.e. .check(|c| Box::pin(analyze_fulltext_plan::<Animal>(c)))
+    pub fn check<F>(mut self, f: F) -> Self
+    where F: for<'a> Fn(&'a mut ClientNoTLS) -> PlanFuture<'a> + Send + Sync + 'static {
+        self.checks.push(Box::new(f));
+        self
+    }
+
+    pub fn autocomp<PK: Serialize + Send + 'static, T: AutoComp<PK> + 'static>(self) -> Self {
+        self.check(|c| Box::pin(analyze_query_plan::<PK, T>(c)))
+    }
+
+    pub fn fulltext<T: FullText + 'static>(self) -> Self {
+        self.check(|c| Box::pin(analyze_fulltext_plan::<T>(c)))
+    }
+
+    /// The number of registered checks
+    pub fn len(&self) -> usize {
+        self.checks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checks.is_empty()
+    }
+
+    /// EXPLAIN every registered query, returning a report for each
+    pub async fn run(&self, client: &mut ClientNoTLS) -> Result<Vec<PlanReport>, PachyDarn> {
+        let mut reports = Vec::new();
+        for check in &self.checks {
+            reports.push(check(&mut *client).await?);
+        }
+        Ok(reports)
+    }
+}
+
+
+/// Run every check, logging each warning. If there are any, a Custom error of kind query_plan listing them
+/// is returned, so a deployment can fail with check_all(...).await?. Otherwise the reports are returned
+pub async fn check_all(client: &mut ClientNoTLS, checks: &PlanChecks) -> Result<Vec<PlanReport>, PachyDarn> {
+    let reports = checks.run(client).await?;
+    let mut listed = Vec::new();
+    for report in reports.iter().filter(|report| !report.warnings.is_empty()) {
+        let line = format!("{} ({}): {}", report.type_name, report.check, report.warnings.join(", "));
+        pachy_log!(error, "pachydurable::plan", "{}", line);
+        listed.push(line);
+    }
+    if listed.is_empty() {
+        return Ok(reports)
+    }
+    Err(PachyDarn::custom("query_plan", format!("{} of {} queries don't use a GIN index: {}", listed.len(), checks.len(), listed.join("; "))))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::{client::RowLike, autocomplete::WhoWhatWhere, testing::TestDb};
+    use super::*;
+
+    struct Thing;
+
+    impl AutoComp<i32> for Thing {
+        fn query_autocomp() -> &'static str {
+            "SELECT id, name FROM things WHERE tsv @@ to_tsquery('simple', $1) ORDER BY name"
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            WhoWhatWhere::new("thing", row.get(0), row.get(1))
+        }
+    }
+
+    impl FullText for Thing {
+        fn query_fulltext() -> &'static str {
+            "SELECT id FROM things WHERE tsv @@ to_tsquery('english', $1)"
+        }
+        fn rowfunc_fulltext<R: RowLike>(_row: &R) -> Self {
+            Thing
+        }
+    }
+
+    #[test]
+    fn plans_are_read_by_schema_and_disabled_nodes() {
+        // as PostgreSQL 18 explains a disabled sequential scan: no inflated cost
+        let plan = serde_json::json!({"Node Type": "Seq Scan", "Schema": "public", "Relation Name": "things",
+            "Disabled Nodes": 1, "Disabled": true, "Total Cost": 25.5});
+        let mut scans = Scans::default();
+        walk_plan(&plan, None, &mut scans);
+        assert_eq!(scans.seq_scans, vec!["things"]);
+        assert!(uses_disabled_node(&plan, &scans));
+        // the bitmap index is in the schema of the table its heap scan reads
+        let plan = serde_json::json!({"Node Type": "Bitmap Heap Scan", "Schema": "tenant_a", "Relation Name": "things",
+            "Disabled Nodes": 0, "Total Cost": 12.0, "Plans": [{"Node Type": "Bitmap Index Scan", "Index Name": "things_tsv_idx"}]});
+        let mut scans = Scans::default();
+        walk_plan(&plan, None, &mut scans);
+        assert_eq!(scans.indexes, vec![("tenant_a".to_string(), "things_tsv_idx".to_string())]);
+        assert!(!uses_disabled_node(&plan, &scans));
+        // and before 18 only the cost shows it
+        let plan = serde_json::json!({"Node Type": "Seq Scan", "Relation Name": "things", "Total Cost": 10000000025.5});
+        assert!(uses_disabled_node(&plan, &Scans::default()));
+    }
+
+    #[test]
+    fn reports_flip_with_the_gin_index() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE things (id SERIAL PRIMARY KEY, name VARCHAR NOT NULL,
+                tsv tsvector GENERATED ALWAYS AS (to_tsvector('simple', name)) STORED);
+                INSERT INTO things (name) VALUES ('exhibit'), ('example'), ('sprocket');").await.unwrap();
+            let mut client = db.client().await.unwrap();
+            let checks = PlanChecks::new().autocomp::<i32, Thing>().fulltext::<Thing>();
+            let report = analyze_query_plan::<i32, Thing>(&mut client).await.unwrap();
+            assert!(!report.uses_gin_index);
+            assert_eq!(report.seq_scans, vec!["things"]);
+            assert!(report.uses_disabled_node, "{:?}", report);
+            assert_eq!(report.warnings, vec!["Seq Scan on things", "no GIN index is used"]);
+            match check_all(&mut client, &checks).await {
+                Err(PachyDarn::Custom{kind, message, ..}) => {
+                    assert_eq!(kind, "query_plan");
+                    assert!(message.starts_with("2 of 2 queries"), "{}", message);
+                },
+                other => panic!("expected a query_plan error, got {:?}", other),
+            }
+            client.batch_execute("CREATE INDEX things_tsv_idx ON things USING GIN (tsv);").await.unwrap();
+            let report = analyze_fulltext_plan::<Thing>(&mut client).await.unwrap();
+            assert!(report.uses_gin_index);
+            assert_eq!(report.gin_indexes, vec!["things_tsv_idx"]);
+            assert!(report.seq_scans.is_empty() && report.warnings.is_empty(), "{:?}", report);
+            assert!(!report.uses_disabled_node, "{:?}", report);
+            let reports = check_all(&mut client, &checks).await.unwrap();
+            assert!(reports.iter().all(|report| report.uses_gin_index));
+        })
+    }
+}
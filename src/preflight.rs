@@ -0,0 +1,248 @@
+//! The preflight module exercises every subsystem once at startup, so a wrong environment variable fails the
+//! deployment instead of the first real request:
+//! ```ignore
+//! let checks = [
+//!     PreflightCheck::table_exists("public", "animals"),
+//!     PreflightCheck::validators(Validators::new().autocomp::<i32, Animal>()),
+//! ];
+//! let report = pachydurable::preflight(&pg_pool, Some(&redis_pool), &checks).await?;
+//! ```
+//! The built-in checks run SELECT 1, read the server version and the current user's search_path, and (given a
+//! Redis pool) PING Redis and SET, GET and DEL a key of its own. Each result is logged as it finishes
+
+use std::{future::Future, pin::Pin, sync::Arc, time::Instant};
+use serde::Serialize;
+use crate::{connect::{ensure_table_exists, get_scalar, ConnPoolNoTLS}, err::PachyDarn, utils::pachy_log};
+use crate::validate::{validate_all, Validators};
+
+
+/// The Redis pool preflight checks: redis::RedisPool, or without the redis feature a type with no values,
+/// so only None can be passed
+#[cfg(feature = "redis")]
+pub type PreflightRedis = crate::redis::RedisPool;
+
+/// The Redis pool preflight checks: redis::RedisPool, or without the redis feature a type with no values,
+/// so only None can be passed
+#[cfg(not(feature = "redis"))]
+pub enum PreflightRedis {}
+
+/// The future a PreflightCheck returns: a short description of what was found (i.e. the server version) or the problem
+pub type PreflightFuture<'a> = Pin<Box<dyn Future<Output = Result<String, PachyDarn>> + Send + 'a>>;
+
+type PreflightFn = Box<dyn for<'a> Fn(&'a ConnPoolNoTLS) -> PreflightFuture<'a> + Send + Sync>;
+
+/// A check for preflight to run after the built-in ones
+pub struct PreflightCheck {
+    name: String,
+    hard: bool,
+    f: PreflightFn,
+}
+
+impl PreflightCheck {
+    /// A check that fails preflight if it returns an error, i.e.
+    /// PreflightCheck::new("animals_seeded", |pool| Box::pin(async move { ... }))
+    pub fn new<F>(name: impl Into<String>, f: F) -> Self
+    where F: for<'a> Fn(&'a ConnPoolNoTLS) -> PreflightFuture<'a> + Send + Sync + 'static {
+        PreflightCheck{name: name.into(), hard: true, f: Box::new(f)}
+    }
+
+    /// Report a failure of this check without failing preflight, i.e. for an optional feature
+    pub fn soft(mut self) -> Self {
+        self.hard = false;
+        self
+    }
+
+    /// Check the table exists and the user can see it, named table_exists:{schema}.{table}
+    pub fn table_exists(schema: &str, table: &str) -> Self {
+        let (schema, table) = (schema.to_string(), table.to_string());
+        PreflightCheck::new(format!("table_exists:{}.{}", schema, table), move |pool| {
+            let (schema, table) = (schema.clone(), table.clone());
+            Box::pin(async move {
+                let client = pool.get().await?;
+                match ensure_table_exists(&client, &schema, &table).await? {
+                    true => Ok("found".to_string()),
+                    false => Err(PachyDarn::custom("missing_table", format!("there is no table {}.{}", schema, table))),
+                }
+            })
+        })
+    }
+
+    /// Run validate_all with the validators, named validators
+    pub fn validators(validators: Validators) -> Self {
+        let validators = Arc::new(validators);
+        PreflightCheck::new("validators", move |pool| {
+            let validators = validators.clone();
+            Box::pin(async move {
+                let mut client = pool.get().await?;
+                validate_all(&mut client, &validators).await?;
+                Ok(format!("{} checks passed", validators.len()))
+            })
+        })
+    }
+}
+
+
+/// The outcome of one preflight check
+#[derive(Serialize, Debug, Clone)]
+pub struct PreflightResult {
+    /// i.e. postgres_version or table_exists:public.animals
+    pub name: String,
+    pub passed: bool,
+    /// false for a PreflightCheck::soft check, whose failure doesn't fail preflight
+    pub hard: bool,
+    /// what the check found (i.e. the server version) or, if it failed, the error
+    pub detail: String,
+    pub latency_ms: f64,
+}
+
+/// Every check preflight ran, in order
+#[derive(Serialize, Debug, Clone)]
+pub struct PreflightReport {
+    pub results: Vec<PreflightResult>,
+}
+
+impl PreflightReport {
+    /// The results of the checks that failed, hard or soft
+    pub fn failures(&self) -> Vec<&PreflightResult> {
+        self.results.iter().filter(|result| !result.passed).collect()
+    }
+}
+
+
+// time the check and record its result
+async fn run_check(results: &mut Vec<PreflightResult>, name: &str, hard: bool, check: impl Future<Output = Result<String, PachyDarn>>) {
+    let start = Instant::now();
+    let outcome = check.await;
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let (passed, detail) = match outcome {
+        Ok(detail) => (true, detail),
+        Err(e) => (false, e.to_string()),
+    };
+    match passed {
+        true => pachy_log!(info, "pachydurable::preflight", "{} passed in {:.1}ms: {}", name, latency_ms, detail),
+        false => pachy_log!(error, "pachydurable::preflight", "{} failed in {:.1}ms: {}", name, latency_ms, detail),
+    }
+    results.push(PreflightResult{name: name.to_string(), passed, hard, detail, latency_ms});
+}
+
+#[cfg(feature = "redis")]
+pub(crate) async fn redis_ping(pool: &crate::redis::RedisPool) -> Result<String, PachyDarn> {
+    let mut rconn = pool.get().await?;
+    let pong: String = mobc_redis::redis::cmd("PING").query_async(&mut *rconn).await?;
+    Ok(pong)
+}
+
+// SET, GET and DEL a key no one else uses, which catches i.e. a read-only replica or a missing ACL permission
+#[cfg(feature = "redis")]
+async fn redis_round_trip(pool: &crate::redis::RedisPool) -> Result<String, PachyDarn> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use crate::redis::rediserde;
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let key = format!("pachydurable_preflight_{}_{}", std::process::id(), nanos);
+    rediserde::set_ex(pool, &key, &nanos.to_string(), std::time::Duration::from_secs(60)).await?;
+    let read: Option<String> = rediserde::get(pool, &key).await?;
+    rediserde::del(pool, &key).await?;
+    match read == Some(nanos.to_string()) {
+        true => Ok(format!("SET, GET and DEL {}", key)),
+        false => Err(PachyDarn::custom("redis_round_trip", format!("read back {:?} from {}", read, key))),
+    }
+}
+
+#[cfg(feature = "redis")]
+async fn run_redis_checks(results: &mut Vec<PreflightResult>, redis: Option<&PreflightRedis>) {
+    if let Some(pool) = redis {
+        run_check(results, "redis_ping", true, redis_ping(pool)).await;
+        run_check(results, "redis_round_trip", true, redis_round_trip(pool)).await;
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+async fn run_redis_checks(_results: &mut Vec<PreflightResult>, _redis: Option<&PreflightRedis>) {}
+
+
+/// Run the built-in checks and then each of checks, returning a report with every result if none of the hard
+/// checks failed. Otherwise a Custom error of kind preflight lists every failure, not just the first.
+/// The Redis checks only run if a Redis pool is given
+pub async fn preflight(pg: &ConnPoolNoTLS, redis: Option<&PreflightRedis>, checks: &[PreflightCheck]) -> Result<PreflightReport, PachyDarn> {
+    let mut results = Vec::new();
+    run_check(&mut results, "postgres_select_1", true, async {
+        let one: i32 = get_scalar(&pg.get().await?, "SELECT 1", &[]).await?;
+        Ok(one.to_string())
+    }).await;
+    run_check(&mut results, "postgres_version", true, async {
+        get_scalar::<String>(&pg.get().await?, "SHOW server_version", &[]).await
+    }).await;
+    run_check(&mut results, "postgres_search_path", true, async {
+        let client = pg.get().await?;
+        let user: String = get_scalar(&client, "SELECT current_user::text", &[]).await?;
+        let search_path: String = get_scalar(&client, "SHOW search_path", &[]).await?;
+        Ok(format!("user={} search_path={}", user, search_path))
+    }).await;
+    run_redis_checks(&mut results, redis).await;
+    for check in checks {
+        run_check(&mut results, &check.name, check.hard, (check.f)(pg)).await;
+    }
+    let report = PreflightReport{results};
+    let failed: Vec<String> = report.results.iter()
+        .filter(|result| result.hard && !result.passed)
+        .map(|result| format!("{}: {}", result.name, result.detail))
+        .collect();
+    if failed.is_empty() {
+        return Ok(report)
+    }
+    Err(PachyDarn::custom("preflight", format!("{} of {} checks failed: {}", failed.len(), report.results.len(), failed.join("; "))))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::testing::TestDb;
+    use super::*;
+
+    fn failing(name: &str) -> PreflightCheck {
+        PreflightCheck::new(name, |_pool| Box::pin(async move { Err(PachyDarn::custom("seed_data", "the animals table is empty")) }))
+    }
+
+    #[test]
+    fn failures_are_aggregated() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pg = db.pool();
+            #[cfg(feature = "redis")]
+            let test_redis = crate::testing::TestRedis::new().await.unwrap();
+            #[cfg(feature = "redis")]
+            let redis: Option<&PreflightRedis> = Some(test_redis.pool());
+            #[cfg(not(feature = "redis"))]
+            let redis: Option<&PreflightRedis> = None;
+            let passing = PreflightCheck::new("always_passes", |_pool| Box::pin(async move { Ok("fine".to_string()) }));
+            let report = preflight(pg, redis, &[passing, failing("optional_seed").soft()]).await.unwrap();
+            let names: Vec<&str> = report.results.iter().map(|result| result.name.as_str()).collect();
+            assert_eq!(&names[..3], &["postgres_select_1", "postgres_version", "postgres_search_path"]);
+            #[cfg(feature = "redis")]
+            assert_eq!(&names[3..5], &["redis_ping", "redis_round_trip"]);
+            assert_eq!(&names[names.len() - 2..], &["always_passes", "optional_seed"]);
+            // the soft failure is reported without failing preflight
+            let failures = report.failures();
+            assert_eq!(failures.len(), 1);
+            assert!(!failures[0].hard && failures[0].detail.contains("the animals table is empty"));
+            assert!(report.results.iter().all(|result| result.latency_ms >= 0.0));
+            let json = serde_json::to_value(&report).unwrap();
+            assert_eq!(json["results"][0]["passed"], true);
+            // every hard failure is listed, alongside the passing built-ins
+            let checks = [failing("seeded"), PreflightCheck::table_exists("public", "pachy_no_such_table")];
+            match preflight(pg, redis, &checks).await {
+                Err(PachyDarn::Custom{kind, message, ..}) => {
+                    assert_eq!(kind, "preflight");
+                    assert!(message.starts_with("2 of "), "{}", message);
+                    assert!(message.contains("seeded: seed_data: the animals table is empty"), "{}", message);
+                    assert!(message.contains("table_exists:public.pachy_no_such_table"), "{}", message);
+                    assert!(!message.contains("postgres_select_1"), "{}", message);
+                },
+                other => panic!("expected a preflight error, got {:?}", other),
+            }
+        })
+    }
+}
@@ -1,22 +1,491 @@
 // standard library
-use std::marker::Sync;
+use std::{marker::Sync, str::FromStr};
 // crates.io
-use tokio_postgres::{row::Row, types::{ToSql}};
-use crate::{err::{PachyDarn, MissingRowError}, connect::ClientNoTLS};
+use serde::{Serialize, Deserialize};
+use tokio_postgres::types::ToSql;
+use crate::{err::{PachyDarn, PachyContext, MissingRowError}, client::{PachyClient, RowLike}, connect::params_summary};
 
 
 /// the get by PK trait makes it easy to return an instance of a struct given its primary key
 /// See also the redis::Cacheable trait, which is more generic and allows caching 
 pub trait GetByPK {
     fn query_get_by_pk() -> &'static str;       // a query to return the struct
-    fn rowfunc_get_by_pk(row: &Row) -> Self;    // returns the struct
+    fn rowfunc_get_by_pk<R: RowLike>(row: &R) -> Self;    // returns the struct
 }
 
-pub async fn get_by_pk<T: GetByPK>(client: &ClientNoTLS, params: &[&(dyn ToSql+Sync)]) -> Result<T, PachyDarn> {
+/// Fetches many instances in one query, i.e. for redis::hydrate_hits. $1 is an array of PKs:
+/// SELECT id, name, description FROM animals WHERE id = ANY($1)
+/// Its rows are read with rowfunc_get_by_pk
+pub trait GetByPKs: GetByPK {
+    fn query_get_by_pks() -> &'static str;
+}
+
+/// Reads the primary key of a row returned by one of T's queries, i.e. to deduplicate hits with fulltext::exec_fulltext_deduped
+pub trait PkFromRow<PK>: GetByPK {
+    fn pk_from_row<R: RowLike>(row: &R) -> PK;
+}
+
+pub async fn get_by_pk<T: GetByPK>(client: &impl PachyClient, params: &[&(dyn ToSql+Sync)]) -> Result<T, PachyDarn> {
     let query = T::query_get_by_pk();
-    let rows = client.query(query, params).await?;
-    let row = rows.get(0).ok_or(MissingRowError{message:"could not get by PK".to_string()})?;
+    let context = || format!("get_by_pk::<{}> failed", std::any::type_name::<T>());
+    let rows = client.query(query, params).await.with_context(context)?;
+    let row = rows.get(0).ok_or_else(|| MissingRowError::for_query_summary(query, params_summary(params))).with_context(context)?;
     let x = T::rowfunc_get_by_pk(row);
     Ok(x)
 }
 
+
+/// A primary key of one or more columns, i.e. (org_id, slug), passed as the query's params in order.
+/// It is implemented for tuples of up to 4 ToSql values, and for Key2
+pub trait CompositeKey {
+    /// the params for query_get_by_pk() (or Cacheable::query()), one per column
+    fn as_params(&self) -> Vec<&(dyn ToSql + Sync)>;
+    /// each column rendered for a cache key, in the same order as as_params
+    fn cache_parts(&self) -> Vec<String>;
+}
+
+/// Render a param for a cache key (redis::Cacheable::redis_key, CompositeKey::cache_parts): its Debug form without quotes,
+/// so the string "abc" renders as abc and a Uuid as the lowercase hyphenated uuid. With the chrono feature, date/time params are rendered as
+/// RFC 3339 (i.e. 2023-04-05 and 2023-04-05T12:30:00Z) rather than relying on Debug staying stable
+pub fn cache_key_param(param: &(dyn ToSql + Sync)) -> String {
+    #[cfg(feature = "chrono")]
+    if let Some(rendered) = date_time_key_param(param) {
+        return rendered
+    }
+    format!("{:?}", param).replace('"', "")
+}
+
+// Date/time params are recognized by the Postgres type they can be written as, and read back with chrono,
+// so any type with the same wire format (i.e. from the time crate) gets the same key
+#[cfg(feature = "chrono")]
+fn date_time_key_param(param: &(dyn ToSql + Sync)) -> Option<String> {
+    use bytes::BytesMut;
+    use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+    use tokio_postgres::types::{FromSql, IsNull, Type};
+    let encoded = |ty: &Type| {
+        let mut buf = BytesMut::new();
+        match param.to_sql_checked(ty, &mut buf) {
+            Ok(IsNull::No) => Some(buf),
+            _ => None,
+        }
+    };
+    if let Some(buf) = encoded(&Type::TIMESTAMPTZ) {
+        return DateTime::<Utc>::from_sql(&Type::TIMESTAMPTZ, &buf).ok().map(|dt| dt.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+    }
+    if let Some(buf) = encoded(&Type::TIMESTAMP) {
+        return NaiveDateTime::from_sql(&Type::TIMESTAMP, &buf).ok().map(|dt| dt.format("%Y-%m-%dT%H:%M:%S%.f").to_string())
+    }
+    if let Some(buf) = encoded(&Type::DATE) {
+        return NaiveDate::from_sql(&Type::DATE, &buf).ok().map(|d| d.format("%Y-%m-%d").to_string())
+    }
+    None
+}
+
+macro_rules! composite_key_tuple {
+    ($($t:ident . $i:tt),+) => {
+        impl<$($t: ToSql + Sync),+> CompositeKey for ($($t,)+) {
+            fn as_params(&self) -> Vec<&(dyn ToSql + Sync)> {
+                vec![$(&self.$i),+]
+            }
+            fn cache_parts(&self) -> Vec<String> {
+                vec![$(cache_key_param(&self.$i)),+]
+            }
+        }
+    };
+}
+
+composite_key_tuple!(A.0);
+composite_key_tuple!(A.0, B.1);
+composite_key_tuple!(A.0, B.1, C.2);
+composite_key_tuple!(A.0, B.1, C.2, D.3);
+
+/// Like get_by_pk, but the params come from a typed key, i.e. get_by_key::<Promo, _>(&client, &("acme", "summer-sale"))
+pub async fn get_by_key<T: GetByPK, K: CompositeKey>(client: &impl PachyClient, key: &K) -> Result<T, PachyDarn> {
+    get_by_pk(client, &key.as_params()).await
+}
+
+/// A two-column key that can be parsed from a request, i.e. get_query_param::<Key2<String, String>>(&req, "pk")
+/// parses pk=acme/summer-sale as Key2("acme", "summer-sale"). The text is split at the first '/', so only B may contain one.
+/// It serializes as an array, like a tuple
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key2<A, B>(pub A, pub B);
+
+impl<A: FromStr, B: FromStr> FromStr for Key2<A, B> {
+    type Err = PachyDarn;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PachyDarn::custom_with_status("invalid_key", format!("could not parse the key {}, expected a/b", s), 400);
+        let (a, b) = s.split_once('/').ok_or_else(invalid)?;
+        Ok(Key2(a.parse().map_err(|_| invalid())?, b.parse().map_err(|_| invalid())?))
+    }
+}
+
+impl<A: ToSql + Sync, B: ToSql + Sync> CompositeKey for Key2<A, B> {
+    fn as_params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![&self.0, &self.1]
+    }
+    fn cache_parts(&self) -> Vec<String> {
+        vec![cache_key_param(&self.0), cache_key_param(&self.1)]
+    }
+}
+
+
+/// Pagination UIs need a total count before rendering page controls. The CountByFK trait holds the count queries for a type, i.e.
+/// query_count_by_fk: SELECT COUNT(*) FROM posts WHERE user_id = $1
+/// query_count_all: SELECT COUNT(*) FROM posts
+/// The first column of the first row is read as an i64, since COUNT returns a bigint
+pub trait CountByFK {
+    fn query_count_by_fk() -> &'static str;     // counts the rows referencing the foreign key(s) in the params
+    fn query_count_all() -> &'static str;       // counts every row, without params
+}
+
+// read the count from the first column of the first row
+async fn count<T>(client: &impl PachyClient, query: &str, params: &[&(dyn ToSql+Sync)], func: &str) -> Result<i64, PachyDarn> {
+    let context = || format!("{}::<{}> failed", func, std::any::type_name::<T>());
+    let rows = client.query(query, params).await.with_context(context)?;
+    let row = rows.get(0).ok_or(MissingRowError::from_str("the count query returned no rows")).with_context(context)?;
+    row.try_get(0).with_context(context)
+}
+
+pub async fn count_by_fk<T: CountByFK>(client: &impl PachyClient, params: &[&(dyn ToSql+Sync)]) -> Result<i64, PachyDarn> {
+    count::<T>(client, T::query_count_by_fk(), params, "count_by_fk").await
+}
+
+pub async fn count_all<T: CountByFK>(client: &impl PachyClient) -> Result<i64, PachyDarn> {
+    count::<T>(client, T::query_count_all(), &[], "count_all").await
+}
+
+
+
+/// Optimistic concurrency: each row has a version column, and an update only applies if the row still has the version
+/// the item was read with, so two clients editing the same row can't silently overwrite each other. For example:
+/// query_update_versioned: UPDATE posts SET title = $1, version = version + 1 WHERE id = $2 AND version = $3 RETURNING version
+/// query_exists_by_pk: SELECT EXISTS(SELECT 1 FROM posts WHERE id = $1)
+/// The version is read as a bigint (or an int)
+pub trait VersionedUpdate {
+    fn query_update_versioned() -> &'static str;        // updates the row if the version matches, returning the new version
+    fn query_exists_by_pk() -> &'static str;            // tells a row with a stale version apart from one that is gone
+    fn update_params(&self) -> Vec<&(dyn ToSql+Sync)>;  // the params for query_update_versioned, including the pk and version
+    fn pk_params(&self) -> Vec<&(dyn ToSql+Sync)>;      // the params for query_exists_by_pk
+}
+
+/// The result of update_versioned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateVersionOutcome {
+    /// The row was updated and now has this version
+    Updated(i64),
+    /// The row exists but has a different version, i.e. someone else updated it first
+    Conflict,
+    /// There is no row with the pk
+    Missing,
+}
+
+impl UpdateVersionOutcome {
+    /// Ok with the new version if the row was updated, otherwise an error: a Custom error of kind version_conflict
+    /// with status 409 for a Conflict, or a MissingRowError (404) if the row is Missing
+    pub fn into_result(self) -> Result<i64, PachyDarn> {
+        match self {
+            UpdateVersionOutcome::Updated(version) => Ok(version),
+            UpdateVersionOutcome::Conflict => Err(PachyDarn::custom_with_status("version_conflict", "the row was modified by someone else, reload it and try again", 409)),
+            UpdateVersionOutcome::Missing => Err(MissingRowError::from_str("the row to update no longer exists").into()),
+        }
+    }
+}
+
+/// Update the row if it still has the item's version. See the VersionedUpdate trait
+pub async fn update_versioned<T: VersionedUpdate>(client: &impl PachyClient, item: &T) -> Result<UpdateVersionOutcome, PachyDarn> {
+    let context = || format!("update_versioned::<{}> failed", std::any::type_name::<T>());
+    let rows = client.query(T::query_update_versioned(), &item.update_params()).await.with_context(context)?;
+    if let Some(row) = rows.get(0) {
+        let version = row.try_get::<_, i64>(0).or_else(|_| row.try_get::<_, i32>(0).map(i64::from)).with_context(context)?;
+        return Ok(UpdateVersionOutcome::Updated(version))
+    }
+    let rows = client.query(T::query_exists_by_pk(), &item.pk_params()).await.with_context(context)?;
+    let exists: bool = rows.get(0).map(|row| row.try_get(0)).transpose().with_context(context)?.unwrap_or(false);
+    Ok(if exists { UpdateVersionOutcome::Conflict } else { UpdateVersionOutcome::Missing })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use tokio_postgres::types::Type;
+    use crate::testing::{MockClient, MockRow, TestDb};
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Food {
+        name: String,
+        color: Option<String>,
+    }
+
+    impl GetByPK for Food {
+        fn query_get_by_pk() -> &'static str {
+            "SELECT name, color FROM foods WHERE name = $1"
+        }
+        fn rowfunc_get_by_pk<R: RowLike>(row: &R) -> Self {
+            Food{name: row.get("name"), color: row.get("color")}
+        }
+    }
+
+    #[test]
+    fn get_by_pk_with_mock_client() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = MockClient::new()
+                .with_rows(vec![MockRow::new().with("name", Type::TEXT, &"kiwi").with("color", Type::TEXT, &None::<String>)])
+                .with_rows(vec![]);
+            let kiwi: Food = get_by_pk(&client, &[&"kiwi"]).await.unwrap();
+            assert_eq!(kiwi, Food{name: "kiwi".to_string(), color: None});
+            let res: Result<Food, PachyDarn> = get_by_pk(&client, &[&"durian"]).await;
+            match res.unwrap_err().root() {
+                PachyDarn::MissingRow(mre) => {
+                    assert_eq!(mre.table.as_deref(), Some("foods"));
+                    assert_eq!(mre.params_debug.as_deref(), Some("[\"durian\"]"));
+                },
+                other => panic!("expected MissingRow, got {:?}", other),
+            }
+        })
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn get_by_uuid_pk() {
+        use uuid::Uuid;
+        #[derive(Debug, PartialEq)]
+        struct Gadget {
+            id: Uuid,
+            name: String,
+        }
+        impl GetByPK for Gadget {
+            fn query_get_by_pk() -> &'static str {
+                "SELECT id, name FROM gadgets WHERE id = $1"
+            }
+            fn rowfunc_get_by_pk<R: RowLike>(row: &R) -> Self {
+                Gadget{id: row.get("id"), name: row.get("name")}
+            }
+        }
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let id = Uuid::new_v4();
+            let client = MockClient::new().with_rows(vec![MockRow::new().with("id", Type::UUID, &id).with("name", Type::TEXT, &"sprocket")]);
+            let gadget: Gadget = get_by_pk(&client, &[&id]).await.unwrap();
+            assert_eq!(gadget, Gadget{id, name: "sprocket".to_string()});
+            assert_eq!(client.calls()[0].params, vec![id.to_string()]);
+        })
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Promo {
+        org_id: String,
+        slug: String,
+    }
+
+    impl GetByPK for Promo {
+        fn query_get_by_pk() -> &'static str {
+            "SELECT org_id, slug FROM promos WHERE org_id = $1 AND slug = $2"
+        }
+        fn rowfunc_get_by_pk<R: RowLike>(row: &R) -> Self {
+            Promo{org_id: row.get("org_id"), slug: row.get("slug")}
+        }
+    }
+
+    #[test]
+    fn composite_keys() {
+        let key: Key2<String, String> = "acme/summer-sale".parse().unwrap();
+        assert_eq!(key, Key2("acme".to_string(), "summer-sale".to_string()));
+        assert_eq!(serde_json::to_string(&key).unwrap(), r#"["acme","summer-sale"]"#);
+        assert_eq!(key.cache_parts(), ("acme", "summer-sale").cache_parts());
+        let err = "acme".parse::<Key2<String, String>>().unwrap_err();
+        assert_eq!(err.http_status(), 400);
+        assert!("acme/x".parse::<Key2<String, i32>>().is_err());
+        assert_eq!((7i32, "a", 2.5f64, true).cache_parts(), vec!["7", "a", "2.5", "true"]);
+        // the parts render like the params of a single-column key
+        #[cfg(feature = "chrono")]
+        {
+            let day = chrono::NaiveDate::from_ymd_opt(2023, 4, 5).unwrap();
+            assert_eq!((day, 7i32).cache_parts(), vec!["2023-04-05", "7"]);
+        }
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = MockClient::new()
+                .with_rows(vec![MockRow::new().with("org_id", Type::TEXT, &"acme").with("slug", Type::TEXT, &"summer-sale")]);
+            let promo: Promo = get_by_key(&client, &key).await.unwrap();
+            assert_eq!(promo, Promo{org_id: "acme".to_string(), slug: "summer-sale".to_string()});
+            assert_eq!(client.calls()[0].params, vec!["\"acme\"", "\"summer-sale\""]);
+        })
+    }
+
+    impl CountByFK for Food {
+        fn query_count_by_fk() -> &'static str {
+            "SELECT COUNT(*) FROM foods WHERE color = $1"
+        }
+        fn query_count_all() -> &'static str {
+            "SELECT COUNT(*) FROM foods"
+        }
+    }
+
+    #[test]
+    fn counts_with_mock_client() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = MockClient::new()
+                .with_rows(vec![MockRow::new().with("count", Type::INT8, &2i64)])
+                .with_rows(vec![MockRow::new().with("count", Type::INT8, &5i64)])
+                .with_rows(vec![MockRow::new().with("count", Type::INT4, &5i32)]);
+            assert_eq!(count_by_fk::<Food>(&client, &[&"red"]).await.unwrap(), 2);
+            assert_eq!(count_all::<Food>(&client).await.unwrap(), 5);
+            assert_eq!(client.calls()[1].sql, "SELECT COUNT(*) FROM foods");
+            // an int4 isn't a bigint
+            assert!(count_all::<Food>(&client).await.is_err());
+        })
+    }
+
+    struct Post {
+        id: i32,
+        title: String,
+        version: i64,
+    }
+
+    impl VersionedUpdate for Post {
+        fn query_update_versioned() -> &'static str {
+            "UPDATE posts SET title = $1, version = version + 1 WHERE id = $2 AND version = $3 RETURNING version"
+        }
+        fn query_exists_by_pk() -> &'static str {
+            "SELECT EXISTS(SELECT 1 FROM posts WHERE id = $1)"
+        }
+        fn update_params(&self) -> Vec<&(dyn ToSql+Sync)> {
+            vec![&self.title, &self.id, &self.version]
+        }
+        fn pk_params(&self) -> Vec<&(dyn ToSql+Sync)> {
+            vec![&self.id]
+        }
+    }
+
+    #[test]
+    fn lost_updates_conflict() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE posts (id INT PRIMARY KEY, title VARCHAR NOT NULL, version INT8 NOT NULL DEFAULT 1);
+                INSERT INTO posts (id, title) VALUES (1, 'draft');").await.unwrap();
+            let client = db.client().await.unwrap();
+            // two admins both read version 1
+            let first = Post{id: 1, title: "first edit".to_string(), version: 1};
+            let second = Post{id: 1, title: "second edit".to_string(), version: 1};
+            assert_eq!(update_versioned(&client, &first).await.unwrap(), UpdateVersionOutcome::Updated(2));
+            let outcome = update_versioned(&client, &second).await.unwrap();
+            assert_eq!(outcome, UpdateVersionOutcome::Conflict);
+            assert_eq!(outcome.into_result().unwrap_err().http_status(), 409);
+            let rows = client.query("SELECT title FROM posts WHERE id = 1", &[]).await.unwrap();
+            assert_eq!(rows[0].get::<_, String>(0), "first edit");
+            let gone = Post{id: 2, title: "nope".to_string(), version: 1};
+            let outcome = update_versioned(&client, &gone).await.unwrap();
+            assert_eq!(outcome, UpdateVersionOutcome::Missing);
+            assert_eq!(outcome.into_result().unwrap_err().http_status(), 404);
+        })
+    }
+}
+
+
+/// Mixing i32 PKs from different tables is a common source of bugs (i.e. passing a user_id where a post_id is
+/// expected). The TypedPK newtype wraps the inner PK with a compile-time discriminant N, so TypedPK<i32, 1>
+/// and TypedPK<i32, 2> are distinct types that can't be confused. Use the typed_pk! macro to name them:
+/// ```ignore
+/// typed_pk!(pub UserId: i32 = 1, pub PostId: i32 = 2);
+/// 
+/// fn delete_post(post_id: PostId) { ... }
+/// 
+/// let user_id = UserId::new(7);
+/// delete_post(user_id); // does not compile- a UserId is not a PostId
+/// ```
+/// ToSql, FromSql, Serialize, and Deserialize all delegate to the inner type,
+/// so a TypedPK can be passed as a query parameter or read from a row like the inner type. 
+pub mod typed {
+    use std::{error::Error, fmt, hash::Hash};
+    use bytes::BytesMut;
+    use serde::{Serialize, Deserialize};
+    use tokio_postgres::types::{to_sql_checked, FromSql, IsNull, ToSql, Type};
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    #[serde(transparent)]
+    pub struct TypedPK<T, const N: u64>(pub T);
+
+    impl<T, const N: u64> TypedPK<T, N> {
+        pub fn new(inner: T) -> Self {
+            TypedPK(inner)
+        }
+
+        /// return a reference to the inner PK
+        pub fn inner(&self) -> &T {
+            &self.0
+        }
+
+        /// consume the TypedPK and return the inner PK
+        pub fn into_inner(self) -> T {
+            self.0
+        }
+    }
+
+    impl<T: fmt::Display, const N: u64> fmt::Display for TypedPK<T, N> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    impl<T: ToSql, const N: u64> ToSql for TypedPK<T, N> {
+        fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+            self.0.to_sql(ty, out)
+        }
+
+        fn accepts(ty: &Type) -> bool {
+            T::accepts(ty)
+        }
+
+        to_sql_checked!();
+    }
+
+    impl<'a, T: FromSql<'a>, const N: u64> FromSql<'a> for TypedPK<T, N> {
+        fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+            T::from_sql(ty, raw).map(TypedPK)
+        }
+
+        fn accepts(ty: &Type) -> bool {
+            T::accepts(ty)
+        }
+    }
+
+
+    /// Define named TypedPK aliases, each with its own discriminant:
+    /// typed_pk!(pub UserId: i32 = 1, pub PostId: i32 = 2);
+    /// expands to 
+    /// pub type UserId = TypedPK<i32, 1>;
+    /// pub type PostId = TypedPK<i32, 2>;
+    /// Be sure to use a different discriminant for each name, otherwise the types will be the same!
+    #[macro_export]
+    macro_rules! typed_pk {
+        ($($vis:vis $name:ident : $inner:ty = $n:literal),* $(,)?) => {
+            $(
+                $vis type $name = $crate::primary_key::typed::TypedPK<$inner, $n>;
+            )*
+        };
+    }
+
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        crate::typed_pk!(UserId: i32 = 1, PostId: i32 = 2);
+
+        #[test]
+        fn delegates_to_inner() {
+            let user_id = UserId::new(7);
+            assert_eq!(user_id.to_string(), "7");
+            assert_eq!(serde_json::to_string(&user_id).unwrap(), "7");
+            let post_id: PostId = serde_json::from_str("11").unwrap();
+            assert_eq!(post_id.into_inner(), 11);
+            assert!(<UserId as ToSql>::accepts(&Type::INT4));
+            assert!(!<UserId as ToSql>::accepts(&Type::TEXT));
+        }
+    }
+}
@@ -7,17 +7,29 @@
 //! REDIS_HOST: The IP where the Redis server is running. Defauls to "127.0.0.1"
 //! REDIS_PORT: The port on which the server is listening. Defaults to 6379
 //! REDIS_PW: The authentication password for Redis
-//! IS_TSL: If set to anything, rediss will be used instead of redis
+//! IS_TLS: If true (1/true/yes/on), rediss will be used instead of redis
 
-use std::env;
-use serde::{Serialize, de::DeserializeOwned};
+use std::{collections::HashMap, hash::Hash, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::Duration};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use tokio::{sync::watch, task::JoinHandle, time::MissedTickBehavior};
 use mobc::Pool;
-use mobc_redis::{RedisConnectionManager, redis::{AsyncCommands, RedisResult, Client, aio::Connection}};
-use tokio_postgres::{row::Row, types::ToSql};
-use crate::err::{PachyDarn, MissingRowError};
-use crate::connect::ClientNoTLS;
+use tokio_util::sync::CancellationToken;
+use mobc_redis::{RedisConnectionManager, redis::{AsyncCommands, ErrorKind, RedisError, RedisResult, Client, aio::Connection}};
+use tokio_postgres::{Client as PgClient, types::ToSql};
+use crate::err::{PachyDarn, PachyContext};
+use crate::connect::{params_summary, statement_within_deadline};
+use crate::utils::{env_bool, env_opt, env_parse, fnv1a_64, within_deadline, Deadline, DryRun};
+// re-exported so redis::strong_etag keeps working: it lives in utils since http_server needs it without the redis feature
+pub use crate::utils::strong_etag;
+use crate::client::{PachyClient, PgPoolLike, RowLike};
 use crate::autocomplete::{AutoComp, WhoWhatWhere};
+use crate::primary_key::{CompositeKey, GetByPKs, PkFromRow};
+pub use crate::primary_key::cache_key_param;
+use crate::analytics::{record_search, RedisSearchCounts, SearchAnalytics};
+use crate::fulltext::normalize_phrase;
+use crate::utils::pachy_log;
 
 // constants for mobc redis connection pools
 // see https://blog.logrocket.com/using-redis-in-a-rust-web-service/
@@ -45,22 +57,80 @@ pub trait Cacheable: Serialize + DeserializeOwned {
     /// When a value is cached to redis, set the expiry in seconds until it is removed auomatically.
     fn seconds_expiry() -> usize;
 
-    /// This method generates a key showing where to cache an instance of a struct in Redis
+    /// How long a cached value lasts, which is what cached_or_cache uses. Defaults to seconds_expiry(),
+    /// so override it instead for a sub-second TTL or one too long for a usize on 32-bit targets
+    fn ttl() -> Duration {
+        Duration::from_secs(Self::seconds_expiry() as u64)
+    }
+
+    /// Identifies the type for include_type_tag(). Defaults to the full type path, i.e. "my_crate::models::User"
+    fn redis_key_unique_marker() -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// Override this to return true if you want keys to include a short hash of redis_key_unique_marker(),
+    /// so two types that happen to share a key_prefix() (i.e. both use "user") never read each other's values.
+    /// Note type_name() may change between compiler versions, which would only cause cache misses 
+    fn include_type_tag() -> bool {
+        false
+    }
+
+    /// This method generates a key showing where to cache an instance of a struct in Redis.
+    /// Each param is rendered with cache_key_param, so i.e. a Uuid param and its to_string() share a key
     fn redis_key(params:&[&(dyn ToSql + Sync)]) -> String {
         let mut key = format!("cacheable_{}", Self::key_prefix());
+        if Self::include_type_tag() {
+            let tag = fnv1a_64(Self::redis_key_unique_marker().as_bytes()) >> 32;
+            key.push_str(&format!("_t{:08x}", tag));
+        }
         for param in params {
-            let delta = format!("_{:?}", param).replace("\"","");
-            key.push_str(&delta);
+            key.push('_');
+            key.push_str(&cache_key_param(*param));
         }
         key
     }
 
+    /// The key for cached_or_cache_key. Each part of the key is escaped, so ("a_b", "c") and ("a", "b_c") get different keys
+    /// (redis_key would give both cacheable_{prefix}_a_b_c). For strings and numbers without an underscore or a backslash,
+    /// it is the same as redis_key with the key's params
+    fn redis_key_for<K: CompositeKey>(key: &K) -> String {
+        let mut redis_key = Self::redis_key(&[]);
+        for part in key.cache_parts() {
+            redis_key.push('_');
+            redis_key.push_str(&part.replace('\\', "\\\\").replace('_', "\\_"));
+        }
+        redis_key
+    }
+
     /// Define the query that should be used with the assocaited parameters (i.e. those used in redis_key()) 
     /// to return an instance of the struct 
     fn query() -> &'static str;
 
     /// Define how to convert a postgres row to as instance of the struct 
-    fn from_row(row: &Row) -> Self;
+    fn from_row<R: RowLike>(row: &R) -> Self;
+
+    /// Override this if query() can return a row that doesn't hold an instance, i.e. a LEFT JOIN whose joined
+    /// columns are NULL: return true for such rows (i.e. row.get::<_, Option<i32>>(0).is_none()) and they are read as None
+    fn row_is_null<R: RowLike>(_row: &R) -> bool {
+        false
+    }
+
+    /// The instance in the row, or None if row_is_null
+    fn from_row_opt<R: RowLike>(row: &R) -> Option<Self> {
+        match Self::row_is_null(row) {
+            true => None,
+            false => Some(Self::from_row(row)),
+        }
+    }
+
+    /// Load the instance from Postgres on a cache miss. The default runs query() with the params and reads the first row
+    /// with from_row_opt. Override it for values computed from more than one query (i.e. a profile plus aggregate counts):
+    /// cached_or_cache still derives the key, caches the result and sets the expiry. None is not cached.
+    /// validate_cacheable only checks query(), so anything else an override runs isn't validated at startup
+    async fn fetch<C: PachyClient>(c: &C, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Self>, PachyDarn> {
+        let rows = c.query(Self::query(), params).await?;
+        Ok(rows.get(0).and_then(Self::from_row_opt))
+    }
 
 }
 
@@ -68,41 +138,111 @@ pub trait Cacheable: Serialize + DeserializeOwned {
 /// It will first check to see if a value has been cached in Redis
 /// If not, it will next check in postgres.
 /// If a value is found, it will be cahced and returned 
-/// If nothing is found in Postgres either, the None variant will be returned
-pub async fn cached_or_cache<T: Cacheable>(c: &ClientNoTLS, pool: &RedisPool, params: &[&(dyn ToSql + Sync)]) -> Result<Option<T>, PachyDarn> {
-    let key = T::redis_key(params);
-    let cached: Option<T> = rediserde::get(pool, &key).await?;
+/// If nothing is found in Postgres either (or the row is null, see Cacheable::row_is_null), the None variant will be returned
+/// and nothing is cached
+pub async fn cached_or_cache<T: Cacheable>(c: &impl PachyClient, pool: &RedisPool, params: &[&(dyn ToSql + Sync)]) -> Result<Option<T>, PachyDarn> {
+    cached_or_cache_at(pool, &T::redis_key(params), T::fetch(c, params), None).await
+}
+
+/// Like cached_or_cache, but each phase is given the remaining budget of the deadline, if there is one (see utils::Deadline).
+/// It takes a client of its own (i.e. &mut a pooled ClientNoTLS), since on a cache miss T::fetch runs on a transaction
+/// Postgres stops when the budget runs out (see connect::with_statement_timeout)
+pub async fn cached_or_cache_within<T: Cacheable>(c: &mut PgClient, pool: &RedisPool, params: &[&(dyn ToSql + Sync)], deadline: Option<Deadline>) -> Result<Option<T>, PachyDarn> {
+    let fetch = async move {
+        match deadline {
+            None => T::fetch(&*c, params).await,
+            Some(deadline) => statement_within_deadline(c, deadline, |tx| async move {
+                let val = T::fetch(&tx, params).await?;
+                Ok((tx, val))
+            }).await,
+        }
+    };
+    cached_or_cache_at(pool, &T::redis_key(params), fetch, deadline).await
+}
+
+/// Like cached_or_cache, but the params come from a typed key (i.e. a tuple or primary_key::Key2),
+/// cached under Cacheable::redis_key_for(key)
+pub async fn cached_or_cache_key<T: Cacheable, K: CompositeKey>(c: &impl PachyClient, pool: &RedisPool, key: &K) -> Result<Option<T>, PachyDarn> {
+    let params = key.as_params();
+    cached_or_cache_at(pool, &T::redis_key_for(key), T::fetch(c, &params), None).await
+}
+
+// look for the key in Redis, awaiting fetch (the Postgres phase) only on a miss
+async fn cached_or_cache_at<T: Cacheable>(pool: &RedisPool, key: &str, fetch: impl std::future::Future<Output = Result<Option<T>, PachyDarn>>, deadline: Option<Deadline>) -> Result<Option<T>, PachyDarn> {
+    let cached: Option<T> = within_deadline(deadline, "redis", rediserde::get(pool, key)).await?;
     match cached {
         Some(val) => Ok(Some(val)),
-        None => {
-            let query = T::query();
-            let rows = c.query(query, params).await?;
-            match rows.get(0) {
-                None => Ok(None),
-                Some(row) => {
-                    let val = T::from_row(row);
-                    let _x = rediserde::set_ex(pool, &key, &val, T::seconds_expiry()).await?;
-                    Ok(Some(val))
-                }
+        None => match fetch.await? {
+            None => Ok(None),
+            Some(val) => {
+                let _x = within_deadline(deadline, "redis", rediserde::set_ex(pool, key, &val, T::ttl())).await?;
+                Ok(Some(val))
             }
-        }
+        },
     }
 }
 
 
 /// the cached_or_cache function returns Result<Option<T>, PachyDarn>
 /// The "_f" in cached_or_cache_f indicates that it forces the code to look for the Some variant,
-/// returning the MissingRow variant of a PachyDarn error if it was not found 
-pub async fn cached_or_cache_f<T: Cacheable>(c: &ClientNoTLS, pool: &RedisPool, params: &[&(dyn ToSql + Sync)]) -> Result<T, PachyDarn> {
-    let opt: Option<T> = cached_or_cache(c, pool, params).await?;
+/// returning the NotFound variant of a PachyDarn error (with the type name and Redis key) if it was not found
+pub async fn cached_or_cache_f<T: Cacheable>(c: &impl PachyClient, pool: &RedisPool, params: &[&(dyn ToSql + Sync)]) -> Result<T, PachyDarn> {
+    let key = T::redis_key(params);
+    let context = || format!("cached_or_cache_f::<{}> failed for params {}", std::any::type_name::<T>(), params_summary(params));
+    let opt: Option<T> = cached_or_cache_at(pool, &key, T::fetch(c, params), None).await.with_context(context)?;
     match opt {
         Some(val) => Ok(val),
-        None => Err(PachyDarn::from(MissingRowError::from_str("cached_or_cache_f found a None variant"))),
+        None => Err(PachyDarn::not_found::<T>(key)).with_context(context),
+    }
+}
+
+
+/// Turn a page of autocomplete (or fulltext) hits into the full instances, i.e. for the rows a UI is showing. The Cacheable keys of all hits
+/// are read with one MGET (each key is T::redis_key(&[&hit.pk]), as with cached_or_cache), the misses are fetched with one
+/// T::query_get_by_pks() query and cached, and the results are returned in the order of the hits.
+/// A hit whose row no longer exists (i.e. it was deleted after the search was cached) is None rather than an error
+pub async fn hydrate_hits<T, PK>(c: &impl PachyClient, rpool: &RedisPool, hits: &[WhoWhatWhere<PK>]) -> Result<Vec<Option<T>>, PachyDarn>
+where
+    T: Cacheable + GetByPKs + PkFromRow<PK>,
+    PK: ToSql + Sync + Serialize + Send + Eq + Hash,
+{
+    let context = || format!("hydrate_hits::<{}> failed", std::any::type_name::<T>());
+    let keys: Vec<String> = hits.iter().map(|hit| T::redis_key(&[&hit.pk])).collect();
+    let mut hydrated: Vec<Option<T>> = rediserde::mget(rpool, &keys).await.with_context(context)?;
+    // the positions of each missing PK, since a page can reference the same row twice
+    let mut missing: HashMap<&PK, Vec<usize>> = HashMap::new();
+    for (i, hit) in hits.iter().enumerate() {
+        if hydrated[i].is_none() {
+            missing.entry(&hit.pk).or_default().push(i);
+        }
+    }
+    if missing.is_empty() {
+        return Ok(hydrated)
+    }
+    let pks: Vec<&PK> = missing.keys().copied().collect();
+    let rows = c.query(T::query_get_by_pks(), &[&pks]).await.with_context(context)?;
+    let mut fetched: Vec<(Vec<usize>, T)> = Vec::new();
+    for row in rows.iter() {
+        if let Some(positions) = missing.remove(&T::pk_from_row(row)) {
+            fetched.push((positions, T::rowfunc_get_by_pk(row)));
+        }
+    }
+    let entries: Vec<(String, &T)> = fetched.iter().map(|(positions, t)| (keys[positions[0]].clone(), t)).collect();
+    rediserde::mset_ex(rpool, &entries, T::ttl()).await.with_context(context)?;
+    for (positions, t) in fetched {
+        // repeats of a PK get a copy through JSON, since T needn't be Clone
+        for i in positions.iter().skip(1) {
+            hydrated[*i] = Some(serde_json::from_value(serde_json::to_value(&t)?)?);
+        }
+        hydrated[positions[0]] = Some(t);
     }
+    Ok(hydrated)
 }
 
 
-/// The PreWarmDepth indicates how many characters (1,2, or 3) should be used for pre-caching autocomplete results
+/// The PreWarmDepth indicates how many characters (1,2, or 3) should be used for pre-caching autocomplete results,
+/// or that only the phrases users actually search for should be (Adaptive).
+/// The counts below are for the default CachedAutoComp::prewarm_chars1() and prewarm_chars23() characters
 pub enum PreWarmDepth {
     /// pre-warm the cache with 1-character deep results: i.e. 36 values
     Char1,
@@ -110,6 +250,8 @@ pub enum PreWarmDepth {
     Char2,
     /// pre-warm the cache with 1+2+3-character deep results: i.e. 36*(1+42)*(1+42) = 66,564 values
     Char3,
+    /// pre-warm the cache with the phrases counted by log_autocomp_query at least min_hits times, most queried first
+    Adaptive { min_hits: u64 },
 }
 
 
@@ -125,40 +267,222 @@ pub trait CachedAutoComp<PKC: Serialize+DeserializeOwned+std::marker::Send>: Aut
     fn dtype() -> &'static str;
     /// The cahced value in redis will expire after this many seconds.
     fn seconds_expiry() -> usize;
+    /// How long cached hits last, which is what cached_autocomp uses. Defaults to seconds_expiry()
+    fn ttl() -> Duration {
+        Duration::from_secs(Self::seconds_expiry() as u64)
+    }
     /// This sets the depth (number of characters) to which a value will be cached in Redis. 
     fn prewarm_depth() -> PreWarmDepth;
+    /// The characters warm_the_cache uses for the first character of each phrase.
+    /// Override this if your data is i.e. mostly product codes or zip codes 
+    fn prewarm_chars1() -> &'static str {
+        "abcdefghijklmnopqrstuvwxyz0123456789"
+    }
+    /// The characters warm_the_cache uses for the second and third characters of each phrase 
+    fn prewarm_chars23() -> &'static str {
+        "abcdefghijklmnopqrstuvwxyz_.!?-0123456789 " // note the space at the end
+    }
+    /// If autocomplete results are access-controlled (i.e. different tenants see different records),
+    /// override this to return the tenant ID so each tenant gets its own cached results.
+    /// Since warm_the_cache goes through the same keys, it warms the cache for the current namespace only 
+    fn cache_namespace() -> Option<String> {
+        None
+    }
+    /// Override this to return true if query_autocomp() only matches rows by prefix, so a phrase can't have hits
+    /// if a prefix of it had none (i.e. "xyloc" when "xylo" returned nothing). Phrases with no hits are then recorded
+    /// in the empty_prefixes_key() set, and cached_autocomp returns nothing for phrases they prefix without querying
+    fn prefix_monotone() -> bool {
+        false
+    }
+    /// How long the empty_prefixes_key() set lasts after a phrase was last added to it. Defaults to ttl().
+    /// Clear it sooner with clear_empty_prefixes, i.e. from a trigger (see listen::empty_prefixes_trigger_sql)
+    fn empty_prefix_ttl() -> Duration {
+        <Self as CachedAutoComp<PKC>>::ttl()
+    }
 }
 
 
 
 
-// generate the Redis key to use for cached autocomplete results for a given <T> and phrase
+// generate the Redis key to use for cached autocomplete results for a given <T> and phrase.
+// The phrase is normalized just as exec_autocomp normalizes it, so the key can't diverge from the query
 fn autocomp_key<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(phrase: &str) -> String {
-    let lphrase = phrase.to_lowercase(); // Postgres tsquery is case insensitive by Redis keys are not
-    let key = format!("autocomp_{}_{}", T::dtype(), &lphrase );
+    let lphrase = normalize_phrase(phrase);
+    let key = match T::cache_namespace() {
+        // the length prefix keeps namespace "a" + phrase "b_c" from colliding with namespace "a_b" + phrase "c"
+        Some(ns) => format!("autocomp_{}_ns{}:{}_{}", T::dtype(), ns.len(), &ns, &lphrase),
+        None => format!("autocomp_{}_{}", T::dtype(), &lphrase ),
+    };
     key
 }
 
 
+/// The Redis set of (normalized, see fulltext::normalize_phrase) phrases for which T had no hits, for CachedAutoComp::prefix_monotone types,
+/// i.e. autocomp_empty_animal (with the cache_namespace(), if there is one)
+pub fn empty_prefixes_key<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>() -> String {
+    match T::cache_namespace() {
+        Some(ns) => format!("autocomp_empty_{}_ns{}:{}", T::dtype(), ns.len(), &ns),
+        None => format!("autocomp_empty_{}", T::dtype()),
+    }
+}
+
+/// Forget the phrases T had no hits for, i.e. after inserting rows that might match them
+pub async fn clear_empty_prefixes<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool) -> Result<(), PachyDarn> {
+    rediserde::del(pool, &empty_prefixes_key::<PKC, T>()).await
+}
+
+// whether a prefix of the phrase (or the phrase itself) is known to have no hits
+async fn has_empty_prefix<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, phrase: &str) -> Result<bool, PachyDarn> {
+    let lphrase = normalize_phrase(phrase);
+    let prefixes: Vec<&str> = lphrase.char_indices().map(|(i, c)| &lphrase[..i + c.len_utf8()]).collect();
+    rediserde::sismember_any_str(pool, &empty_prefixes_key::<PKC, T>(), &prefixes).await
+}
+
+
+
+/// Cached autocomplete results are stored in Redis inside this envelope, along with the ETag of their JSON,
+/// so HTTP handlers can answer If-None-Match requests without re-serializing the value just to hash it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CacheEnvelope<T> {
+    pub etag: String,
+    pub value: T,
+}
+
+impl<T: Serialize> CacheEnvelope<T> {
+    /// wrap a value, computing the strong_etag of its JSON serialization 
+    pub fn new(value: T) -> Result<Self, PachyDarn> {
+        let jz = serde_json::to_vec(&value)?;
+        Ok(CacheEnvelope{etag: strong_etag(&jz), value})
+    }
+}
+
 
 /// as the name implies, recache will redo the postgres query for autocomplete results for a given phrase and cache the value,
 /// overwiting any previous result. 
-pub async fn recache<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &ClientNoTLS, phrase: &str) -> Result<Vec<WhoWhatWhere<PKC>>, PachyDarn> {
-    let key = autocomp_key::<PKC, T>(&phrase);
+pub async fn recache<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, phrase: &str) -> Result<Vec<WhoWhatWhere<PKC>>, PachyDarn> {
+    Ok(recache_envelope::<PKC, T>(pool, c, phrase).await?.value)
+}
+
+
+// like recache, but returns the whole CacheEnvelope that was cached 
+async fn recache_envelope<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, phrase: &str) -> Result<CacheEnvelope<Vec<WhoWhatWhere<PKC>>>, PachyDarn> {
+    let phrase = normalize_phrase(phrase);
     let hits: Vec<WhoWhatWhere<PKC>> = <T as AutoComp<PKC>>::exec_autocomp(c, &phrase).await?;
-    let _x = rediserde::set_ex(pool, &key, &hits, T::seconds_expiry()).await?;
-    Ok(hits)
+    cache_hits::<PKC, T>(pool, &phrase, hits, None).await
+}
+
+// cache the hits for the (normalized) phrase, noting a phrase with none for prefix_monotone types
+async fn cache_hits<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, phrase: &str, hits: Vec<WhoWhatWhere<PKC>>, deadline: Option<Deadline>) -> Result<CacheEnvelope<Vec<WhoWhatWhere<PKC>>>, PachyDarn> {
+    let key = autocomp_key::<PKC, T>(phrase);
+    if hits.is_empty() && T::prefix_monotone() && !phrase.is_empty() {
+        within_deadline(deadline, "redis", rediserde::sadd_str_ex(pool, &empty_prefixes_key::<PKC, T>(), phrase, T::empty_prefix_ttl())).await?;
+    }
+    let envelope = CacheEnvelope::new(hits)?;
+    let _x = within_deadline(deadline, "redis", rediserde::set_ex(pool, &key, &envelope, <T as CachedAutoComp<PKC>>::ttl())).await?;
+    Ok(envelope)
 }
 
 
 /// the cached_autocomp function will first look in Redis for cached autocomplete results before looking in Postgres.  
 /// See more detail under the CachedAutoComp trait. 
-pub async fn cached_autocomp<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &ClientNoTLS, phrase: &str) -> Result<Vec<WhoWhatWhere<PKC>>, PachyDarn> {
+pub async fn cached_autocomp<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, phrase: &str) -> Result<Vec<WhoWhatWhere<PKC>>, PachyDarn> {
+    Ok(cached_autocomp_envelope::<PKC, T>(pool, c, phrase).await?.value)
+}
+
+
+/// Like cached_autocomp, but returns the CacheEnvelope so the ETag stored alongside the hits is available.
+/// Values cached before the envelope was introduced fail to deserialize, and are treated as a cache miss
+pub async fn cached_autocomp_envelope<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, phrase: &str) -> Result<CacheEnvelope<Vec<WhoWhatWhere<PKC>>>, PachyDarn> {
+    Ok(cached_autocomp_envelope_from::<PKC, T>(pool, c, phrase).await?.0)
+}
+
+// like cached_autocomp_envelope, along with whether it came from the cache
+async fn cached_autocomp_envelope_from<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, phrase: &str) -> Result<(CacheEnvelope<Vec<WhoWhatWhere<PKC>>>, bool), PachyDarn> {
+    match cached_envelope::<PKC, T>(pool, phrase, None).await? {
+        Some(envelope) => Ok((envelope, true)),
+        None => Ok((recache_envelope::<PKC, T>(pool, c, phrase).await?, false)),
+    }
+}
+
+// the cached hits for the phrase, or no hits if a prefix of it is known to have none.
+// Values cached before the envelope was introduced fail to deserialize, and are treated as a cache miss
+async fn cached_envelope<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, phrase: &str, deadline: Option<Deadline>) -> Result<Option<CacheEnvelope<Vec<WhoWhatWhere<PKC>>>>, PachyDarn> {
     let key = autocomp_key::<PKC, T>(phrase);
-    let cached: Option<Vec<WhoWhatWhere<PKC>>> = rediserde::get(pool, &key).await?;
+    let cached: Result<Option<CacheEnvelope<Vec<WhoWhatWhere<PKC>>>>, PachyDarn> = within_deadline(deadline, "redis", rediserde::get(pool, &key)).await;
     match cached {
-        Some(hits) => Ok(hits),
-        None => { recache::<PKC, T>(pool, c, phrase).await }
+        Ok(Some(envelope)) => Ok(Some(envelope)),
+        Ok(None) | Err(PachyDarn::SerdeJSON(_)) => {
+            // the phrase extends one with no hits, so it has none either
+            if T::prefix_monotone() && within_deadline(deadline, "redis", has_empty_prefix::<PKC, T>(pool, phrase)).await? {
+                return Ok(Some(CacheEnvelope::new(Vec::new())?))
+            }
+            Ok(None)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+
+/// Like cached_autocomp, but each phase is given the remaining budget of the deadline, if there is one (see utils::Deadline).
+/// It takes a client of its own (i.e. &mut a pooled ClientNoTLS), since on a cache miss exec_autocomp runs on a transaction
+/// Postgres stops when the budget runs out (see connect::with_statement_timeout)
+pub async fn cached_autocomp_within<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &mut PgClient, phrase: &str, deadline: Option<Deadline>) -> Result<Vec<WhoWhatWhere<PKC>>, PachyDarn> {
+    if let Some(envelope) = cached_envelope::<PKC, T>(pool, phrase, deadline).await? {
+        return Ok(envelope.value)
+    }
+    let phrase = normalize_phrase(phrase);
+    let lphrase = phrase.as_str();
+    let hits: Vec<WhoWhatWhere<PKC>> = match deadline {
+        None => <T as AutoComp<PKC>>::exec_autocomp(&*c, lphrase).await?,
+        Some(deadline) => statement_within_deadline(c, deadline, |tx| async move {
+            let hits = <T as AutoComp<PKC>>::exec_autocomp(&tx, lphrase).await?;
+            Ok((tx, hits))
+        }).await?,
+    };
+    Ok(cache_hits::<PKC, T>(pool, lphrase, hits, deadline).await?.value)
+}
+
+
+/// Like cached_autocomp, but the search is recorded with the analytics sink (if there is one) under T::dtype().
+/// A sink that fails is only logged (see analytics::record_search), so it never fails the search
+pub async fn cached_autocomp_with_analytics<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, phrase: &str, analytics: Option<&dyn SearchAnalytics>) -> Result<Vec<WhoWhatWhere<PKC>>, PachyDarn> {
+    let (envelope, from_cache) = cached_autocomp_envelope_from::<PKC, T>(pool, c, phrase).await?;
+    record_search(analytics, T::dtype(), phrase, envelope.value.len(), from_cache).await;
+    Ok(envelope.value)
+}
+
+
+/// Like cached_autocomp, but if Redis is unavailable the results come straight from Postgres instead of failing.
+/// The cache is an optimization, so an outage should degrade performance but not break autocomplete.  
+/// Postgres errors are still returned.
+pub async fn cached_autocomp_degraded<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, phrase: &str) -> Result<Vec<WhoWhatWhere<PKC>>, PachyDarn> {
+    Ok(cached_autocomp_envelope_degraded::<PKC, T>(pool, c, phrase).await?.value)
+}
+
+
+/// The CacheEnvelope counterpart of cached_autocomp_degraded: if Redis is unavailable the ETag is computed from the Postgres results
+pub async fn cached_autocomp_envelope_degraded<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, phrase: &str) -> Result<CacheEnvelope<Vec<WhoWhatWhere<PKC>>>, PachyDarn> {
+    match cached_autocomp_envelope::<PKC, T>(pool, c, phrase).await {
+        Ok(envelope) => Ok(envelope),
+        Err(e) if e.is_redis_error() => CacheEnvelope::new(<T as AutoComp<PKC>>::exec_autocomp(c, phrase).await?),
+        Err(e) => Err(e),
+    }
+}
+
+
+/// Like cached_autocomp_envelope_degraded, but takes the Postgres pool and only checks a client out of it on a cache miss
+/// (or a Redis outage), so a cache hit costs no Postgres round trip
+pub async fn cached_autocomp_envelope_pooled<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, pg: &impl PgPoolLike, phrase: &str) -> Result<CacheEnvelope<Vec<WhoWhatWhere<PKC>>>, PachyDarn> {
+    match cached_envelope::<PKC, T>(pool, phrase, None).await {
+        Ok(Some(envelope)) => return Ok(envelope),
+        Ok(None) => {},
+        Err(e) if e.is_redis_error() => return CacheEnvelope::new(<T as AutoComp<PKC>>::exec_autocomp(&pg.client().await?, phrase).await?),
+        Err(e) => return Err(e),
+    }
+    let client = pg.client().await?;
+    match recache_envelope::<PKC, T>(pool, &client, phrase).await {
+        Err(e) if e.is_redis_error() => CacheEnvelope::new(<T as AutoComp<PKC>>::exec_autocomp(&client, phrase).await?),
+        res => res,
     }
 }
 
@@ -166,30 +490,304 @@ pub async fn cached_autocomp<PKC: Serialize+DeserializeOwned+std::marker::Send,
 /// The AutoComp trait queries postgres for matching WhoWhatWhere<PKC> structs.  This is typically slowest for the first few
 /// characters (i.e. very short strings) because they will generate the most matches. It is helpful to therefore
 /// defind a method that will iterate over many short strings and pre-query the database and cache the results to Redis. 
-pub async fn warm_the_cache<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &ClientNoTLS) -> Result<(), PachyDarn> {
-    let chars1 =  "abcdefghijklmnopqrstuvwxyz0123456789";
-    let chars23 = "abcdefghijklmnopqrstuvwxyz_.!?-0123456789 "; // note the space at the end
-    for c1 in chars1.chars() {
-        let mut phrase = c1.to_string();
-        let _hits = recache::<PKC, T>(pool, c, &phrase).await?;
+pub async fn warm_the_cache<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient) -> Result<(), PachyDarn> {
+    let _stats = warm_the_cache_cancellable::<PKC, T>(pool, c, CancellationToken::new()).await?;
+    Ok(())
+}
+
+
+/// The phrases warm_the_cache will recache, in order, based on the prewarm_depth(), prewarm_chars1(), and prewarm_chars23() of T.
+/// This is empty for PreWarmDepth::Adaptive, whose phrases are read from Redis (see adaptive_phrases)
+pub fn prewarm_phrases<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>() -> Vec<String> {
+    let mut phrases = Vec::new();
+    if let PreWarmDepth::Adaptive{..} = T::prewarm_depth() {
+        return phrases
+    }
+    for c1 in T::prewarm_chars1().chars() {
+        phrases.push(c1.to_string());
         match T::prewarm_depth() {
             PreWarmDepth::Char1 => continue,
             _ => {}
         }
-        for c2 in chars23.chars() {
-            phrase.push(c2);
-            let _hits = recache::<PKC, T>(pool, c, &phrase).await?;
+        for c2 in T::prewarm_chars23().chars() {
+            let phrase2 = format!("{}{}", c1, c2);
+            phrases.push(phrase2.clone());
             match T::prewarm_depth() {
                 PreWarmDepth::Char3 => {},
                 _ => continue
             }
-            for c3 in chars23.chars() {
-                phrase.push(c3);
-                let _hits = recache::<PKC, T>(pool, c, &phrase).await?;
+            for c3 in T::prewarm_chars23().chars() {
+                phrases.push(format!("{}{}", &phrase2, c3));
             }
         }
     }
-    Ok(())
+    phrases
+}
+
+
+// the phrases warm_the_cache recaches: prewarm_phrases, or adaptive_phrases for PreWarmDepth::Adaptive
+async fn warm_phrases<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool) -> Result<Vec<String>, PachyDarn> {
+    match T::prewarm_depth() {
+        PreWarmDepth::Adaptive{min_hits} => adaptive_phrases(pool, T::dtype(), min_hits).await,
+        _ => Ok(prewarm_phrases::<PKC, T>()),
+    }
+}
+
+
+/// How long the autocomp_queries_key() counts last after the last query logged for the dtype
+pub const AUTOCOMP_QUERIES_SECONDS: usize = 7 * 86400;
+
+/// The most phrases log_autocomp_query keeps counts for per dtype: past this the least queried are dropped
+pub const AUTOCOMP_QUERIES_MAX: usize = 10_000;
+
+/// The Redis sorted set log_autocomp_query counts queries in, scored by count, i.e. autocomp_queries_animal
+pub fn autocomp_queries_key(dtype: &str) -> String {
+    format!("autocomp_queries_{}", dtype)
+}
+
+/// Count a query for PreWarmDepth::Adaptive, i.e. from request-logging middleware in front of cached_autocomp,
+/// returning the phrase's new count. The phrase is normalized as the cache key is (see fulltext::normalize_phrase),
+/// so " Dog" and "dog" are counted together. Counts aren't kept per cache_namespace(), phrases that normalize
+/// to nothing aren't counted, and only the AUTOCOMP_QUERIES_MAX most queried phrases are kept
+pub async fn log_autocomp_query(pool: &RedisPool, dtype: &str, phrase: &str) -> Result<u64, PachyDarn> {
+    let phrase = normalize_phrase(phrase);
+    if phrase.is_empty() {
+        return Ok(0)
+    }
+    rediserde::zincr_ex_trim(pool, &autocomp_queries_key(dtype), &phrase, AUTOCOMP_QUERIES_SECONDS, AUTOCOMP_QUERIES_MAX).await
+}
+
+/// The phrases logged with log_autocomp_query for dtype at least min_hits times, most queried first (ties alphabetically)
+pub async fn adaptive_phrases(pool: &RedisPool, dtype: &str, min_hits: u64) -> Result<Vec<String>, PachyDarn> {
+    let mut counted = rediserde::zrevrange_min_u64(pool, &autocomp_queries_key(dtype), min_hits).await?;
+    // ZREVRANGEBYSCORE breaks ties in reverse order
+    counted.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(counted.into_iter().map(|(phrase, _)| phrase).collect())
+}
+
+
+/// What warm_the_cache_cancellable got done before it finished or was cancelled 
+#[derive(Serialize, Debug, Default)]
+pub struct WarmStats {
+    /// how many phrases were recached
+    pub phrases_warmed: usize,
+    /// the total number of hits cached across all phrases
+    pub hits_cached: usize,
+    /// true if the token was cancelled before every phrase was warmed
+    pub cancelled: bool,
+}
+
+
+/// Like warm_the_cache, but the token is checked between phrases so the warming can be stopped cleanly
+/// (i.e. on SIGTERM during a deployment). Cancellation is not an error: the stats collected so far are returned 
+pub async fn warm_the_cache_cancellable<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, token: CancellationToken) -> Result<WarmStats, PachyDarn> {
+    let mut stats = WarmStats::default();
+    for phrase in warm_phrases::<PKC, T>(pool).await? {
+        if token.is_cancelled() {
+            stats.cancelled = true;
+            break
+        }
+        let hits = recache::<PKC, T>(pool, c, &phrase).await?;
+        stats.phrases_warmed += 1;
+        stats.hits_cached += hits.len();
+    }
+    Ok(stats)
+}
+
+
+/// The plan for warming the cache of a CachedAutoComp, and what was done if it was executed
+#[derive(Serialize, Debug)]
+pub struct WarmReport {
+    pub mode: DryRun,
+    /// the phrases to recache, in order
+    pub phrases: Vec<String>,
+    /// how many autocomplete queries the warming runs against Postgres (one per phrase)
+    pub estimated_queries: usize,
+    /// all zero for a Preview
+    pub stats: WarmStats,
+}
+
+/// Like warm_the_cache, but return the plan it follows and the resulting stats.
+/// With DryRun::Preview only the plan is computed: Postgres isn't touched, and Redis is only read from
+/// for PreWarmDepth::Adaptive, to find the phrases
+pub async fn warm_the_cache_with<PKC: Serialize+DeserializeOwned+std::marker::Send, T: CachedAutoComp<PKC>>(pool: &RedisPool, c: &impl PachyClient, dry_run: DryRun) -> Result<WarmReport, PachyDarn> {
+    let phrases = warm_phrases::<PKC, T>(pool).await?;
+    let estimated_queries = phrases.len();
+    let stats = match dry_run {
+        DryRun::Preview => WarmStats::default(),
+        DryRun::Execute => warm_the_cache_cancellable::<PKC, T>(pool, c, CancellationToken::new()).await?,
+    };
+    Ok(WarmReport{mode: dry_run, phrases, estimated_queries, stats})
+}
+
+
+/// Where spawn_autocomp_refresher gets the phrases to recache on each tick
+pub enum PhraseSource {
+    /// the phrases warm_the_cache recaches (prewarm_phrases::<PKC, T>(), or adaptive_phrases for PreWarmDepth::Adaptive)
+    Prewarm,
+    /// the (at most) limit phrases searched most over the last days, as counted by analytics::RedisSearchCounts
+    TopSearched{counts: Arc<RedisSearchCounts>, days: usize, limit: usize},
+}
+
+/// How spawn_autocomp_refresher recaches
+pub struct RefreshConfig {
+    /// a tick starts this often, unless the previous one is still running
+    pub interval: Duration,
+    /// how many phrases are recached at once, each with its own client
+    pub concurrency: usize,
+    /// the most autocomplete queries started against Postgres per second, across ticks
+    pub max_queries_per_second: u32,
+    pub phrases: PhraseSource,
+}
+
+impl Default for RefreshConfig {
+    fn default() -> Self {
+        RefreshConfig{interval: Duration::from_secs(300), concurrency: 2, max_queries_per_second: 20, phrases: PhraseSource::Prewarm}
+    }
+}
+
+/// What one tick of an autocomplete refresher did
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct RefreshReport {
+    /// the ticks that have run, counting this one
+    pub tick: u64,
+    /// how many phrases were recached
+    pub phrases_refreshed: usize,
+    /// how many phrases failed to recache (each is logged). If the phrases couldn't be read, this is 1 and none were refreshed
+    pub errors: usize,
+    /// the ticks skipped so far because the previous one was still running
+    pub skipped_ticks: u64,
+    pub elapsed: Duration,
+}
+
+/// Stops an autocomplete refresher, and receives its reports
+pub struct RefresherHandle {
+    token: CancellationToken,
+    reports: watch::Receiver<RefreshReport>,
+}
+
+impl RefresherHandle {
+    /// Stop the refresher: a running tick stops before its next phrase, and no more ticks start.
+    /// The JoinHandle returned with this completes once the running tick has stopped
+    pub fn shutdown(&self) {
+        self.token.cancel()
+    }
+
+    /// The report of the latest tick, which changes as each tick finishes (until the first has, it is the default report)
+    pub fn reports(&self) -> watch::Receiver<RefreshReport> {
+        self.reports.clone()
+    }
+}
+
+// the state shared by a refresher and its ticks
+struct Refresher<P> {
+    pool: RedisPool,
+    pg_pool: P,
+    config: RefreshConfig,
+    token: CancellationToken,
+    reports: watch::Sender<RefreshReport>,
+    skipped_ticks: AtomicU64,
+    // spaces out the queries, so it is shared by the ticks
+    pacer: tokio::sync::Mutex<tokio::time::Interval>,
+}
+
+/// Spawn a task that recaches the autocomplete results of T for the config's phrases every interval, rather than
+/// re-warming the cache from a cron job outside the app. The queries are paced to config.max_queries_per_second, and a tick
+/// is skipped if the previous one is still running. It has to be called within a tokio runtime:
+/// ```ignore
+/// let (join, refresher) = spawn_autocomp_refresher::<i32, Animal, _>(rpool.clone(), pg_pool.clone(), RefreshConfig::default());
+/// // on SIGTERM
+/// refresher.shutdown();
+/// join.await?;
+/// ```
+pub fn spawn_autocomp_refresher<PKC, T, P>(pool: RedisPool, pg_pool: P, config: RefreshConfig) -> (JoinHandle<()>, RefresherHandle)
+where
+    PKC: Serialize + DeserializeOwned + Send + 'static,
+    T: CachedAutoComp<PKC> + 'static,
+    P: PgPoolLike + Send + 'static,
+{
+    let token = CancellationToken::new();
+    let (tx, rx) = watch::channel(RefreshReport::default());
+    let mut pacer = tokio::time::interval(Duration::from_secs_f64(1.0 / config.max_queries_per_second.max(1) as f64));
+    pacer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let refresher = Refresher{pool, pg_pool, config, token: token.clone(), reports: tx, skipped_ticks: AtomicU64::new(0), pacer: tokio::sync::Mutex::new(pacer)};
+    let join = tokio::spawn(run_refresher::<PKC, T, P>(Arc::new(refresher)));
+    (join, RefresherHandle{token, reports: rx})
+}
+
+// the refresher task: start a tick every interval unless the last is still running
+async fn run_refresher<PKC, T, P>(refresher: Arc<Refresher<P>>)
+where
+    PKC: Serialize + DeserializeOwned + Send + 'static,
+    T: CachedAutoComp<PKC> + 'static,
+    P: PgPoolLike + Send + 'static,
+{
+    let mut interval = tokio::time::interval(refresher.config.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut running: Option<JoinHandle<()>> = None;
+    let mut ticks = 0;
+    loop {
+        tokio::select! {
+            _ = refresher.token.cancelled() => break,
+            _ = interval.tick() => {},
+        }
+        if running.as_ref().map(|tick| !tick.is_finished()).unwrap_or(false) {
+            refresher.skipped_ticks.fetch_add(1, Ordering::SeqCst);
+            continue
+        }
+        ticks += 1;
+        running = Some(tokio::spawn(refresh_tick::<PKC, T, P>(refresher.clone(), ticks)));
+    }
+    if let Some(tick) = running {
+        let _x = tick.await;
+    }
+}
+
+// recache each phrase, publishing a report when done
+async fn refresh_tick<PKC, T, P>(refresher: Arc<Refresher<P>>, tick: u64)
+where
+    PKC: Serialize + DeserializeOwned + Send + 'static,
+    T: CachedAutoComp<PKC> + 'static,
+    P: PgPoolLike + Send + 'static,
+{
+    let start = std::time::Instant::now();
+    let mut report = RefreshReport{tick, ..Default::default()};
+    let phrases = match &refresher.config.phrases {
+        PhraseSource::Prewarm => warm_phrases::<PKC, T>(&refresher.pool).await,
+        PhraseSource::TopSearched{counts, days, limit} => counts.top_phrases(T::dtype(), *days, *limit).await,
+    };
+    match phrases {
+        Ok(phrases) => {
+            let refresher = &refresher;
+            let outcomes: Vec<Option<Result<(), PachyDarn>>> = stream::iter(phrases).map(|phrase| async move {
+                if refresher.token.is_cancelled() {
+                    return None
+                }
+                refresher.pacer.lock().await.tick().await;
+                let refreshed = match refresher.pg_pool.client().await {
+                    Ok(client) => recache::<PKC, T>(&refresher.pool, &client, &phrase).await.map(|_hits| ()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = &refreshed {
+                    pachy_log!(warn, "pachydurable::redis", "failed to refresh the {} autocomplete for \"{}\": {}", T::dtype(), phrase, e);
+                }
+                Some(refreshed)
+            }).buffer_unordered(refresher.config.concurrency.max(1)).collect().await;
+            for outcome in outcomes.into_iter().flatten() {
+                match outcome {
+                    Ok(()) => report.phrases_refreshed += 1,
+                    Err(_) => report.errors += 1,
+                }
+            }
+        },
+        Err(e) => {
+            pachy_log!(warn, "pachydurable::redis", "failed to read the {} phrases to refresh: {}", T::dtype(), e);
+            report.errors = 1;
+        },
+    }
+    report.skipped_ticks = refresher.skipped_ticks.load(Ordering::SeqCst);
+    report.elapsed = start.elapsed();
+    refresher.reports.send_replace(report);
 }
 
 
@@ -211,50 +809,64 @@ pub async fn new_pool_from_client(client: Client) -> Result<RedisPool, PachyDarn
 
 /// Create a new pool from a client generated with these environment variables:
 pub async fn new_pool_from_env() -> Result<RedisPool, PachyDarn> {
-    let client = new_client_from_env()?;
+    let client = try_new_client_from_env()?;
     new_pool_from_client(client).await
 }
 
 
+/// Load .env and .env.{profile} (see utils::load_env) and then create a new pool from environment variables
+#[cfg(feature = "dotenvy")]
+pub async fn new_pool_from_env_with_profile(profile: Option<&str>) -> Result<RedisPool, PachyDarn> {
+    let report = crate::utils::load_env(profile)?;
+    crate::utils::pachy_log!(info, "pachydurable::redis", "loaded environment variables {:?}", report.sources);
+    new_pool_from_env().await
+}
+
+
 /// Generate a new client based on a uri scheme, a host, and a password
 pub fn new_client(uri_scheme: &str, redis_host: &str, redis_pw: &str) -> RedisResult<Client> {
     let redis_conn_url = format!("{}://:{}@{}", uri_scheme, redis_pw, redis_host);
     Client::open(redis_conn_url)
 }
 
-/// Generate a new client from environment variables
+/// Generate a new client from environment variables.
+/// An invalid IS_TLS or REDIS_PORT is an InvalidClientConfig error; see try_new_client_from_env for one naming the variable
 pub fn new_client_from_env() -> RedisResult<Client>  {
-    let uri_scheme = match env::var("IS_TLS") {
-        Ok(_) => "rediss",
-        Err(_) => "redis",
-    };
+    let (uri_scheme, redis_host, redis_pw) = client_env()
+        .map_err(|e| RedisError::from((ErrorKind::InvalidClientConfig, "invalid environment variable", e.to_string())))?;
+    new_client(uri_scheme, &redis_host, &redis_pw)
+}
 
-    let redis_host: String = match env::var("REDIS_HOST") {
-        Ok(val) => val,
-        Err(_) => {
-            match env::var("REDIS_PORT")  {
-                Ok(port) => format!("127.0.0.1:{}", port),
-                Err(_) => "127.0.0.1:6379".to_string(),
-            }
-        },
+/// Like new_client_from_env, but an invalid IS_TLS or REDIS_PORT is returned as an error naming the variable
+pub fn try_new_client_from_env() -> Result<Client, PachyDarn>  {
+    let (uri_scheme, redis_host, redis_pw) = client_env()?;
+    Ok(new_client(uri_scheme, &redis_host, &redis_pw)?)
+}
+
+// the uri scheme, host and password the environment variables name
+fn client_env() -> Result<(&'static str, String, String), PachyDarn> {
+    let uri_scheme = match env_bool("IS_TLS")? {
+        true => "rediss",
+        false => "redis",
     };
-    let redis_pw: String = match env::var("REDIS_PW") {
-        Ok(val) => val,
-        Err(_) => "".to_string(),
+    let redis_host: String = match env_opt::<String>("REDIS_HOST")? {
+        Some(val) => val,
+        None => format!("127.0.0.1:{}", env_parse::<u16>("REDIS_PORT", 6379)?),
     };
-    new_client(&uri_scheme, &redis_host, &redis_pw)
+    let redis_pw: String = env_parse("REDIS_PW", String::new())?;
+    Ok((uri_scheme, redis_host, redis_pw))
 }
 
 
 
 pub mod rediserde {
     use super::{RedisPool};
-    use mobc_redis::redis::AsyncCommands;
-    use crate::err::PachyDarn;
+    use mobc_redis::redis::{self, AsyncCommands};
+    use crate::{err::PachyDarn, utils::DryRun};
+    use std::{collections::{HashMap, HashSet}, sync::atomic::{AtomicU8, Ordering}, time::Duration};
     use serde::{Serialize, de::DeserializeOwned};
     use serde_json;
 
-
     /// Delete a key 
     pub async fn del(pool: &RedisPool, key: &str) -> Result<(), PachyDarn> {
         let mut rconn = pool.get().await?;
@@ -290,11 +902,59 @@ pub mod rediserde {
         Ok(())
     }
 
-    /// This is like set but with an expiry 
-    pub async fn set_ex<T: Serialize>(pool: &RedisPool, key: &str, value: &T, seconds_expiry: usize) -> Result<(), PachyDarn> {
+    // the TTL in milliseconds for SET PX, rounded up so a sub-millisecond TTL still sets the key. A zero TTL would
+    // evict the key at once and Redis rejects one over i64::MAX milliseconds, so both are errors naming the TTL
+    pub(crate) fn ttl_millis(ttl: Duration) -> Result<u64, PachyDarn> {
+        if ttl.is_zero() {
+            return Err(PachyDarn::custom("invalid_ttl", "a TTL of 0 would expire the key immediately"))
+        }
+        let millis = (ttl.as_nanos() + 999_999) / 1_000_000;
+        match i64::try_from(millis) {
+            Ok(millis) => Ok(millis as u64),
+            Err(_) => Err(PachyDarn::custom("invalid_ttl", format!("a TTL of {:?} is longer than Redis allows", ttl))),
+        }
+    }
+
+    /// This is like set but with an expiry, to the millisecond (SET PX)
+    pub async fn set_ex<T: Serialize>(pool: &RedisPool, key: &str, value: &T, ttl: Duration) -> Result<(), PachyDarn> {
+        let ttl_ms = ttl_millis(ttl)?;
         let mut rconn = pool.get().await?;
         let jz: String = serde_json::to_string(value)?;
-        let _ : () = rconn.set_ex(key, jz, seconds_expiry).await?;
+        let _ : () = redis::cmd("SET").arg(key).arg(jz).arg("PX").arg(ttl_ms).query_async(&mut *rconn).await?;
+        Ok(())
+    }
+
+    /// set_ex with the expiry in seconds, as it was before set_ex took a Duration
+    #[deprecated(note = "use set_ex with a Duration, i.e. Duration::from_secs(seconds_expiry)")]
+    pub async fn set_ex_secs<T: Serialize>(pool: &RedisPool, key: &str, value: &T, seconds_expiry: usize) -> Result<(), PachyDarn> {
+        set_ex(pool, key, value, Duration::from_secs(seconds_expiry as u64)).await
+    }
+
+    /// Get many deserialized values in one round trip (MGET), in the order of the keys, with None for keys that don't exist
+    pub async fn mget<T: DeserializeOwned>(pool: &RedisPool, keys: &[String]) -> Result<Vec<Option<T>>, PachyDarn> {
+        if keys.is_empty() {
+            return Ok(Vec::new())
+        }
+        let mut rconn = pool.get().await?;
+        let jzs: Vec<Option<String>> = redis::cmd("MGET").arg(keys).query_async(&mut *rconn).await?;
+        jzs.into_iter().map(|jz| match jz {
+            Some(jz) => Ok(Some(serde_json::from_str(&jz)?)),
+            None => Ok(None),
+        }).collect()
+    }
+
+    /// Set many values, each with the same expiry, in one round trip
+    pub async fn mset_ex<T: Serialize>(pool: &RedisPool, entries: &[(String, &T)], ttl: Duration) -> Result<(), PachyDarn> {
+        if entries.is_empty() {
+            return Ok(())
+        }
+        let ttl_ms = ttl_millis(ttl)?;
+        let mut rconn = pool.get().await?;
+        let mut pipe = redis::pipe();
+        for (key, value) in entries {
+            pipe.cmd("SET").arg(key).arg(serde_json::to_string(value)?).arg("PX").arg(ttl_ms).ignore();
+        }
+        let _ : () = pipe.query_async(&mut *rconn).await?;
         Ok(())
     }
 
@@ -320,6 +980,32 @@ pub mod rediserde {
         Ok(ismember)
     }
 
+    /// add a string to a set and (re)set the expiry of the set, to the millisecond, in one round trip
+    pub async fn sadd_str_ex(pool: &RedisPool, key: &str, val: &str, ttl: Duration) -> Result<(), PachyDarn> {
+        let ttl_ms = ttl_millis(ttl)?;
+        let mut rconn = pool.get().await?;
+        let _ : () = redis::pipe().atomic().sadd(key, val).ignore().cmd("PEXPIRE").arg(key).arg(ttl_ms).ignore().query_async(&mut *rconn).await?;
+        Ok(())
+    }
+
+    /// report if any of the strings is a member of a set, with one SMISMEMBER (Redis 6.2 or later)
+    pub async fn sismember_any_str(pool: &RedisPool, key: &str, vals: &[&str]) -> Result<bool, PachyDarn> {
+        if vals.is_empty() {
+            return Ok(false)
+        }
+        let mut rconn = pool.get().await?;
+        let members: Vec<bool> = redis::cmd("SMISMEMBER").arg(key).arg(vals).query_async(&mut *rconn).await?;
+        Ok(members.into_iter().any(|member| member))
+    }
+
+    /// atomically move a string from one set to another (SMOVE), i.e. a job from "pending" to "processing".
+    /// Returns true if it was moved, or false if it wasn't a member of the source set 
+    pub async fn smove_str(pool: &RedisPool, source_key: &str, dest_key: &str, member: &str) -> Result<bool, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let moved: bool = rconn.smove(source_key, dest_key, member).await?;
+        Ok(moved)
+    }
+
     pub async fn spop_str(pool: &RedisPool, key: &str) -> Result<Option<String>, PachyDarn> {
         // This pool.get() hangs sometimes with the error "Timed out in mobc". What to do?  
         let mut rconn = pool.get().await?;
@@ -351,56 +1037,399 @@ pub mod rediserde {
         Ok(cardinality)
     }
 
-}
-
+    /// Compare-and-swap: set the key to new_value only if it currently holds a value equal to expected.
+    /// This uses WATCH + MULTI + SET + EXEC, so it returns true if the swap succeeded and false if
+    /// the current value did not match or the transaction was aborted due to a concurrent write
+    pub async fn cas<T: Serialize + DeserializeOwned + PartialEq>(pool: &RedisPool, key: &str, expected: &T, new_value: &T) -> Result<bool, PachyDarn> {
+        let jz: String = serde_json::to_string(new_value)?;
+        let mut rconn = pool.get().await?;
+        let _ : () = redis::cmd("WATCH").arg(key).query_async(&mut *rconn).await?;
+        let matches: Result<bool, PachyDarn> = match rconn.get::<_, Option<String>>(key).await {
+            Ok(Some(current)) => serde_json::from_str::<T>(&current).map(|current| &current == expected).map_err(PachyDarn::from),
+            Ok(None) => Ok(false),
+            Err(e) => Err(e.into()),
+        };
+        if !matches!(matches, Ok(true)) {
+            // only EXEC ends the WATCH, so UNWATCH rather than return a connection to the pool that is still watching
+            // a key (and would abort its next MULTI). An error reading the value is returned ahead of one from UNWATCH
+            let unwatched: Result<(), PachyDarn> = redis::cmd("UNWATCH").query_async(&mut *rconn).await.map_err(PachyDarn::from);
+            matches?;
+            unwatched?;
+            return Ok(false)
+        }
+        // EXEC returns nil (i.e. None) if the watched key was modified after WATCH
+        let swapped: Option<()> = redis::pipe().atomic().set(key, jz).ignore().query_async(&mut *rconn).await?;
+        Ok(swapped.is_some())
+    }
 
+    /// Increment a counter and (re)set its expiry in one round trip, returning the new count.
+    /// With a key per time window (i.e. per second), this counts events for a rate limit
+    pub async fn incr_ex(pool: &RedisPool, key: &str, seconds_expiry: usize) -> Result<u64, PachyDarn> {
+        ttl_millis(Duration::from_secs(seconds_expiry as u64))?;
+        let mut rconn = pool.get().await?;
+        let (count,): (u64,) = redis::pipe().atomic().incr(key, 1).expire(key, seconds_expiry).ignore().query_async(&mut *rconn).await?;
+        Ok(count)
+    }
 
+    /// Increment a field of a hash and (re)set the expiry of the hash in one round trip, returning the field's new count
+    pub async fn hincr_ex(pool: &RedisPool, key: &str, field: &str, seconds_expiry: usize) -> Result<u64, PachyDarn> {
+        ttl_millis(Duration::from_secs(seconds_expiry as u64))?;
+        let mut rconn = pool.get().await?;
+        let (count,): (u64,) = redis::pipe().atomic().hincr(key, field, 1).expire(key, seconds_expiry).ignore().query_async(&mut *rconn).await?;
+        Ok(count)
+    }
 
+    /// Increment a member of a sorted set, drop all but the max_members highest scored and (re)set the expiry of the set
+    /// in one round trip, returning the member's new score. The member itself is dropped if it isn't in the top max_members
+    pub async fn zincr_ex_trim(pool: &RedisPool, key: &str, member: &str, seconds_expiry: usize, max_members: usize) -> Result<u64, PachyDarn> {
+        ttl_millis(Duration::from_secs(seconds_expiry as u64))?;
+        let keep = isize::try_from(max_members).unwrap_or(isize::MAX).max(1);
+        let mut rconn = pool.get().await?;
+        let (score,): (f64,) = redis::pipe().atomic()
+            .zincr(key, member, 1)
+            .zremrangebyrank(key, 0, -keep - 1).ignore()
+            .expire(key, seconds_expiry).ignore()
+            .query_async(&mut *rconn).await?;
+        Ok(score as u64)
+    }
 
-#[cfg(test)]
-mod tests {
-    use mobc_redis;
-    use rand::{Rng, distributions::Alphanumeric}; 
-    use tokio::runtime::Runtime;
-    use serde::{Serialize, Deserialize};
-    use super::*;
+    /// The members of a sorted set of counters (i.e. written by zincr_ex_trim) scored at least min_score, with their
+    /// scores, highest first. Empty if the key doesn't exist
+    pub async fn zrevrange_min_u64(pool: &RedisPool, key: &str, min_score: u64) -> Result<Vec<(String, u64)>, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let scored: Vec<(String, f64)> = rconn.zrevrangebyscore_withscores(key, "+inf", min_score).await?;
+        Ok(scored.into_iter().map(|(member, score)| (member, score as u64)).collect())
+    }
 
-    // use different keys for different tests-
-    // remember they all get executed at once asynchronously 
-    const OBSCURE_TEST_KEY_1: &'static str = "_OBSCURE_TEST_KEY_1";
-    const OBSCURE_TEST_KEY_2: &'static str = "_OBSCURE_TEST_KEY_2";
+    /// Every field of a hash of counters (i.e. written by hincr_ex), or an empty map if the key doesn't exist
+    pub async fn hgetall_u64(pool: &RedisPool, key: &str) -> Result<HashMap<String, u64>, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let counts: HashMap<String, u64> = rconn.hgetall(key).await?;
+        Ok(counts)
+    }
 
-    fn gen_rand_int() -> i32 {
-        rand::thread_rng().gen_range(1..1000)
+    /// push a string onto the end of a list, i.e. to queue work for a consumer
+    pub async fn rpush_str(pool: &RedisPool, key: &str, val: &str) -> Result<(), PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let _ : () = rconn.rpush(key, val).await?;
+        Ok(())
     }
 
-    #[derive(Serialize, Deserialize)]
-    struct DemoStruct {
-        id: i32,
-        name: String,
+    /// pop the string at the front of a list, or None if it is empty
+    pub async fn lpop_str(pool: &RedisPool, key: &str) -> Result<Option<String>, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let val: Option<String> = redis::cmd("LPOP").arg(key).query_async(&mut *rconn).await?;
+        Ok(val)
     }
 
-    impl mobc_redis::redis::ToRedisArgs for DemoStruct {
-        fn write_redis_args<W>(&self, out: &mut W)
-            where
-                W: ?Sized + mobc_redis::redis::RedisWrite {
-            out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize DemoStruct"))
+    // whether the server understands SET key value GET (Redis 6.2+): 0 if unknown, 1 if it does, 2 if it doesn't
+    static SET_GET_SUPPORT: AtomicU8 = AtomicU8::new(0);
+
+    /// Atomically set the key to new_value and return the value it held before (or None if it didn't exist),
+    /// i.e. to rotate a lock token. This uses SET key value GET, falling back to the deprecated GETSET
+    /// on servers older than Redis 6.2 (which is detected the first time it is used)
+    pub async fn getset<T: Serialize + DeserializeOwned>(pool: &RedisPool, key: &str, new_value: &T) -> Result<Option<T>, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let jz: String = serde_json::to_string(new_value)?;
+        let old: Option<String> = match SET_GET_SUPPORT.load(Ordering::Relaxed) {
+            2 => redis::cmd("GETSET").arg(key).arg(&jz).query_async(&mut *rconn).await?,
+            support => match redis::cmd("SET").arg(key).arg(&jz).arg("GET").query_async(&mut *rconn).await {
+                Ok(old) => {
+                    SET_GET_SUPPORT.store(1, Ordering::Relaxed);
+                    old
+                },
+                // older servers reject the GET option as a syntax error
+                Err(e) if support == 0 && e.to_string().to_lowercase().contains("syntax") => {
+                    SET_GET_SUPPORT.store(2, Ordering::Relaxed);
+                    redis::cmd("GETSET").arg(key).arg(&jz).query_async(&mut *rconn).await?
+                },
+                Err(e) => return Err(e.into()),
+            },
+        };
+        match old {
+            Some(jz) => Ok(Some(serde_json::from_str(&jz)?)),
+            None => Ok(None),
         }
     }
 
-    #[test]
-    fn get_set_int() {
-        // ensure you can set and get a value 
-        let rt = Runtime::new().unwrap();
-        rt.block_on(async {
-            let rpool = new_pool_from_env().await.unwrap();
-            let mut rconn = rpool.get().await.unwrap();
-            let rand_int = gen_rand_int();
-            let _ : () = rconn.set(OBSCURE_TEST_KEY_1, rand_int).await.unwrap();
-            let ox: Option<i32> = rconn.get(OBSCURE_TEST_KEY_1).await.unwrap();
-            assert_eq!(ox.unwrap(), rand_int);
-            println!("redis::get_set_int passed: {} == {}", ox.unwrap(), rand_int);
-
+    /// Add elements to a HyperLogLog, i.e. to count unique visitors without storing every ID.
+    /// Returns true if the estimated cardinality changed 
+    pub async fn pfadd(pool: &RedisPool, key: &str, elements: &[&str]) -> Result<bool, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let changed: bool = rconn.pfadd(key, elements).await?;
+        Ok(changed)
+    }
+
+    /// The estimated number of distinct elements added to a HyperLogLog.
+    /// If several keys are given, this is the estimate for their union 
+    pub async fn pfcount(pool: &RedisPool, keys: &[&str]) -> Result<u64, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let count: u64 = rconn.pfcount(keys).await?;
+        Ok(count)
+    }
+
+    /// Merge the source HyperLogLogs into dest_key, so it estimates the union of them all (and its own prior contents)
+    pub async fn pfmerge(pool: &RedisPool, dest_key: &str, source_keys: &[&str]) -> Result<(), PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let _ : () = rconn.pfmerge(dest_key, source_keys).await?;
+        Ok(())
+    }
+
+    /// The SCAN MATCH pattern for keys starting with prefix, with the glob characters in prefix escaped 
+    fn prefix_pattern(prefix: &str) -> String {
+        let mut pattern = String::with_capacity(prefix.len() + 1);
+        for c in prefix.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('*');
+        pattern
+    }
+
+    /// The keys matching a glob-style pattern (i.e. "autocomp_animal_*"), at most max_results of them.
+    /// This iterates SCAN rather than calling KEYS: KEYS walks the whole keyspace in one command, blocking every
+    /// other client of a busy Redis until it finishes, while each SCAN call only looks at a few keys.
+    /// Keys added or removed during the scan may or may not be included, and a key SCAN returns twice is only listed once
+    pub async fn keys_matching(pool: &RedisPool, pattern: &str, max_results: Option<usize>) -> Result<Vec<String>, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let mut cursor: u64 = 0;
+        let mut seen = HashSet::new();
+        let mut matching = Vec::new();
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(pattern).arg("COUNT").arg(100)
+                .query_async(&mut *rconn).await?;
+            for key in keys {
+                if matches!(max_results, Some(max) if matching.len() >= max) {
+                    return Ok(matching)
+                }
+                if seen.insert(key.clone()) {
+                    matching.push(key);
+                }
+            }
+            if next == 0 {
+                return Ok(matching)
+            }
+            cursor = next;
+        }
+    }
+
+    /// Delete every key starting with prefix, returning how many were deleted.
+    /// This uses SCAN rather than KEYS so it doesn't block Redis, but keys written while it runs may survive 
+    pub async fn delete_by_prefix(pool: &RedisPool, prefix: &str) -> Result<u64, PachyDarn> {
+        Ok(delete_by_prefix_with(pool, prefix, DryRun::Execute).await?.deleted)
+    }
+
+    /// How many keys delete_by_prefix_with reports by name
+    pub const FLUSH_SAMPLE_KEYS: usize = 10;
+
+    /// The keys a flush by prefix matched, and how many it deleted
+    #[derive(Serialize, Debug)]
+    pub struct FlushReport {
+        pub mode: DryRun,
+        /// SCAN can return a key more than once, so this is an upper bound
+        pub matching_keys: u64,
+        /// up to FLUSH_SAMPLE_KEYS of the matching keys
+        pub sample_keys: Vec<String>,
+        /// always 0 for a Preview
+        pub deleted: u64,
+    }
+
+    /// Like delete_by_prefix, but return a FlushReport. With DryRun::Preview the keys are only counted and sampled
+    pub async fn delete_by_prefix_with(pool: &RedisPool, prefix: &str, dry_run: DryRun) -> Result<FlushReport, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let pattern = prefix_pattern(prefix);
+        let mut cursor: u64 = 0;
+        let mut report = FlushReport{mode: dry_run, matching_keys: 0, sample_keys: Vec::new(), deleted: 0};
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(100)
+                .query_async(&mut *rconn).await?;
+            report.matching_keys += keys.len() as u64;
+            let room = FLUSH_SAMPLE_KEYS.saturating_sub(report.sample_keys.len());
+            report.sample_keys.extend(keys.iter().take(room).cloned());
+            if dry_run == DryRun::Execute && !keys.is_empty() {
+                let n: u64 = rconn.del(&keys).await?;
+                report.deleted += n;
+            }
+            if next == 0 {
+                return Ok(report)
+            }
+            cursor = next;
+        }
+    }
+
+    /// The type of value stored at a key, as reported by the Redis TYPE command
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RedisKeyType {
+        String,
+        List,
+        Set,
+        ZSet,
+        Hash,
+    }
+
+    /// Report the type of value stored at a key, i.e. to check a key holds a string before calling get,
+    /// or to debug a key written as a string by one code path and expected as a set by another.
+    /// Returns None if the key does not exist. Types not in RedisKeyType (i.e. streams) yield an error
+    pub async fn type_of(pool: &RedisPool, key: &str) -> Result<Option<RedisKeyType>, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let key_type: String = redis::cmd("TYPE").arg(key).query_async(&mut *rconn).await?;
+        match key_type.as_str() {
+            "none" => Ok(None),
+            "string" => Ok(Some(RedisKeyType::String)),
+            "list" => Ok(Some(RedisKeyType::List)),
+            "set" => Ok(Some(RedisKeyType::Set)),
+            "zset" => Ok(Some(RedisKeyType::ZSet)),
+            "hash" => Ok(Some(RedisKeyType::Hash)),
+            other => Err(PachyDarn::custom("unsupported_redis_type", format!("key {} has unsupported type {}", key, other))),
+        }
+    }
+
+    /// The access frequency counter (OBJECT FREQ) of a key, or None if the key does not exist.
+    /// The counter drives eviction under the allkeys-lfu and volatile-lfu maxmemory policies, 
+    /// so this shows which autocomplete keys are at risk of being evicted before they are warmed again.
+    /// Under any other policy Redis refuses the command and PachyDarn::Redis is returned 
+    pub async fn object_freq(pool: &RedisPool, key: &str) -> Result<Option<u64>, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let freq: Option<u64> = redis::cmd("OBJECT").arg("FREQ").arg(key).query_async(&mut *rconn).await?;
+        Ok(freq)
+    }
+
+    /// The value of a key serialized by Redis (DUMP), or None if the key does not exist. Pass it to restore
+    /// to copy the value to another key or Redis instance without deserializing it, whatever its type
+    pub async fn dump(pool: &RedisPool, key: &str) -> Result<Option<Vec<u8>>, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let serialized: Option<Vec<u8>> = redis::cmd("DUMP").arg(key).query_async(&mut *rconn).await?;
+        Ok(serialized)
+    }
+
+    /// Create key from a value serialized by dump (RESTORE), expiring after ttl_ms milliseconds (0 for no expiry).
+    /// To keep the expiry of the original key, read its PTTL before dumping it. If key already exists Redis
+    /// refuses with a BUSYKEY error, so delete it first to overwrite it
+    pub async fn restore(pool: &RedisPool, key: &str, ttl_ms: u64, serialized: &[u8]) -> Result<(), PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let _ : () = redis::cmd("RESTORE").arg(key).arg(ttl_ms).arg(serialized).query_async(&mut *rconn).await?;
+        Ok(())
+    }
+
+    /// Copy the value of source to dest (COPY), whatever its type. Returns false if nothing was copied because dest
+    /// already exists and replace is false (or source doesn't exist). The copy keeps no TTL; see copy_key_ex
+    pub async fn copy_key(pool: &RedisPool, source: &str, dest: &str, replace: bool) -> Result<bool, PachyDarn> {
+        let mut rconn = pool.get().await?;
+        let mut cmd = redis::cmd("COPY");
+        cmd.arg(source).arg(dest);
+        if replace {
+            cmd.arg("REPLACE");
+        }
+        let copied: bool = cmd.query_async(&mut *rconn).await?;
+        Ok(copied)
+    }
+
+    // COPY has no EX option, so the copy and its EXPIRE run in one script: otherwise a failed copy would set the
+    // TTL of the dest that already existed, and a successful one could briefly exist without a TTL
+    const COPY_EX_SCRIPT: &str = "
+        local copied
+        if ARGV[2] == '1' then
+            copied = redis.call('COPY', KEYS[1], KEYS[2], 'REPLACE')
+        else
+            copied = redis.call('COPY', KEYS[1], KEYS[2])
+        end
+        if copied == 1 then
+            redis.call('PEXPIRE', KEYS[2], ARGV[1])
+        end
+        return copied";
+
+    /// Like copy_key, but dest expires after ttl, which is checked like set_ex's. The copy and the expiry are atomic,
+    /// and the TTL of an existing dest is left alone if nothing was copied
+    pub async fn copy_key_ex(pool: &RedisPool, source: &str, dest: &str, ttl: Duration, replace: bool) -> Result<bool, PachyDarn> {
+        let ttl_ms = ttl_millis(ttl)?;
+        let mut rconn = pool.get().await?;
+        let copied: bool = redis::cmd("EVAL").arg(COPY_EX_SCRIPT).arg(2).arg(source).arg(dest).arg(ttl_ms).arg(if replace { "1" } else { "0" })
+            .query_async(&mut *rconn).await?;
+        Ok(copied)
+    }
+
+    // a sorted set of the hits in the window, scored by the server's clock in microseconds so every process agrees on it.
+    // A hit's member is its time and the count before it, which is unique since hits in the same microsecond count up.
+    // The member is built from TIME's strings, as Lua would format the number now with only 14 significant digits
+    const SLIDING_WINDOW_SCRIPT: &str = "
+        local time = redis.call('TIME')
+        local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+        redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - tonumber(ARGV[2]) * 1000)
+        local count = redis.call('ZCARD', KEYS[1])
+        if count >= tonumber(ARGV[1]) then
+            return 0
+        end
+        redis.call('ZADD', KEYS[1], now, time[1] .. '.' .. time[2] .. '-' .. count)
+        redis.call('PEXPIRE', KEYS[1], ARGV[2])
+        return 1";
+
+    /// Count a hit against a limit of hits in any window (i.e. the last second), returning false without counting it
+    /// if the limit has been reached. Unlike a counter per fixed window, a burst straddling two windows can't get twice the limit
+    pub async fn sliding_window_hit(pool: &RedisPool, key: &str, limit: usize, window: Duration) -> Result<bool, PachyDarn> {
+        let window_ms = ttl_millis(window)?;
+        let mut rconn = pool.get().await?;
+        let allowed: bool = redis::cmd("EVAL").arg(SLIDING_WINDOW_SCRIPT).arg(1).arg(key).arg(limit).arg(window_ms)
+            .query_async(&mut *rconn).await?;
+        Ok(allowed)
+    }
+
+}
+
+
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+    use mobc_redis;
+    use rand::{Rng, distributions::Alphanumeric}; 
+    use tokio::runtime::Runtime;
+    use serde::{Serialize, Deserialize};
+    use crate::testing::{TestDb, TestRedis, DEMO_SCHEMA_SQL};
+    use super::*;
+
+    // TestRedis namespaces the keys, so tests running at once don't collide and nothing is left behind.
+    // Keys named by a test type's key_prefix() or dtype() can't come from a TestRedis, so those names are unique
+    // to the process instead, i.e. gadget_4242, and the tests delete the keys they wrote
+    fn process_name(cell: &'static OnceLock<String>, name: &str) -> &'static str {
+        cell.get_or_init(|| format!("{}_{}", name, std::process::id()))
+    }
+
+    fn gen_rand_int() -> i32 {
+        rand::thread_rng().gen_range(1..1000)
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+    struct DemoStruct {
+        id: i32,
+        name: String,
+    }
+
+    impl mobc_redis::redis::ToRedisArgs for DemoStruct {
+        fn write_redis_args<W>(&self, out: &mut W)
+            where
+                W: ?Sized + mobc_redis::redis::RedisWrite {
+            out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize DemoStruct"))
+        }
+    }
+
+    #[test]
+    fn get_set_int() {
+        // ensure you can set and get a value 
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let key = test_redis.key("int");
+            let mut rconn = test_redis.pool().get().await.unwrap();
+            let rand_int = gen_rand_int();
+            let _ : () = rconn.set(&key, rand_int).await.unwrap();
+            let ox: Option<i32> = rconn.get(&key).await.unwrap();
+            assert_eq!(ox.unwrap(), rand_int);
+            crate::utils::pachy_log!(debug, "pachydurable::redis", "redis::get_set_int passed: {} == {}", ox.unwrap(), rand_int);
+
         })
     }
 
@@ -409,21 +1438,1176 @@ mod tests {
         // ensure you save and load an instance of a struct 
         let rt = Runtime::new().unwrap();
         rt.block_on(async {
-            let rpool = new_pool_from_env().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = test_redis.key("struct");
             // ensure you get delete a key and then get the None variant back 
-            let _x = rediserde::del(&rpool, OBSCURE_TEST_KEY_2).await.unwrap();
-            let ods2: Option<DemoStruct> = rediserde::get(&rpool, OBSCURE_TEST_KEY_2).await.unwrap();
+            let _x = rediserde::del(rpool, &key).await.unwrap();
+            let ods2: Option<DemoStruct> = rediserde::get(rpool, &key).await.unwrap();
             assert!(ods2.is_none());
             // Then set it and ensure you can get the Some() variant back
             let id = gen_rand_int();
             let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(7).map(char::from).collect();
             let ds = DemoStruct{id, name};
-            let _x = rediserde::set(&rpool, OBSCURE_TEST_KEY_2, &ds).await.unwrap();
-            let ods2: Option<DemoStruct> = rediserde::get(&rpool, OBSCURE_TEST_KEY_2).await.unwrap();
+            let _x = rediserde::set(rpool, &key, &ds).await.unwrap();
+            let ods2: Option<DemoStruct> = rediserde::get(rpool, &key).await.unwrap();
             let ds2 = ods2.unwrap();
             assert_eq!(&ds.id, &ds2.id);
             assert_eq!(&ds.name, &ds2.name);
         })
     }
-}
 
+    #[test]
+    fn compare_and_swap() {
+        // ensure cas only swaps when the current value matches the expected value
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = test_redis.key("cas");
+            let first = DemoStruct{id: 1, name: "first".to_string()};
+            let second = DemoStruct{id: 2, name: "second".to_string()};
+            let third = DemoStruct{id: 3, name: "third".to_string()};
+            let _x = rediserde::set(rpool, &key, &first).await.unwrap();
+            // the expected value matches, so the swap succeeds
+            assert!(rediserde::cas(rpool, &key, &first, &second).await.unwrap());
+            // the value is now second, so expecting first fails and leaves the value untouched
+            assert!(!rediserde::cas(rpool, &key, &first, &third).await.unwrap());
+            let current: Option<DemoStruct> = rediserde::get(rpool, &key).await.unwrap();
+            assert_eq!(current.unwrap(), second);
+            // a value that doesn't deserialize is an error, and leaves the (only) connection watching nothing:
+            // a write to that key would otherwise abort the next swap on the connection
+            let single: RedisPool = Pool::builder().max_open(1).build(RedisConnectionManager::new(new_client_from_env().unwrap()));
+            let garbage = test_redis.key("cas_garbage");
+            rediserde::set(rpool, &garbage, &"not a DemoStruct").await.unwrap();
+            assert!(rediserde::cas(&single, &garbage, &first, &second).await.is_err());
+            rediserde::set(rpool, &garbage, &"still not a DemoStruct").await.unwrap();
+            assert!(rediserde::cas(&single, &key, &second, &third).await.unwrap());
+        })
+    }
+
+    #[test]
+    fn type_of_keys() {
+        use rediserde::RedisKeyType;
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let (string_key, set_key) = (test_redis.key("string"), test_redis.key("set"));
+            assert_eq!(rediserde::type_of(rpool, &string_key).await.unwrap(), None);
+            let _x = rediserde::set(rpool, &string_key, &1).await.unwrap();
+            assert_eq!(rediserde::type_of(rpool, &string_key).await.unwrap(), Some(RedisKeyType::String));
+            let _x = rediserde::sadd_str(rpool, &set_key, "emu").await.unwrap();
+            assert_eq!(rediserde::type_of(rpool, &set_key).await.unwrap(), Some(RedisKeyType::Set));
+        })
+    }
+
+    #[test]
+    fn smove_between_sets() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let (pending, processing) = (test_redis.key("pending"), test_redis.key("processing"));
+            let _x = rediserde::sadd_str(rpool, &pending, "job_1").await.unwrap();
+            assert!(rediserde::smove_str(rpool, &pending, &processing, "job_1").await.unwrap());
+            assert!(!rediserde::sismember_str(rpool, &pending, "job_1").await.unwrap());
+            assert!(rediserde::sismember_str(rpool, &processing, "job_1").await.unwrap());
+            // it has already been moved, so a second worker gets false
+            assert!(!rediserde::smove_str(rpool, &pending, &processing, "job_1").await.unwrap());
+        })
+    }
+
+    #[test]
+    fn sismember_any_checks_every_val() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = test_redis.key("empty_prefixes");
+            let _x = rediserde::sadd_str(rpool, &key, "ott").await.unwrap();
+            assert!(rediserde::sismember_any_str(rpool, &key, &["o", "ot", "ott"]).await.unwrap());
+            assert!(!rediserde::sismember_any_str(rpool, &key, &["s", "se"]).await.unwrap());
+            assert!(!rediserde::sismember_any_str(rpool, &key, &[]).await.unwrap());
+        })
+    }
+
+    #[test]
+    fn sliding_window_limits_hits() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = test_redis.key("rate");
+            let window = Duration::from_millis(300);
+            for _ in 0..3 {
+                assert!(rediserde::sliding_window_hit(rpool, &key, 3, window).await.unwrap());
+            }
+            assert!(!rediserde::sliding_window_hit(rpool, &key, 3, window).await.unwrap());
+            // the refused hit isn't counted, so the window frees up once the first hits leave it
+            tokio::time::sleep(window).await;
+            assert!(rediserde::sliding_window_hit(rpool, &key, 3, window).await.unwrap());
+        })
+    }
+
+    #[test]
+    fn sliding_window_admits_exactly_the_limit() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = test_redis.key("burst");
+            let window = Duration::from_secs(30);
+            // a burst of hits landing within the same few microseconds must each get their own member
+            let hits = (0..60).map(|_| rediserde::sliding_window_hit(rpool, &key, 20, window));
+            let admitted = futures_util::future::join_all(hits).await.into_iter().filter(|hit| *hit.as_ref().unwrap()).count();
+            assert_eq!(admitted, 20);
+            let mut rconn = rpool.get().await.unwrap();
+            let members: usize = redis::cmd("ZCARD").arg(&key).query_async(&mut *rconn).await.unwrap();
+            assert_eq!(members, 20);
+        })
+    }
+
+    #[test]
+    fn getset_returns_previous_value() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = test_redis.key("lock_token");
+            assert_eq!(rediserde::getset::<String>(rpool, &key, &"token_1".to_string()).await.unwrap(), None);
+            assert_eq!(rediserde::getset(rpool, &key, &"token_2".to_string()).await.unwrap(), Some("token_1".to_string()));
+            assert_eq!(rediserde::get::<String>(rpool, &key).await.unwrap(), Some("token_2".to_string()));
+        })
+    }
+
+    #[test]
+    fn object_freq_of_keys() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = test_redis.key("freq");
+            assert_eq!(rediserde::object_freq(rpool, &key).await.unwrap(), None);
+            let _x = rediserde::set(rpool, &key, &1).await.unwrap();
+            // the counter only exists under an LFU maxmemory-policy
+            match rediserde::object_freq(rpool, &key).await {
+                Ok(freq) => assert!(freq.is_some()),
+                Err(PachyDarn::Redis(_)) => {},
+                Err(e) => panic!("expected a Redis error, got {}", e),
+            }
+        })
+    }
+
+    #[test]
+    fn hyperloglog() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let (monday, tuesday, week) = (test_redis.key("monday"), test_redis.key("tuesday"), test_redis.key("week"));
+            assert!(rediserde::pfadd(rpool, &monday, &["emu", "ibis", "kea"]).await.unwrap());
+            // nothing new, so the estimate doesn't change 
+            assert!(!rediserde::pfadd(rpool, &monday, &["emu"]).await.unwrap());
+            assert!(rediserde::pfadd(rpool, &tuesday, &["kea", "moa"]).await.unwrap());
+            assert_eq!(rediserde::pfcount(rpool, &[&monday]).await.unwrap(), 3);
+            // the union counts kea once
+            assert_eq!(rediserde::pfcount(rpool, &[&monday, &tuesday]).await.unwrap(), 4);
+            rediserde::pfmerge(rpool, &week, &[&monday, &tuesday]).await.unwrap();
+            assert_eq!(rediserde::pfcount(rpool, &[&week]).await.unwrap(), 4);
+        })
+    }
+
+    #[test]
+    fn dump_and_restore() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let (source, copy, members) = (test_redis.key("source"), test_redis.key("copy"), test_redis.key("members"));
+            let val = DemoStruct{id: gen_rand_int(), name: "dumped".to_string()};
+            rediserde::set(rpool, &source, &val).await.unwrap();
+            let serialized = rediserde::dump(rpool, &source).await.unwrap().unwrap();
+            rediserde::restore(rpool, &copy, 60_000, &serialized).await.unwrap();
+            assert_eq!(rediserde::get::<DemoStruct>(rpool, &copy).await.unwrap(), Some(val));
+            let mut rconn = rpool.get().await.unwrap();
+            let pttl: i64 = mobc_redis::redis::cmd("PTTL").arg(&copy).query_async(&mut *rconn).await.unwrap();
+            assert!(pttl > 0 && pttl <= 60_000, "{}", pttl);
+            // the key already exists
+            assert!(rediserde::restore(rpool, &copy, 0, &serialized).await.is_err());
+            // values of any type can be copied
+            rediserde::sadd_str(rpool, &members, "kea").await.unwrap();
+            let serialized = rediserde::dump(rpool, &members).await.unwrap().unwrap();
+            rediserde::del(rpool, &members).await.unwrap();
+            rediserde::restore(rpool, &members, 0, &serialized).await.unwrap();
+            assert!(rediserde::sismember_str(rpool, &members, "kea").await.unwrap());
+            assert!(rediserde::dump(rpool, &test_redis.key("missing")).await.unwrap().is_none());
+        })
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    struct SlowGizmo {
+        id: i32,
+    }
+
+    impl Cacheable for SlowGizmo {
+        fn key_prefix() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "slow_gizmo") }
+        fn seconds_expiry() -> usize { 60 }
+        fn query() -> &'static str { "SELECT $1::INT, pg_sleep(2)::TEXT" }
+        fn from_row<R: RowLike>(row: &R) -> Self { SlowGizmo{id: row.get(0)} }
+    }
+
+    fn deadline_phase(res: Result<impl std::fmt::Debug, PachyDarn>) -> &'static str {
+        match res {
+            Err(PachyDarn::DeadlineExceeded{phase, elapsed}) => {
+                assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+                phase
+            },
+            other => panic!("expected DeadlineExceeded, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deadlines_name_the_phase_that_ran_out() {
+        use crate::{connect::{get_vec_within, Row}, utils::Deadline};
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("").await.unwrap();
+            let pg = db.pool().clone();
+            let mut client = pg.get().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let budget = || Some(Deadline::after(Duration::from_millis(200)));
+            let text_of = |row: &Row| row.get::<_, String>(0);
+            // the Postgres query is slow: the deadline passes during it, and Postgres stops it
+            let id = gen_rand_int();
+            let res: Result<Option<SlowGizmo>, PachyDarn> = cached_or_cache_within(&mut client, test_redis.pool(), &[&id], budget()).await;
+            assert_eq!(deadline_phase(res), "postgres");
+            let res = get_vec_within(&mut client, "SELECT pg_sleep(2)::TEXT", &text_of, &[], budget()).await;
+            assert_eq!(deadline_phase(res), "postgres");
+            let start = std::time::Instant::now();
+            let rows = client.query("SELECT 1", &[]).await.unwrap();
+            assert_eq!(rows.len(), 1);
+            assert!(start.elapsed() < Duration::from_secs(1), "the timed out queries were still running");
+            // every connection of the Redis pool is checked out, so the deadline passes waiting for one
+            let single: RedisPool = Pool::builder().max_open(1).build(RedisConnectionManager::new(new_client_from_env().unwrap()));
+            let _held = single.get().await.unwrap();
+            let res: Result<Option<SlowGizmo>, PachyDarn> = cached_or_cache_within(&mut client, &single, &[&id], budget()).await;
+            assert_eq!(deadline_phase(res), "redis");
+            // the deadline is an ordinary value, so it applies inside a spawned task too
+            let (spawn_pg, spawn_redis, deadline) = (pg.clone(), test_redis.pool().clone(), budget());
+            let res = tokio::spawn(async move {
+                let mut client = spawn_pg.get().await.unwrap();
+                let res: Result<Option<SlowGizmo>, PachyDarn> = cached_or_cache_within(&mut client, &spawn_redis, &[&id], deadline).await;
+                res
+            }).await.unwrap();
+            assert_eq!(deadline_phase(res), "postgres");
+            // without a deadline nothing is timed, and a phase that finishes in time is unaffected
+            let rows = get_vec_within(&mut client, "SELECT pg_sleep(0.3)::TEXT", &text_of, &[], None).await.unwrap();
+            assert_eq!(rows.len(), 1);
+            let rows = get_vec_within(&mut client, "SELECT 'quick'::TEXT", &text_of, &[], budget()).await.unwrap();
+            assert_eq!(rows, vec!["quick".to_string()]);
+        })
+    }
+
+    #[test]
+    fn ttls_are_durations() {
+        // the Duration defaults convert the seconds methods
+        assert_eq!(<SlowGizmo as Cacheable>::ttl(), Duration::from_secs(60));
+        assert_eq!(<DemoAutoComp as CachedAutoComp<i32>>::ttl(), Duration::from_secs(60));
+        assert_eq!(rediserde::ttl_millis(Duration::from_micros(1)).unwrap(), 1);
+        assert_eq!(rediserde::ttl_millis(Duration::from_millis(1500)).unwrap(), 1500);
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = test_redis.key("brief");
+            // a sub-second TTL is set to the millisecond rather than rounded to 0 or 1s
+            rediserde::set_ex(rpool, &key, &"brief", Duration::from_millis(300)).await.unwrap();
+            let mut rconn = rpool.get().await.unwrap();
+            let pttl: i64 = mobc_redis::redis::cmd("PTTL").arg(&key).query_async(&mut *rconn).await.unwrap();
+            assert!(pttl > 0 && pttl <= 300, "{}", pttl);
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            assert_eq!(rediserde::get::<String>(rpool, &key).await.unwrap(), None);
+            // a zero TTL is an error rather than a write that is evicted at once, as is one too long for Redis
+            for ttl in [Duration::ZERO, Duration::MAX] {
+                match rediserde::set_ex(rpool, &key, &"never", ttl).await {
+                    Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "invalid_ttl"),
+                    other => panic!("expected an invalid_ttl error, got {:?}", other),
+                }
+            }
+            assert!(rediserde::incr_ex(rpool, &key, 0).await.is_err());
+            assert_eq!(rediserde::get::<String>(rpool, &key).await.unwrap(), None);
+        })
+    }
+
+    #[test]
+    fn copy_keys() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let (shared, mine, expiring) = (test_redis.key("shared"), test_redis.key("mine"), test_redis.key("expiring"));
+            let val = DemoStruct{id: gen_rand_int(), name: "shared".to_string()};
+            rediserde::set(rpool, &shared, &val).await.unwrap();
+            assert!(rediserde::copy_key(rpool, &shared, &mine, false).await.unwrap());
+            assert_eq!(rediserde::get::<DemoStruct>(rpool, &mine).await.unwrap(), Some(val.clone()));
+            // dest exists, so only replace copies over it
+            let other = DemoStruct{id: gen_rand_int(), name: "other".to_string()};
+            rediserde::set(rpool, &shared, &other).await.unwrap();
+            assert!(!rediserde::copy_key(rpool, &shared, &mine, false).await.unwrap());
+            assert_eq!(rediserde::get::<DemoStruct>(rpool, &mine).await.unwrap(), Some(val));
+            assert!(rediserde::copy_key(rpool, &shared, &mine, true).await.unwrap());
+            assert_eq!(rediserde::get::<DemoStruct>(rpool, &mine).await.unwrap(), Some(other.clone()));
+            // the copy expires, and a refused copy leaves the TTL of dest alone
+            let mut rconn = rpool.get().await.unwrap();
+            let minute = Duration::from_secs(60);
+            assert!(rediserde::copy_key_ex(rpool, &shared, &expiring, minute, false).await.unwrap());
+            let ttl: i64 = mobc_redis::redis::cmd("TTL").arg(&expiring).query_async(&mut *rconn).await.unwrap();
+            assert!(ttl > 0 && ttl <= 60, "{}", ttl);
+            assert!(!rediserde::copy_key_ex(rpool, &shared, &mine, minute, false).await.unwrap());
+            let ttl: i64 = mobc_redis::redis::cmd("TTL").arg(&mine).query_async(&mut *rconn).await.unwrap();
+            assert_eq!(ttl, -1);
+            assert!(rediserde::copy_key_ex(rpool, &shared, &mine, minute, true).await.unwrap());
+            let ttl: i64 = mobc_redis::redis::cmd("TTL").arg(&mine).query_async(&mut *rconn).await.unwrap();
+            assert!(ttl > 0 && ttl <= 60, "{}", ttl);
+            // a zero TTL would expire the copy at once, so it is refused before anything is copied
+            match rediserde::copy_key_ex(rpool, &shared, &test_redis.key("zero"), Duration::ZERO, false).await {
+                Err(PachyDarn::Custom{kind, ..}) => assert_eq!(kind, "invalid_ttl"),
+                other => panic!("expected invalid_ttl, got {:?}", other),
+            }
+            assert_eq!(rediserde::get::<DemoStruct>(rpool, &test_redis.key("zero")).await.unwrap(), None);
+            assert!(!rediserde::copy_key(rpool, &test_redis.key("missing"), &test_redis.key("nowhere"), true).await.unwrap());
+        })
+    }
+
+    struct DemoAutoComp;
+
+    impl AutoComp<i32> for DemoAutoComp {
+        fn query_autocomp() -> &'static str {
+            "SELECT id, name FROM animals WHERE autocomp_tsv @@ to_tsquery('simple', $1) ORDER BY name LIKE $2 || '%' DESC, name LIMIT 5;"
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            WhoWhatWhere::new("animal", row.get(0), row.get(1))
+        }
+    }
+
+    impl CachedAutoComp<i32> for DemoAutoComp {
+        fn dtype() -> &'static str {
+            static NAME: OnceLock<String> = OnceLock::new();
+            process_name(&NAME, "demo_animal")
+        }
+        fn seconds_expiry() -> usize {
+            60
+        }
+        fn prewarm_depth() -> PreWarmDepth {
+            PreWarmDepth::Char2
+        }
+    }
+
+    #[test]
+    fn pre_envelope_values_are_a_cache_miss() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(DEMO_SCHEMA_SQL).await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            // cache a bare Vec, the way autocomplete results were cached before CacheEnvelope
+            let key = autocomp_key::<i32, DemoAutoComp>("zq");
+            let bare: Vec<WhoWhatWhere<i32>> = Vec::new();
+            let _x = rediserde::set(rpool, &key, &bare).await.unwrap();
+            let envelope = cached_autocomp_envelope::<i32, DemoAutoComp>(rpool, &client, "zq").await.unwrap();
+            assert_eq!(envelope.etag, strong_etag(&serde_json::to_vec(&envelope.value).unwrap()));
+            // the envelope replaced the bare value
+            let cached: Option<CacheEnvelope<Vec<WhoWhatWhere<i32>>>> = rediserde::get(rpool, &key).await.unwrap();
+            assert_eq!(cached.unwrap().etag, envelope.etag);
+            let _x = rediserde::del(rpool, &key).await.unwrap();
+        })
+    }
+
+    thread_local! {
+        // the tenant TenantAutoComp caches for, as a request-scoped tenant ID would be
+        static TENANT: std::cell::RefCell<Option<String>> = std::cell::RefCell::new(None);
+    }
+
+    fn set_tenant(tenant: Option<&str>) {
+        TENANT.with(|cell| *cell.borrow_mut() = tenant.map(str::to_string));
+    }
+
+    struct TenantAutoComp;
+
+    impl AutoComp<i32> for TenantAutoComp {
+        fn query_autocomp() -> &'static str {
+            DemoAutoComp::query_autocomp()
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            DemoAutoComp::rowfunc_autocomp(row)
+        }
+    }
+
+    impl CachedAutoComp<i32> for TenantAutoComp {
+        fn dtype() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "tenant_animal") }
+        fn seconds_expiry() -> usize { 60 }
+        fn prewarm_depth() -> PreWarmDepth { PreWarmDepth::Char1 }
+        fn cache_namespace() -> Option<String> { TENANT.with(|cell| cell.borrow().clone()) }
+    }
+
+    #[test]
+    fn cache_namespace_separates_tenants() {
+        let dtype = TenantAutoComp::dtype();
+        set_tenant(None);
+        assert_eq!(autocomp_key::<i32, TenantAutoComp>("fi"), format!("autocomp_{}_fi", dtype));
+        assert_eq!(empty_prefixes_key::<i32, TenantAutoComp>(), format!("autocomp_empty_{}", dtype));
+        set_tenant(Some("acme"));
+        assert_eq!(autocomp_key::<i32, TenantAutoComp>("fi"), format!("autocomp_{}_ns4:acme_fi", dtype));
+        assert_eq!(empty_prefixes_key::<i32, TenantAutoComp>(), format!("autocomp_empty_{}_ns4:acme", dtype));
+        // the length of the namespace keeps namespace "a" + phrase "b_c" apart from namespace "a_b" + phrase "c"
+        set_tenant(Some("a"));
+        let short_ns = autocomp_key::<i32, TenantAutoComp>("b_c");
+        set_tenant(Some("a_b"));
+        assert_ne!(short_ns, autocomp_key::<i32, TenantAutoComp>("c"));
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(DEMO_SCHEMA_SQL).await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            set_tenant(Some("tenant_a"));
+            let hits = cached_autocomp::<i32, TenantAutoComp>(rpool, &client, "fi").await.unwrap();
+            assert_eq!(hits.len(), 1);
+            let key_a = autocomp_key::<i32, TenantAutoComp>("fi");
+            set_tenant(Some("tenant_b"));
+            let key_b = autocomp_key::<i32, TenantAutoComp>("fi");
+            // tenant_a's hits were cached under its own key, so tenant_b has nothing cached yet
+            let cached_a: Option<CacheEnvelope<Vec<WhoWhatWhere<i32>>>> = rediserde::get(rpool, &key_a).await.unwrap();
+            let cached_b: Option<CacheEnvelope<Vec<WhoWhatWhere<i32>>>> = rediserde::get(rpool, &key_b).await.unwrap();
+            assert_eq!(cached_a.unwrap().value.len(), 1);
+            assert!(cached_b.is_none());
+            let _x = rediserde::delete_by_prefix(rpool, &format!("autocomp_{}_", dtype)).await;
+        });
+        set_tenant(None);
+    }
+
+    #[test]
+    fn messy_phrases_share_a_cache_entry() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(DEMO_SCHEMA_SQL).await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = autocomp_key::<i32, DemoAutoComp>("fi");
+            let _x = rediserde::del(rpool, &key).await;
+            let pks_names = |hits: &[WhoWhatWhere<i32>]| hits.iter().map(|hit| (hit.pk, hit.name.clone())).collect::<Vec<(i32, String)>>();
+            let expected = pks_names(&DemoAutoComp::exec_autocomp(&client, "fi").await.unwrap());
+            assert_eq!(expected.iter().map(|(_, name)| name.as_str()).collect::<Vec<&str>>(), vec!["fish"]);
+            let variants = ["fi", " fi", "fi ", "FI", "Fi", "  fI  ", "\tfi\n", "fi!", "fi:*", "(fi)", "'fi'", "fi &", "!fi|"];
+            for variant in variants {
+                assert_eq!(normalize_phrase(variant), "fi");
+                assert_eq!(autocomp_key::<i32, DemoAutoComp>(variant), key);
+                let hits = cached_autocomp::<i32, DemoAutoComp>(rpool, &client, variant).await.unwrap();
+                assert_eq!(pks_names(&hits), expected, "{:?}", variant);
+            }
+            // internal whitespace is collapsed rather than removed, so different phrases keep different keys
+            assert_eq!(autocomp_key::<i32, DemoAutoComp>("dog   food"), autocomp_key::<i32, DemoAutoComp>("Dog food"));
+            for different in ["f i", "fis", "f", "fi-", "fi_"] {
+                assert_ne!(autocomp_key::<i32, DemoAutoComp>(different), key, "{:?}", different);
+            }
+            let _x = rediserde::del(rpool, &key).await;
+        })
+    }
+
+    // three types that all (accidentally) use the "user" key_prefix 
+    #[derive(Serialize, Deserialize)]
+    struct UntaggedUser;
+    #[derive(Serialize, Deserialize)]
+    struct TaggedUser;
+    #[derive(Serialize, Deserialize)]
+    struct TaggedAccount;
+
+    macro_rules! user_cacheable {
+        ($name:ident, $tagged:expr) => {
+            impl Cacheable for $name {
+                fn key_prefix() -> &'static str { "user" }
+                fn seconds_expiry() -> usize { 60 }
+                fn query() -> &'static str { "SELECT 1" }
+                fn from_row<R: RowLike>(_row: &R) -> Self { $name }
+                fn include_type_tag() -> bool { $tagged }
+            }
+        };
+    }
+    user_cacheable!(UntaggedUser, false);
+    user_cacheable!(TaggedUser, true);
+    user_cacheable!(TaggedAccount, true);
+
+    #[test]
+    fn type_tags_separate_shared_prefixes() {
+        let id: i32 = 7;
+        assert_eq!(UntaggedUser::redis_key(&[&id]), "cacheable_user_7");
+        let user_key = TaggedUser::redis_key(&[&id]);
+        let account_key = TaggedAccount::redis_key(&[&id]);
+        assert!(user_key.starts_with("cacheable_user_t") && user_key.ends_with("_7"), "{}", user_key);
+        assert_ne!(user_key, account_key);
+        assert_eq!(user_key, TaggedUser::redis_key(&[&id]));
+    }
+
+    #[test]
+    fn prewarm_phrase_count() {
+        // 36 single characters, each followed by 42 second characters 
+        let phrases = prewarm_phrases::<i32, DemoAutoComp>();
+        assert_eq!(phrases.len(), 36*(1+42));
+        assert!(phrases.iter().all(|phrase| phrase.chars().count() <= 2));
+        assert_eq!(&phrases[0..3], &["a", "aa", "ab"]);
+    }
+
+    struct AdaptiveAutoComp;
+
+    impl AutoComp<i32> for AdaptiveAutoComp {
+        fn query_autocomp() -> &'static str {
+            DemoAutoComp::query_autocomp()
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            DemoAutoComp::rowfunc_autocomp(row)
+        }
+    }
+
+    impl CachedAutoComp<i32> for AdaptiveAutoComp {
+        fn dtype() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "adaptive_animal") }
+        fn seconds_expiry() -> usize { 60 }
+        fn prewarm_depth() -> PreWarmDepth { PreWarmDepth::Adaptive{min_hits: 2} }
+    }
+
+    #[test]
+    fn zincr_ex_trim_keeps_the_top_members() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let key = test_redis.key("counts");
+            for member in ["a", "b", "b", "c", "c", "c"] {
+                rediserde::zincr_ex_trim(test_redis.pool(), &key, member, 60, 2).await.unwrap();
+            }
+            assert_eq!(rediserde::zrevrange_min_u64(test_redis.pool(), &key, 1).await.unwrap(), vec![("c".to_string(), 3), ("b".to_string(), 2)]);
+            assert_eq!(rediserde::zrevrange_min_u64(test_redis.pool(), &key, 3).await.unwrap(), vec![("c".to_string(), 3)]);
+            // a new member below the top 2 is dropped straight away
+            assert_eq!(rediserde::zincr_ex_trim(test_redis.pool(), &key, "d", 60, 2).await.unwrap(), 1);
+            assert_eq!(rediserde::zrevrange_min_u64(test_redis.pool(), &key, 1).await.unwrap().len(), 2);
+        })
+    }
+
+    #[test]
+    fn adaptive_warming_follows_logged_queries() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(DEMO_SCHEMA_SQL).await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let counts_key = autocomp_queries_key(AdaptiveAutoComp::dtype());
+            let _x = rediserde::del(rpool, &counts_key).await;
+            let keys: Vec<String> = ["fi", "ca", "do"].iter().map(|phrase| autocomp_key::<i32, AdaptiveAutoComp>(phrase)).collect();
+            for key in &keys {
+                let _x = rediserde::del(rpool, key).await;
+            }
+            assert!(prewarm_phrases::<i32, AdaptiveAutoComp>().is_empty());
+            for phrase in ["fi", " FI", "fi!", "ca", "Ca", "do", ""] {
+                log_autocomp_query(rpool, AdaptiveAutoComp::dtype(), phrase).await.unwrap();
+            }
+            assert_eq!(adaptive_phrases(rpool, AdaptiveAutoComp::dtype(), 2).await.unwrap(), vec!["fi", "ca"]);
+            assert_eq!(adaptive_phrases(rpool, AdaptiveAutoComp::dtype(), 1).await.unwrap(), vec!["fi", "ca", "do"]);
+            let preview = warm_the_cache_with::<i32, AdaptiveAutoComp>(rpool, &client, DryRun::Preview).await.unwrap();
+            assert_eq!(preview.phrases, vec!["fi", "ca"]);
+            let stats = warm_the_cache_cancellable::<i32, AdaptiveAutoComp>(rpool, &client, CancellationToken::new()).await.unwrap();
+            assert_eq!(stats.phrases_warmed, 2);
+            // the rarely searched phrase wasn't warmed
+            for (key, warmed) in keys.iter().zip([true, true, false]) {
+                assert_eq!(rediserde::type_of(rpool, key).await.unwrap().is_some(), warmed, "{}", key);
+                let _x = rediserde::del(rpool, key).await;
+            }
+            let _x = rediserde::del(rpool, &counts_key).await;
+        })
+    }
+
+    struct TinyAutoComp;
+
+    impl AutoComp<i32> for TinyAutoComp {
+        fn query_autocomp() -> &'static str {
+            DemoAutoComp::query_autocomp()
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            DemoAutoComp::rowfunc_autocomp(row)
+        }
+    }
+
+    impl CachedAutoComp<i32> for TinyAutoComp {
+        fn dtype() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "tiny_animal") }
+        fn seconds_expiry() -> usize { 60 }
+        fn prewarm_depth() -> PreWarmDepth { PreWarmDepth::Char1 }
+        fn prewarm_chars1() -> &'static str { "qz" }
+        fn cache_namespace() -> Option<String> { Some("dry_run_test".to_string()) }
+    }
+
+    struct RefreshAutoComp;
+
+    impl AutoComp<i32> for RefreshAutoComp {
+        fn query_autocomp() -> &'static str {
+            DemoAutoComp::query_autocomp()
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            DemoAutoComp::rowfunc_autocomp(row)
+        }
+    }
+
+    impl CachedAutoComp<i32> for RefreshAutoComp {
+        fn dtype() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "refresh_animal") }
+        fn seconds_expiry() -> usize { 60 }
+        fn prewarm_depth() -> PreWarmDepth { PreWarmDepth::Char1 }
+        fn prewarm_chars1() -> &'static str { "bcdf" }
+        fn cache_namespace() -> Option<String> { Some("refresher_test".to_string()) }
+    }
+
+    // a pool that records when each client was checked out
+    struct CountingPool {
+        pool: crate::connect::ConnPoolNoTLS,
+        checkouts: Arc<std::sync::Mutex<Vec<std::time::Instant>>>,
+    }
+
+    #[async_trait]
+    impl PgPoolLike for CountingPool {
+        type Client = crate::connect::ClientNoTLS;
+
+        async fn client(&self) -> Result<Self::Client, PachyDarn> {
+            self.checkouts.lock().unwrap().push(std::time::Instant::now());
+            Ok(self.pool.get().await?)
+        }
+    }
+
+    #[test]
+    fn refresher_paces_and_skips_busy_ticks() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(DEMO_SCHEMA_SQL).await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let checkouts = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let pg_pool = CountingPool{pool: db.pool().clone(), checkouts: checkouts.clone()};
+            // 4 phrases at 10 queries per second take over 300ms, so most of the 50ms ticks find the last one still running
+            let config = RefreshConfig{interval: Duration::from_millis(50), concurrency: 2, max_queries_per_second: 10, phrases: PhraseSource::Prewarm};
+            let (join, refresher) = spawn_autocomp_refresher::<i32, RefreshAutoComp, _>(rpool.clone(), pg_pool, config);
+            let mut reports = refresher.reports();
+            let report = tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    reports.changed().await.unwrap();
+                    let report = reports.borrow().clone();
+                    if report.tick >= 2 {
+                        return report
+                    }
+                }
+            }).await.unwrap();
+            refresher.shutdown();
+            join.await.unwrap();
+            assert_eq!((report.phrases_refreshed, report.errors), (4, 0));
+            assert!(report.skipped_ticks > 0, "{:?}", report);
+            // the queries were spaced at least ~100ms apart, within and across ticks
+            let checkouts = checkouts.lock().unwrap().clone();
+            assert!(checkouts.len() >= 8);
+            for pair in checkouts.windows(2) {
+                assert!(pair[1] - pair[0] >= Duration::from_millis(80), "{:?}", pair[1] - pair[0]);
+            }
+            for phrase in prewarm_phrases::<i32, RefreshAutoComp>() {
+                let _x = rediserde::del(rpool, &autocomp_key::<i32, RefreshAutoComp>(&phrase)).await;
+            }
+        })
+    }
+
+    #[test]
+    fn warm_preview_matches_execute() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let keys: Vec<String> = ["q", "z"].iter().map(|phrase| autocomp_key::<i32, TinyAutoComp>(phrase)).collect();
+            for key in &keys {
+                let _x = rediserde::del(rpool, key).await;
+            }
+            // the mock has no replies, so any query in a preview would fail
+            let client = crate::testing::MockClient::new();
+            let preview = warm_the_cache_with::<i32, TinyAutoComp>(rpool, &client, DryRun::Preview).await.unwrap();
+            assert_eq!((preview.phrases.clone(), preview.estimated_queries), (vec!["q".to_string(), "z".to_string()], 2));
+            assert!(client.calls().is_empty());
+            for key in &keys {
+                assert!(rediserde::type_of(rpool, key).await.unwrap().is_none());
+            }
+            let client = crate::testing::MockClient::new().with_rows(vec![]).with_rows(vec![]);
+            let report = warm_the_cache_with::<i32, TinyAutoComp>(rpool, &client, DryRun::Execute).await.unwrap();
+            assert_eq!(report.phrases, preview.phrases);
+            assert_eq!((client.calls().len(), report.stats.phrases_warmed), (preview.estimated_queries, 2));
+            for key in &keys {
+                assert!(rediserde::type_of(rpool, key).await.unwrap().is_some());
+                let _x = rediserde::del(rpool, key).await;
+            }
+        })
+    }
+
+    struct MonotoneAutoComp;
+
+    impl AutoComp<i32> for MonotoneAutoComp {
+        fn query_autocomp() -> &'static str {
+            DemoAutoComp::query_autocomp()
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            DemoAutoComp::rowfunc_autocomp(row)
+        }
+    }
+
+    impl CachedAutoComp<i32> for MonotoneAutoComp {
+        fn dtype() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "monotone_animal") }
+        fn seconds_expiry() -> usize { 60 }
+        fn prewarm_depth() -> PreWarmDepth { PreWarmDepth::Char1 }
+        fn cache_namespace() -> Option<String> { Some("empty_prefix_test".to_string()) }
+        fn prefix_monotone() -> bool { true }
+        fn empty_prefix_ttl() -> Duration { Duration::from_secs(1) }
+    }
+
+    #[test]
+    fn empty_prefixes_short_circuit() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let phrases = ["Xylo", "xyloc", "xyloca", "xylocab", "xylocabs"];
+            let clean = || async {
+                for phrase in phrases {
+                    let _x = rediserde::del(rpool, &autocomp_key::<i32, MonotoneAutoComp>(phrase)).await;
+                }
+                clear_empty_prefixes::<i32, MonotoneAutoComp>(rpool).await.unwrap();
+            };
+            clean().await;
+            let client = crate::testing::MockClient::new().with_rows(vec![]).with_rows(vec![]).with_rows(vec![]);
+            let search = |phrase| cached_autocomp::<i32, MonotoneAutoComp>(rpool, &client, phrase);
+            assert!(search("Xylo").await.unwrap().is_empty());
+            assert!(rediserde::sismember_str(rpool, &empty_prefixes_key::<i32, MonotoneAutoComp>(), "xylo").await.unwrap());
+            // xylo had no hits, so xyloc can't have any
+            assert!(search("xyloc").await.unwrap().is_empty());
+            assert_eq!(client.calls().len(), 1);
+            // once the set expires, Postgres is asked again
+            tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+            assert!(search("xyloca").await.unwrap().is_empty());
+            assert_eq!(client.calls().len(), 2);
+            assert!(search("xylocab").await.unwrap().is_empty());
+            assert_eq!(client.calls().len(), 2);
+            // as it is once the set is cleared
+            clear_empty_prefixes::<i32, MonotoneAutoComp>(rpool).await.unwrap();
+            assert!(search("xylocabs").await.unwrap().is_empty());
+            assert_eq!(client.calls().len(), 3);
+            clean().await;
+        })
+    }
+
+    #[test]
+    fn analytics_failures_dont_fail_searches() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let key = autocomp_key::<i32, TinyAutoComp>("qa");
+            let _x = rediserde::del(rpool, &key).await;
+            let client = crate::testing::MockClient::new().with_rows(vec![]);
+            let sink = crate::analytics::tests::FailingSink::default();
+            for _ in 0..2 {
+                let hits = cached_autocomp_with_analytics::<i32, TinyAutoComp>(rpool, &client, "qa", Some(&sink)).await.unwrap();
+                assert!(hits.is_empty());
+            }
+            // the second search came from the cache
+            assert_eq!(client.calls().len(), 1);
+            assert_eq!(*sink.calls.lock().unwrap(), vec![("qa".to_string(), 0, false), ("qa".to_string(), 0, true)]);
+            let _x = rediserde::del(rpool, &key).await;
+        })
+    }
+
+    #[test]
+    fn keys_matching_scans() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            for n in 0..250 {
+                rediserde::set(rpool, &test_redis.key(&format!("scan_{}", n)), &n).await.unwrap();
+            }
+            rediserde::set(rpool, &test_redis.key("other"), &0i32).await.unwrap();
+            let pattern = format!("{}*", test_redis.key("scan_"));
+            let mut keys = rediserde::keys_matching(rpool, &pattern, None).await.unwrap();
+            assert_eq!(keys.len(), 250);
+            keys.sort();
+            keys.dedup();
+            assert_eq!(keys.len(), 250);
+            let capped = rediserde::keys_matching(rpool, &pattern, Some(20)).await.unwrap();
+            assert_eq!(capped.len(), 20);
+            assert!(rediserde::keys_matching(rpool, &test_redis.key("nothing_*"), None).await.unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn flush_preview_matches_execute() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            for name in ["flush_a", "flush_b", "flush_c", "kept"] {
+                rediserde::set(rpool, &test_redis.key(name), &1i32).await.unwrap();
+            }
+            let prefix = test_redis.key("flush_");
+            let preview = rediserde::delete_by_prefix_with(rpool, &prefix, DryRun::Preview).await.unwrap();
+            assert_eq!((preview.matching_keys, preview.deleted), (3, 0));
+            let mut sample = preview.sample_keys.clone();
+            sample.sort();
+            assert_eq!(sample, vec![test_redis.key("flush_a"), test_redis.key("flush_b"), test_redis.key("flush_c")]);
+            assert!(rediserde::type_of(rpool, &test_redis.key("flush_a")).await.unwrap().is_some());
+            let report = rediserde::delete_by_prefix_with(rpool, &prefix, DryRun::Execute).await.unwrap();
+            assert_eq!((report.matching_keys, report.deleted), (3, 3));
+            assert!(rediserde::type_of(rpool, &test_redis.key("flush_a")).await.unwrap().is_none());
+            assert!(rediserde::type_of(rpool, &test_redis.key("kept")).await.unwrap().is_some());
+        })
+    }
+
+    #[cfg(feature = "uuid")]
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Gadget {
+        id: uuid::Uuid,
+        name: String,
+    }
+
+    #[cfg(feature = "uuid")]
+    impl Cacheable for Gadget {
+        fn key_prefix() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "gadget") }
+        fn seconds_expiry() -> usize { 60 }
+        fn query() -> &'static str { "SELECT id, name FROM gadgets WHERE id = $1" }
+        fn from_row<R: RowLike>(row: &R) -> Self { Gadget{id: row.get(0), name: row.get(1)} }
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuid_cache_keys_and_round_trip() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let id = uuid::Uuid::new_v4();
+            let key = Gadget::redis_key(&[&id]);
+            assert_eq!(key, format!("cacheable_{}_{}", Gadget::key_prefix(), id));
+            assert_eq!(key, Gadget::redis_key(&[&id.to_string()]));
+            let db = TestDb::new("CREATE TABLE gadgets (id UUID PRIMARY KEY, name VARCHAR NOT NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            client.execute("INSERT INTO gadgets (id, name) VALUES ($1, 'sprocket')", &[&id]).await.unwrap();
+            let gadget: Gadget = cached_or_cache_f(&client, rpool, &[&id]).await.unwrap();
+            assert_eq!(gadget, Gadget{id, name: "sprocket".to_string()});
+            // the second lookup is served from Redis, after the row is gone
+            client.execute("DELETE FROM gadgets", &[]).await.unwrap();
+            let cached: Gadget = cached_or_cache_f(&client, rpool, &[&id]).await.unwrap();
+            assert_eq!(cached.id, id);
+            let _x = rediserde::del(rpool, &key).await.unwrap();
+        })
+    }
+
+    #[cfg(feature = "chrono")]
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct DailySales {
+        day: chrono::NaiveDate,
+        total: i64,
+        closed_at: Option<chrono::DateTime<chrono::Utc>>,
+    }
+
+    #[cfg(feature = "chrono")]
+    impl Cacheable for DailySales {
+        fn key_prefix() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "daily_sales") }
+        fn seconds_expiry() -> usize { 60 }
+        fn query() -> &'static str { "SELECT day, total, closed_at FROM daily_sales WHERE day = $1" }
+        fn from_row<R: RowLike>(row: &R) -> Self { DailySales{day: row.get(0), total: row.get(1), closed_at: row.get(2)} }
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn date_time_cache_keys() {
+        use chrono::{NaiveDate, TimeZone, Utc};
+        let day = NaiveDate::from_ymd_opt(2023, 4, 5).unwrap();
+        assert_eq!(DailySales::redis_key(&[&day]), format!("cacheable_{}_2023-04-05", DailySales::key_prefix()));
+        let dt = Utc.with_ymd_and_hms(2023, 4, 5, 12, 30, 0).unwrap();
+        assert_eq!(cache_key_param(&dt), "2023-04-05T12:30:00Z");
+        assert_eq!(cache_key_param(&dt.naive_utc()), "2023-04-05T12:30:00");
+        assert_eq!(cache_key_param(&Some(day)), "2023-04-05");
+        // other params are unaffected
+        assert_eq!(cache_key_param(&7i32), "7");
+        assert_eq!(cache_key_param(&"kiwi"), "kiwi");
+        assert_eq!(cache_key_param(&None::<NaiveDate>), "None");
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn cacheable_keyed_by_date() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE daily_sales (day DATE PRIMARY KEY, total INT8 NOT NULL, closed_at TIMESTAMPTZ);
+                INSERT INTO daily_sales VALUES ('2023-04-05', 12, '2023-04-05 22:00:00+00'), ('2023-04-06', 3, NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            // Redis is shared between test runs, so each run moves the rows to its own far-future dates
+            let offset = rand::thread_rng().gen_range(1000..100_000);
+            client.execute("UPDATE daily_sales SET day = day + $1::INT", &[&offset]).await.unwrap();
+            let closed_day = chrono::NaiveDate::from_ymd_opt(2023, 4, 5).unwrap() + chrono::Duration::days(offset as i64);
+            let open_day = closed_day.succ_opt().unwrap();
+            let closed: DailySales = cached_or_cache_f(&client, rpool, &[&closed_day]).await.unwrap();
+            assert_eq!(closed.closed_at.unwrap().to_rfc3339(), "2023-04-05T22:00:00+00:00");
+            let open: DailySales = cached_or_cache_f(&client, rpool, &[&open_day]).await.unwrap();
+            assert_eq!((open.total, open.closed_at), (3, None));
+            // the nullable timestamp survives the round trip through Redis
+            let cached: Option<DailySales> = rediserde::get(rpool, &DailySales::redis_key(&[&open_day])).await.unwrap();
+            assert_eq!(cached.unwrap(), open);
+            for day in [closed_day, open_day] {
+                let _x = rediserde::del(rpool, &DailySales::redis_key(&[&day])).await.unwrap();
+            }
+        })
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Pet {
+        name: String,
+    }
+
+    // the pet of a person, who may not have one
+    impl Cacheable for Pet {
+        fn key_prefix() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "pet_of_person") }
+        fn seconds_expiry() -> usize { 60 }
+        fn query() -> &'static str { "SELECT pets.name FROM people LEFT JOIN pets ON pets.owner_id = people.id WHERE people.id = $1" }
+        fn from_row<R: RowLike>(row: &R) -> Self { Pet{name: row.get(0)} }
+        fn row_is_null<R: RowLike>(row: &R) -> bool { row.get::<_, Option<String>>(0).is_none() }
+    }
+
+    #[test]
+    fn null_joined_rows_are_none() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE people (id INT PRIMARY KEY); CREATE TABLE pets (owner_id INT NOT NULL, name VARCHAR NOT NULL);
+                INSERT INTO people VALUES (1), (2); INSERT INTO pets VALUES (1, 'rex');").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            // Redis is shared between test runs, so each run uses its own ids
+            let offset = gen_rand_int() * 1000;
+            client.execute("UPDATE people SET id = id + $1", &[&offset]).await.unwrap();
+            client.execute("UPDATE pets SET owner_id = owner_id + $1", &[&offset]).await.unwrap();
+            let (owner, petless) = (1 + offset, 2 + offset);
+            let pet: Option<Pet> = cached_or_cache(&client, rpool, &[&owner]).await.unwrap();
+            assert_eq!(pet, Some(Pet{name: "rex".to_string()}));
+            let pet: Option<Pet> = cached_or_cache(&client, rpool, &[&petless]).await.unwrap();
+            assert_eq!(pet, None);
+            // a null row isn't cached
+            assert!(rediserde::type_of(rpool, &Pet::redis_key(&[&petless])).await.unwrap().is_none());
+            let _x = rediserde::del(rpool, &Pet::redis_key(&[&owner])).await;
+        })
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Author {
+        id: i32,
+        name: String,
+        post_count: i64,
+    }
+
+    // an author row plus a count from a second table
+    #[async_trait]
+    impl Cacheable for Author {
+        fn key_prefix() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "author") }
+        fn seconds_expiry() -> usize { 60 }
+        fn query() -> &'static str { "SELECT id, name FROM authors WHERE id = $1" }
+        fn from_row<R: RowLike>(row: &R) -> Self { Author{id: row.get(0), name: row.get(1), post_count: 0} }
+
+        async fn fetch<C: PachyClient>(c: &C, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Self>, PachyDarn> {
+            let mut author = match c.query(Self::query(), params).await?.get(0) {
+                Some(row) => Self::from_row(row),
+                None => return Ok(None),
+            };
+            let rows = c.query("SELECT COUNT(*) FROM posts WHERE author_id = $1", &[&author.id]).await?;
+            author.post_count = rows[0].get(0);
+            Ok(Some(author))
+        }
+    }
+
+    #[test]
+    fn computed_values_are_cached() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE authors (id INT PRIMARY KEY, name VARCHAR NOT NULL); CREATE TABLE posts (author_id INT NOT NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            // Redis is shared between test runs, so each run uses its own ids
+            let id = rand::thread_rng().gen_range(1000..1_000_000) * 10;
+            client.execute("INSERT INTO authors VALUES ($1, 'ursula')", &[&id]).await.unwrap();
+            client.execute("INSERT INTO posts SELECT $1 FROM generate_series(1, 3)", &[&id]).await.unwrap();
+            let author: Author = cached_or_cache_f(&client, rpool, &[&id]).await.unwrap();
+            assert_eq!(author, Author{id, name: "ursula".to_string(), post_count: 3});
+            // the computed value is served from Redis, so the new post isn't counted
+            client.execute("INSERT INTO posts VALUES ($1)", &[&id]).await.unwrap();
+            let cached: Author = cached_or_cache_f(&client, rpool, &[&id]).await.unwrap();
+            assert_eq!(cached.post_count, 3);
+            let missing: Option<Author> = cached_or_cache(&client, rpool, &[&(id + 1)]).await.unwrap();
+            assert!(missing.is_none());
+            let _x = rediserde::del(rpool, &Author::redis_key(&[&id])).await;
+        })
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Gizmo {
+        id: i32,
+        name: String,
+    }
+
+    impl Cacheable for Gizmo {
+        fn key_prefix() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "gizmo") }
+        fn seconds_expiry() -> usize { 60 }
+        fn query() -> &'static str { "SELECT id, name FROM gizmos WHERE id = $1" }
+        fn from_row<R: RowLike>(row: &R) -> Self { Gizmo{id: row.get(0), name: row.get(1)} }
+    }
+
+    impl crate::primary_key::GetByPK for Gizmo {
+        fn query_get_by_pk() -> &'static str { Gizmo::query() }
+        fn rowfunc_get_by_pk<R: RowLike>(row: &R) -> Self { Gizmo::from_row(row) }
+    }
+
+    impl GetByPKs for Gizmo {
+        fn query_get_by_pks() -> &'static str { "SELECT id, name FROM gizmos WHERE id = ANY($1)" }
+    }
+
+    impl PkFromRow<i32> for Gizmo {
+        fn pk_from_row<R: RowLike>(row: &R) -> i32 { row.get(0) }
+    }
+
+    #[test]
+    fn hydrate_mixed_hits() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE gizmos (id INT PRIMARY KEY, name VARCHAR NOT NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            // Redis is shared between test runs, so each run uses its own ids
+            let offset = rand::thread_rng().gen_range(1000..1_000_000) * 10;
+            let (cached, uncached, deleted) = (offset + 1, offset + 2, offset + 3);
+            client.execute("INSERT INTO gizmos VALUES ($1, 'sprocket'), ($2, 'cog'), ($3, 'widget')", &[&cached, &uncached, &deleted]).await.unwrap();
+            let _x: Gizmo = cached_or_cache_f(&client, rpool, &[&cached]).await.unwrap();
+            // the cached gizmo is served from Redis, so the rename isn't seen
+            client.execute("UPDATE gizmos SET name = 'flange' WHERE id = $1", &[&cached]).await.unwrap();
+            client.execute("DELETE FROM gizmos WHERE id = $1", &[&deleted]).await.unwrap();
+            let hit = |pk: i32| WhoWhatWhere::new("gizmo", pk, String::new());
+            let hits = vec![hit(uncached), hit(cached), hit(deleted), hit(uncached)];
+            let gizmos: Vec<Option<Gizmo>> = hydrate_hits(&client, rpool, &hits).await.unwrap();
+            let names: Vec<Option<&str>> = gizmos.iter().map(|gizmo| gizmo.as_ref().map(|g| g.name.as_str())).collect();
+            assert_eq!(names, vec![Some("cog"), Some("sprocket"), None, Some("cog")]);
+            // the miss was cached, the deleted row wasn't
+            let cog: Option<Gizmo> = rediserde::get(rpool, &Gizmo::redis_key(&[&uncached])).await.unwrap();
+            assert_eq!(cog, Some(Gizmo{id: uncached, name: "cog".to_string()}));
+            assert!(rediserde::type_of(rpool, &Gizmo::redis_key(&[&deleted])).await.unwrap().is_none());
+            let none: Vec<Option<Gizmo>> = hydrate_hits::<Gizmo, i32>(&client, rpool, &[]).await.unwrap();
+            assert!(none.is_empty());
+            for id in [cached, uncached] {
+                let _x = rediserde::del(rpool, &Gizmo::redis_key(&[&id])).await;
+            }
+        })
+    }
+
+    #[test]
+    fn missing_rows_name_the_type_and_key() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE gizmos (id INT PRIMARY KEY, name VARCHAR NOT NULL);").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            let missing = rand::thread_rng().gen_range(1000..1_000_000) * 10;
+            let err = cached_or_cache_f::<Gizmo>(&client, rpool, &[&missing]).await.unwrap_err();
+            let key = Gizmo::redis_key(&[&missing]);
+            let message = err.to_string();
+            assert!(message.contains(std::any::type_name::<Gizmo>()), "{}", message);
+            assert!(message.contains(&key), "{}", message);
+            assert!(message.contains(&format!("params [{}]", missing)), "{}", message);
+            assert_eq!(err.http_status(), 404);
+            match err.root() {
+                PachyDarn::NotFound{type_name, key: found} => {
+                    assert_eq!(type_name, std::any::type_name::<Gizmo>());
+                    assert_eq!(found, &key);
+                },
+                other => panic!("expected NotFound, got {:?}", other),
+            }
+        })
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Promo {
+        org_id: String,
+        slug: String,
+        name: String,
+    }
+
+    impl Cacheable for Promo {
+        fn key_prefix() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "promo") }
+        fn seconds_expiry() -> usize { 60 }
+        fn query() -> &'static str { "SELECT org_id, slug, name FROM promos WHERE org_id = $1 AND slug = $2" }
+        fn from_row<R: RowLike>(row: &R) -> Self { Promo{org_id: row.get(0), slug: row.get(1), name: row.get(2)} }
+    }
+
+    impl crate::primary_key::GetByPK for Promo {
+        fn query_get_by_pk() -> &'static str { Promo::query() }
+        fn rowfunc_get_by_pk<R: RowLike>(row: &R) -> Self { Promo::from_row(row) }
+    }
+
+    struct PromoAutoComp;
+
+    impl AutoComp<(String, String)> for PromoAutoComp {
+        fn query_autocomp() -> &'static str {
+            "SELECT org_id, slug, name FROM promos WHERE to_tsvector('simple', name) @@ to_tsquery('simple', $1) ORDER BY name"
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<(String, String)> {
+            WhoWhatWhere::new("promo", (row.get(0), row.get(1)), row.get(2))
+        }
+    }
+
+    impl CachedAutoComp<(String, String)> for PromoAutoComp {
+        fn dtype() -> &'static str { static NAME: OnceLock<String> = OnceLock::new(); process_name(&NAME, "promo_test") }
+        fn seconds_expiry() -> usize { 60 }
+        fn prewarm_depth() -> PreWarmDepth { PreWarmDepth::Char1 }
+    }
+
+    #[test]
+    fn composite_primary_keys() {
+        use crate::primary_key::{get_by_key, Key2};
+        assert_eq!(Promo::redis_key_for(&("acme", "summer-sale")), Promo::redis_key(&[&"acme", &"summer-sale"]));
+        assert_ne!(Promo::redis_key_for(&("a_b", "c")), Promo::redis_key_for(&("a", "b_c")));
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new("CREATE TABLE promos (org_id VARCHAR NOT NULL, slug VARCHAR NOT NULL, name VARCHAR NOT NULL, PRIMARY KEY (org_id, slug));").await.unwrap();
+            let client = db.client().await.unwrap();
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = test_redis.pool();
+            // Redis is shared between test runs, so each run uses its own org
+            let org: String = rand::thread_rng().sample_iter(&Alphanumeric).take(8).map(char::from).collect();
+            client.execute("INSERT INTO promos VALUES ($1, 'summer-sale', 'Summer Sale'), ($1, 'spring', 'Spring Sale ' || $1)", &[&org]).await.unwrap();
+            // i.e. from pk=acme/summer-sale
+            let key: Key2<String, String> = format!("{}/summer-sale", org).parse().unwrap();
+            let promo: Promo = get_by_key(&client, &key).await.unwrap();
+            assert_eq!(promo.name, "Summer Sale");
+            let cached: Option<Promo> = cached_or_cache_key(&client, rpool, &key).await.unwrap();
+            assert_eq!(cached.as_ref(), Some(&promo));
+            // the second lookup is served from Redis, after the row is gone
+            client.execute("DELETE FROM promos WHERE slug = 'summer-sale'", &[]).await.unwrap();
+            let cached: Option<Promo> = cached_or_cache_key(&client, rpool, &(org.as_str(), "summer-sale")).await.unwrap();
+            assert_eq!(cached, Some(promo));
+            let hits = cached_autocomp::<(String, String), PromoAutoComp>(rpool, &client, &org).await.unwrap();
+            assert_eq!(hits.len(), 1);
+            assert_eq!(hits[0].pk, (org.clone(), "spring".to_string()));
+            let json = serde_json::to_value(&hits[0]).unwrap();
+            assert_eq!(json["pk"], serde_json::json!([org, "spring"]));
+            let _x = rediserde::del(rpool, &Promo::redis_key_for(&key)).await;
+            let _x = rediserde::del(rpool, &autocomp_key::<(String, String), PromoAutoComp>(&org)).await;
+        })
+    }
+}
@@ -0,0 +1,381 @@
+//! The testing module (enabled with the "testing" feature) gives integration tests throwaway resources,
+//! so tests can run concurrently and leave nothing behind:
+//!
+//! TestDb creates a schema named pachy_test_{random}, points the search_path of its pool at it, runs your
+//! DDL/seed SQL there, and drops the schema (CASCADE) when it goes out of scope.
+//!
+//! TestRedis (with the "redis" feature) hands out keys that all start with a random prefix, and deletes every key
+//! with that prefix when it goes out of scope.
+//! ```ignore
+//! let db = TestDb::new(DEMO_SCHEMA_SQL).await?;
+//! let client = db.client().await?;
+//! let hits = Animal::exec_autocomp(&client, "fi").await?;
+//! let redis = TestRedis::new().await?;
+//! rediserde::set(redis.pool(), &redis.key("greeting"), &"hello").await?;
+//! ```
+//! Both connect using the same environment variables as pool_no_tls_from_env and redis::new_pool_from_env
+//!
+//! Logic that only needs rows can be unit tested without a database at all: MockClient implements PachyClient
+//! and replays canned MockRows, recording every query it was sent.
+//! ```ignore
+//! let client = MockClient::new().with_rows(vec![
+//!     MockRow::new().with("id", Type::INT4, &3i32).with("name", Type::TEXT, &"fish"),
+//! ]);
+//! let hits = Animal::exec_autocomp(&client, "fi").await?;
+//! assert_eq!(hits[0].name, "fish");
+//! assert_eq!(client.calls()[0].params[0], "\"fi:*\"");
+//! ```
+
+use std::{collections::VecDeque, sync::{Mutex, atomic::{AtomicU64, Ordering}}, thread, time::{SystemTime, UNIX_EPOCH}};
+use async_trait::async_trait;
+use bytes::BytesMut;
+use tokio_postgres::{NoTls, types::{FromSql, IsNull, ToSql, Type}};
+use crate::client::{ColumnIdx, PachyClient, RowLike};
+use crate::connect::{pg_config_from, pool_no_tls_from_pg_config, ClientNoTLS, ConnPoolNoTLS, SimpleConfig};
+use crate::err::PachyDarn;
+#[cfg(feature = "redis")]
+use crate::redis::{new_pool_from_env, rediserde, RedisPool};
+use crate::utils::pachy_log;
+
+/// The schema of the example API: the animals and foods tables with a few rows each
+pub const DEMO_SCHEMA_SQL: &str = include_str!("../examples/schema.sql");
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// a suffix unique to this process and call, i.e. "3f9a01c2_7"
+fn unique_suffix() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    let seed = (std::process::id() as u64) << 32 | nanos as u64;
+    format!("{:x}_{}", seed, COUNTER.fetch_add(1, Ordering::SeqCst))
+}
+
+// run the cleanup on its own thread and runtime, since Drop can't await and may run inside another runtime
+fn block_on_cleanup<F: FnOnce() -> Result<(), PachyDarn> + Send + 'static>(what: String, cleanup: F) {
+    let res = thread::spawn(cleanup).join();
+    match res {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => pachy_log!(warn, "pachydurable::testing", "could not clean up {}: {}", what, e),
+        Err(_) => pachy_log!(warn, "pachydurable::testing", "cleaning up {} panicked", what),
+    }
+}
+
+
+/// A throwaway Postgres schema. See the module documentation
+pub struct TestDb {
+    schema: String,
+    config: SimpleConfig,
+    pool: ConnPoolNoTLS,
+}
+
+impl TestDb {
+    /// Create the schema and run setup_sql (i.e. DEMO_SCHEMA_SQL) in it
+    pub async fn new(setup_sql: &str) -> Result<TestDb, PachyDarn> {
+        let config = SimpleConfig::try_new_from_env()?;
+        let schema = format!("pachy_test_{}", unique_suffix());
+        let mut pg_config = pg_config_from(&config);
+        pg_config.options(&format!("-c search_path={}", schema));
+        let pool = pool_no_tls_from_pg_config(pg_config, &config).await?;
+        // from here on, Drop removes the schema even if the setup fails
+        let db = TestDb{schema, config, pool};
+        let client = db.client().await?;
+        client.batch_execute(&format!("CREATE SCHEMA {};", db.schema)).await?;
+        client.batch_execute(setup_sql).await?;
+        Ok(db)
+    }
+
+    /// The name of the schema, i.e. pachy_test_3f9a01c2_7
+    pub fn schema(&self) -> &str {
+        &self.schema
+    }
+
+    /// The pool, whose connections resolve unqualified names in the schema
+    pub fn pool(&self) -> &ConnPoolNoTLS {
+        &self.pool
+    }
+
+    /// Get a client from the pool
+    pub async fn client(&self) -> Result<ClientNoTLS, PachyDarn> {
+        Ok(self.pool.get().await?)
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let pg_config = pg_config_from(&self.config);
+        let schema = self.schema.clone();
+        block_on_cleanup(format!("schema {}", schema), move || {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            rt.block_on(async {
+                let (client, connection) = pg_config.connect(NoTls).await?;
+                tokio::spawn(connection);
+                client.batch_execute(&format!("DROP SCHEMA IF EXISTS {} CASCADE;", schema)).await?;
+                Ok::<(), PachyDarn>(())
+            })
+        });
+    }
+}
+
+
+/// A namespace of Redis keys. See the module documentation
+#[cfg(feature = "redis")]
+pub struct TestRedis {
+    prefix: String,
+    pool: RedisPool,
+}
+
+#[cfg(feature = "redis")]
+impl TestRedis {
+    pub async fn new() -> Result<TestRedis, PachyDarn> {
+        let pool = new_pool_from_env().await?;
+        Ok(TestRedis{prefix: format!("pachy_test_{}:", unique_suffix()), pool})
+    }
+
+    /// The prefix of every key, i.e. pachy_test_3f9a01c2_7:
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The namespaced key for a name, i.e. pachy_test_3f9a01c2_7:greeting
+    pub fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    pub fn pool(&self) -> &RedisPool {
+        &self.pool
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Drop for TestRedis {
+    fn drop(&mut self) {
+        let prefix = self.prefix.clone();
+        block_on_cleanup(format!("keys {}*", prefix), move || {
+            // the pool belongs to the runtime that created it, so the cleanup needs its own
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            rt.block_on(async {
+                let pool = new_pool_from_env().await?;
+                rediserde::delete_by_prefix(&pool, &prefix).await?;
+                Ok::<(), PachyDarn>(())
+            })
+        });
+    }
+}
+
+
+/// A row built by hand. Each value is encoded with ToSql for its Postgres type, and decoded with FromSql by
+/// get/try_get, so the conversions (and type mismatches) behave like they would for a tokio_postgres::Row
+#[derive(Debug, Clone, Default)]
+pub struct MockRow {
+    names: Vec<String>,
+    cells: Vec<(Type, Option<Vec<u8>>)>,
+}
+
+impl MockRow {
+    pub fn new() -> Self {
+        MockRow::default()
+    }
+
+    /// Append a column, i.e. .with("id", Type::INT4, &3i32). Use &None::<T> for NULL.
+    /// Panics if the value can't be encoded as ty, since the mock itself would be wrong
+    pub fn with(mut self, name: &str, ty: Type, value: &(dyn ToSql + Sync)) -> Self {
+        let mut buf = BytesMut::new();
+        let raw = match value.to_sql_checked(&ty, &mut buf) {
+            Ok(IsNull::Yes) => None,
+            Ok(IsNull::No) => Some(buf.to_vec()),
+            Err(e) => panic!("MockRow column {} can't be encoded as {}: {}", name, ty, e),
+        };
+        self.names.push(name.to_string());
+        self.cells.push((ty, raw));
+        self
+    }
+}
+
+impl RowLike for MockRow {
+    fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn try_get<'a, I: ColumnIdx, T: FromSql<'a>>(&'a self, idx: I) -> Result<T, PachyDarn> {
+        let pos = idx.position(&self.names)
+            .ok_or_else(|| PachyDarn::custom("column_error", format!("invalid column {}", idx)))?;
+        let (ty, raw) = &self.cells[pos];
+        if !T::accepts(ty) {
+            return Err(PachyDarn::custom("column_error", format!("cannot convert column {} of type {} to {}", idx, ty, std::any::type_name::<T>())))
+        }
+        T::from_sql_nullable(ty, raw.as_deref())
+            .map_err(|e| PachyDarn::custom("column_error", format!("error deserializing column {}: {}", idx, e)))
+    }
+}
+
+
+/// What a MockClient replies with, in the order they were added
+#[derive(Debug)]
+pub enum MockReply {
+    Rows(Vec<MockRow>),
+    Affected(u64),
+    Err(PachyDarn),
+}
+
+/// A query (or statement) a MockClient was sent. The params are formatted with Debug, i.e. "\"fi:*\""
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockCall {
+    pub sql: String,
+    pub params: Vec<String>,
+}
+
+/// A PachyClient that replays canned replies instead of talking to Postgres. See the module documentation.
+/// Once the replies run out, every call returns a mock_client_exhausted error
+#[derive(Debug, Default)]
+pub struct MockClient {
+    replies: Mutex<VecDeque<MockReply>>,
+    calls: Mutex<Vec<MockCall>>,
+}
+
+impl MockClient {
+    pub fn new() -> Self {
+        MockClient::default()
+    }
+
+    /// reply to the next call to query with these rows
+    pub fn with_rows(self, rows: Vec<MockRow>) -> Self {
+        self.with_reply(MockReply::Rows(rows))
+    }
+
+    /// reply to the next call to execute with this number of modified rows
+    pub fn with_affected(self, n: u64) -> Self {
+        self.with_reply(MockReply::Affected(n))
+    }
+
+    /// reply to the next call with this error
+    pub fn with_err(self, err: PachyDarn) -> Self {
+        self.with_reply(MockReply::Err(err))
+    }
+
+    pub fn with_reply(self, reply: MockReply) -> Self {
+        self.replies.lock().unwrap().push_back(reply);
+        self
+    }
+
+    /// every call the client has been sent so far, oldest first
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    // record the call and pop the next reply
+    fn next_reply(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<MockReply, PachyDarn> {
+        let params = params.iter().map(|param| format!("{:?}", param)).collect();
+        self.calls.lock().unwrap().push(MockCall{sql: sql.to_string(), params});
+        match self.replies.lock().unwrap().pop_front() {
+            Some(MockReply::Err(e)) => Err(e),
+            Some(reply) => Ok(reply),
+            None => Err(PachyDarn::custom("mock_client_exhausted", format!("no reply left for \"{}\"", sql))),
+        }
+    }
+}
+
+#[async_trait]
+impl PachyClient for MockClient {
+    type Row = MockRow;
+
+    async fn query(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<MockRow>, PachyDarn> {
+        match self.next_reply(sql, params)? {
+            MockReply::Rows(rows) => Ok(rows),
+            other => Err(PachyDarn::custom("mock_reply_mismatch", format!("query was sent but the next reply is {:?}", other))),
+        }
+    }
+
+    async fn execute(&self, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, PachyDarn> {
+        match self.next_reply(sql, params)? {
+            MockReply::Affected(n) => Ok(n),
+            other => Err(PachyDarn::custom("mock_reply_mismatch", format!("execute was sent but the next reply is {:?}", other))),
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use super::*;
+
+    async fn animal_names(db: &TestDb) -> Vec<String> {
+        let client = db.client().await.unwrap();
+        let rows = client.query("SELECT name FROM animals ORDER BY name", &[]).await.unwrap();
+        rows.iter().map(|row| row.get(0)).collect()
+    }
+
+    #[test]
+    fn concurrent_test_dbs_are_isolated() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (a, b) = tokio::join!(TestDb::new(DEMO_SCHEMA_SQL), TestDb::new(DEMO_SCHEMA_SQL));
+            let (a, b) = (a.unwrap(), b.unwrap());
+            assert_ne!(a.schema(), b.schema());
+            let (client_a, client_b) = (a.client().await.unwrap(), b.client().await.unwrap());
+            let (res_a, res_b) = tokio::join!(
+                client_a.execute("INSERT INTO animals (name) VALUES ('kea')", &[]),
+                client_b.execute("DELETE FROM animals WHERE name = 'cat'", &[]),
+            );
+            assert_eq!((res_a.unwrap(), res_b.unwrap()), (1, 1));
+            assert_eq!(animal_names(&a).await, vec!["cat", "dog", "emu", "fish", "kea"]);
+            assert_eq!(animal_names(&b).await, vec!["dog", "emu", "fish"]);
+            // dropping the TestDb drops its schema
+            let schema = a.schema().to_string();
+            drop((client_a, client_b));
+            drop(a);
+            let client = b.client().await.unwrap();
+            let row = client.query_one("SELECT COUNT(*) FROM information_schema.schemata WHERE schema_name = $1", &[&schema]).await.unwrap();
+            assert_eq!(row.get::<_, i64>(0), 0);
+        })
+    }
+
+    #[test]
+    fn mock_rows_convert_like_rows() {
+        let row = MockRow::new().with("id", Type::INT4, &3i32).with("name", Type::TEXT, &"fish").with("color", Type::TEXT, &None::<String>);
+        assert_eq!(row.len(), 3);
+        assert_eq!(row.get::<_, i32>(0), 3);
+        assert_eq!(row.get::<_, String>("name"), "fish");
+        assert_eq!(row.get::<_, Option<String>>("color"), None);
+        // NULL into a non-Option, the wrong type, and a missing column are all errors
+        assert!(row.try_get::<_, String>("color").is_err());
+        assert!(row.try_get::<_, i64>(0).is_err());
+        assert!(row.try_get::<_, i32>(3).is_err());
+        assert!(row.try_get::<_, i32>("legs").is_err());
+    }
+
+    #[test]
+    fn mock_client_replays_in_order() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = MockClient::new()
+                .with_rows(vec![MockRow::new().with("n", Type::INT8, &1i64)])
+                .with_affected(2)
+                .with_err(PachyDarn::custom("boom", "the database is on fire"));
+            let rows = client.query("SELECT $1::BIGINT AS n", &[&1i64]).await.unwrap();
+            assert_eq!(rows[0].get::<_, i64>("n"), 1);
+            assert_eq!(client.execute("DELETE FROM animals", &[]).await.unwrap(), 2);
+            assert!(matches!(client.query("SELECT 1", &[]).await, Err(PachyDarn::Custom{kind, ..}) if kind == "boom"));
+            assert!(matches!(client.query("SELECT 1", &[]).await, Err(PachyDarn::Custom{kind, ..}) if kind == "mock_client_exhausted"));
+            let calls = client.calls();
+            assert_eq!(calls.len(), 4);
+            assert_eq!(calls[0], MockCall{sql: "SELECT $1::BIGINT AS n".to_string(), params: vec!["1".to_string()]});
+        })
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn test_redis_cleans_up() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let test_redis = TestRedis::new().await.unwrap();
+            let rpool = new_pool_from_env().await.unwrap();
+            let key = test_redis.key("greeting");
+            assert!(key.starts_with(test_redis.prefix()));
+            rediserde::set(test_redis.pool(), &key, &"hello").await.unwrap();
+            rediserde::sadd_str(test_redis.pool(), &test_redis.key("birds"), "emu").await.unwrap();
+            drop(test_redis);
+            assert_eq!(rediserde::type_of(&rpool, &key).await.unwrap(), None);
+        })
+    }
+}
@@ -0,0 +1,384 @@
+//! The validate module checks implementations of AutoComp, FullText, GetByPK and Cacheable against the live database,
+//! so a query referencing a renamed column is caught at startup rather than when a user first types in a search box.
+//! Each check PREPAREs the trait's SQL, checks it takes as many parameters as the execution function binds, and then
+//! runs it with harmless parameters (a short ts_expression, or NULLs) inside a transaction that is rolled back,
+//! passing any rows returned through the rowfunc to catch type mismatches:
+//! ```ignore
+//! let validators = Validators::new()
+//!     .autocomp::<i32, Animal>()
+//!     .fulltext::<Animal>()
+//!     .get_by_pk::<Food>();
+//! // refuse to boot if any implementation doesn't match the schema
+//! validate_all(&mut client, &validators).await?;
+//! ```
+
+use std::{any::{type_name, Any}, error::Error, fmt, future::Future, panic::{catch_unwind, AssertUnwindSafe}, pin::Pin};
+use bytes::BytesMut;
+use serde::Serialize;
+use tokio_postgres::{Row, Transaction, types::{IsNull, ToSql, Type, to_sql_checked}};
+use crate::{autocomplete::{autocomp_params, AutoComp}, connect::ClientNoTLS, err::PachyDarn, utils::pachy_log};
+use crate::fulltext::{FullText, ts_expression, ts_expression_cfg};
+use crate::primary_key::GetByPK;
+#[cfg(feature = "redis")]
+use crate::redis::Cacheable;
+
+
+// the phrase queries are checked with: short, and not a stopword in any configuration, since to_tsquery('english', 'a:*')
+// is empty and would match no rows for the rowfunc to convert
+pub(crate) const PROBE_PHRASE: &str = "ex";
+
+/// One way an implementation doesn't match the database
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ValidationProblem {
+    /// The implementing type, i.e. "my_crate::models::Animal"
+    pub type_name: &'static str,
+    /// The trait that was checked: "autocomp", "fulltext", "get_by_pk" or "cacheable"
+    pub check: &'static str,
+    /// What went wrong, i.e. the Postgres error message: column "nmae" does not exist
+    pub message: String,
+}
+
+impl fmt::Display for ValidationProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.type_name, self.check, self.message)
+    }
+}
+
+
+// a NULL for a parameter of any type, so queries can be run without knowing their parameter types
+#[derive(Debug)]
+struct AnyNull;
+
+impl ToSql for AnyNull {
+    fn to_sql(&self, _ty: &Type, _out: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        Ok(IsNull::Yes)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+
+// Where a check stands: either it can carry on, or a problem was found and the transaction can't be used any more
+enum Step<T> {
+    Continue(T),
+    Problem(ValidationProblem),
+}
+
+// Run one check against T in a transaction that is always rolled back.
+// Errors from Postgres become problems, unless the connection itself was lost
+struct Check {
+    type_name: &'static str,
+    check: &'static str,
+}
+
+impl Check {
+    fn new<T>(check: &'static str) -> Self {
+        Check{type_name: type_name::<T>(), check}
+    }
+
+    fn problem(&self, message: String) -> ValidationProblem {
+        ValidationProblem{type_name: self.type_name, check: self.check, message}
+    }
+
+    // a closed connection is an error, anything else Postgres complains about is a problem with the implementation
+    fn step<T>(&self, res: Result<T, tokio_postgres::Error>) -> Result<Step<T>, PachyDarn> {
+        match res {
+            Ok(val) => Ok(Step::Continue(val)),
+            Err(e) if e.is_closed() => Err(e.into()),
+            Err(e) => {
+                let message = match e.as_db_error() {
+                    Some(db) => db.message().to_string(),
+                    None => e.to_string(),
+                };
+                Ok(Step::Problem(self.problem(message)))
+            },
+        }
+    }
+
+    // prepare the query, check the number of parameters, then run it with the provided parameters
+    async fn run(&self, tx: &Transaction<'_>, query: &str, params: &[&(dyn ToSql + Sync)], exec_fn: &str) -> Result<Step<Vec<Row>>, PachyDarn> {
+        let stmt = match self.step(tx.prepare(query).await)? {
+            Step::Continue(stmt) => stmt,
+            Step::Problem(p) => return Ok(Step::Problem(p)),
+        };
+        if stmt.params().len() != params.len() {
+            return Ok(Step::Problem(self.problem(format!("the query takes {} parameters, but {} binds {}", stmt.params().len(), exec_fn, params.len()))))
+        }
+        self.step(tx.query(&stmt, params).await)
+    }
+
+    // convert each row with the rowfunc, turning a panic (i.e. from row.get on a mismatched type) into a problem
+    fn convert(&self, rows: &[Row], rowfunc: impl Fn(&Row)) -> Vec<ValidationProblem> {
+        for row in rows {
+            if let Err(payload) = catch_unwind(AssertUnwindSafe(|| rowfunc(row))) {
+                return vec![self.problem(format!("the rowfunc panicked: {}", panic_message(&*payload)))]
+            }
+        }
+        Vec::new()
+    }
+}
+
+// the message a panic was raised with, i.e. "error retrieving column 2: ..."
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+
+/// Check T::query_autocomp() prepares, takes the parameters exec_autocomp binds ($1 and, if referenced, $2), and that the rows it returns
+/// for a short phrase convert with T::rowfunc_autocomp. Returns the problems found, which is empty if T is valid
+pub async fn validate_autocomp<PK: Serialize + Send, T: AutoComp<PK>>(client: &mut ClientNoTLS) -> Result<Vec<ValidationProblem>, PachyDarn> {
+    let check = Check::new::<T>("autocomp");
+    let tx = client.transaction().await?;
+    let (ts_expr, phrase) = (ts_expression_cfg(PROBE_PHRASE, T::ts_config_autocomp()), PROBE_PHRASE);
+    let params = autocomp_params(T::query_autocomp(), &ts_expr, &phrase);
+    let problems = match check.run(&tx, T::query_autocomp(), &params, "exec_autocomp").await? {
+        Step::Continue(rows) => check.convert(&rows, |row| { T::rowfunc_autocomp(row); }),
+        Step::Problem(p) => vec![p],
+    };
+    tx.rollback().await?;
+    Ok(problems)
+}
+
+/// Check T::query_fulltext() prepares, takes the 1 parameter exec_fulltext binds, and that the rows it returns
+/// for a short phrase convert with T::rowfunc_fulltext
+pub async fn validate_fulltext<T: FullText>(client: &mut ClientNoTLS) -> Result<Vec<ValidationProblem>, PachyDarn> {
+    let check = Check::new::<T>("fulltext");
+    let tx = client.transaction().await?;
+    let ts_expr = ts_expression(PROBE_PHRASE);
+    let problems = match check.run(&tx, T::query_fulltext(), &[&ts_expr], "exec_fulltext").await? {
+        Step::Continue(rows) => check.convert(&rows, |row| { T::rowfunc_fulltext(row); }),
+        Step::Problem(p) => vec![p],
+    };
+    tx.rollback().await?;
+    Ok(problems)
+}
+
+// the number of parameters a query takes, so they can all be bound as NULL
+async fn null_params(tx: &Transaction<'_>, check: &Check, query: &str) -> Result<Step<usize>, PachyDarn> {
+    Ok(match check.step(tx.prepare(query).await)? {
+        Step::Continue(stmt) => Step::Continue(stmt.params().len()),
+        Step::Problem(p) => Step::Problem(p),
+    })
+}
+
+/// Check T::query_get_by_pk() prepares and runs with every parameter NULL.
+/// The parameter types aren't known, so rows are only converted if the query returns some for NULLs
+pub async fn validate_get_by_pk<T: GetByPK>(client: &mut ClientNoTLS) -> Result<Vec<ValidationProblem>, PachyDarn> {
+    let check = Check::new::<T>("get_by_pk");
+    let tx = client.transaction().await?;
+    let problems = match null_params(&tx, &check, T::query_get_by_pk()).await? {
+        Step::Continue(n) => {
+            let params: Vec<&(dyn ToSql + Sync)> = (0..n).map(|_| &AnyNull as &(dyn ToSql + Sync)).collect();
+            match check.run(&tx, T::query_get_by_pk(), &params, "get_by_pk").await? {
+                Step::Continue(rows) => check.convert(&rows, |row| { T::rowfunc_get_by_pk(row); }),
+                Step::Problem(p) => vec![p],
+            }
+        },
+        Step::Problem(p) => vec![p],
+    };
+    tx.rollback().await?;
+    Ok(problems)
+}
+
+/// Check T::query() prepares and runs with every parameter NULL, like validate_get_by_pk
+#[cfg(feature = "redis")]
+pub async fn validate_cacheable<T: Cacheable>(client: &mut ClientNoTLS) -> Result<Vec<ValidationProblem>, PachyDarn> {
+    let check = Check::new::<T>("cacheable");
+    let tx = client.transaction().await?;
+    let problems = match null_params(&tx, &check, T::query()).await? {
+        Step::Continue(n) => {
+            let params: Vec<&(dyn ToSql + Sync)> = (0..n).map(|_| &AnyNull as &(dyn ToSql + Sync)).collect();
+            match check.run(&tx, T::query(), &params, "cached_or_cache").await? {
+                Step::Continue(rows) => check.convert(&rows, |row| { T::from_row(row); }),
+                Step::Problem(p) => vec![p],
+            }
+        },
+        Step::Problem(p) => vec![p],
+    };
+    tx.rollback().await?;
+    Ok(problems)
+}
+
+
+/// The future a registered check returns
+pub type ValidationFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<ValidationProblem>, PachyDarn>> + Send + 'a>>;
+
+type ValidationFn = Box<dyn for<'a> Fn(&'a mut ClientNoTLS) -> ValidationFuture<'a> + Send + Sync>;
+
+/// A registry of checks for validate_all to run, i.e. one per trait implementation a service uses
+#[derive(Default)]
+pub struct Validators {
+    checks: Vec<ValidationFn>,
+}
+
+impl Validators {
+    pub fn new() -> Self {
+        Validators::default()
+    }
+
+    /// Register any check, i.e. .check(|c| Box::pin(validate_fulltext::<Animal>(c)))
+    pub fn check<F>(mut self, f: F) -> Self
+    where F: for<'a> Fn(&'a mut ClientNoTLS) -> ValidationFuture<'a> + Send + Sync + 'static {
+        self.checks.push(Box::new(f));
+        self
+    }
+
+    pub fn autocomp<PK: Serialize + Send + 'static, T: AutoComp<PK> + 'static>(self) -> Self {
+        self.check(|c| Box::pin(validate_autocomp::<PK, T>(c)))
+    }
+
+    pub fn fulltext<T: FullText + 'static>(self) -> Self {
+        self.check(|c| Box::pin(validate_fulltext::<T>(c)))
+    }
+
+    pub fn get_by_pk<T: GetByPK + 'static>(self) -> Self {
+        self.check(|c| Box::pin(validate_get_by_pk::<T>(c)))
+    }
+
+    #[cfg(feature = "redis")]
+    pub fn cacheable<T: Cacheable + 'static>(self) -> Self {
+        self.check(|c| Box::pin(validate_cacheable::<T>(c)))
+    }
+
+    /// The number of registered checks
+    pub fn len(&self) -> usize {
+        self.checks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checks.is_empty()
+    }
+
+    /// Run every check, returning all the problems found
+    pub async fn run(&self, client: &mut ClientNoTLS) -> Result<Vec<ValidationProblem>, PachyDarn> {
+        let mut problems = Vec::new();
+        for check in &self.checks {
+            problems.extend(check(&mut *client).await?);
+        }
+        Ok(problems)
+    }
+}
+
+
+/// Run every check in validators, logging each problem. If there are any, a Custom error of kind
+/// schema_validation listing them is returned, so a service can refuse to boot with validate_all(...).await?
+pub async fn validate_all(client: &mut ClientNoTLS, validators: &Validators) -> Result<(), PachyDarn> {
+    let problems = validators.run(client).await?;
+    if problems.is_empty() {
+        return Ok(())
+    }
+    for problem in &problems {
+        pachy_log!(error, "pachydurable::validate", "{}", problem);
+    }
+    let listed: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+    Err(PachyDarn::custom("schema_validation", format!("{} of {} checks failed: {}", problems.len(), validators.len(), listed.join("; "))))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use tokio::runtime::Runtime;
+    use crate::{client::RowLike, autocomplete::WhoWhatWhere, testing::{TestDb, DEMO_SCHEMA_SQL}};
+    use super::*;
+
+    struct Animal;
+
+    impl AutoComp<i32> for Animal {
+        fn query_autocomp() -> &'static str {
+            "SELECT id, name FROM animals WHERE autocomp_tsv @@ to_tsquery('simple', $1) ORDER BY name LIKE $2 || '%' DESC"
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            WhoWhatWhere::new("animal", row.get(0), row.get(1))
+        }
+    }
+
+    impl GetByPK for Animal {
+        fn query_get_by_pk() -> &'static str {
+            "SELECT id, name FROM animals WHERE id = $1"
+        }
+        fn rowfunc_get_by_pk<R: RowLike>(_row: &R) -> Self {
+            Animal
+        }
+    }
+
+    /// the column was renamed from nmae to name, but the query wasn't updated
+    struct BrokenFood;
+
+    impl FullText for BrokenFood {
+        fn query_fulltext() -> &'static str {
+            "SELECT nmae FROM foods WHERE fulltext_tsv @@ to_tsquery('english', $1)"
+        }
+        fn rowfunc_fulltext<R: RowLike>(_row: &R) -> Self {
+            BrokenFood
+        }
+    }
+
+    /// reads the name column as an integer
+    struct MistypedAnimal;
+
+    impl AutoComp<i32> for MistypedAnimal {
+        fn query_autocomp() -> &'static str {
+            "SELECT name FROM animals WHERE $1::text IS NOT NULL AND $2::text IS NOT NULL"
+        }
+        fn rowfunc_autocomp<R: RowLike>(row: &R) -> WhoWhatWhere<i32> {
+            WhoWhatWhere::new("animal", row.get(0), row.get(0))
+        }
+    }
+
+    /// reads the name column as an integer, from an english query that only returns rows for a phrase that isn't a stopword
+    struct MistypedFood;
+
+    impl FullText for MistypedFood {
+        fn query_fulltext() -> &'static str {
+            "SELECT name FROM foods WHERE numnode(to_tsquery('english', $1)) > 0"
+        }
+        fn rowfunc_fulltext<R: RowLike>(row: &R) -> Self {
+            let _: i32 = row.get(0);
+            MistypedFood
+        }
+    }
+
+    #[test]
+    fn validate_against_schema() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let db = TestDb::new(DEMO_SCHEMA_SQL).await.unwrap();
+            let mut client = db.client().await.unwrap();
+            assert!(validate_autocomp::<i32, Animal>(&mut client).await.unwrap().is_empty());
+            assert!(validate_get_by_pk::<Animal>(&mut client).await.unwrap().is_empty());
+            let problems = validate_fulltext::<BrokenFood>(&mut client).await.unwrap();
+            assert_eq!(problems.len(), 1);
+            let message = problems[0].to_string();
+            assert!(message.contains("BrokenFood") && message.contains("nmae"), "{}", message);
+            let problems = validate_autocomp::<i32, MistypedAnimal>(&mut client).await.unwrap();
+            assert!(problems[0].message.contains("rowfunc panicked"), "{:?}", problems);
+            let problems = validate_fulltext::<MistypedFood>(&mut client).await.unwrap();
+            assert!(problems[0].message.contains("rowfunc panicked"), "{:?}", problems);
+            // all the checks run, and the error lists every problem
+            let validators = Validators::new()
+                .autocomp::<i32, Animal>()
+                .fulltext::<BrokenFood>()
+                .get_by_pk::<Animal>();
+            match validate_all(&mut client, &validators).await {
+                Err(PachyDarn::Custom{kind, message, ..}) => {
+                    assert_eq!(kind, "schema_validation");
+                    assert!(message.starts_with("1 of 3 checks failed") && message.contains("BrokenFood"), "{}", message);
+                },
+                other => panic!("expected a schema_validation error, got {:?}", other),
+            }
+            let validators = Validators::new().autocomp::<i32, Animal>();
+            validate_all(&mut client, &validators).await.unwrap();
+        })
+    }
+}